@@ -0,0 +1,182 @@
+//! Error handling
+//!
+//! The server says what went wrong with the status code, and sometimes a [`TeslaError`] body.
+//! The status codes with a meaning of their own get a variant each, the rest keep the body.
+
+use std::time::Duration;
+
+use fla_common::responses::TeslaError;
+use reqwest::{header::RETRY_AFTER, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use thiserror::Error;
+use tokio_tungstenite::tungstenite;
+
+/// An error from a request
+#[derive(Error, Debug)]
+pub enum Error {
+    /// The request could not be sent, or the response could not be read
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+
+    /// The access token has expired, or is not valid (401)
+    #[error("Token expired")]
+    TokenExpired,
+
+    /// The token does not have the scopes needed for the request (403)
+    #[error("Missing scopes")]
+    MissingScopes,
+
+    /// The vehicle or resource does not exist, or is not shared with the user (404)
+    #[error("Not found")]
+    NotFound,
+
+    /// The vehicle is not online, it might be asleep (408)
+    #[error("Vehicle unavailable")]
+    VehicleUnavailable,
+
+    /// The vehicle responded with an error (540)
+    #[error("Vehicle error")]
+    VehicleError,
+
+    /// Too many requests, try again after the given time (429)
+    #[error("Rate limited, retry after {retry_after:?}")]
+    RateLimited { retry_after: Option<Duration> },
+
+    /// Any other error response, with the error from the body
+    #[error("Error response {status}: {error:?}")]
+    Tesla {
+        status: StatusCode,
+        error: TeslaError,
+    },
+
+    /// Any other error response, when the body is not a [`TeslaError`]
+    #[error("Error response {status}: '{body}'")]
+    Http { status: StatusCode, body: String },
+
+    /// The response does not have the expected shape
+    #[error("Cannot deserialize '{path}': {error}, response '{body}'")]
+    Deserialize {
+        path: String,
+        error: serde_json::Error,
+        body: String,
+    },
+
+    /// The websocket could not be opened
+    #[error("WebSocket failed: {0}")]
+    WebSocket(Box<tungstenite::Error>),
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(err: tungstenite::Error) -> Self {
+        Self::WebSocket(Box::new(err))
+    }
+}
+
+impl Error {
+    /// The status code of the response, if there was one
+    #[must_use]
+    pub fn status(&self) -> Option<StatusCode> {
+        match self {
+            Self::Request(err) => err.status(),
+            Self::TokenExpired => Some(StatusCode::UNAUTHORIZED),
+            Self::MissingScopes => Some(StatusCode::FORBIDDEN),
+            Self::NotFound => Some(StatusCode::NOT_FOUND),
+            Self::VehicleUnavailable => Some(StatusCode::REQUEST_TIMEOUT),
+            Self::VehicleError => StatusCode::from_u16(540).ok(),
+            Self::RateLimited { .. } => Some(StatusCode::TOO_MANY_REQUESTS),
+            Self::Tesla { status, .. } | Self::Http { status, .. } => Some(*status),
+            Self::WebSocket(err) => match &**err {
+                tungstenite::Error::Http(response) => Some(response.status()),
+                _ => None,
+            },
+            Self::Deserialize { .. } => None,
+        }
+    }
+
+    /// Turn an error response into an error
+    async fn from_response(response: Response) -> Self {
+        let status = response.status();
+        let retry_after = response
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.parse::<u64>().ok())
+            .map(Duration::from_secs);
+        let body = match response.text().await {
+            Ok(body) => body,
+            Err(err) => return Self::Request(err),
+        };
+        let error = serde_json::from_str::<TeslaError>(&body).ok();
+
+        match (status.as_u16(), error) {
+            (401, _) => Self::TokenExpired,
+            // A driver is also forbidden from some requests, that is not about scopes.
+            (403, None) => Self::MissingScopes,
+            (403, Some(error)) if error.error.contains("missing scopes") => Self::MissingScopes,
+            (404, _) => Self::NotFound,
+            (408, _) => Self::VehicleUnavailable,
+            (429, _) => Self::RateLimited { retry_after },
+            (540, _) => Self::VehicleError,
+            (_, Some(error)) => Self::Tesla { status, error },
+            (_, None) => Self::Http { status, body },
+        }
+    }
+}
+
+/// Send a request, and check the response is not an error
+pub(crate) async fn send(request: reqwest::RequestBuilder) -> Result<Response, Error> {
+    let response = request.send().await?;
+    if response.status().is_success() {
+        Ok(response)
+    } else {
+        Err(Error::from_response(response).await)
+    }
+}
+
+/// Deserialize a response, keeping the body if it does not match
+pub(crate) async fn json<T: DeserializeOwned>(response: Response) -> Result<T, Error> {
+    let body = response.text().await?;
+    let deserializer = &mut serde_json::Deserializer::from_str(&body);
+    serde_path_to_error::deserialize(deserializer).map_err(|err| Error::Deserialize {
+        path: err.path().to_string(),
+        error: err.into_inner(),
+        body,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    async fn error(status: u16, headers: &[(&str, &str)], body: &str) -> Error {
+        let mut response = http::Response::builder().status(status);
+        for (name, value) in headers {
+            response = response.header(*name, *value);
+        }
+        let response = response.body(body.to_string()).unwrap_or_default();
+        Error::from_response(Response::from(response)).await
+    }
+
+    #[tokio::test]
+    async fn test_from_response() {
+        let body =
+            r#"{"error":"Unauthorized missing scopes","error_description":"","messages":{}}"#;
+        assert!(matches!(error(403, &[], body).await, Error::MissingScopes));
+
+        let body = r#"{"error":"Forbidden","error_description":"Forbidden"}"#;
+        let err = error(403, &[], body).await;
+        assert!(matches!(err, Error::Tesla { error, .. } if error.error == "Forbidden"));
+
+        let err = error(429, &[("Retry-After", "30")], "").await;
+        assert!(matches!(
+            err,
+            Error::RateLimited { retry_after: Some(retry_after) } if retry_after == Duration::from_secs(30)
+        ));
+
+        let err = error(540, &[], "Device responded with an error").await;
+        assert_eq!(err.status().map(|status| status.as_u16()), Some(540));
+
+        let err = error(502, &[], "Bad Gateway").await;
+        assert!(matches!(err, Error::Http { body, .. } if body == "Bad Gateway"));
+    }
+}
@@ -1,35 +1,54 @@
-use std::{collections::HashSet, str::FromStr, time::Duration};
+mod errors;
+pub mod streaming;
+mod tokens;
+
+use std::{collections::HashSet, str::FromStr, sync::Arc, time::Duration};
 
 use chrono::{DateTime, Utc};
 use fla_common::{
-    auth::{RawToken, RefreshTokenRequest, TokenRequest},
+    auth::RawToken,
     responses::{
-        TeslaResponse, TeslaResponseSuccess, VehicleDataResponse, VehicleResponse, VehiclesResponse,
+        ClockResponse, FaultsResponse, FleetTelemetryConfigResponse,
+        FleetTelemetryConfigUpdatedResponse, InvitationResponse, InvitationsResponse,
+        MetricsResponse, ParkedPolicyResponse, RateLimitsResponse, RedeemInvitationResponse,
+        SleepPolicyResponse, SnapshotResponse, SnapshotsResponse, TeslaResponse,
+        TeslaResponseSuccess, UsersResponse, VehicleDataResponse, VehicleResponse,
+        VehicleStatusResponse, VehicleStatusesResponse, VehiclesResponse,
+    },
+    simulator::{
+        AdvanceRequest, Fault, FleetVehicle, ParkedPolicy, RateLimits, SimulationStateEnum,
+        SleepPolicy, SteerRequest,
     },
-    simulator::SimulationStateEnum,
-    streaming::{
-        DataError, FromServerStreamingMessage, StreamingData, StreamingFields,
-        ToServerStreamingMessage,
+    streaming::{StreamingData, StreamingFields, StreamingOptions},
+    telemetry::{FleetTelemetryConfig, FleetTelemetryConfigRequest, Payload},
+    types::{
+        InvitationId, RedeemInvitationRequest, Timestamp, UserId, VehicleData, VehicleDataEndpoint,
+        VehicleDefinition, VehicleGuid, VehicleId, VehicleStateEnum,
     },
-    types::{Timestamp, VehicleData, VehicleDataEndpoint, VehicleGuid, VehicleId},
 };
-use futures_util::{SinkExt, StreamExt};
-use http::StatusCode;
-use tap::{Pipe, Tap};
+use futures_util::{Stream, StreamExt};
+use http::{header::AUTHORIZATION, HeaderValue};
+use prost::Message as _;
+use reqwest::StatusCode;
+use serde::de::DeserializeOwned;
+use tap::Pipe;
 use thiserror::Error;
-use tokio::{select, sync::mpsc};
+use tokio::{select, sync::mpsc, time::Instant};
 use tokio_tungstenite::{
     connect_async,
-    tungstenite::{
-        protocol::{frame::coding::CloseCode, CloseFrame},
-        Error, Message,
-    },
+    tungstenite::{self, client::IntoClientRequest, Message},
 };
 use tracing::{debug, error};
 use url::Url;
 
+use crate::tokens::Tokens;
+
+pub use crate::errors::Error;
+pub use crate::streaming::{Backoff, StreamingError, StreamingEvent, StreamingSession};
+pub use crate::tokens::TokenCallback;
+
 /// A new token
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Token {
     /// The access token
     pub access_token: String,
@@ -71,12 +90,60 @@ pub struct NoTokens {}
 
 pub struct HasToken {
     token: Token,
+    on_token_updated: Option<TokenCallback>,
+    http: HttpOptions,
+}
+
+/// How requests are sent
+struct HttpOptions {
+    client: Option<reqwest::Client>,
+    timeout: Duration,
+    connect_timeout: Duration,
+    user_agent: String,
+    proxies: Vec<reqwest::Proxy>,
+    root_certificates: Vec<reqwest::Certificate>,
+}
+
+impl Default for HttpOptions {
+    fn default() -> Self {
+        Self {
+            client: None,
+            timeout: Duration::from_secs(30),
+            connect_timeout: Duration::from_secs(10),
+            user_agent: concat!("fla_client/", env!("CARGO_PKG_VERSION")).into(),
+            proxies: Vec::new(),
+            root_certificates: Vec::new(),
+        }
+    }
+}
+
+impl HttpOptions {
+    fn build(self) -> Result<reqwest::Client, reqwest::Error> {
+        if let Some(client) = self.client {
+            return Ok(client);
+        }
+
+        let builder = reqwest::Client::builder()
+            .timeout(self.timeout)
+            .connect_timeout(self.connect_timeout)
+            .user_agent(self.user_agent);
+        let builder = self
+            .proxies
+            .into_iter()
+            .fold(builder, reqwest::ClientBuilder::proxy);
+        self.root_certificates
+            .into_iter()
+            .fold(builder, reqwest::ClientBuilder::add_root_certificate)
+            .build()
+    }
 }
 
 pub struct Config<T> {
     auth_url: Option<String>,
     owner_url: Option<String>,
     streaming_url: Option<String>,
+    streaming_backoff: Backoff,
+    wake_backoff: Backoff,
     extra: T,
 }
 
@@ -84,6 +151,9 @@ pub struct Config<T> {
 pub enum ConfigBuildError {
     #[error("{0}")]
     UrlParseError(#[from] url::ParseError),
+
+    #[error("Cannot create HTTP client: {0}")]
+    HttpClientError(#[from] reqwest::Error),
 }
 
 impl Config<NoTokens> {
@@ -92,6 +162,8 @@ impl Config<NoTokens> {
             auth_url: None,
             owner_url: None,
             streaming_url: None,
+            streaming_backoff: Backoff::default(),
+            wake_backoff: Backoff::default(),
             extra: NoTokens {},
         }
     }
@@ -101,7 +173,13 @@ impl Config<NoTokens> {
             auth_url: self.auth_url,
             owner_url: self.owner_url,
             streaming_url: self.streaming_url,
-            extra: HasToken { token },
+            streaming_backoff: self.streaming_backoff,
+            wake_backoff: self.wake_backoff,
+            extra: HasToken {
+                token,
+                on_token_updated: None,
+                http: HttpOptions::default(),
+            },
         }
     }
 }
@@ -127,15 +205,78 @@ impl<T> Config<T> {
         self.streaming_url = Some(streaming_url.into());
         self
     }
+
+    /// How long streaming waits before connecting or subscribing again
+    pub fn streaming_backoff(mut self, streaming_backoff: Backoff) -> Self {
+        self.streaming_backoff = streaming_backoff;
+        self
+    }
+
+    /// How long [`Client::wake_and_wait`] waits before checking the vehicle again
+    pub fn wake_backoff(mut self, wake_backoff: Backoff) -> Self {
+        self.wake_backoff = wake_backoff;
+        self
+    }
 }
 
 impl Config<HasToken> {
+    /// Call this with the new token after it is refreshed, to save the new refresh token
+    pub fn on_token_updated(mut self, callback: impl Fn(&Token) + Send + Sync + 'static) -> Self {
+        self.extra.on_token_updated = Some(Box::new(callback));
+        self
+    }
+
+    /// Send requests with this client, to share its connections with other clients
+    ///
+    /// The other HTTP options are ignored, they are set when the client is built.
+    pub fn http_client(mut self, client: reqwest::Client) -> Self {
+        self.extra.http.client = Some(client);
+        self
+    }
+
+    /// How long a request can take, from connecting until the response has been read
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.extra.http.timeout = timeout;
+        self
+    }
+
+    /// How long connecting to the server can take
+    pub fn connect_timeout(mut self, connect_timeout: Duration) -> Self {
+        self.extra.http.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn user_agent(mut self, user_agent: impl Into<String>) -> Self {
+        self.extra.http.user_agent = user_agent.into();
+        self
+    }
+
+    /// Send requests through a proxy, streaming connects directly
+    pub fn proxy(mut self, proxy: reqwest::Proxy) -> Self {
+        self.extra.http.proxies.push(proxy);
+        self
+    }
+
+    /// Trust servers with certificates signed by this certificate
+    pub fn add_root_certificate(mut self, certificate: reqwest::Certificate) -> Self {
+        self.extra.http.root_certificates.push(certificate);
+        self
+    }
+
     pub fn build(self) -> Result<Client, ConfigBuildError> {
+        let auth_url = self
+            .auth_url
+            .unwrap_or_else(|| "https://auth.tesla.com/".into())
+            .pipe(|x| Url::parse(&x))?;
+        let http = self.extra.http.build()?;
+        let tokens = Tokens::new(
+            self.extra.token,
+            self.extra.on_token_updated,
+            auth_url,
+            http.clone(),
+        );
+
         Client {
-            auth_url: self
-                .auth_url
-                .unwrap_or_else(|| "https://auth.tesla.com/".into())
-                .pipe(|x| Url::parse(&x))?,
             owner_url: self
                 .owner_url
                 .unwrap_or_else(|| "https://owner-api.teslamotors.com/".into())
@@ -145,7 +286,10 @@ impl Config<HasToken> {
                 .streaming_url
                 .unwrap_or_else(|| "wss://streaming.vn.teslamotors.com/streaming/".into())
                 .pipe(|x| Url::parse(&x))?,
-            token: self.extra.token,
+            streaming_backoff: self.streaming_backoff,
+            wake_backoff: self.wake_backoff,
+            http,
+            tokens: Arc::new(tokens),
         }
         .pipe(Ok)
     }
@@ -153,13 +297,15 @@ impl Config<HasToken> {
 
 /// The client configuration
 pub struct Client {
-    auth_url: Url,
     owner_url: Url,
     streaming_url: Url,
-    token: Token,
+    streaming_backoff: Backoff,
+    wake_backoff: Backoff,
+    http: reqwest::Client,
+    tokens: Arc<Tokens>,
 }
 
-#[derive(Error, Debug)]
+#[derive(Error, Debug, Clone)]
 pub enum StreamingFieldError {
     #[error("Invalid Time '{0}'")]
     InvalidTime(String),
@@ -208,6 +354,19 @@ fn deserialize_fields(
                 StreamingFields::Range => parse_field(&mut data.range, value, field, n)?,
                 StreamingFields::EstRange => parse_field(&mut data.est_range, value, field, n)?,
                 StreamingFields::Heading => parse_field(&mut data.heading, value, field, n)?,
+                StreamingFields::NativeLatitude => {
+                    parse_field(&mut data.native_latitude, value, field, n)?;
+                }
+                StreamingFields::NativeLongitude => {
+                    parse_field(&mut data.native_longitude, value, field, n)?;
+                }
+                StreamingFields::NativePower => {
+                    parse_field(&mut data.native_power, value, field, n)?;
+                }
+                StreamingFields::NativeType => parse_field(&mut data.native_type, value, field, n)?,
+                StreamingFields::NativeLocationSupported => {
+                    parse_field(&mut data.native_location_supported, value, field, n)?;
+                }
             }
 
             Ok(())
@@ -237,85 +396,72 @@ fn parse_field<T: FromStr>(
 }
 
 impl Client {
-    pub async fn refresh_token(&mut self) -> Result<(), reqwest::Error> {
-        let body = TokenRequest::RefreshToken(RefreshTokenRequest {
-            refresh_token: self.token.refresh_token.clone(),
-            client_id: "ownerapi".into(),
-            // scope has user_data removed but vehicle_device_data added
-            scope: "openid offline_access vehicle_device_data vehicle_cmds vehicle_charging_cmds energy_device_data energy_cmds".into(),
-        });
-
-        let url = format!("{}oauth2/v3/token", self.auth_url);
-        let token: Token = reqwest::Client::new()
-            .post(url)
-            .json(&body)
-            .header("Content-Type", "application/json")
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<RawToken>()
-            .await?
-            .into();
-
-        self.token = token;
-        Ok(())
+    /// Get a new token now, even if the current one is not due to be renewed
+    pub async fn refresh_token(&self) -> Result<(), Error> {
+        let (generation, _) = self.tokens.get();
+        self.tokens.refresh(generation).await
     }
 
-    pub async fn check_refresh_token(&mut self) -> Result<(), reqwest::Error> {
-        let now = chrono::Utc::now();
-        let renew_at = self.token.renew_at;
-        let expires_at = self.token.expires_at;
+    /// Get a new token if the current one is due to be renewed
+    ///
+    /// Requests do this anyway, there is no need to call it first.
+    pub async fn check_refresh_token(&self) -> Result<(), Error> {
+        self.tokens.access_token().await.map(|_| ())
+    }
 
-        if now > renew_at || now > expires_at {
-            self.refresh_token().await?;
+    /// Send a request with the access token
+    ///
+    /// If the token is rejected, it is refreshed and the request is sent once more.
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, Error> {
+        let retry = request.try_clone();
+        let (generation, access_token) = self.tokens.access_token().await?;
+        let result = request.bearer_auth(access_token).pipe(errors::send).await;
+
+        match (result, retry) {
+            (Err(Error::TokenExpired), Some(retry)) => {
+                debug!("Token rejected, refreshing it");
+                self.tokens.refresh(generation).await?;
+                let (_, token) = self.tokens.get();
+                retry
+                    .bearer_auth(token.access_token)
+                    .pipe(errors::send)
+                    .await
+            }
+            (result, _) => result,
         }
+    }
 
-        Ok(())
+    /// Send a request with the access token, and deserialize the response
+    async fn json<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T, Error> {
+        errors::json(self.send(request).await?).await
     }
 
-    pub async fn get_vehicles(&self) -> Result<VehiclesResponse, reqwest::Error> {
+    pub async fn get_vehicles(&self) -> Result<VehiclesResponse, Error> {
         let url = format!("{}api/1/products", self.owner_url);
-        let text = reqwest::Client::new()
+        self.http
             .get(url)
             .header("Content-Type", "application/json")
-            .bearer_auth(&self.token.access_token)
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
-            .await?;
-
-        let jd = &mut serde_json::Deserializer::from_str(&text);
-        let result: Result<VehiclesResponse, _> = serde_path_to_error::deserialize(jd);
-        let vehicles = result
-            .map_err(|err| {
-                panic!("Error deserializing vehicle: {}", err);
-            })
-            .unwrap();
-
-        Ok(vehicles)
+            .pipe(|request| self.json(request))
+            .await
     }
 
-    pub async fn get_vehicle(&self, id: VehicleId) -> Result<VehicleResponse, reqwest::Error> {
-        let url = format!("{}api/1/vehicles/{}", self.owner_url, id.to_string());
-        let vehicles = reqwest::Client::new()
+    pub async fn get_vehicle(&self, id: VehicleId) -> Result<VehicleResponse, Error> {
+        let url = format!("{}api/1/vehicles/{}", self.owner_url, id);
+        self.http
             .get(url)
             .header("Content-Type", "application/json")
-            .bearer_auth(&self.token.access_token)
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<VehicleResponse>()
-            .await?;
-
-        Ok(vehicles)
+            .pipe(|request| self.json(request))
+            .await
     }
 
     pub async fn get_vehicle_data(
         &self,
         id: VehicleId,
         endpoints: &HashSet<VehicleDataEndpoint>,
-    ) -> Result<VehicleDataResponse, reqwest::Error> {
+    ) -> Result<VehicleDataResponse, Error> {
         let endpoints = endpoints
             .iter()
             .map(|x| x.to_string())
@@ -324,227 +470,568 @@ impl Client {
 
         let query = [("endpoints", endpoints)];
 
-        let url = format!(
-            "{}api/1/vehicles/{}/vehicle_data",
-            self.owner_url,
-            id.to_string()
-        );
-        let text = reqwest::Client::new()
+        let url = format!("{}api/1/vehicles/{}/vehicle_data", self.owner_url, id);
+        // The untagged response would hide where the data does not match.
+        let vehicles: TeslaResponseSuccess<VehicleData> = self
+            .http
             .get(url)
             .query(&query)
             .header("Content-Type", "application/json")
-            .bearer_auth(&self.token.access_token)
-            // .tap(|x| println!("Request: {:#?}", x))
-            .send()
-            .await?
-            .error_for_status()?
-            .text()
+            .pipe(|request| self.json(request))
             .await?;
 
-        {
-            let json: serde_json::Value = serde_json::from_str(&text).unwrap();
-            println!("Response: {:#?}", json);
+        Ok(TeslaResponse::success(vehicles.response))
+    }
+
+    pub async fn wake_up(&self, id: VehicleId) -> Result<VehicleResponse, Error> {
+        let url = format!("{}api/1/vehicles/{}/wake_up", self.owner_url, id);
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Wake up a vehicle, and wait until it is online
+    ///
+    /// The vehicle is checked again after a wait that doubles each time, or as long as the server
+    /// asks if the request was rate limited, and once more at the timeout. Returns
+    /// [`Error::VehicleUnavailable`] if the vehicle is not online within `timeout`, or the rate
+    /// limit error if the server asks to wait longer.
+    pub async fn wake_and_wait(
+        &self,
+        id: VehicleId,
+        timeout: Duration,
+    ) -> Result<VehicleDefinition, Error> {
+        let deadline = Instant::now() + timeout;
+        let mut woken = false;
+        let mut attempt = 0;
+
+        loop {
+            // Once the vehicle is waking up, checking on it does not use the wake up budget.
+            let result = if woken {
+                self.get_vehicle(id).await
+            } else {
+                self.wake_up(id).await
+            };
+
+            attempt += 1;
+            let delay = self.wake_backoff.delay(attempt);
+            let (delay, err) = match result {
+                Ok(TeslaResponse::Success { response }) => {
+                    if response.state == VehicleStateEnum::Online {
+                        return Ok(response);
+                    }
+                    woken = true;
+                    (delay, Error::VehicleUnavailable)
+                }
+                Ok(TeslaResponse::Error(error)) => {
+                    return Err(Error::Tesla {
+                        status: StatusCode::OK,
+                        error,
+                    })
+                }
+                Err(Error::VehicleUnavailable) => {
+                    woken = true;
+                    (delay, Error::VehicleUnavailable)
+                }
+                Err(Error::RateLimited { retry_after }) => (
+                    retry_after.unwrap_or(delay),
+                    Error::RateLimited { retry_after },
+                ),
+                Err(err) => return Err(err),
+            };
+
+            // The last check is at the deadline, the vehicle may come online before then.
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let too_late = matches!(err, Error::RateLimited { .. }) && delay > remaining;
+            if remaining.is_zero() || too_late {
+                return Err(err);
+            }
+            let delay = delay.min(remaining);
+            debug!("Vehicle {id} is not online yet, checking again in {delay:?}");
+            tokio::time::sleep(delay).await;
         }
+    }
 
-        let jd = &mut serde_json::Deserializer::from_str(&text);
-        let result: Result<TeslaResponseSuccess<VehicleData>, _> =
-            serde_path_to_error::deserialize(jd);
-        let vehicles = result
-            .map_err(|err| {
-                panic!("Error deserializing vehicle: {}", err);
-            })
-            .unwrap();
+    /// List the invitations to share a vehicle
+    pub async fn invitations(&self, id: VehicleId) -> Result<InvitationsResponse, Error> {
+        let url = format!("{}api/1/vehicles/{}/invitations", self.owner_url, id);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
 
-        Ok(TeslaResponse::success(vehicles.response))
+    /// Create an invitation to share a vehicle
+    pub async fn create_invitation(&self, id: VehicleId) -> Result<InvitationResponse, Error> {
+        let url = format!("{}api/1/vehicles/{}/invitations", self.owner_url, id);
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
     }
 
-    pub async fn wake_up(&self, id: VehicleId) -> Result<VehicleResponse, reqwest::Error> {
+    /// Revoke an invitation to share a vehicle
+    pub async fn revoke_invitation(
+        &self,
+        id: VehicleId,
+        invitation_id: InvitationId,
+    ) -> Result<TeslaResponse<bool>, Error> {
         let url = format!(
-            "{}api/1/vehicles/{}/wake_up",
-            self.owner_url,
-            id.to_string()
+            "{}api/1/vehicles/{}/invitations/{}/revoke",
+            self.owner_url, id, invitation_id
         );
-        let vehicle = reqwest::Client::new()
+        self.http
             .post(url)
             .header("Content-Type", "application/json")
-            .bearer_auth(&self.token.access_token)
-            .send()
-            .await?
-            .error_for_status()?
-            .json::<VehicleResponse>()
-            .await?;
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Redeem an invitation, the code is the end of the share link
+    pub async fn redeem_invitation(&self, code: &str) -> Result<RedeemInvitationResponse, Error> {
+        let url = format!("{}api/1/invitations/redeem", self.owner_url);
+        let request = RedeemInvitationRequest {
+            invitation_code: code.to_string(),
+        };
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .json(&request)
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Get the Fleet Telemetry configuration of a vehicle
+    pub async fn fleet_telemetry_config(
+        &self,
+        id: VehicleId,
+    ) -> Result<FleetTelemetryConfigResponse, Error> {
+        let url = format!(
+            "{}api/1/vehicles/{}/fleet_telemetry_config",
+            self.owner_url, id
+        );
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
 
-        Ok(vehicle)
+    /// Configure vehicles to send Fleet Telemetry records
+    pub async fn set_fleet_telemetry_config(
+        &self,
+        vins: Vec<String>,
+        config: FleetTelemetryConfig,
+    ) -> Result<FleetTelemetryConfigUpdatedResponse, Error> {
+        let url = format!("{}api/1/vehicles/fleet_telemetry_config", self.owner_url);
+        let request = FleetTelemetryConfigRequest { vins, config };
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .json(&request)
+            .pipe(|request| self.json(request))
+            .await
     }
 
-    pub async fn simulate(
+    /// Stop a vehicle sending Fleet Telemetry records
+    pub async fn delete_fleet_telemetry_config(
         &self,
         id: VehicleId,
-        state: SimulationStateEnum,
-    ) -> Result<(), reqwest::Error> {
+    ) -> Result<FleetTelemetryConfigUpdatedResponse, Error> {
         let url = format!(
-            "{}api/1/vehicles/{}/simulate",
-            self.owner_url,
-            id.to_string()
+            "{}api/1/vehicles/{}/fleet_telemetry_config",
+            self.owner_url, id
         );
-        reqwest::Client::new()
+        self.http
+            .delete(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    pub async fn simulate(&self, id: VehicleId, state: SimulationStateEnum) -> Result<(), Error> {
+        let url = format!("{}api/1/vehicles/{}/simulate", self.owner_url, id);
+        self.http
             .post(url)
             .header("Content-Type", "application/json")
-            .bearer_auth(&self.token.access_token)
             .json(&state)
-            .send()
-            .await?
-            .error_for_status()?;
+            .pipe(|request| self.send(request))
+            .await?;
 
         Ok(())
     }
 
-    // FIXME: This is yuck
-    pub fn streaming(
+    /// Get the simulator clock (simulator only)
+    pub async fn clock(&self) -> Result<ClockResponse, Error> {
+        let url = format!("{}admin/clock", self.owner_url);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Advance a manual simulator clock (simulator only)
+    pub async fn advance(&self, duration: Duration) -> Result<ClockResponse, Error> {
+        let url = format!("{}admin/clock/advance", self.owner_url);
+        let request = AdvanceRequest {
+            seconds: duration.as_secs_f64(),
+        };
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .json(&request)
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Get the simulator counters (simulator only)
+    pub async fn metrics(&self) -> Result<MetricsResponse, Error> {
+        let url = format!("{}admin/metrics", self.owner_url);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Get the rate limits (simulator only)
+    pub async fn rate_limits(&self) -> Result<RateLimitsResponse, Error> {
+        let url = format!("{}admin/rate_limits", self.owner_url);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Set the rate limits (simulator only)
+    pub async fn set_rate_limits(&self, limits: &RateLimits) -> Result<RateLimitsResponse, Error> {
+        let url = format!("{}admin/rate_limits", self.owner_url);
+        self.http
+            .put(url)
+            .header("Content-Type", "application/json")
+            .json(limits)
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Get the sleep policy of a vehicle (simulator only)
+    pub async fn sleep_policy(&self, id: VehicleId) -> Result<SleepPolicyResponse, Error> {
+        let url = format!("{}admin/vehicles/{}/sleep_policy", self.owner_url, id);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Set the sleep policy of a vehicle (simulator only)
+    pub async fn set_sleep_policy(
         &self,
-        id: VehicleGuid,
-        fields: Vec<StreamingFields>,
-    ) -> Result<mpsc::Receiver<StreamingData>, Error> {
-        let (tx, rx) = mpsc::channel(10);
+        id: VehicleId,
+        policy: &SleepPolicy,
+    ) -> Result<SleepPolicyResponse, Error> {
+        let url = format!("{}admin/vehicles/{}/sleep_policy", self.owner_url, id);
+        self.http
+            .put(url)
+            .header("Content-Type", "application/json")
+            .json(policy)
+            .pipe(|request| self.json(request))
+            .await
+    }
 
-        let token = self.token.access_token.clone();
-        let url = self.streaming_url.clone();
+    /// Get the parked policy of a vehicle (simulator only)
+    pub async fn parked_policy(&self, id: VehicleId) -> Result<ParkedPolicyResponse, Error> {
+        let url = format!("{}admin/vehicles/{}/parked_policy", self.owner_url, id);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
 
-        let string_fields = fields
-            .iter()
-            .map(|x| x.to_string())
-            .collect::<Vec<_>>()
-            .join(",");
+    /// Set the parked policy of a vehicle (simulator only)
+    pub async fn set_parked_policy(
+        &self,
+        id: VehicleId,
+        policy: &ParkedPolicy,
+    ) -> Result<ParkedPolicyResponse, Error> {
+        let url = format!("{}admin/vehicles/{}/parked_policy", self.owner_url, id);
+        self.http
+            .put(url)
+            .header("Content-Type", "application/json")
+            .json(policy)
+            .pipe(|request| self.json(request))
+            .await
+    }
 
-        tokio::spawn(async move {
-            let (mut socket, response) = connect_async(url).await.unwrap();
-            assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+    /// List the faults injected into a vehicle (simulator only)
+    pub async fn faults(&self, id: VehicleId) -> Result<FaultsResponse, Error> {
+        let url = format!("{}admin/vehicles/{}/faults", self.owner_url, id);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
 
-            let msg = ToServerStreamingMessage::DataSubscribeOauth {
-                token,
-                value: string_fields,
-                tag: id.to_string(),
-            };
-            let msg = serde_json::to_string(&msg).unwrap();
-            debug!("Sending: {:#?}", msg);
-            socket.send(Message::Text(msg)).await.unwrap();
+    /// Inject a fault into a vehicle (simulator only)
+    pub async fn add_fault(&self, id: VehicleId, fault: &Fault) -> Result<FaultsResponse, Error> {
+        let url = format!("{}admin/vehicles/{}/faults", self.owner_url, id);
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .json(fault)
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Remove all faults from a vehicle (simulator only)
+    pub async fn clear_faults(&self, id: VehicleId) -> Result<FaultsResponse, Error> {
+        let url = format!("{}admin/vehicles/{}/faults", self.owner_url, id);
+        self.http
+            .delete(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Get the internal state of all simulated vehicles (simulator only)
+    pub async fn simulator_vehicles(&self) -> Result<VehicleStatusesResponse, Error> {
+        let url = format!("{}admin/vehicles", self.owner_url);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Start simulating a new vehicle (simulator only)
+    pub async fn add_vehicle(
+        &self,
+        vehicle: &FleetVehicle,
+    ) -> Result<VehicleStatusResponse, Error> {
+        let url = format!("{}admin/vehicles", self.owner_url);
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .json(vehicle)
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Get the internal state of a simulated vehicle (simulator only)
+    pub async fn simulator_vehicle(&self, id: VehicleId) -> Result<VehicleStatusResponse, Error> {
+        let url = format!("{}admin/vehicles/{}", self.owner_url, id);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Stop simulating a vehicle (simulator only)
+    pub async fn remove_vehicle(&self, id: VehicleId) -> Result<VehicleStatusResponse, Error> {
+        let url = format!("{}admin/vehicles/{}", self.owner_url, id);
+        self.http
+            .delete(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Put a vehicle back into the state it was created in (simulator only)
+    pub async fn reset_vehicle(&self, id: VehicleId) -> Result<VehicleStatusResponse, Error> {
+        let url = format!("{}admin/vehicles/{}/reset", self.owner_url, id);
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
 
+    /// Change the heading of a vehicle (simulator only)
+    pub async fn steer(&self, id: VehicleId, heading: u16) -> Result<VehicleStatusResponse, Error> {
+        let url = format!("{}admin/vehicles/{}/steer", self.owner_url, id);
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .json(&SteerRequest { heading })
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// List the named snapshots of the simulated vehicles (simulator only)
+    pub async fn snapshots(&self) -> Result<SnapshotsResponse, Error> {
+        let url = format!("{}admin/snapshots", self.owner_url);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Save a snapshot of every simulated vehicle under a name (simulator only)
+    pub async fn save_snapshot(&self, name: &str) -> Result<SnapshotResponse, Error> {
+        let url = format!("{}admin/snapshots/{}", self.owner_url, name);
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Replace every simulated vehicle with a named snapshot (simulator only)
+    pub async fn restore_snapshot(&self, name: &str) -> Result<SnapshotResponse, Error> {
+        let url = format!("{}admin/snapshots/{}/restore", self.owner_url, name);
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Remove a named snapshot (simulator only)
+    pub async fn delete_snapshot(&self, name: &str) -> Result<TeslaResponse<()>, Error> {
+        let url = format!("{}admin/snapshots/{}", self.owner_url, name);
+        self.http
+            .delete(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// List the simulated users (simulator only)
+    pub async fn users(&self) -> Result<UsersResponse, Error> {
+        let url = format!("{}admin/users", self.owner_url);
+        self.http
+            .get(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Get a token for a simulated user, with the same scopes as this client (simulator only)
+    pub async fn user_token(&self, id: UserId) -> Result<RawToken, Error> {
+        let url = format!("{}admin/users/{}/token", self.owner_url, id);
+        self.http
+            .post(url)
+            .header("Content-Type", "application/json")
+            .pipe(|request| self.json(request))
+            .await
+    }
+
+    /// Read the records sent to the built-in Fleet Telemetry receiver (simulator only)
+    ///
+    /// Only records sent after this returns are received.
+    pub async fn telemetry(&self) -> Result<mpsc::Receiver<Payload>, Error> {
+        let url = format!("{}admin/telemetry", self.owner_url).replacen("http", "ws", 1);
+        let mut request = url.into_client_request()?;
+        let (_, access_token) = self.tokens.access_token().await?;
+        let authorization = HeaderValue::from_str(&format!("Bearer {access_token}"))
+            .map_err(|err| tungstenite::Error::from(http::Error::from(err)))?;
+        request.headers_mut().insert(AUTHORIZATION, authorization);
+
+        let (mut socket, _) = connect_async(request).await?;
+        let (tx, rx) = mpsc::channel(10);
+
+        tokio::spawn(async move {
             loop {
-                let result = select! {
-                  maybe_msg = socket.next()  => {
-                    match maybe_msg {
-                        Some(Ok(Message::Text(msg))) => {
-                            msg
-                            .tap(|x| debug!("Received text message: {:#?}", x))
-                            .pipe(|msg| process_message(msg, &fields, &tx)).await
-                        }
-                        Some(Ok(Message::Binary(msg))) => {
-                            let msg = String::from_utf8(msg);
-                            match msg {
-                                Ok(msg) => {
-                                    debug!("Received binary: {msg:?}");
-                                    process_message(msg, &fields, &tx).await
-                                }
-                                Err(err) => {
-                                    error!("Error decoding message: {err}");
-                                    Err(format!("Error decoding message: {err}"))
+                select! {
+                    maybe_msg = socket.next() => match maybe_msg {
+                        Some(Ok(Message::Binary(msg))) => match Payload::decode(msg.as_slice()) {
+                            Ok(payload) => {
+                                if tx.send(payload).await.is_err() {
+                                    break;
                                 }
                             }
-                        }
-                        Some(Ok(msg)) => {
-                            debug!("Received unexpected: {msg:?}");
-                            Ok(())
-                        }
-                        Some(Err(e)) => {
-                            error!("Error: {e:?}");
-                            Err(format!("Error: {e:?}"))
+                            Err(err) => error!("Error decoding telemetry record: {err}"),
+                        },
+                        Some(Ok(msg)) => debug!("Received unexpected: {msg:?}"),
+                        Some(Err(err)) => {
+                            error!("Error: {err:?}");
+                            break;
                         }
                         None => {
                             debug!("Disconnected");
                             break;
                         }
+                    },
+                    () = tx.closed() => {
+                        debug!("Client disconnected");
+                        break;
                     }
-                  }
-                  _ = tx.closed() => {
-                    debug!("Client disconnected");
-                    break;
-                  }
-                };
-
-                if let Err(err) = result {
-                    error!("Error processing message: {err}");
-                    break;
                 }
             }
 
             socket
-                .close(Some(CloseFrame {
-                    code: CloseCode::Normal,
-                    reason: "I hate you".into(),
-                }))
+                .close(None)
                 .await
-                .unwrap_or_else(|err| error!("Error closing socket: {err}"));
+                .unwrap_or_else(|err| debug!("Error closing socket: {err}"));
         });
 
         Ok(rx)
     }
 
-    /// Get the token (for testing)
-    pub fn token(&self) -> &Token {
-        &self.token
+    /// Start a session that streams several vehicles over one connection
+    ///
+    /// Every subscription uses the current access token, renewing it if it is due, so a session
+    /// keeps working after the token is refreshed.
+    pub fn streaming_session(&self) -> StreamingSession {
+        StreamingSession::new(
+            self.streaming_url.clone(),
+            self.streaming_backoff,
+            self.tokens.clone(),
+        )
     }
-}
 
-async fn process_message(
-    msg: String,
-    fields: &[StreamingFields],
-    tx: &mpsc::Sender<StreamingData>,
-) -> Result<(), String> {
-    let msg: FromServerStreamingMessage = serde_json::from_str(&msg).unwrap();
-    match msg {
-        FromServerStreamingMessage::ControlHello {
-            connection_timeout: _,
-        } => {
-            debug!("Received: {msg:?}");
-            Ok(())
-        }
-        FromServerStreamingMessage::DataUpdate { tag, value } => {
-            let vehicle_id = tag.parse::<VehicleGuid>().unwrap();
-
-            match deserialize_fields(vehicle_id, &value, fields) {
-                Ok(data) => {
-                    tx.send(data)
-                        .await
-                        .unwrap_or_else(|err| error!("Error sending data: {err}"));
-                    Ok(())
-                }
-                Err(err) => {
-                    error!("Error deserializing data: {err}");
-                    Ok(())
-                }
-            }
-        }
-        FromServerStreamingMessage::DataError(data_error) => {
-            let DataError {
-                tag,
-                error_type,
-                value,
-            } = data_error;
-            error!("Received data error: {tag} {error_type:?} {value}");
-            match error_type {
-                fla_common::streaming::ErrorType::VehicleDisconnected => {
-                    error!("Vehicle disconnected");
-                    Err(format!("Vehicle disconnected: {value}"))
-                }
-                fla_common::streaming::ErrorType::VehicleError => {
-                    error!("Vehicle error");
-                    Err(format!("Vehicle error: {value}"))
-                }
-                fla_common::streaming::ErrorType::ClientError => {
-                    error!("Client error");
-                    Err(format!("Client error: {value}"))
-                }
-            }
-        }
+    /// Stream data from a vehicle until the stream is dropped
+    ///
+    /// The connection is opened again if it is lost, and the vehicle is subscribed again after
+    /// it disconnects. The stream only ends if the server rejects the subscription.
+    pub fn streaming(
+        &self,
+        id: VehicleGuid,
+        fields: Vec<StreamingFields>,
+    ) -> impl Stream<Item = Result<StreamingEvent, StreamingError>> + Send + Unpin {
+        self.streaming_session().add(id, fields)
+    }
+
+    /// Stream with an update interval, or only when the data changes
+    ///
+    /// Only the simulator understands the options, the real server sends every row.
+    pub fn streaming_with_options(
+        &self,
+        id: VehicleGuid,
+        fields: Vec<StreamingFields>,
+        options: StreamingOptions,
+    ) -> impl Stream<Item = Result<StreamingEvent, StreamingError>> + Send + Unpin {
+        self.streaming_session()
+            .add_with_options(id, fields, options)
+    }
+
+    /// Stream with the legacy `data:subscribe` message
+    ///
+    /// Authenticates with the email of the account and one of the `tokens` of the vehicle,
+    /// instead of the access token.
+    pub fn streaming_with_vehicle_token(
+        &self,
+        id: VehicleGuid,
+        email: &str,
+        vehicle_token: &str,
+        fields: Vec<StreamingFields>,
+    ) -> impl Stream<Item = Result<StreamingEvent, StreamingError>> + Send + Unpin {
+        self.streaming_session()
+            .add_with_vehicle_token(id, email, vehicle_token, fields)
+    }
+
+    /// Get the current token
+    pub fn token(&self) -> Token {
+        self.tokens.get().1
     }
 }
@@ -0,0 +1,672 @@
+//! Streaming API client
+//!
+//! A [`StreamingSession`] streams any number of vehicles over one connection, and routes each row
+//! to the stream of its vehicle by the tag. The session runs in its own task until every stream
+//! is dropped. Lost connections are opened again, and a vehicle is subscribed again after
+//! `vehicle_disconnected`, waiting longer after each failure. Every subscription uses the current
+//! access token, so the session carries on after the token is refreshed. Only a `client_error`,
+//! such as a bad token or revoked access, ends the stream of a vehicle. A stream that is not read
+//! skips events rather than holding up the others.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use fla_common::{
+    streaming::{
+        DataError, ErrorType, FromServerStreamingMessage, StreamingData, StreamingFields,
+        StreamingOptions, ToServerStreamingMessage,
+    },
+    types::VehicleGuid,
+};
+use futures_util::{stream, SinkExt, Stream, StreamExt};
+use thiserror::Error;
+use tokio::{
+    net::TcpStream,
+    select,
+    sync::mpsc::{self, error::TrySendError},
+    time::Instant,
+};
+use tokio_tungstenite::{connect_async, tungstenite, MaybeTlsStream, WebSocketStream};
+use tracing::debug;
+use url::Url;
+
+use crate::{deserialize_fields, tokens::Tokens, StreamingFieldError};
+
+/// How long to wait before connecting or subscribing again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// The wait after the first failure
+    pub initial: Duration,
+
+    /// The longest wait, the wait doubles after every failure until it gets here
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_secs(1),
+            max: Duration::from_mins(1),
+        }
+    }
+}
+
+impl Backoff {
+    /// The wait before the given attempt, counting from 1
+    #[must_use]
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let factor = 2_u32.saturating_pow(attempt.saturating_sub(1));
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Something that happened to a subscription
+#[derive(Debug, Clone)]
+pub enum StreamingEvent {
+    /// Connected to the server, which closes the connection after `connection_timeout` without
+    /// data
+    Hello { connection_timeout: Duration },
+
+    /// A row of data
+    Data(StreamingData),
+
+    /// The server reported an error, the vehicle is subscribed again after a wait
+    Error(DataError),
+
+    /// The connection was lost, and is opened again after `delay`
+    Reconnecting { attempt: u32, delay: Duration },
+
+    /// The stream was not read fast enough, so `skipped` events were dropped
+    Lagged { skipped: u64 },
+}
+
+/// Something went wrong with a subscription
+///
+/// Only [`StreamingError::Rejected`] ends the stream, the stream carries on after the others.
+/// Errors about the connection go to the stream of every vehicle, so they are shared.
+#[derive(Error, Debug, Clone)]
+pub enum StreamingError {
+    #[error("Cannot connect: {0}")]
+    Connect(Arc<tungstenite::Error>),
+
+    #[error("Connection failed: {0}")]
+    Connection(Arc<tungstenite::Error>),
+
+    #[error("Invalid message '{message}': {error}")]
+    InvalidMessage {
+        message: String,
+        error: Arc<serde_json::Error>,
+    },
+
+    #[error("Invalid tag '{0}'")]
+    InvalidTag(String),
+
+    #[error("Invalid data '{value}': {error}")]
+    InvalidData {
+        value: String,
+        error: StreamingFieldError,
+    },
+
+    #[error("Subscription rejected: {0}")]
+    Rejected(DataError),
+}
+
+type Item = Result<StreamingEvent, StreamingError>;
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// How many events wait for a slow reader before the session drops events for it
+///
+/// The session never waits for a reader, so one stream that is not read does not hold up the
+/// others.
+const EVENT_CAPACITY: usize = 10;
+
+/// Stream several vehicles over one connection
+///
+/// Cloning the session gives another handle to the same connection. The connection is opened
+/// when the first vehicle is added, and closed when the last one is removed.
+#[derive(Clone)]
+pub struct StreamingSession {
+    control: mpsc::UnboundedSender<Control>,
+    next_key: Arc<AtomicU64>,
+}
+
+impl StreamingSession {
+    /// Start a session, it runs until the session and every stream from it are dropped
+    pub(crate) fn new(url: Url, backoff: Backoff, tokens: Arc<Tokens>) -> Self {
+        let (control, rx) = mpsc::unbounded_channel();
+        let session = Session {
+            url,
+            backoff,
+            tokens,
+            control: rx,
+            vehicles: HashMap::new(),
+            gone: Vec::new(),
+            attempt: 0,
+        };
+        tokio::spawn(session.run());
+
+        Self {
+            control,
+            next_key: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Stream data from a vehicle until the stream is dropped
+    ///
+    /// Adding a vehicle that is already in the session replaces it, and ends its old stream.
+    pub fn add(
+        &self,
+        id: VehicleGuid,
+        fields: Vec<StreamingFields>,
+    ) -> impl Stream<Item = Item> + Send + Unpin {
+        self.add_with_options(id, fields, StreamingOptions::default())
+    }
+
+    /// Stream with an update interval, or only when the data changes
+    ///
+    /// Only the simulator understands the options, the real server sends every row.
+    pub fn add_with_options(
+        &self,
+        id: VehicleGuid,
+        fields: Vec<StreamingFields>,
+        options: StreamingOptions,
+    ) -> impl Stream<Item = Item> + Send + Unpin {
+        let subscribe = Subscribe::Oauth {
+            value: join_fields(&fields),
+            tag: id.to_string(),
+            options,
+        };
+        self.add_subscribe(id, subscribe, fields)
+    }
+
+    /// Stream with the legacy `data:subscribe` message
+    ///
+    /// Authenticates with the email of the account and one of the `tokens` of the vehicle,
+    /// instead of the access token.
+    pub fn add_with_vehicle_token(
+        &self,
+        id: VehicleGuid,
+        email: &str,
+        vehicle_token: &str,
+        fields: Vec<StreamingFields>,
+    ) -> impl Stream<Item = Item> + Send + Unpin {
+        let msg = ToServerStreamingMessage::DataSubscribe {
+            token: STANDARD.encode(format!("{email}:{vehicle_token}")),
+            value: join_fields(&fields),
+            tag: id.to_string(),
+            options: StreamingOptions::default(),
+        };
+        // Serializing the message cannot fail, it only has strings and numbers.
+        let subscribe = Subscribe::Message(serde_json::to_string(&msg).unwrap_or_default());
+        self.add_subscribe(id, subscribe, fields)
+    }
+
+    /// Stop streaming a vehicle, its stream ends
+    pub fn remove(&self, id: VehicleGuid) {
+        // The session only stops once every handle is gone, so it is still listening.
+        _ = self.control.send(Control::Remove(id, None));
+    }
+
+    fn add_subscribe(
+        &self,
+        id: VehicleGuid,
+        subscribe: Subscribe,
+        fields: Vec<StreamingFields>,
+    ) -> impl Stream<Item = Item> + Send + Unpin {
+        let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = mpsc::channel(EVENT_CAPACITY);
+
+        let vehicle = Vehicle {
+            key,
+            subscribe,
+            fields,
+            tx,
+            skipped: 0,
+            attempt: 0,
+            resubscribe_at: None,
+        };
+        _ = self.control.send(Control::Add(id, Box::new(vehicle)));
+
+        let guard = RemoveOnDrop {
+            id,
+            key,
+            control: self.control.clone(),
+        };
+        Box::pin(stream::unfold((rx, guard), |(mut rx, guard)| async move {
+            rx.recv().await.map(|item| (item, (rx, guard)))
+        }))
+    }
+}
+
+fn join_fields(fields: &[StreamingFields]) -> String {
+    fields
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Removes a vehicle from the session when its stream is dropped
+struct RemoveOnDrop {
+    id: VehicleGuid,
+    key: u64,
+    control: mpsc::UnboundedSender<Control>,
+}
+
+impl Drop for RemoveOnDrop {
+    fn drop(&mut self) {
+        _ = self.control.send(Control::Remove(self.id, Some(self.key)));
+    }
+}
+
+/// A change to the vehicles in a session
+enum Control {
+    Add(VehicleGuid, Box<Vehicle>),
+
+    /// Remove a vehicle, only if it was added with the key if there is one
+    Remove(VehicleGuid, Option<u64>),
+}
+
+/// How a vehicle subscribes
+enum Subscribe {
+    /// With the access token, which is read again for every subscription
+    Oauth {
+        value: String,
+        tag: String,
+        options: StreamingOptions,
+    },
+
+    /// With a message that never changes
+    Message(String),
+}
+
+impl Subscribe {
+    /// The message to subscribe with now
+    async fn message(&self, tokens: &Tokens) -> String {
+        let (value, tag, options) = match self {
+            Self::Oauth {
+                value,
+                tag,
+                options,
+            } => (value, tag, options),
+            Self::Message(msg) => return msg.clone(),
+        };
+
+        let token = match tokens.access_token().await {
+            Ok((_, token)) => token,
+            Err(err) => {
+                // The server rejects the subscription if the token has really expired.
+                debug!("Cannot renew token for streaming: {err}");
+                tokens.get().1.access_token
+            }
+        };
+        let msg = ToServerStreamingMessage::DataSubscribeOauth {
+            token,
+            value: value.clone(),
+            tag: tag.clone(),
+            options: *options,
+        };
+        // Serializing the message cannot fail, it only has strings and numbers.
+        serde_json::to_string(&msg).unwrap_or_default()
+    }
+}
+
+/// A vehicle in a session
+struct Vehicle {
+    key: u64,
+    subscribe: Subscribe,
+    fields: Vec<StreamingFields>,
+    tx: mpsc::Sender<Item>,
+
+    /// Events dropped since the stream was last read
+    skipped: u64,
+
+    /// Failures since data was last received
+    attempt: u32,
+
+    /// When to subscribe again after the vehicle disconnected
+    resubscribe_at: Option<Instant>,
+}
+
+/// What to do after a connection ends
+enum Next {
+    Reconnect,
+    Stop,
+}
+
+struct Session {
+    url: Url,
+    backoff: Backoff,
+    tokens: Arc<Tokens>,
+    control: mpsc::UnboundedReceiver<Control>,
+    vehicles: HashMap<VehicleGuid, Vehicle>,
+
+    /// Vehicles whose streams were dropped, that the server still sends data for
+    gone: Vec<VehicleGuid>,
+
+    /// Connection failures since the server last said hello
+    attempt: u32,
+}
+
+impl Session {
+    async fn run(mut self) {
+        loop {
+            // There is nothing to connect for until a vehicle is added.
+            while self.vehicles.is_empty() {
+                match self.control.recv().await {
+                    Some(control) => self.control(control),
+                    None => {
+                        debug!("Streaming session stopped");
+                        return;
+                    }
+                }
+            }
+
+            let next = match connect_async(self.url.as_str()).await {
+                Ok((mut socket, _)) => {
+                    let next = self.connection(&mut socket).await;
+                    socket
+                        .close(None)
+                        .await
+                        .unwrap_or_else(|err| debug!("Error closing socket: {err}"));
+                    next
+                }
+                Err(err) => {
+                    let error = StreamingError::Connect(Arc::new(err));
+                    self.broadcast(&Err(error));
+                    Next::Reconnect
+                }
+            };
+            if matches!(next, Next::Stop) {
+                debug!("Streaming session stopped");
+                return;
+            }
+            if self.vehicles.is_empty() {
+                continue;
+            }
+
+            self.attempt = self.attempt.saturating_add(1);
+            let delay = self.backoff.delay(self.attempt);
+            let attempt = self.attempt;
+            self.broadcast(&Ok(StreamingEvent::Reconnecting { attempt, delay }));
+
+            let sleep = tokio::time::sleep(delay);
+            tokio::pin!(sleep);
+            loop {
+                select! {
+                    () = &mut sleep => break,
+                    control = self.control.recv() => match control {
+                        Some(control) => self.control(control),
+                        None => return,
+                    },
+                }
+            }
+        }
+    }
+
+    /// Subscribe every vehicle on a connection, and read from it until it ends
+    async fn connection(&mut self, socket: &mut Socket) -> Next {
+        let mut subscribe = Vec::with_capacity(self.vehicles.len());
+        for vehicle in self.vehicles.values_mut() {
+            vehicle.resubscribe_at = None;
+            subscribe.push(vehicle.subscribe.message(&self.tokens).await);
+        }
+        for msg in subscribe {
+            if let Err(err) = send(socket, msg).await {
+                return self.connection_failed(err);
+            }
+        }
+
+        loop {
+            let resubscribe_at = self
+                .vehicles
+                .values()
+                .filter_map(|vehicle| vehicle.resubscribe_at)
+                .min();
+
+            let result = select! {
+                msg = socket.next() => match msg {
+                    Some(Ok(tungstenite::Message::Text(text))) => {
+                        self.message(text);
+                        Ok(())
+                    }
+                    Some(Ok(tungstenite::Message::Binary(bytes))) => {
+                        self.message(String::from_utf8_lossy(&bytes).into_owned());
+                        Ok(())
+                    }
+                    Some(Ok(tungstenite::Message::Close(_))) | None => {
+                        debug!("Streaming connection closed");
+                        return Next::Reconnect;
+                    }
+                    Some(Ok(msg)) => {
+                        debug!("Received unexpected: {msg:?}");
+                        Ok(())
+                    }
+                    Some(Err(err)) => Err(err),
+                },
+                control = self.control.recv() => match control {
+                    Some(Control::Add(id, vehicle)) => {
+                        let msg = vehicle.subscribe.message(&self.tokens).await;
+                        self.vehicles.insert(id, *vehicle);
+                        send(socket, msg).await
+                    }
+                    Some(control) => {
+                        self.control(control);
+                        Ok(())
+                    }
+                    None => return Next::Stop,
+                },
+                Some(now) = maybe_sleep_until(resubscribe_at) => self.resubscribe(socket, now).await,
+            };
+
+            if let Err(err) = result {
+                return self.connection_failed(err);
+            }
+            if let Err(err) = self.unsubscribe_gone(socket).await {
+                return self.connection_failed(err);
+            }
+
+            // The connection is opened again when a vehicle is added.
+            if self.vehicles.is_empty() {
+                return Next::Reconnect;
+            }
+        }
+    }
+
+    /// Add or remove a vehicle
+    fn control(&mut self, control: Control) {
+        match control {
+            Control::Add(id, vehicle) => {
+                self.vehicles.insert(id, *vehicle);
+            }
+            Control::Remove(id, key) => {
+                let matches = self
+                    .vehicles
+                    .get(&id)
+                    .is_some_and(|vehicle| key.is_none_or(|key| key == vehicle.key));
+                if matches {
+                    self.vehicles.remove(&id);
+                    self.gone.push(id);
+                }
+            }
+        }
+    }
+
+    /// Subscribe again to the vehicles that are due
+    async fn resubscribe(
+        &mut self,
+        socket: &mut Socket,
+        now: Instant,
+    ) -> Result<(), tungstenite::Error> {
+        for (id, vehicle) in &mut self.vehicles {
+            if vehicle.resubscribe_at.is_some_and(|at| at <= now) {
+                debug!("Subscribing to {id} again");
+                vehicle.resubscribe_at = None;
+                let msg = vehicle.subscribe.message(&self.tokens).await;
+                send(socket, msg).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Tell the server to stop sending data for vehicles that were removed
+    async fn unsubscribe_gone(&mut self, socket: &mut Socket) -> Result<(), tungstenite::Error> {
+        for id in std::mem::take(&mut self.gone) {
+            if self.vehicles.contains_key(&id) {
+                continue;
+            }
+            let msg = ToServerStreamingMessage::DataUnsubscribe {
+                tag: id.to_string(),
+            };
+            send(socket, serde_json::to_string(&msg).unwrap_or_default()).await?;
+        }
+        Ok(())
+    }
+
+    /// Handle a message from the server
+    fn message(&mut self, text: String) {
+        match serde_json::from_str::<FromServerStreamingMessage>(&text) {
+            Ok(FromServerStreamingMessage::ControlHello { connection_timeout }) => {
+                self.attempt = 0;
+                let connection_timeout = Duration::from_millis(connection_timeout);
+                self.broadcast(&Ok(StreamingEvent::Hello { connection_timeout }));
+            }
+            Ok(FromServerStreamingMessage::DataUpdate { tag, value }) => {
+                let Ok(id) = tag.parse::<VehicleGuid>() else {
+                    self.broadcast(&Err(StreamingError::InvalidTag(tag)));
+                    return;
+                };
+                let Some(vehicle) = self.vehicles.get_mut(&id) else {
+                    debug!("Ignoring data for {id}, it is not in the session");
+                    return;
+                };
+                let item = match deserialize_fields(id, &value, &vehicle.fields) {
+                    Ok(data) => {
+                        vehicle.attempt = 0;
+                        Ok(StreamingEvent::Data(data))
+                    }
+                    Err(error) => Err(StreamingError::InvalidData { value, error }),
+                };
+                self.send_to(id, item);
+            }
+            Ok(FromServerStreamingMessage::DataError(error)) => {
+                let Some(vehicle) = error
+                    .tag
+                    .parse::<VehicleGuid>()
+                    .ok()
+                    .and_then(|id| Some(id).zip(self.vehicles.get_mut(&id)))
+                else {
+                    debug!("Ignoring error for {}: {error}", error.tag);
+                    return;
+                };
+                let (id, vehicle) = vehicle;
+
+                match error.error_type {
+                    ErrorType::VehicleDisconnected | ErrorType::VehicleError => {
+                        vehicle.attempt = vehicle.attempt.saturating_add(1);
+                        let delay = self.backoff.delay(vehicle.attempt);
+                        debug!("Subscribing to {id} again in {delay:?}");
+                        vehicle.resubscribe_at = Some(Instant::now() + delay);
+                        self.send_to(id, Ok(StreamingEvent::Error(error)));
+                    }
+                    ErrorType::ClientError => {
+                        // Subscribing again would only be rejected again.
+                        self.send_to(id, Err(StreamingError::Rejected(error)));
+                        self.vehicles.remove(&id);
+                    }
+                }
+            }
+            Err(error) => {
+                let error = StreamingError::InvalidMessage {
+                    message: text,
+                    error: Arc::new(error),
+                };
+                self.broadcast(&Err(error));
+            }
+        }
+    }
+
+    fn connection_failed(&mut self, err: tungstenite::Error) -> Next {
+        let error = StreamingError::Connection(Arc::new(err));
+        self.broadcast(&Err(error));
+        Next::Reconnect
+    }
+
+    /// Pass an item on to one vehicle, removing it if nobody is reading its stream any more
+    ///
+    /// If the stream is full the item is dropped, and the reader gets [`StreamingEvent::Lagged`]
+    /// once there is room again.
+    fn send_to(&mut self, id: VehicleGuid, item: Item) {
+        let Some(vehicle) = self.vehicles.get_mut(&id) else {
+            return;
+        };
+
+        // Tell the reader what it missed first, while it is still full the item is skipped too.
+        let result = match vehicle.skipped {
+            0 => vehicle.tx.try_send(item),
+            skipped => match vehicle.tx.try_send(Ok(StreamingEvent::Lagged { skipped })) {
+                Ok(()) => {
+                    vehicle.skipped = 0;
+                    vehicle.tx.try_send(item)
+                }
+                Err(err) => Err(err),
+            },
+        };
+
+        match result {
+            Ok(()) => {}
+            Err(TrySendError::Full(_)) => {
+                vehicle.skipped = vehicle.skipped.saturating_add(1);
+                debug!("Stream for {id} is full, skipped {}", vehicle.skipped);
+            }
+            Err(TrySendError::Closed(_)) => {
+                self.vehicles.remove(&id);
+                self.gone.push(id);
+            }
+        }
+    }
+
+    /// Pass an item on to every vehicle
+    fn broadcast(&mut self, item: &Item) {
+        let ids: Vec<VehicleGuid> = self.vehicles.keys().copied().collect();
+        for id in ids {
+            self.send_to(id, item.clone());
+        }
+    }
+}
+
+async fn send(socket: &mut Socket, msg: String) -> Result<(), tungstenite::Error> {
+    debug!("Sending: {msg}");
+    socket.send(tungstenite::Message::Text(msg)).await
+}
+
+async fn maybe_sleep_until(time: Option<Instant>) -> Option<Instant> {
+    let time = time?;
+    tokio::time::sleep_until(time).await;
+    Some(time)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let backoff = Backoff {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+        };
+        assert_eq!(backoff.delay(1), Duration::from_secs(1));
+        assert_eq!(backoff.delay(2), Duration::from_secs(2));
+        assert_eq!(backoff.delay(3), Duration::from_secs(4));
+        assert_eq!(backoff.delay(4), Duration::from_secs(5));
+        assert_eq!(backoff.delay(100), Duration::from_secs(5));
+    }
+}
@@ -0,0 +1,113 @@
+//! Keeping the token fresh
+//!
+//! Every request reads the token, so it lives behind a lock. Only one refresh runs at a time, and
+//! callers that were waiting for it use the token it got instead of refreshing again. Streaming
+//! sessions share the tokens with the client, so they subscribe with the current token.
+
+use std::sync::{PoisonError, RwLock};
+
+use fla_common::auth::{RawToken, RefreshTokenRequest, TokenRequest};
+use tap::Pipe;
+use tokio::sync::Mutex;
+use tracing::debug;
+use url::Url;
+
+use crate::{errors, Error, Token};
+
+/// Called with the new token after every refresh
+pub type TokenCallback = Box<dyn Fn(&Token) + Send + Sync>;
+
+/// The token of a client
+pub(crate) struct Tokens {
+    /// The token, and how many times it has been refreshed
+    current: RwLock<(u64, Token)>,
+
+    /// Held while a refresh is in flight
+    refreshing: Mutex<()>,
+
+    on_updated: Option<TokenCallback>,
+    auth_url: Url,
+    http: reqwest::Client,
+}
+
+impl Tokens {
+    pub(crate) fn new(
+        token: Token,
+        on_updated: Option<TokenCallback>,
+        auth_url: Url,
+        http: reqwest::Client,
+    ) -> Self {
+        Self {
+            current: RwLock::new((0, token)),
+            refreshing: Mutex::new(()),
+            on_updated,
+            auth_url,
+            http,
+        }
+    }
+
+    /// The token, and how many times it has been refreshed
+    pub(crate) fn get(&self) -> (u64, Token) {
+        self.current
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// The access token, and its generation, after renewing it if it is due
+    pub(crate) async fn access_token(&self) -> Result<(u64, String), Error> {
+        let (generation, token) = self.get();
+        let now = chrono::Utc::now();
+        if now < token.renew_at && now < token.expires_at {
+            return Ok((generation, token.access_token));
+        }
+
+        match self.refresh(generation).await {
+            Ok(()) => {}
+            // The old token still works until it expires.
+            Err(err) if now < token.expires_at => debug!("Cannot renew token yet: {err}"),
+            Err(err) => return Err(err),
+        }
+
+        let (generation, token) = self.get();
+        Ok((generation, token.access_token))
+    }
+
+    /// Replace the token, unless it has been refreshed since `generation`
+    pub(crate) async fn refresh(&self, generation: u64) -> Result<(), Error> {
+        let _refreshing = self.refreshing.lock().await;
+
+        let (current, token) = self.get();
+        if current != generation {
+            // Somebody else refreshed it while we were waiting.
+            return Ok(());
+        }
+
+        let token = self.request_token(token.refresh_token).await?;
+        if let Some(on_updated) = &self.on_updated {
+            on_updated(&token);
+        }
+        *self.current.write().unwrap_or_else(PoisonError::into_inner) = (generation + 1, token);
+        Ok(())
+    }
+
+    async fn request_token(&self, refresh_token: String) -> Result<Token, Error> {
+        let body = TokenRequest::RefreshToken(RefreshTokenRequest {
+            refresh_token,
+            client_id: "ownerapi".into(),
+            // scope has user_data removed but vehicle_device_data added
+            scope: "openid offline_access vehicle_device_data vehicle_cmds vehicle_charging_cmds energy_device_data energy_cmds".into(),
+        });
+
+        let url = format!("{}oauth2/v3/token", self.auth_url);
+        let response = self
+            .http
+            .post(url)
+            .json(&body)
+            .header("Content-Type", "application/json")
+            .pipe(errors::send)
+            .await?;
+
+        errors::json::<RawToken>(response).await.map(Token::from)
+    }
+}
@@ -2,4 +2,5 @@ pub mod auth;
 pub mod responses;
 pub mod simulator;
 pub mod streaming;
+pub mod telemetry;
 pub mod types;
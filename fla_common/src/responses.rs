@@ -4,7 +4,14 @@ use std::collections::HashMap;
 
 use serde::{Deserialize, Serialize};
 
-use crate::types::{VehicleData, VehicleDefinition};
+use crate::{
+    simulator::{
+        ClockStatus, Fault, FleetUser, Metrics, ParkedPolicy, RateLimits, SleepPolicy,
+        SnapshotInfo, VehicleStatus,
+    },
+    telemetry::{FleetTelemetryConfigStatus, FleetTelemetryConfigUpdated},
+    types::{Invitation, RedeemedInvitation, VehicleData, VehicleDefinition},
+};
 
 /// An error from the Tesla API
 #[derive(Serialize, Deserialize, Debug)]
@@ -13,9 +20,11 @@ pub struct TeslaError {
     pub error: String,
 
     /// The error description
+    #[serde(default)]
     pub error_description: String,
 
     /// Additional messages
+    #[serde(default)]
     pub messages: HashMap<String, String>,
 }
 
@@ -74,6 +83,22 @@ pub fn error(error: impl Into<String>, description: impl Into<String>) -> TeslaR
 pub type VehiclesResponse = TeslaResponse<Vec<VehicleDefinition>>;
 pub type VehicleResponse = TeslaResponse<VehicleDefinition>;
 pub type VehicleDataResponse = TeslaResponse<VehicleData>;
+pub type InvitationsResponse = TeslaResponse<Vec<Invitation>>;
+pub type InvitationResponse = TeslaResponse<Invitation>;
+pub type RedeemInvitationResponse = TeslaResponse<RedeemedInvitation>;
+pub type FleetTelemetryConfigResponse = TeslaResponse<FleetTelemetryConfigStatus>;
+pub type FleetTelemetryConfigUpdatedResponse = TeslaResponse<FleetTelemetryConfigUpdated>;
+pub type ClockResponse = TeslaResponse<ClockStatus>;
+pub type SleepPolicyResponse = TeslaResponse<SleepPolicy>;
+pub type ParkedPolicyResponse = TeslaResponse<ParkedPolicy>;
+pub type FaultsResponse = TeslaResponse<Vec<Fault>>;
+pub type RateLimitsResponse = TeslaResponse<RateLimits>;
+pub type MetricsResponse = TeslaResponse<Metrics>;
+pub type VehicleStatusResponse = TeslaResponse<VehicleStatus>;
+pub type VehicleStatusesResponse = TeslaResponse<Vec<VehicleStatus>>;
+pub type SnapshotResponse = TeslaResponse<SnapshotInfo>;
+pub type SnapshotsResponse = TeslaResponse<Vec<SnapshotInfo>>;
+pub type UsersResponse = TeslaResponse<Vec<FleetUser>>;
 
 #[cfg(test)]
 mod test {
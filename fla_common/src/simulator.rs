@@ -1,7 +1,9 @@
-use std::str::FromStr;
+use std::{str::FromStr, time::Duration};
 
 use serde::{Deserialize, Serialize};
 
+use crate::types::{Timestamp, UserId, VehicleGuid, VehicleId};
+
 #[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
 pub enum SimulationStateEnum {
     /// The vehicle is driving
@@ -16,6 +18,9 @@ pub enum SimulationStateEnum {
     /// The vehicle is idle but should not go to sleep
     IdleNoSleep,
 
+    /// The vehicle has stopped responding but is still reported as online
+    Drowsy,
+
     /// The vehicle is sleeping
     Sleeping,
 }
@@ -29,8 +34,503 @@ impl FromStr for SimulationStateEnum {
             "charging" => Ok(Self::Charging),
             "idle" => Ok(Self::Idle),
             "idle_no_sleep" => Ok(Self::IdleNoSleep),
+            "drowsy" => Ok(Self::Drowsy),
             "sleeping" => Ok(Self::Sleeping),
             _ => Err(format!("Unknown simulation state: {}", s)),
         }
     }
 }
+
+/// The state of the simulator clock
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct ClockStatus {
+    /// Current simulated time, Unix timestamp in milliseconds
+    pub time: Timestamp,
+
+    /// How many simulated seconds pass per real second, `None` if the clock is manual
+    pub speed: Option<f64>,
+}
+
+/// Request to move a manual clock forward
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct AdvanceRequest {
+    /// How far to move the clock, in seconds
+    pub seconds: f64,
+}
+
+/// Request to change the heading of a vehicle
+#[derive(Debug, Serialize, Deserialize, Clone, Copy)]
+pub struct SteerRequest {
+    /// New heading in degrees, from 0 up to 360
+    pub heading: u16,
+}
+
+/// When a simulated vehicle falls asleep and wakes up
+///
+/// Real vehicles only fall asleep after a period without activity, and take a while to come
+/// back online after a wake up request.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(default)]
+pub struct SleepPolicy {
+    /// Seconds without activity before the vehicle falls asleep
+    pub idle_timeout_seconds: u64,
+
+    /// Seconds from a wake up request until the vehicle is online
+    pub wake_latency_seconds: u64,
+
+    /// Whether vehicle data requests count as activity and keep the vehicle awake
+    pub polling_keeps_awake: bool,
+
+    /// Seconds the vehicle is still reported as online after it has stopped responding
+    pub drowsy_seconds: u64,
+}
+
+impl Default for SleepPolicy {
+    fn default() -> Self {
+        Self {
+            idle_timeout_seconds: 60,
+            wake_latency_seconds: 60,
+            polling_keeps_awake: true,
+            drowsy_seconds: 0,
+        }
+    }
+}
+
+impl SleepPolicy {
+    /// Time without activity before the vehicle falls asleep
+    #[must_use]
+    pub const fn idle_timeout(&self) -> Duration {
+        Duration::from_secs(self.idle_timeout_seconds)
+    }
+
+    /// Time from a wake up request until the vehicle is online
+    #[must_use]
+    pub const fn wake_latency(&self) -> Duration {
+        Duration::from_secs(self.wake_latency_seconds)
+    }
+
+    /// Time the vehicle is still reported as online after it has stopped responding
+    #[must_use]
+    pub const fn drowsy_period(&self) -> Duration {
+        Duration::from_secs(self.drowsy_seconds)
+    }
+}
+
+/// A value for each tyre
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq)]
+pub struct TyreValues {
+    /// Front left
+    pub fl: f64,
+
+    /// Front right
+    pub fr: f64,
+
+    /// Rear left
+    pub rl: f64,
+
+    /// Rear right
+    pub rr: f64,
+}
+
+/// How a simulated vehicle changes while it is parked
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default)]
+pub struct ParkedPolicy {
+    /// Battery percentage lost per hour while parked and awake
+    pub awake_drain_per_hour: f64,
+
+    /// Battery percentage lost per hour while asleep
+    pub asleep_drain_per_hour: f64,
+
+    /// Average outside temperature over a day, in °C
+    pub mean_outside_temp: f64,
+
+    /// Difference between the coldest and warmest time of the day, in °C
+    pub daily_temp_range: f64,
+
+    /// Tyre pressure lost per day, in bar
+    pub tyre_leak_per_day: TyreValues,
+}
+
+impl Default for ParkedPolicy {
+    fn default() -> Self {
+        Self {
+            awake_drain_per_hour: 0.5,
+            asleep_drain_per_hour: 0.04,
+            mean_outside_temp: 15.0,
+            daily_temp_range: 10.0,
+            tyre_leak_per_day: TyreValues::default(),
+        }
+    }
+}
+
+/// A kind of fault that can be injected into a simulated vehicle
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum FaultKind {
+    /// Requests fail with 408 device not available
+    DeviceNotAvailable,
+
+    /// Requests fail with 540 device unexpected response
+    DeviceUnexpectedResponse,
+
+    /// Requests are answered after a delay
+    Latency,
+
+    /// The vehicle never answers, so requests time out
+    Timeout,
+
+    /// Streaming connections to the vehicle are dropped
+    DropStreaming,
+
+    /// Responses are not valid JSON
+    MalformedJson,
+
+    /// Responses are cut off part way through
+    PartialJson,
+}
+
+/// A fault injected into a simulated vehicle
+///
+/// With neither `probability` nor `count` set, the fault applies to every request until it is
+/// cleared.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, PartialEq)]
+pub struct Fault {
+    /// What goes wrong
+    pub kind: FaultKind,
+
+    /// Chance between 0 and 1 that a request is affected
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub probability: Option<f64>,
+
+    /// Only affect this many requests, then remove the fault
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub count: Option<u32>,
+
+    /// Delay in milliseconds, for `FaultKind::Latency`
+    #[serde(default)]
+    pub latency_ms: u64,
+}
+
+impl Fault {
+    /// Create a fault that affects every request
+    #[must_use]
+    pub const fn new(kind: FaultKind) -> Self {
+        Self {
+            kind,
+            probability: None,
+            count: None,
+            latency_ms: 0,
+        }
+    }
+
+    /// Only affect requests with this probability
+    #[must_use]
+    pub const fn probability(mut self, probability: f64) -> Self {
+        self.probability = Some(probability);
+        self
+    }
+
+    /// Only affect the next `count` requests
+    #[must_use]
+    pub const fn count(mut self, count: u32) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    /// Delay requests by this long
+    #[must_use]
+    pub const fn latency_ms(mut self, latency_ms: u64) -> Self {
+        self.latency_ms = latency_ms;
+        self
+    }
+}
+
+/// How many requests are allowed in a period of time
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Eq, PartialEq)]
+pub struct RateBudget {
+    /// Number of requests allowed in the period
+    pub requests: u32,
+
+    /// Length of the period in seconds
+    pub period_seconds: u64,
+}
+
+impl RateBudget {
+    /// Length of the period
+    #[must_use]
+    pub const fn period(&self) -> Duration {
+        Duration::from_secs(self.period_seconds)
+    }
+}
+
+/// Request budgets for each kind of request, `None` is unlimited
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+#[serde(default)]
+pub struct RateBudgets {
+    /// Requests for vehicle lists and vehicle data
+    pub data: Option<RateBudget>,
+
+    /// Wake up requests
+    pub wake_up: Option<RateBudget>,
+
+    /// Commands sent to vehicles
+    pub command: Option<RateBudget>,
+}
+
+/// Rate limits applied by the server
+///
+/// A request is only allowed if it is within the budget for its user and within the budget for its
+/// vehicle.
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, Eq, PartialEq)]
+#[serde(default)]
+pub struct RateLimits {
+    /// Budgets for each user, shared by all of their access tokens
+    pub per_token: RateBudgets,
+
+    /// Budgets for each vehicle, shared by all access tokens
+    pub per_vehicle: RateBudgets,
+}
+
+/// Where a simulated vehicle is parked when it starts
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FleetLocation {
+    /// Latitude in degrees
+    pub latitude: f64,
+
+    /// Longitude in degrees
+    pub longitude: f64,
+
+    /// Heading in degrees
+    pub heading: u16,
+}
+
+impl Default for FleetLocation {
+    fn default() -> Self {
+        Self {
+            latitude: 37.776_549_4,
+            longitude: -122.419_541_8,
+            heading: 0,
+        }
+    }
+}
+
+/// The battery of a simulated vehicle when it starts
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct FleetBattery {
+    /// Battery level in percent
+    pub level: f64,
+
+    /// Charge limit in percent
+    pub charge_limit_soc: u8,
+}
+
+impl Default for FleetBattery {
+    fn default() -> Self {
+        Self {
+            level: 42.0,
+            charge_limit_soc: 90,
+        }
+    }
+}
+
+const fn default_state() -> SimulationStateEnum {
+    SimulationStateEnum::Idle
+}
+
+/// The user that owns vehicles unless a fleet says otherwise
+pub const DEFAULT_USER: UserId = UserId::new(800_001);
+
+const fn default_owner() -> UserId {
+    DEFAULT_USER
+}
+
+fn default_users() -> Vec<FleetUser> {
+    vec![FleetUser {
+        id: DEFAULT_USER,
+        email: "test@example.com".to_string(),
+    }]
+}
+
+/// A simulated user, who can own and drive vehicles
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FleetUser {
+    /// The user ID, used as the subject of access tokens
+    pub id: UserId,
+
+    /// The email address of the user
+    pub email: String,
+}
+
+/// A user a vehicle is shared with
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct FleetDriver {
+    /// The user
+    pub user: UserId,
+
+    /// Hide the location of the vehicle from this driver
+    #[serde(default)]
+    pub hide_private: bool,
+}
+
+/// The model of a simulated vehicle
+#[derive(Debug, Serialize, Deserialize, Copy, Clone, Default, Eq, PartialEq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum VehicleModel {
+    /// Model S
+    ModelS,
+
+    /// Model 3
+    #[default]
+    Model3,
+
+    /// Model X
+    ModelX,
+
+    /// Model Y
+    ModelY,
+
+    /// Cybertruck
+    Cybertruck,
+}
+
+/// A simulated vehicle in a fleet file
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FleetVehicle {
+    /// The vehicle ID
+    pub id: VehicleId,
+
+    /// The vehicle GUID, used for streaming
+    pub vehicle_id: VehicleGuid,
+
+    /// The vehicle identification number
+    pub vin: String,
+
+    /// The name of the vehicle
+    pub display_name: String,
+
+    /// The model, which decides the vehicle configuration
+    #[serde(default)]
+    pub model: VehicleModel,
+
+    /// The trim badge of the model, which decides pack size and efficiency
+    ///
+    /// Defaults to the first trim of the model.
+    #[serde(default)]
+    pub trim: Option<String>,
+
+    /// The colour of the vehicle
+    #[serde(default)]
+    pub color: Option<String>,
+
+    /// Comma separated option codes, generated from the model if not given
+    #[serde(default)]
+    pub option_codes: Option<String>,
+
+    /// Where the vehicle is parked
+    #[serde(default)]
+    pub location: FleetLocation,
+
+    /// The battery state
+    #[serde(default)]
+    pub battery: FleetBattery,
+
+    /// What the vehicle is doing when the simulator starts
+    #[serde(default = "default_state")]
+    pub state: SimulationStateEnum,
+
+    /// The user that owns the vehicle
+    #[serde(default = "default_owner")]
+    pub owner: UserId,
+
+    /// Other users the vehicle is shared with
+    #[serde(default)]
+    pub drivers: Vec<FleetDriver>,
+}
+
+/// All simulated vehicles, as read from a fleet file
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Fleet {
+    /// The users, defaults to a single user that owns every vehicle
+    #[serde(default = "default_users")]
+    pub users: Vec<FleetUser>,
+
+    /// The vehicles
+    pub vehicles: Vec<FleetVehicle>,
+}
+
+/// The internal state of a simulated vehicle
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct VehicleStatus {
+    /// How the vehicle was defined, resetting the vehicle goes back to this
+    pub vehicle: FleetVehicle,
+
+    /// What the vehicle is doing
+    pub simulation_state: SimulationStateEnum,
+
+    /// Battery level in percent, including fractions of a percent
+    pub battery_level: f64,
+
+    /// Current latitude in degrees
+    pub latitude: f64,
+
+    /// Current longitude in degrees
+    pub longitude: f64,
+
+    /// Current heading in degrees
+    pub heading: u16,
+
+    /// When the vehicle falls asleep and wakes up
+    pub sleep_policy: SleepPolicy,
+
+    /// How the vehicle changes while parked
+    pub parked_policy: ParkedPolicy,
+
+    /// The active faults
+    pub faults: Vec<Fault>,
+
+    /// Number of streaming connections receiving data from the vehicle
+    pub streaming_connections: usize,
+}
+
+/// Counters for streaming delivery since the server started
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamingMetrics {
+    /// Rows sent to streaming clients
+    pub rows_sent: u64,
+
+    /// Rows a slow client never got, because newer data arrived first
+    pub rows_skipped: u64,
+
+    /// Number of times a subscription fell behind and skipped rows
+    pub lag_events: u64,
+
+    /// Streaming connections that are open now
+    pub connections: u64,
+}
+
+/// Counters for the whole simulator
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// Streaming delivery
+    pub streaming: StreamingMetrics,
+}
+
+/// Summary of a stored snapshot of the simulated fleet
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SnapshotInfo {
+    /// The name of the snapshot
+    pub name: String,
+
+    /// Simulated time the snapshot was taken, Unix timestamp in milliseconds
+    pub time: Timestamp,
+
+    /// Number of vehicles in the snapshot
+    pub vehicles: usize,
+}
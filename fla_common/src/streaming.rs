@@ -5,7 +5,7 @@ use thiserror::Error;
 
 use crate::types::{ShiftState, Timestamp, VehicleGuid};
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy)]
 pub enum ErrorType {
     #[serde(rename = "vehicle_disconnected")]
     VehicleDisconnected,
@@ -17,7 +17,7 @@ pub enum ErrorType {
     ClientError,
 }
 
-#[derive(Deserialize, Serialize, Debug, Error)]
+#[derive(Deserialize, Serialize, Debug, Clone, Error)]
 pub struct DataError {
     pub tag: String,
     pub error_type: ErrorType,
@@ -60,14 +60,37 @@ impl std::fmt::Display for DataError {
     }
 }
 
+/// How often a subscription gets rows, the real server ignores these
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StreamingOptions {
+    /// Minimum time between rows in milliseconds, rows in between are skipped
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub interval_ms: Option<u64>,
+
+    /// Skip rows where none of the subscribed fields changed, like a parked car
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub change_only: bool,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 #[serde(tag = "msg_type")]
 pub enum ToServerStreamingMessage {
+    /// Legacy subscription, the token is base64 encoded `email:vehicle_token`
+    #[serde(rename = "data:subscribe")]
+    DataSubscribe {
+        token: String,
+        value: String,
+        tag: String,
+        #[serde(flatten)]
+        options: StreamingOptions,
+    },
     #[serde(rename = "data:subscribe_oauth")]
     DataSubscribeOauth {
         token: String,
         value: String,
         tag: String,
+        #[serde(flatten)]
+        options: StreamingOptions,
     },
     #[serde(rename = "data:unsubscribe")]
     DataUnsubscribe { tag: String },
@@ -110,6 +133,11 @@ pub enum StreamingFields {
     Range,
     EstRange,
     Heading,
+    NativeLatitude,
+    NativeLongitude,
+    NativePower,
+    NativeType,
+    NativeLocationSupported,
 }
 
 impl FromStr for StreamingFields {
@@ -129,27 +157,38 @@ impl FromStr for StreamingFields {
             "range" => Ok(Self::Range),
             "est_range" => Ok(Self::EstRange),
             "heading" => Ok(Self::Heading),
+            "native_latitude" => Ok(Self::NativeLatitude),
+            "native_longitude" => Ok(Self::NativeLongitude),
+            "native_power" => Ok(Self::NativePower),
+            "native_type" => Ok(Self::NativeType),
+            "native_location_supported" => Ok(Self::NativeLocationSupported),
             _ => Err(()),
         }
     }
 }
 
-impl ToString for StreamingFields {
-    fn to_string(&self) -> String {
-        match self {
-            StreamingFields::Speed => "speed".to_string(),
-            StreamingFields::Odometer => "odometer".to_string(),
-            StreamingFields::Soc => "soc".to_string(),
-            StreamingFields::Elevation => "elevation".to_string(),
-            StreamingFields::EstHeading => "est_heading".to_string(),
-            StreamingFields::EstLat => "est_lat".to_string(),
-            StreamingFields::EstLng => "est_lng".to_string(),
-            StreamingFields::Power => "power".to_string(),
-            StreamingFields::ShiftState => "shift_state".to_string(),
-            StreamingFields::Range => "range".to_string(),
-            StreamingFields::EstRange => "est_range".to_string(),
-            StreamingFields::Heading => "heading".to_string(),
-        }
+impl std::fmt::Display for StreamingFields {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
+            StreamingFields::Speed => "speed",
+            StreamingFields::Odometer => "odometer",
+            StreamingFields::Soc => "soc",
+            StreamingFields::Elevation => "elevation",
+            StreamingFields::EstHeading => "est_heading",
+            StreamingFields::EstLat => "est_lat",
+            StreamingFields::EstLng => "est_lng",
+            StreamingFields::Power => "power",
+            StreamingFields::ShiftState => "shift_state",
+            StreamingFields::Range => "range",
+            StreamingFields::EstRange => "est_range",
+            StreamingFields::Heading => "heading",
+            StreamingFields::NativeLatitude => "native_latitude",
+            StreamingFields::NativeLongitude => "native_longitude",
+            StreamingFields::NativePower => "native_power",
+            StreamingFields::NativeType => "native_type",
+            StreamingFields::NativeLocationSupported => "native_location_supported",
+        };
+        f.write_str(str)
     }
 }
 
@@ -197,6 +236,21 @@ pub struct StreamingData {
 
     /// Heading in degrees.
     pub heading: Option<u16>,
+
+    /// Latitude in the vehicle's native coordinate system.
+    pub native_latitude: Option<f64>,
+
+    /// Longitude in the vehicle's native coordinate system.
+    pub native_longitude: Option<f64>,
+
+    /// Power usage in kW, as reported by the vehicle.
+    pub native_power: Option<i32>,
+
+    /// The vehicle's native coordinate system, e.g. `wgs`.
+    pub native_type: Option<String>,
+
+    /// 1 if the vehicle reports a native location.
+    pub native_location_supported: Option<u8>,
 }
 
 impl StreamingData {
@@ -216,6 +270,11 @@ impl StreamingData {
             range: None,
             est_range: None,
             heading: None,
+            native_latitude: None,
+            native_longitude: None,
+            native_power: None,
+            native_type: None,
+            native_location_supported: None,
         }
     }
 }
@@ -0,0 +1,351 @@
+//! Fleet Telemetry records and configuration
+//!
+//! The protobuf messages are wire compatible with `vehicle_data.proto` from Tesla's
+//! fleet-telemetry project, but only include the fields the simulator can report.
+
+use std::collections::BTreeMap;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// A value a vehicle can report
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    prost::Enumeration,
+)]
+#[repr(i32)]
+pub enum Field {
+    /// Not a known field
+    Unknown = 0,
+
+    /// Charging state, as in `charge_state.charging_state`
+    ChargeState = 2,
+
+    /// Speed in miles per hour
+    VehicleSpeed = 4,
+
+    /// Odometer in miles
+    Odometer = 5,
+
+    /// State of charge in percent
+    Soc = 8,
+
+    /// Shift state, `P`, `D` or `R`
+    Gear = 10,
+
+    /// Latitude and longitude
+    Location = 21,
+
+    /// Heading in degrees
+    GpsHeading = 23,
+
+    /// Rated range in miles
+    RatedRange = 32,
+
+    /// Charge limit in percent
+    ChargeLimitSoc = 38,
+
+    /// Estimated range in miles
+    EstBatteryRange = 40,
+
+    /// Ideal range in miles
+    IdealBatteryRange = 41,
+
+    /// Battery level in percent
+    BatteryLevel = 42,
+
+    /// Charging current in amps
+    ChargeAmps = 49,
+}
+
+/// A latitude and longitude
+#[derive(Clone, Copy, PartialEq, prost::Message)]
+pub struct LocationValue {
+    /// Latitude in degrees
+    #[prost(double, tag = "1")]
+    pub latitude: f64,
+
+    /// Longitude in degrees
+    #[prost(double, tag = "2")]
+    pub longitude: f64,
+}
+
+/// The value of a field
+#[derive(Clone, PartialEq, prost::Oneof)]
+pub enum Value {
+    /// A string value
+    #[prost(string, tag = "1")]
+    StringValue(String),
+
+    /// A 32 bit integer value
+    #[prost(int32, tag = "2")]
+    IntValue(i32),
+
+    /// A 64 bit integer value
+    #[prost(int64, tag = "3")]
+    LongValue(i64),
+
+    /// A single precision value
+    #[prost(float, tag = "4")]
+    FloatValue(f32),
+
+    /// A double precision value
+    #[prost(double, tag = "5")]
+    DoubleValue(f64),
+
+    /// A boolean value
+    #[prost(bool, tag = "6")]
+    BooleanValue(bool),
+
+    /// A location value
+    #[prost(message, tag = "7")]
+    LocationValue(LocationValue),
+}
+
+/// Wrapper for the value oneof, as in `vehicle_data.proto`
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct DatumValue {
+    /// The value
+    #[prost(oneof = "Value", tags = "1, 2, 3, 4, 5, 6, 7")]
+    pub value: Option<Value>,
+}
+
+/// A single field and its value
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Datum {
+    /// The field
+    #[prost(enumeration = "Field", tag = "1")]
+    pub key: i32,
+
+    /// The value of the field
+    #[prost(message, optional, tag = "2")]
+    pub value: Option<DatumValue>,
+}
+
+impl Datum {
+    /// Create a datum for a field
+    #[must_use]
+    pub fn new(key: Field, value: Value) -> Self {
+        Self {
+            key: key.into(),
+            value: Some(DatumValue { value: Some(value) }),
+        }
+    }
+
+    /// The field, `Field::Unknown` if it is not known
+    #[must_use]
+    pub fn field(&self) -> Field {
+        Field::try_from(self.key).unwrap_or(Field::Unknown)
+    }
+
+    /// The value of the field
+    #[must_use]
+    pub fn value(&self) -> Option<&Value> {
+        self.value.as_ref().and_then(|value| value.value.as_ref())
+    }
+}
+
+/// A point in time, as `google.protobuf.Timestamp`
+#[derive(Clone, Copy, PartialEq, Eq, prost::Message)]
+pub struct ProtoTimestamp {
+    /// Seconds since the Unix epoch
+    #[prost(int64, tag = "1")]
+    pub seconds: i64,
+
+    /// Nanoseconds within the second
+    #[prost(int32, tag = "2")]
+    pub nanos: i32,
+}
+
+impl From<DateTime<Utc>> for ProtoTimestamp {
+    fn from(time: DateTime<Utc>) -> Self {
+        Self {
+            seconds: time.timestamp(),
+            nanos: i32::try_from(time.timestamp_subsec_nanos()).unwrap_or_default(),
+        }
+    }
+}
+
+impl From<ProtoTimestamp> for Option<DateTime<Utc>> {
+    fn from(time: ProtoTimestamp) -> Self {
+        DateTime::from_timestamp(time.seconds, u32::try_from(time.nanos).ok()?)
+    }
+}
+
+/// A record sent by a vehicle
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Payload {
+    /// The fields that are due
+    #[prost(message, repeated, tag = "1")]
+    pub data: Vec<Datum>,
+
+    /// When the record was created
+    #[prost(message, optional, tag = "2")]
+    pub created_at: Option<ProtoTimestamp>,
+
+    /// The VIN of the vehicle
+    #[prost(string, tag = "3")]
+    pub vin: String,
+
+    /// The record was sent before
+    #[prost(bool, tag = "4")]
+    pub is_resend: bool,
+}
+
+impl Payload {
+    /// Find the value of a field
+    #[must_use]
+    pub fn get(&self, field: Field) -> Option<&Value> {
+        self.data
+            .iter()
+            .find(|datum| datum.field() == field)
+            .and_then(Datum::value)
+    }
+}
+
+/// How often a field is sent
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldConfig {
+    /// Seconds between records containing the field
+    pub interval_seconds: u32,
+}
+
+const fn default_port() -> u16 {
+    443
+}
+
+/// Where a vehicle sends records, and what it sends
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FleetTelemetryConfig {
+    /// Host name of the receiver
+    pub hostname: String,
+
+    /// Port of the receiver
+    #[serde(default = "default_port")]
+    pub port: u16,
+
+    /// Certificate authority of the receiver
+    #[serde(default)]
+    pub ca: Option<String>,
+
+    /// The fields to send
+    pub fields: BTreeMap<Field, FieldConfig>,
+
+    /// Alerts to send
+    #[serde(default)]
+    pub alert_types: Vec<String>,
+
+    /// When the configuration expires, in seconds since the Unix epoch
+    #[serde(default)]
+    pub exp: Option<i64>,
+}
+
+/// Request to configure vehicles
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FleetTelemetryConfigRequest {
+    /// The VINs of the vehicles to configure
+    pub vins: Vec<String>,
+
+    /// The configuration
+    pub config: FleetTelemetryConfig,
+}
+
+/// Vehicles that were not configured, the simulator supports every vehicle
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SkippedVehicles {
+    /// The vehicle has not paired the key of the application
+    pub missing_key: Vec<String>,
+
+    /// The vehicle is too old
+    pub unsupported_hardware: Vec<String>,
+
+    /// The vehicle needs a software update
+    pub unsupported_firmware: Vec<String>,
+}
+
+/// Result of configuring vehicles
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FleetTelemetryConfigUpdated {
+    /// How many vehicles were configured
+    pub updated_vehicles: usize,
+
+    /// Vehicles that were not configured
+    #[serde(default)]
+    pub skipped_vehicles: SkippedVehicles,
+}
+
+/// The configuration of a vehicle
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct FleetTelemetryConfigStatus {
+    /// The vehicle is using the configuration
+    pub synced: bool,
+
+    /// The configuration, if there is one
+    pub config: Option<FleetTelemetryConfig>,
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+
+    use chrono::TimeZone;
+    use prost::Message;
+
+    use super::*;
+
+    #[test]
+    fn test_payload_round_trip() {
+        let created_at = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+        let payload = Payload {
+            data: vec![
+                Datum::new(Field::VehicleSpeed, Value::DoubleValue(60.0)),
+                Datum::new(
+                    Field::Location,
+                    Value::LocationValue(LocationValue {
+                        latitude: -37.8,
+                        longitude: 145.0,
+                    }),
+                ),
+            ],
+            created_at: Some(created_at.into()),
+            vin: "5YJ3E1EA7JF000789".to_string(),
+            is_resend: false,
+        };
+
+        let decoded = Payload::decode(payload.encode_to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, payload);
+        assert_eq!(
+            decoded.get(Field::VehicleSpeed),
+            Some(&Value::DoubleValue(60.0))
+        );
+        assert_eq!(decoded.get(Field::Odometer), None);
+        assert_eq!(
+            Option::<DateTime<Utc>>::from(decoded.created_at.unwrap()),
+            Some(created_at)
+        );
+    }
+
+    #[test]
+    fn test_config() {
+        let config: FleetTelemetryConfig = serde_json::from_str(
+            r#"{"hostname":"localhost","fields":{"VehicleSpeed":{"interval_seconds":10}}}"#,
+        )
+        .unwrap();
+        assert_eq!(config.port, 443);
+        assert_eq!(
+            config.fields[&Field::VehicleSpeed],
+            FieldConfig {
+                interval_seconds: 10
+            }
+        );
+    }
+}
@@ -2,6 +2,7 @@
 
 use std::{convert::Infallible, num::ParseIntError, str::FromStr};
 
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize, Serializer};
 use serde_enum_str::{Deserialize_enum_str, Serialize_enum_str};
 
@@ -11,7 +12,7 @@ use crate::simulator::SimulationStateEnum;
 pub type Timestamp = i64;
 
 /// A vehicle ID
-#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
 pub struct VehicleId(u64);
 
 impl FromStr for VehicleId {
@@ -22,9 +23,9 @@ impl FromStr for VehicleId {
     }
 }
 
-impl ToString for VehicleId {
-    fn to_string(&self) -> String {
-        self.0.to_string()
+impl std::fmt::Display for VehicleId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
@@ -54,9 +55,9 @@ impl FromStr for VehicleGuid {
     }
 }
 
-impl ToString for VehicleGuid {
-    fn to_string(&self) -> String {
-        self.0.to_string()
+impl std::fmt::Display for VehicleGuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
     }
 }
 
@@ -68,6 +69,118 @@ impl VehicleGuid {
     }
 }
 
+/// A user ID
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct UserId(i64);
+
+impl FromStr for UserId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl std::fmt::Display for UserId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<UserId> for i64 {
+    fn from(id: UserId) -> Self {
+        id.0
+    }
+}
+
+impl UserId {
+    /// Create a new UserId
+    #[must_use]
+    pub const fn new(id: i64) -> Self {
+        Self(id)
+    }
+}
+
+/// An invitation ID
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct InvitationId(u64);
+
+impl FromStr for InvitationId {
+    type Err = ParseIntError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self(s.parse()?))
+    }
+}
+
+impl std::fmt::Display for InvitationId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl InvitationId {
+    /// Create a new InvitationId
+    #[must_use]
+    pub const fn new(id: u64) -> Self {
+        Self(id)
+    }
+}
+
+/// The state of an invitation to share a vehicle
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvitationState {
+    /// Waiting to be redeemed
+    Pending,
+
+    /// Redeemed, the vehicle is shared with another user
+    Redeemed,
+
+    /// Revoked by the owner, the vehicle is no longer shared
+    Revoked,
+}
+
+/// An invitation to share a vehicle with another user
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Invitation {
+    pub id: InvitationId,
+    pub owner_id: UserId,
+    pub sender_id: UserId,
+    pub vin: String,
+    pub state: InvitationState,
+    pub share_type: String,
+    pub share_link: String,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+    pub redeemed_at: Option<DateTime<Utc>>,
+    pub id_s: String,
+}
+
+impl Invitation {
+    /// The code used to redeem the invitation, the end of the share link
+    #[must_use]
+    pub fn code(&self) -> &str {
+        self.share_link
+            .rsplit('/')
+            .next()
+            .unwrap_or(&self.share_link)
+    }
+}
+
+/// Request to redeem an invitation
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RedeemInvitationRequest {
+    pub invitation_code: String,
+}
+
+/// The vehicle shared by a redeemed invitation
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct RedeemedInvitation {
+    pub vehicle_id_s: String,
+    pub vin: String,
+}
+
 /// Enum representing a vehicle's shift state.
 #[derive(Deserialize_enum_str, Serialize_enum_str, Clone, Eq, PartialEq, Debug)]
 #[serde(rename_all = "snake_case")]
@@ -90,6 +203,7 @@ impl From<SimulationStateEnum> for VehicleStateEnum {
             SimulationStateEnum::Charging => Self::Online,
             SimulationStateEnum::Idle => Self::Online,
             SimulationStateEnum::IdleNoSleep => Self::Online,
+            SimulationStateEnum::Drowsy => Self::Online,
             SimulationStateEnum::Sleeping => Self::Offline,
         }
     }
@@ -185,15 +299,15 @@ impl FromStr for ShiftState {
 }
 
 /// Required for streaming.
-impl ToString for ShiftState {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for ShiftState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
             Self::Park => "P",
             Self::Drive => "D",
             Self::Reverse => "R",
             Self::Unknown(s) => s,
-        }
-        .to_string()
+        };
+        f.write_str(str)
     }
 }
 
@@ -565,9 +679,9 @@ impl FromStr for VehicleDataEndpoint {
     }
 }
 
-impl ToString for VehicleDataEndpoint {
-    fn to_string(&self) -> String {
-        match self {
+impl std::fmt::Display for VehicleDataEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let str = match self {
             Self::ChargeState => "charge_state",
             Self::ClimateState => "climate_state",
             Self::ClosuresState => "closures_state",
@@ -577,8 +691,8 @@ impl ToString for VehicleDataEndpoint {
             Self::VehicleConfig => "vehicle_config",
             Self::VehicleState => "vehicle_state",
             Self::VehicleDataCombo => "vehicle_data_combo",
-        }
-        .to_string()
+        };
+        f.write_str(str)
     }
 }
 
@@ -0,0 +1,641 @@
+//! Simulator administration API
+//!
+//! These endpoints do not exist on the real Tesla servers. They control the simulator itself, and
+//! see every vehicle of every user, so they all need a token with the `simulator_admin` scope.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, State,
+    },
+    middleware::{from_fn, from_fn_with_state},
+    response::IntoResponse,
+    routing::{get, post},
+    Extension, Json, Router,
+};
+use fla_common::{
+    auth::RawToken,
+    responses::{
+        ClockResponse, FaultsResponse, MetricsResponse, ParkedPolicyResponse, RateLimitsResponse,
+        SleepPolicyResponse, SnapshotResponse, SnapshotsResponse, TeslaResponse, UsersResponse,
+        VehicleStatusResponse, VehicleStatusesResponse,
+    },
+    simulator::{
+        AdvanceRequest, ClockStatus, Fault, FleetVehicle, ParkedPolicy, RateLimits, SleepPolicy,
+        SteerRequest,
+    },
+    telemetry::Payload,
+    types::{UserId, VehicleId},
+};
+use futures::future::try_join_all;
+use prost::Message as _;
+use tokio::{
+    select,
+    sync::broadcast::{self, error::RecvError},
+};
+use tracing::{debug, error};
+
+use crate::{
+    errors::ResponseError,
+    invitations::Invitations,
+    middleware,
+    rate_limit::RateLimiter,
+    simulator::{
+        clock::ClockMode,
+        snapshot::{Snapshot, Snapshots},
+        telemetry::Receiver,
+        Context,
+    },
+    tokens,
+    types::{Users, Vehicles},
+    Config,
+};
+
+/// Retrieve router for the simulator administration API
+pub fn router(config: &Config) -> Router {
+    Router::new()
+        .route("/admin/clock", get(clock_handler))
+        .route("/admin/clock/advance", post(advance_handler))
+        .route("/admin/metrics", get(metrics_handler))
+        .route(
+            "/admin/rate_limits",
+            get(rate_limits_handler).put(set_rate_limits_handler),
+        )
+        .route(
+            "/admin/snapshot",
+            get(snapshot_handler).put(restore_handler),
+        )
+        .route("/admin/snapshots", get(snapshots_handler))
+        .route(
+            "/admin/snapshots/:name",
+            get(named_snapshot_handler)
+                .post(save_snapshot_handler)
+                .delete(delete_snapshot_handler),
+        )
+        .route(
+            "/admin/snapshots/:name/restore",
+            post(restore_snapshot_handler),
+        )
+        .route("/admin/telemetry", get(telemetry_handler))
+        .route("/admin/users", get(users_handler))
+        .route("/admin/users/:id/token", post(user_token_handler))
+        .route(
+            "/admin/vehicles",
+            get(vehicles_handler).post(add_vehicle_handler),
+        )
+        .route(
+            "/admin/vehicles/:id",
+            get(vehicle_handler).delete(remove_vehicle_handler),
+        )
+        .route("/admin/vehicles/:id/reset", post(reset_vehicle_handler))
+        .route("/admin/vehicles/:id/steer", post(steer_handler))
+        .route(
+            "/admin/vehicles/:id/sleep_policy",
+            get(sleep_policy_handler).put(set_sleep_policy_handler),
+        )
+        .route(
+            "/admin/vehicles/:id/parked_policy",
+            get(parked_policy_handler).put(set_parked_policy_handler),
+        )
+        .route(
+            "/admin/vehicles/:id/faults",
+            get(faults_handler)
+                .post(add_fault_handler)
+                .delete(clear_faults_handler),
+        )
+        .layer(from_fn(middleware::simulator_admin))
+        .layer(from_fn_with_state(config.clone(), middleware::access_token))
+        .with_state(config.clone())
+}
+
+fn clock_status(context: &Context) -> ClockStatus {
+    let clock = &context.clock;
+    ClockStatus {
+        time: clock.utc_now().timestamp_millis(),
+        speed: match clock.mode() {
+            ClockMode::Running { speed } => Some(speed),
+            ClockMode::Manual => None,
+        },
+    }
+}
+
+/// Get the state of the simulator clock
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+#[allow(clippy::unused_async)]
+pub async fn clock_handler(
+    State(context): State<Arc<Context>>,
+) -> Result<Json<ClockResponse>, ResponseError> {
+    Ok(Json(TeslaResponse::success(clock_status(&context))))
+}
+
+/// Move a manual simulator clock forward
+///
+/// Returns after every vehicle has processed all events that are due.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 400 Bad Request if the duration is invalid or the clock is not manual.
+pub async fn advance_handler(
+    State(context): State<Arc<Context>>,
+    State(vehicles): State<Arc<Vehicles>>,
+    Json(request): Json<AdvanceRequest>,
+) -> Result<Json<ClockResponse>, ResponseError> {
+    let duration = Duration::try_from_secs_f64(request.seconds).map_err(|err| {
+        error!("Invalid duration {}: {err}", request.seconds);
+        ResponseError::InvalidField
+    })?;
+
+    context.clock.advance(duration).map_err(|err| {
+        error!("Cannot advance clock: {err}");
+        ResponseError::InvalidCommand
+    })?;
+
+    try_join_all(vehicles.list().iter().map(|v| v.command.sync())).await?;
+
+    Ok(Json(TeslaResponse::success(clock_status(&context))))
+}
+
+/// Get the simulator counters
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+#[allow(clippy::unused_async)]
+pub async fn metrics_handler(
+    State(context): State<Arc<Context>>,
+) -> Result<Json<MetricsResponse>, ResponseError> {
+    Ok(Json(TeslaResponse::success(context.metrics.snapshot())))
+}
+
+/// Get the internal state of all simulated vehicles
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+pub async fn vehicles_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+) -> Result<Json<VehicleStatusesResponse>, ResponseError> {
+    let statuses = try_join_all(vehicles.list().iter().map(|v| v.command.status())).await?;
+    Ok(Json(TeslaResponse::success(statuses)))
+}
+
+/// Start simulating a new vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 400 Bad Request if the vehicle is invalid or clashes with an existing vehicle.
+pub async fn add_vehicle_handler(
+    State(context): State<Arc<Context>>,
+    State(vehicles): State<Arc<Vehicles>>,
+    State(users): State<Arc<Users>>,
+    Json(vehicle): Json<FleetVehicle>,
+) -> Result<Json<VehicleStatusResponse>, ResponseError> {
+    let vehicle = vehicles.add(vehicle, &users, &context).map_err(|err| {
+        error!("Cannot add vehicle: {err}");
+        ResponseError::InvalidValue(err.to_string())
+    })?;
+
+    let status = vehicle.command.status().await?;
+    Ok(Json(TeslaResponse::success(status)))
+}
+
+/// Get the internal state of a simulated vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+pub async fn vehicle_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<VehicleStatusResponse>, ResponseError> {
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    let status = vehicle.command.status().await?;
+    Ok(Json(TeslaResponse::success(status)))
+}
+
+/// Stop simulating a vehicle
+///
+/// Returns the last state of the vehicle.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+pub async fn remove_vehicle_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    State(invitations): State<Arc<Invitations>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<VehicleStatusResponse>, ResponseError> {
+    let vehicle = vehicles.remove(id).ok_or(ResponseError::NotFound)?;
+    invitations.remove_vehicle(&vehicle);
+
+    let status = vehicle.command.status().await?;
+    Ok(Json(TeslaResponse::success(status)))
+}
+
+/// Put a vehicle back into the state it was created in
+///
+/// Invitations for the vehicle are forgotten, and drivers that redeemed them lose access.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+pub async fn reset_vehicle_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    State(invitations): State<Arc<Invitations>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<VehicleStatusResponse>, ResponseError> {
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    vehicle.command.reset().await?;
+    invitations.remove_vehicle(&vehicle);
+    let status = vehicle.command.status().await?;
+    Ok(Json(TeslaResponse::success(status)))
+}
+
+/// Change the heading of a vehicle
+///
+/// A driving vehicle turns immediately, otherwise the vehicle sets off in this direction the next
+/// time it drives.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+/// Returns a 400 Bad Request if the heading is not less than 360.
+pub async fn steer_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+    Json(request): Json<SteerRequest>,
+) -> Result<Json<VehicleStatusResponse>, ResponseError> {
+    if request.heading >= 360 {
+        return Err(ResponseError::InvalidValue(format!(
+            "heading {} is not less than 360",
+            request.heading
+        )));
+    }
+
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    vehicle.command.steer(request.heading).await?;
+    let status = vehicle.command.status().await?;
+    Ok(Json(TeslaResponse::success(status)))
+}
+
+/// Get the sleep policy of a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+pub async fn sleep_policy_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<SleepPolicyResponse>, ResponseError> {
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    let policy = vehicle.command.get_sleep_policy().await?;
+    Ok(Json(TeslaResponse::success(policy)))
+}
+
+/// Replace the sleep policy of a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+pub async fn set_sleep_policy_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+    Json(policy): Json<SleepPolicy>,
+) -> Result<Json<SleepPolicyResponse>, ResponseError> {
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    vehicle.command.set_sleep_policy(policy).await?;
+    Ok(Json(TeslaResponse::success(policy)))
+}
+
+/// Get the parked policy of a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+pub async fn parked_policy_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<ParkedPolicyResponse>, ResponseError> {
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    let policy = vehicle.command.get_parked_policy().await?;
+    Ok(Json(TeslaResponse::success(policy)))
+}
+
+/// Replace the parked policy of a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+pub async fn set_parked_policy_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+    Json(policy): Json<ParkedPolicy>,
+) -> Result<Json<ParkedPolicyResponse>, ResponseError> {
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    vehicle.command.set_parked_policy(policy).await?;
+    Ok(Json(TeslaResponse::success(policy)))
+}
+
+/// List the faults injected into a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+pub async fn faults_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<FaultsResponse>, ResponseError> {
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    let faults = vehicle.command.get_faults().await?;
+    Ok(Json(TeslaResponse::success(faults)))
+}
+
+/// Inject a fault into a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+/// Returns a 400 Bad Request if the probability or count is invalid.
+pub async fn add_fault_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+    Json(fault): Json<Fault>,
+) -> Result<Json<FaultsResponse>, ResponseError> {
+    if fault.probability.is_some_and(|p| !(0.0..=1.0).contains(&p)) || fault.count == Some(0) {
+        error!("Invalid fault {fault:?}");
+        return Err(ResponseError::InvalidField);
+    }
+
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    vehicle.command.add_fault(fault).await?;
+    let faults = vehicle.command.get_faults().await?;
+    Ok(Json(TeslaResponse::success(faults)))
+}
+
+/// Remove all faults from a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist.
+pub async fn clear_faults_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<FaultsResponse>, ResponseError> {
+    let vehicle = vehicles.find(id).ok_or(ResponseError::NotFound)?;
+
+    vehicle.command.clear_faults().await?;
+    Ok(Json(TeslaResponse::success(vec![])))
+}
+
+/// Get the rate limits
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+#[allow(clippy::unused_async)]
+pub async fn rate_limits_handler(
+    State(limiter): State<Arc<RateLimiter>>,
+) -> Result<Json<RateLimitsResponse>, ResponseError> {
+    Ok(Json(TeslaResponse::success(limiter.limits())))
+}
+
+/// Replace the rate limits
+///
+/// All budgets start again from empty.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+#[allow(clippy::unused_async)]
+pub async fn set_rate_limits_handler(
+    State(limiter): State<Arc<RateLimiter>>,
+    Json(limits): Json<RateLimits>,
+) -> Result<Json<RateLimitsResponse>, ResponseError> {
+    limiter.set_limits(limits);
+    Ok(Json(TeslaResponse::success(limits)))
+}
+
+/// Get a snapshot of every simulated vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+pub async fn snapshot_handler(
+    State(context): State<Arc<Context>>,
+    State(vehicles): State<Arc<Vehicles>>,
+) -> Result<Json<TeslaResponse<Snapshot>>, ResponseError> {
+    let snapshot = Snapshot::take(&vehicles, &context).await?;
+    Ok(Json(TeslaResponse::success(snapshot)))
+}
+
+/// Replace every simulated vehicle with the vehicles in a snapshot
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 400 Bad Request if the vehicles in the snapshot are invalid.
+pub async fn restore_handler(
+    State(context): State<Arc<Context>>,
+    State(vehicles): State<Arc<Vehicles>>,
+    State(invitations): State<Arc<Invitations>>,
+    State(users): State<Arc<Users>>,
+    Json(snapshot): Json<Snapshot>,
+) -> Result<Json<VehicleStatusesResponse>, ResponseError> {
+    snapshot.restore(&vehicles, &users, &context).await?;
+    invitations.clear();
+    let statuses = try_join_all(vehicles.list().iter().map(|v| v.command.status())).await?;
+    Ok(Json(TeslaResponse::success(statuses)))
+}
+
+/// List the named snapshots
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+#[allow(clippy::unused_async)]
+pub async fn snapshots_handler(
+    State(snapshots): State<Arc<Snapshots>>,
+) -> Result<Json<SnapshotsResponse>, ResponseError> {
+    Ok(Json(TeslaResponse::success(snapshots.list()?)))
+}
+
+/// Get a named snapshot
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the snapshot does not exist.
+#[allow(clippy::unused_async)]
+pub async fn named_snapshot_handler(
+    State(snapshots): State<Arc<Snapshots>>,
+    Path(name): Path<String>,
+) -> Result<Json<TeslaResponse<Snapshot>>, ResponseError> {
+    Ok(Json(TeslaResponse::success(snapshots.load(&name)?)))
+}
+
+/// Save a snapshot of every simulated vehicle under a name
+///
+/// A snapshot with the same name is replaced.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 400 Bad Request if the name is invalid.
+pub async fn save_snapshot_handler(
+    State(context): State<Arc<Context>>,
+    State(vehicles): State<Arc<Vehicles>>,
+    State(snapshots): State<Arc<Snapshots>>,
+    Path(name): Path<String>,
+) -> Result<Json<SnapshotResponse>, ResponseError> {
+    let snapshot = Snapshot::take(&vehicles, &context).await?;
+    let info = snapshot.info(&name);
+    snapshots.save(&name, snapshot)?;
+    Ok(Json(TeslaResponse::success(info)))
+}
+
+/// Replace every simulated vehicle with the vehicles in a named snapshot
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the snapshot does not exist.
+pub async fn restore_snapshot_handler(
+    State(context): State<Arc<Context>>,
+    State(vehicles): State<Arc<Vehicles>>,
+    State(invitations): State<Arc<Invitations>>,
+    State(users): State<Arc<Users>>,
+    State(snapshots): State<Arc<Snapshots>>,
+    Path(name): Path<String>,
+) -> Result<Json<SnapshotResponse>, ResponseError> {
+    let snapshot = snapshots.load(&name)?;
+    snapshot.restore(&vehicles, &users, &context).await?;
+    invitations.clear();
+    Ok(Json(TeslaResponse::success(snapshot.info(&name))))
+}
+
+/// Remove a named snapshot
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the snapshot does not exist.
+#[allow(clippy::unused_async)]
+pub async fn delete_snapshot_handler(
+    State(snapshots): State<Arc<Snapshots>>,
+    Path(name): Path<String>,
+) -> Result<Json<TeslaResponse<()>>, ResponseError> {
+    snapshots.delete(&name)?;
+    Ok(Json(TeslaResponse::success(())))
+}
+
+/// Read the records sent to the built-in Fleet Telemetry receiver
+///
+/// Each record is a binary websocket message containing a protobuf `Payload`. Records are only
+/// sent from the time of the request, a reader that falls behind skips the oldest ones. The
+/// records of every vehicle go to every reader, so like the rest of the admin API this needs the
+/// `simulator_admin` scope.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+#[allow(clippy::unused_async)]
+pub async fn telemetry_handler(
+    State(receiver): State<Arc<Receiver>>,
+    ws: WebSocketUpgrade,
+) -> Result<impl IntoResponse, ResponseError> {
+    // Subscribe now, so records sent once the upgrade completes are not missed.
+    let rx = receiver.subscribe();
+    Ok(ws.on_upgrade(|socket| send_telemetry(socket, rx)))
+}
+
+async fn send_telemetry(mut socket: WebSocket, mut rx: broadcast::Receiver<Arc<Payload>>) {
+    loop {
+        select! {
+            result = rx.recv() => match result {
+                Ok(payload) => {
+                    if socket.send(Message::Binary(payload.encode_to_vec())).await.is_err() {
+                        break;
+                    }
+                }
+                Err(RecvError::Lagged(skipped)) => {
+                    debug!("Telemetry reader fell behind, skipped {skipped} records");
+                }
+                Err(RecvError::Closed) => break,
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+/// List the simulated users
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+#[allow(clippy::unused_async)]
+pub async fn users_handler(
+    State(users): State<Arc<Users>>,
+) -> Result<Json<UsersResponse>, ResponseError> {
+    Ok(Json(TeslaResponse::success(users.list().to_vec())))
+}
+
+/// Get a token for a simulated user
+///
+/// Only a token with the `simulator_admin` scope may do this. The new token has the same scopes as
+/// the token used for the request, except for `simulator_admin`.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the `simulator_admin` scope.
+/// Returns a 404 Not Found if the user does not exist.
+#[allow(clippy::unused_async)]
+pub async fn user_token_handler(
+    State(users): State<Arc<Users>>,
+    State(token_config): State<Arc<tokens::Config>>,
+    Extension(config): Extension<Arc<tokens::AccessClaims>>,
+    Path(id): Path<UserId>,
+) -> Result<Json<RawToken>, ResponseError> {
+    // The new token is for an ordinary user, it cannot be used to create more tokens.
+    let scopes: HashSet<_> = config
+        .scopes
+        .iter()
+        .copied()
+        .filter(|scope| *scope != tokens::ScopeEnum::SimulatorAdmin)
+        .collect();
+
+    let user = users.find(id).ok_or(ResponseError::NotFound)?;
+    let token = tokens::new_token(&token_config, user.id, &scopes)
+        .map_err(|err| ResponseError::internal_error(format!("Could not create token: {err:?}")))?;
+
+    Ok(Json(token))
+}
@@ -72,7 +72,7 @@ fn renew_token(
         ));
     }
 
-    let token = new_token(config, &claims.scopes).map_err(|err| {
+    let token = new_token(config, claims.sub, &claims.scopes).map_err(|err| {
         errors::ResponseError::internal_error(format!("Could not create token: {err:?}"))
     })?;
 
@@ -1,4 +1,5 @@
 //! Tesla API handlers
+pub mod admin;
 pub mod auth;
 pub mod owner;
 pub mod streaming;
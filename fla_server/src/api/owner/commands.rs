@@ -12,17 +12,17 @@ use fla_common::{
     types::VehicleId,
 };
 
-use crate::{errors::ResponseError, tokens, types::Vehicle};
+use crate::{errors::ResponseError, tokens, types::Vehicles};
 
 /// Wake up the vehicle
 ///
 /// # Errors
 ///
 /// Returns a 403 Forbidden if the token does not have the required scopes.
-/// Returns a 404 Not Found if the vehicle does not exist.
+/// Returns a 404 Not Found if the vehicle does not exist or is not shared with the user.
 #[allow(clippy::unused_async)]
 pub async fn wake_up_handler(
-    State(vehicles): State<Arc<Vec<Vehicle>>>,
+    State(vehicles): State<Arc<Vehicles>>,
     Extension(config): Extension<Arc<tokens::AccessClaims>>,
     Path(id): Path<VehicleId>,
 ) -> Result<Json<VehicleResponse>, ResponseError> {
@@ -30,9 +30,8 @@ pub async fn wake_up_handler(
         return Err(ResponseError::MissingScopes);
     }
 
-    let vehicle = vehicles
-        .iter()
-        .find(|v| v.id == id)
+    let (vehicle, _) = vehicles
+        .find_for_user(id, config.sub)
         .ok_or(ResponseError::NotFound)?;
 
     vehicle.command.wake_up().await?;
@@ -46,10 +45,10 @@ pub async fn wake_up_handler(
 /// # Errors
 ///
 /// Returns a 403 Forbidden if the token does not have the required scopes.
-/// Returns a 404 Not Found if the vehicle does not exist.
+/// Returns a 404 Not Found if the vehicle does not exist or is not shared with the user.
 #[allow(clippy::unused_async)]
 pub async fn simulate_handler(
-    State(vehicles): State<Arc<Vec<Vehicle>>>,
+    State(vehicles): State<Arc<Vehicles>>,
     Extension(config): Extension<Arc<tokens::AccessClaims>>,
     Path(id): Path<VehicleId>,
     Json(state): Json<SimulationStateEnum>,
@@ -58,9 +57,8 @@ pub async fn simulate_handler(
         return Err(ResponseError::MissingScopes);
     }
 
-    let vehicle = vehicles
-        .iter()
-        .find(|v| v.id == id)
+    let (vehicle, _) = vehicles
+        .find_for_user(id, config.sub)
         .ok_or(ResponseError::NotFound)?;
 
     vehicle.command.simulate(state).await?;
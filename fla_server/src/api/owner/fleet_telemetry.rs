@@ -0,0 +1,136 @@
+//! Configure Fleet Telemetry
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    Extension, Json,
+};
+use fla_common::{
+    responses::{FleetTelemetryConfigResponse, FleetTelemetryConfigUpdatedResponse, TeslaResponse},
+    telemetry::{
+        Field, FleetTelemetryConfigRequest, FleetTelemetryConfigStatus,
+        FleetTelemetryConfigUpdated, SkippedVehicles,
+    },
+    types::VehicleId,
+};
+
+use crate::{errors::ResponseError, simulator::telemetry::Receiver, tokens, types::Vehicles};
+
+/// Configure vehicles to send Fleet Telemetry records
+///
+/// Either every vehicle is configured, or none of them are.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if a vehicle does not exist or is not shared with the user.
+/// Returns a 400 Bad Request if a field cannot be sent, or has an interval of zero seconds.
+pub async fn set_fleet_telemetry_config_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    State(receiver): State<Arc<Receiver>>,
+    Extension(config): Extension<Arc<tokens::AccessClaims>>,
+    Json(request): Json<FleetTelemetryConfigRequest>,
+) -> Result<Json<FleetTelemetryConfigUpdatedResponse>, ResponseError> {
+    if !config
+        .scopes
+        .contains(&tokens::ScopeEnum::VehicleDeviceData)
+    {
+        return Err(ResponseError::MissingScopes);
+    }
+
+    for (field, field_config) in &request.config.fields {
+        if *field == Field::Unknown {
+            return Err(ResponseError::InvalidValue(format!(
+                "Field {field:?} cannot be sent"
+            )));
+        }
+        if field_config.interval_seconds == 0 {
+            return Err(ResponseError::InvalidValue(format!(
+                "interval_seconds for {field:?} must be at least 1"
+            )));
+        }
+    }
+
+    let targets = request
+        .vins
+        .iter()
+        .map(|vin| {
+            vehicles
+                .find_by_vin_for_user(vin, config.sub)
+                .map(|(vehicle, _)| vehicle)
+                .ok_or(ResponseError::NotFound)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    for vehicle in &targets {
+        let output = receiver.output(&request.config);
+        vehicle
+            .command
+            .set_telemetry_config(Some((request.config.clone(), output)))
+            .await?;
+    }
+
+    Ok(Json(TeslaResponse::success(FleetTelemetryConfigUpdated {
+        updated_vehicles: targets.len(),
+        skipped_vehicles: SkippedVehicles::default(),
+    })))
+}
+
+/// Get the Fleet Telemetry configuration of a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist or is not shared with the user.
+pub async fn fleet_telemetry_config_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Extension(config): Extension<Arc<tokens::AccessClaims>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<FleetTelemetryConfigResponse>, ResponseError> {
+    if !config
+        .scopes
+        .contains(&tokens::ScopeEnum::VehicleDeviceData)
+    {
+        return Err(ResponseError::MissingScopes);
+    }
+
+    let (vehicle, _) = vehicles
+        .find_for_user(id, config.sub)
+        .ok_or(ResponseError::NotFound)?;
+
+    let config = vehicle.command.telemetry_config().await?;
+    Ok(Json(TeslaResponse::success(FleetTelemetryConfigStatus {
+        synced: config.is_some(),
+        config,
+    })))
+}
+
+/// Stop a vehicle sending Fleet Telemetry records
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist or is not shared with the user.
+pub async fn delete_fleet_telemetry_config_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    Extension(config): Extension<Arc<tokens::AccessClaims>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<FleetTelemetryConfigUpdatedResponse>, ResponseError> {
+    if !config
+        .scopes
+        .contains(&tokens::ScopeEnum::VehicleDeviceData)
+    {
+        return Err(ResponseError::MissingScopes);
+    }
+
+    let (vehicle, _) = vehicles
+        .find_for_user(id, config.sub)
+        .ok_or(ResponseError::NotFound)?;
+
+    vehicle.command.set_telemetry_config(None).await?;
+    Ok(Json(TeslaResponse::success(FleetTelemetryConfigUpdated {
+        updated_vehicles: 1,
+        skipped_vehicles: SkippedVehicles::default(),
+    })))
+}
@@ -0,0 +1,137 @@
+//! Invitations to share a vehicle with another user
+
+use std::sync::Arc;
+
+use axum::{
+    extract::{Path, State},
+    Extension, Json,
+};
+use fla_common::{
+    responses::{InvitationResponse, InvitationsResponse, RedeemInvitationResponse, TeslaResponse},
+    types::{InvitationId, RedeemInvitationRequest, RedeemedInvitation, VehicleId},
+};
+
+use crate::{
+    errors::ResponseError,
+    invitations::Invitations,
+    simulator::Context,
+    tokens,
+    types::{AccessType, Vehicle, Vehicles},
+};
+
+/// Find a vehicle the user owns
+fn find_owned(
+    vehicles: &Vehicles,
+    id: VehicleId,
+    claims: &tokens::AccessClaims,
+) -> Result<Arc<Vehicle>, ResponseError> {
+    match vehicles.find_for_user(id, claims.sub) {
+        Some((vehicle, AccessType::Owner)) => Ok(vehicle),
+        Some((_, AccessType::Driver { .. })) => Err(ResponseError::Forbidden),
+        None => Err(ResponseError::NotFound),
+    }
+}
+
+/// List the invitations to share a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes, or the user does not
+/// own the vehicle.
+/// Returns a 404 Not Found if the vehicle does not exist or is not shared with the user.
+#[allow(clippy::unused_async)]
+pub async fn invitations_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    State(invitations): State<Arc<Invitations>>,
+    Extension(config): Extension<Arc<tokens::AccessClaims>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<InvitationsResponse>, ResponseError> {
+    if !config
+        .scopes
+        .contains(&tokens::ScopeEnum::VehicleDeviceData)
+    {
+        return Err(ResponseError::MissingScopes);
+    }
+
+    let vehicle = find_owned(&vehicles, id, &config)?;
+    Ok(Json(TeslaResponse::success(invitations.list(vehicle.id))))
+}
+
+/// Create an invitation to share a vehicle
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes, or the user does not
+/// own the vehicle.
+/// Returns a 404 Not Found if the vehicle does not exist or is not shared with the user.
+#[allow(clippy::unused_async)]
+pub async fn create_invitation_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    State(invitations): State<Arc<Invitations>>,
+    State(context): State<Arc<Context>>,
+    Extension(config): Extension<Arc<tokens::AccessClaims>>,
+    Path(id): Path<VehicleId>,
+) -> Result<Json<InvitationResponse>, ResponseError> {
+    if !config.scopes.contains(&tokens::ScopeEnum::VehicleCmds) {
+        return Err(ResponseError::MissingScopes);
+    }
+
+    let vehicle = find_owned(&vehicles, id, &config)?;
+    let invitation = invitations.create(&vehicle, config.sub, &context);
+    Ok(Json(TeslaResponse::success(invitation)))
+}
+
+/// Revoke an invitation to share a vehicle
+///
+/// If the invitation was redeemed, the vehicle is no longer shared with that user.
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes, or the user does not
+/// own the vehicle.
+/// Returns a 404 Not Found if the vehicle or invitation does not exist.
+/// Returns a 400 Bad Request if the invitation was already revoked.
+#[allow(clippy::unused_async)]
+pub async fn revoke_invitation_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    State(invitations): State<Arc<Invitations>>,
+    Extension(config): Extension<Arc<tokens::AccessClaims>>,
+    Path((id, invitation_id)): Path<(VehicleId, InvitationId)>,
+) -> Result<Json<TeslaResponse<bool>>, ResponseError> {
+    if !config.scopes.contains(&tokens::ScopeEnum::VehicleCmds) {
+        return Err(ResponseError::MissingScopes);
+    }
+
+    let vehicle = find_owned(&vehicles, id, &config)?;
+    invitations.revoke(&vehicle, invitation_id)?;
+    Ok(Json(TeslaResponse::success(true)))
+}
+
+/// Redeem an invitation, sharing the vehicle with the authenticated user
+///
+/// # Errors
+///
+/// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if there is no invitation with the code.
+/// Returns a 400 Bad Request if the invitation is not pending, has expired, or the user already
+/// has access to the vehicle.
+#[allow(clippy::unused_async)]
+pub async fn redeem_invitation_handler(
+    State(vehicles): State<Arc<Vehicles>>,
+    State(invitations): State<Arc<Invitations>>,
+    State(context): State<Arc<Context>>,
+    Extension(config): Extension<Arc<tokens::AccessClaims>>,
+    Json(request): Json<RedeemInvitationRequest>,
+) -> Result<Json<RedeemInvitationResponse>, ResponseError> {
+    if !config.scopes.contains(&tokens::ScopeEnum::VehicleCmds) {
+        return Err(ResponseError::MissingScopes);
+    }
+
+    let (vehicle, invitation) =
+        invitations.redeem(&vehicles, &request.invitation_code, config.sub, &context)?;
+
+    Ok(Json(TeslaResponse::success(RedeemedInvitation {
+        vehicle_id_s: vehicle.id.to_string(),
+        vin: invitation.vin,
+    })))
+}
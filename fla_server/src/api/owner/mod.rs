@@ -1,25 +1,70 @@
 //! Tesla Owner API
 
 use self::commands::{simulate_handler, wake_up_handler};
+use self::fleet_telemetry::{
+    delete_fleet_telemetry_config_handler, fleet_telemetry_config_handler,
+    set_fleet_telemetry_config_handler,
+};
+use self::invitations::{
+    create_invitation_handler, invitations_handler, redeem_invitation_handler,
+    revoke_invitation_handler,
+};
 use self::vehicles::{vehicle_data_handler, vehicle_handler, vehicles_handler};
 use crate::{middleware, Config};
-use axum::routing::post;
+use axum::routing::{delete, post};
 use axum::{middleware::from_fn_with_state, routing::get, Router};
 
 pub mod commands;
+pub mod fleet_telemetry;
+pub mod invitations;
 pub mod vehicles;
 
 /// Retrieve router for Tesla Owner API
 pub fn router(config: &Config) -> Router {
+    let fault = || from_fn_with_state(config.clone(), middleware::response_fault);
+    let data = || from_fn_with_state(config.clone(), middleware::rate_limit_data);
+    let wake_up = || from_fn_with_state(config.clone(), middleware::rate_limit_wake_up);
+    let command = || from_fn_with_state(config.clone(), middleware::rate_limit_command);
+
     Router::new()
-        .route("/api/1/vehicles", get(vehicles_handler))
-        .route("/api/1/vehicles/:id", get(vehicle_handler))
-        .route("/api/1/vehicles/:id/simulate", post(simulate_handler))
+        .route(
+            "/api/1/invitations/redeem",
+            post(redeem_invitation_handler).layer(command()),
+        )
+        .route("/api/1/vehicles", get(vehicles_handler).layer(data()))
+        .route(
+            "/api/1/vehicles/fleet_telemetry_config",
+            post(set_fleet_telemetry_config_handler).layer(command()),
+        )
+        .route("/api/1/vehicles/:id", get(vehicle_handler).layer(data()))
+        .route(
+            "/api/1/vehicles/:id/fleet_telemetry_config",
+            get(fleet_telemetry_config_handler)
+                .layer(data())
+                .merge(delete(delete_fleet_telemetry_config_handler).layer(command())),
+        )
+        .route(
+            "/api/1/vehicles/:id/invitations",
+            get(invitations_handler)
+                .layer(data())
+                .merge(post(create_invitation_handler).layer(command())),
+        )
+        .route(
+            "/api/1/vehicles/:id/invitations/:invitation_id/revoke",
+            post(revoke_invitation_handler).layer(command()),
+        )
+        .route(
+            "/api/1/vehicles/:id/simulate",
+            post(simulate_handler).layer(command()),
+        )
         .route(
             "/api/1/vehicles/:id/vehicle_data",
-            get(vehicle_data_handler),
+            get(vehicle_data_handler).layer(fault()).layer(data()),
+        )
+        .route(
+            "/api/1/vehicles/:id/wake_up",
+            post(wake_up_handler).layer(fault()).layer(wake_up()),
         )
-        .route("/api/1/vehicles/:id/wake_up", post(wake_up_handler))
         .layer(from_fn_with_state(config.clone(), middleware::access_token))
         .with_state(config.clone())
 }
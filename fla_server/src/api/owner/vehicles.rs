@@ -9,7 +9,7 @@ use std::{collections::HashSet, str::FromStr, sync::Arc};
 use tap::Pipe;
 use tracing::error;
 
-use crate::{errors::ResponseError, tokens, types::Vehicle};
+use crate::{errors::ResponseError, tokens, types::Vehicles};
 use fla_common::{
     responses::{TeslaResponse, VehicleDataResponse, VehicleResponse, VehiclesResponse},
     types::{
@@ -26,7 +26,7 @@ use fla_common::{
 #[allow(clippy::module_name_repetitions)]
 #[allow(clippy::unused_async)]
 pub async fn vehicles_handler(
-    State(vehicles): State<Arc<Vec<Vehicle>>>,
+    State(vehicles): State<Arc<Vehicles>>,
     Extension(config): Extension<Arc<tokens::AccessClaims>>,
 ) -> Result<Json<VehiclesResponse>, ResponseError> {
     if !config
@@ -37,23 +37,25 @@ pub async fn vehicles_handler(
     }
 
     let vehicles: Vec<VehicleDefinition> = vehicles
+        .list_for_user(config.sub)
         .iter()
-        .map(|v| async { v.data.read().await.clone() })
+        .map(|(v, _)| async { v.data.read().await.clone() })
         .pipe(join_all)
         .await;
 
     Ok(Json(TeslaResponse::success(vehicles)))
 }
 
-/// Get a list of vehicles associated with the authenticated account.
+/// Get a vehicle associated with the authenticated account.
 ///
 /// # Errors
 ///
 /// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist or is not shared with the user.
 #[allow(clippy::module_name_repetitions)]
 #[allow(clippy::unused_async)]
 pub async fn vehicle_handler(
-    State(vehicles): State<Arc<Vec<Vehicle>>>,
+    State(vehicles): State<Arc<Vehicles>>,
     Extension(config): Extension<Arc<tokens::AccessClaims>>,
     Path(id): Path<VehicleId>,
 ) -> Result<Json<VehicleResponse>, ResponseError> {
@@ -65,9 +67,9 @@ pub async fn vehicle_handler(
     }
 
     let vehicle = vehicles
-        .iter()
-        .find(|v| v.id == id)
+        .find_for_user(id, config.sub)
         .ok_or(ResponseError::NotFound)?
+        .0
         .data
         .read()
         .await
@@ -78,12 +80,15 @@ pub async fn vehicle_handler(
 
 /// Get live vehicle data
 ///
+/// Drivers that may not see private data get no location.
+///
 /// # Errors
 ///
 /// Returns a 403 Forbidden if the token does not have the required scopes.
+/// Returns a 404 Not Found if the vehicle does not exist or is not shared with the user.
 #[allow(clippy::unused_async)]
 pub async fn vehicle_data_handler(
-    State(vehicles): State<Arc<Vec<Vehicle>>>,
+    State(vehicles): State<Arc<Vehicles>>,
     Extension(config): Extension<Arc<tokens::AccessClaims>>,
     Path(id): Path<VehicleId>,
     query: Query<VehicleDataQuery>,
@@ -95,9 +100,8 @@ pub async fn vehicle_data_handler(
         return Err(ResponseError::MissingScopes);
     }
 
-    let vehicle = vehicles
-        .iter()
-        .find(|v| v.id == id)
+    let (vehicle, access) = vehicles
+        .find_for_user(id, config.sub)
         .ok_or(ResponseError::NotFound)?;
 
     let data = vehicle.command.get_vehicle_data().await?;
@@ -130,10 +134,24 @@ pub async fn vehicle_data_handler(
         if let Some(ds) = data.drive_state {
             let location = endpoints.contains(&VehicleDataEndpoint::LocationData);
 
-            DriveState {
+            let ds = DriveState {
                 latitude: ds.latitude.filter(|_| location),
                 longitude: ds.longitude.filter(|_| location),
                 ..ds
+            };
+
+            if access.hide_private() {
+                DriveState {
+                    active_route_latitude: 0.0,
+                    active_route_longitude: 0.0,
+                    latitude: None,
+                    longitude: None,
+                    native_latitude: None,
+                    native_longitude: None,
+                    ..ds
+                }
+            } else {
+                ds
             }
             .pipe(Some)
         } else {
@@ -157,12 +175,12 @@ pub async fn vehicle_data_handler(
 
     let response = VehicleData {
         id: data.id,
-        user_id: data.user_id,
+        user_id: config.sub.into(),
         vehicle_id: data.vehicle_id,
         vin: data.vin,
         color: data.color,
-        access_type: data.access_type,
-        granular_access: data.granular_access,
+        access_type: access.name().to_string(),
+        granular_access: access.granular_access(),
         tokens: data.tokens,
         state: data.state,
         in_service: data.in_service,
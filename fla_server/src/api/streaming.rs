@@ -1,5 +1,15 @@
 //! Streaming handler
-use std::{collections::HashMap, sync::Arc};
+//!
+//! Like the real server, a subscription ends with `vehicle_disconnected` when the vehicle goes to
+//! sleep or stops sending data for `connection_timeout`, and a connection without subscriptions
+//! is closed after `connection_timeout`. Timeouts are measured on the simulator clock. A rejected
+//! subscription gets a `client_error` for its tag, the connection stays open for the others.
+//!
+//! A subscription may ask for rows less often with `interval_ms`, or only for rows that differ
+//! from the last one sent with `change_only`. Skipped rows do not count as data for the timeout,
+//! so a parked car with `change_only` goes quiet like it does on the real server.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
 use axum::{
     extract::{
@@ -10,24 +20,39 @@ use axum::{
     routing::get,
     Router,
 };
+use base64::{engine::general_purpose::STANDARD, Engine};
 use fla_common::{
     streaming::{
         DataError, ErrorType, FromServerStreamingMessage, StreamingData, StreamingFields,
-        ToServerStreamingMessage,
+        StreamingOptions, ToServerStreamingMessage,
     },
-    types::VehicleGuid,
+    types::{Timestamp, UserId, VehicleGuid},
 };
 use futures::{stream::FuturesUnordered, StreamExt};
 use thiserror::Error;
-use tokio::{select, sync::broadcast};
+use tokio::{
+    select,
+    sync::{
+        broadcast::{
+            self,
+            error::{RecvError, TryRecvError},
+        },
+        watch,
+    },
+    time::Instant,
+};
 use tracing::{debug, error};
 
 use crate::{
+    simulator::{metrics::Metrics, Context},
     tokens::{self, validate_access_token},
-    types::Vehicle,
+    types::{Access, AccessType, Users, Vehicles},
     Config,
 };
 
+/// How long a subscription may go without data, and a connection without subscriptions
+const CONNECTION_TIMEOUT: Duration = Duration::from_secs(30);
+
 /// Retrieve router for Tesla streaming API
 ///
 pub fn router(config: &Config) -> Router {
@@ -36,16 +61,32 @@ pub fn router(config: &Config) -> Router {
         .with_state(config.clone())
 }
 
-fn deserialize_field_names(str: &str) -> Vec<StreamingFields> {
-    str.split(',')
-        .filter_map(|x| match x.parse() {
-            Ok(field) => Some(field),
-            Err(_) => None,
-        })
-        .collect()
+/// Parse the requested field names, or return the names that are not known
+fn deserialize_field_names(str: &str) -> Result<Vec<StreamingFields>, Vec<String>> {
+    let mut fields = Vec::new();
+    let mut unknown = Vec::new();
+
+    for name in str.split(',').filter(|x| !x.is_empty()) {
+        match name.parse() {
+            Ok(field) => fields.push(field),
+            Err(()) => unknown.push(name.to_string()),
+        }
+    }
+
+    if unknown.is_empty() {
+        Ok(fields)
+    } else {
+        Err(unknown)
+    }
 }
 
-fn serialize_fields(fields: &[StreamingFields], data: &StreamingData) -> String {
+fn serialize_fields(
+    fields: &[StreamingFields],
+    data: &StreamingData,
+    hide_private: bool,
+) -> String {
+    let location = |value: Option<f64>| value.filter(|_| !hide_private);
+
     let mut result = Vec::new();
     result.push(data.time.to_string());
 
@@ -56,13 +97,24 @@ fn serialize_fields(fields: &[StreamingFields], data: &StreamingData) -> String
             StreamingFields::Soc => push_data(&mut result, data.soc),
             StreamingFields::Elevation => push_data(&mut result, data.elevation),
             StreamingFields::EstHeading => push_data(&mut result, data.est_heading),
-            StreamingFields::EstLat => push_data(&mut result, data.est_lat),
-            StreamingFields::EstLng => push_data(&mut result, data.est_lng),
+            StreamingFields::EstLat => push_data(&mut result, location(data.est_lat)),
+            StreamingFields::EstLng => push_data(&mut result, location(data.est_lng)),
             StreamingFields::Power => push_data(&mut result, data.power),
             StreamingFields::ShiftState => push_data(&mut result, data.shift_state.clone()),
             StreamingFields::Range => push_data(&mut result, data.range),
             StreamingFields::EstRange => push_data(&mut result, data.est_range),
             StreamingFields::Heading => push_data(&mut result, data.heading),
+            StreamingFields::NativeLatitude => {
+                push_data(&mut result, location(data.native_latitude));
+            }
+            StreamingFields::NativeLongitude => {
+                push_data(&mut result, location(data.native_longitude));
+            }
+            StreamingFields::NativePower => push_data(&mut result, data.native_power),
+            StreamingFields::NativeType => push_data(&mut result, data.native_type.clone()),
+            StreamingFields::NativeLocationSupported => {
+                push_data(&mut result, data.native_location_supported);
+            }
         }
     }
     result.join(",")
@@ -80,12 +132,14 @@ fn push_data<T: ToString>(result: &mut Vec<String>, data: Option<T>) {
 #[allow(clippy::unused_async)]
 pub async fn ws_handler(
     State(config): State<Arc<tokens::Config>>,
-    State(vehicles): State<Arc<Vec<Vehicle>>>,
+    State(vehicles): State<Arc<Vehicles>>,
+    State(users): State<Arc<Users>>,
+    State(context): State<Arc<Context>>,
     ws: WebSocketUpgrade,
 ) -> impl IntoResponse {
     // finalize the upgrade process by returning upgrade callback.
     // we can customize the callback by sending additional info such as address.
-    ws.on_upgrade(|socket| handle_socket(socket, config, vehicles))
+    ws.on_upgrade(|socket| handle_socket(socket, config, vehicles, users, context))
 }
 
 #[derive(Error, Debug)]
@@ -101,9 +155,16 @@ enum SocketError {
 async fn handle_socket(
     mut socket: WebSocket,
     config: Arc<tokens::Config>,
-    vehicles: Arc<Vec<Vehicle>>,
+    vehicles: Arc<Vehicles>,
+    users: Arc<Users>,
+    context: Arc<Context>,
 ) {
-    match handle_socket_internal(&mut socket, config, vehicles).await {
+    context.metrics.connected();
+    let result =
+        handle_socket_internal(&mut socket, config, vehicles, users, context.clone()).await;
+    context.metrics.disconnected();
+
+    match result {
         Err(SocketError::ReportableError(err)) => {
             error!("Reportable error: {err}");
             send_error(&mut socket, err).await;
@@ -123,18 +184,121 @@ async fn handle_socket(
 
 struct Subscription {
     vehicle_id: VehicleGuid,
-    fields: Arc<Vec<StreamingFields>>,
+    fields: Vec<StreamingFields>,
+    options: StreamingOptions,
+    user: UserId,
+    access: watch::Receiver<Access>,
     rx: broadcast::Receiver<Arc<StreamingData>>,
+    initial: Option<Arc<StreamingData>>,
+    last_data: Instant,
+
+    /// Time and values of the last row sent
+    last_row: Option<(Timestamp, String)>,
+}
+
+/// Something that happened to a subscription
+enum SubscriptionEvent {
+    /// A row to send, from new simulator data, or an error if the vehicle stopped streaming
+    Data(Result<String, broadcast::error::RecvError>),
+
+    /// The users that may use the vehicle changed
+    AccessChanged,
+}
+
+impl Subscription {
+    /// Wait for a row to send or for the access to change
+    ///
+    /// A subscription that falls behind skips to the newest data, the rows it missed are counted
+    /// in the metrics.
+    async fn next(&mut self, metrics: &Metrics) -> SubscriptionEvent {
+        loop {
+            let data = select! {
+                data = self.rx.recv() => match data {
+                    Ok(data) => data,
+                    Err(RecvError::Lagged(skipped)) => match self.latest(skipped, metrics) {
+                        Some(data) => data,
+                        None => continue,
+                    },
+                    Err(err) => return SubscriptionEvent::Data(Err(err)),
+                },
+                Ok(()) = self.access.changed() => return SubscriptionEvent::AccessChanged,
+            };
+
+            let Some(access) = self.access_type() else {
+                return SubscriptionEvent::AccessChanged;
+            };
+            if let Some(row) = self.row(&data, access.hide_private()) {
+                return SubscriptionEvent::Data(Ok(row));
+            }
+        }
+    }
+
+    /// The newest data waiting after falling behind, everything older is skipped
+    fn latest(&mut self, skipped: u64, metrics: &Metrics) -> Option<Arc<StreamingData>> {
+        let mut skipped = skipped;
+        let mut latest = None;
+        loop {
+            match self.rx.try_recv() {
+                Ok(data) => {
+                    if latest.replace(data).is_some() {
+                        skipped += 1;
+                    }
+                }
+                Err(TryRecvError::Lagged(more)) => skipped += more,
+                Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+            }
+        }
+
+        debug!(
+            "Subscription for {} skipped {skipped} rows",
+            self.vehicle_id
+        );
+        metrics.lagged(skipped);
+        latest
+    }
+
+    /// The row to send for new data, `None` if the options skip it
+    fn row(&mut self, data: &StreamingData, hide_private: bool) -> Option<String> {
+        let row = serialize_fields(&self.fields, data, hide_private);
+        // Everything but the time, which is always different.
+        let values = row.split_once(',').map_or("", |(_, values)| values);
+
+        if let Some((time, last_values)) = &self.last_row {
+            let interval = self
+                .options
+                .interval_ms
+                .and_then(|interval| Timestamp::try_from(interval).ok())
+                .unwrap_or_default();
+            if data.time < time.saturating_add(interval) {
+                return None;
+            }
+            if self.options.change_only && values == last_values {
+                return None;
+            }
+        }
+
+        self.last_row = Some((data.time, values.to_string()));
+        Some(row)
+    }
+
+    /// How the user may use the vehicle now, `None` if access was revoked
+    fn access_type(&self) -> Option<AccessType> {
+        self.access.borrow().for_user(self.user)
+    }
 }
 
 async fn handle_socket_internal(
     socket: &mut WebSocket,
     config: Arc<tokens::Config>,
-    vehicles: Arc<Vec<Vehicle>>,
+    vehicles: Arc<Vehicles>,
+    users: Arc<Users>,
+    context: Arc<Context>,
 ) -> Result<(), SocketError> {
+    let clock = &context.clock;
+
     // Say hello to the client. Pretend to be polite. The client will never guess the truth.
     let hello = FromServerStreamingMessage::ControlHello {
-        connection_timeout: 30000,
+        connection_timeout: CONNECTION_TIMEOUT.as_secs() * 1000,
     };
     send_message(socket, hello).await.map_err(|err| {
         error!("Could not send hello: {err:?}");
@@ -142,28 +306,58 @@ async fn handle_socket_internal(
     })?;
 
     let mut subscriptions: HashMap<VehicleGuid, Subscription> = HashMap::new();
+    let mut idle_since = clock.now();
 
     // Wait for data, either from simulator or from client.
     loop {
         let delete_subscription;
         let add_subscription;
+        let mut timed_out = false;
+
+        // The earliest time a subscription goes quiet, or the connection has been idle too long.
+        let deadline = subscriptions
+            .values()
+            .map(|s| s.last_data)
+            .min()
+            .unwrap_or(idle_since)
+            + CONNECTION_TIMEOUT;
 
         {
             let mut futures = {
                 let futures = FuturesUnordered::new();
                 for (id, s) in subscriptions.iter_mut() {
-                    futures.push(async { (*id, s.fields.clone(), s.rx.recv().await) });
+                    futures.push(async {
+                        let event = s.next(&context.metrics).await;
+                        if matches!(event, SubscriptionEvent::Data(Ok(_))) {
+                            s.last_data = clock.now();
+                        }
+                        (*id, s.access_type(), event)
+                    });
                 }
                 futures
             };
 
             (delete_subscription, add_subscription) = select! {
+                // Data that is already waiting wins over a timeout.
+                biased;
+
                 // We got Data from the simulator.
-                Some((vehicle_id, fields, data)) = futures.next() => {
-                    match data {
-                        Ok(data) => {
-                            let value = serialize_fields(&fields, &data);
-                            let msg = FromServerStreamingMessage::data_update(vehicle_id, value );
+                Some((vehicle_id, access, event)) = futures.next() => {
+                    match (access, event) {
+                        // Access was revoked, stop streaming straight away.
+                        (None, _) => {
+                            let error = DataError::new(
+                                vehicle_id.to_string(),
+                                ErrorType::ClientError,
+                                "Access revoked",
+                            );
+                            send_error(socket, error).await;
+                            (Some(vehicle_id), None)
+                        }
+                        (Some(_), SubscriptionEvent::AccessChanged) => (None, None),
+                        (Some(_), SubscriptionEvent::Data(Ok(value))) => {
+                            let msg = FromServerStreamingMessage::data_update(vehicle_id, value);
+                            context.metrics.row_sent();
 
                             debug!("Sending: {msg:?}");
                             send_message(socket, msg).await.map_err(|err| {
@@ -176,7 +370,7 @@ async fn handle_socket_internal(
 
                             (None, None)
                         }
-                        Err(_err) => {
+                        (Some(_), SubscriptionEvent::Data(Err(_err))) => {
                             let error = DataError::disconnected(vehicle_id);
                             send_error(socket, error).await;
                             (Some(vehicle_id), None)
@@ -226,10 +420,37 @@ async fn handle_socket_internal(
 
                     if let Some(text) = text {
                         debug!("Received: {text}");
-                        process_client_message(text, &config, &vehicles).await?
+                        process_client_message(socket, text, &config, &vehicles, &users, &context).await?
                     } else  { (None, None)
                     }
                 }
+
+                () = clock.sleep_until(deadline) => {
+                    timed_out = true;
+                    (None, None)
+                }
+            }
+        }
+
+        if timed_out {
+            if subscriptions.is_empty() {
+                debug!("Closing connection without subscriptions");
+                break;
+            }
+
+            // The vehicles that stopped sending data, like the real server the client has to
+            // subscribe again.
+            let now = clock.now();
+            let quiet: Vec<VehicleGuid> = subscriptions
+                .values()
+                .filter(|s| now >= s.last_data + CONNECTION_TIMEOUT)
+                .map(|s| s.vehicle_id)
+                .collect();
+
+            for vehicle_id in quiet {
+                debug!("No data from {vehicle_id:?}, disconnecting");
+                send_error(socket, DataError::disconnected(vehicle_id)).await;
+                subscriptions.remove(&vehicle_id);
             }
         }
 
@@ -237,9 +458,26 @@ async fn handle_socket_internal(
             subscriptions.remove(&vehicle_id);
         }
 
-        if let Some(subscription) = add_subscription {
+        if let Some(mut subscription) = add_subscription {
+            // Send the current data straight away, rather than making the client wait for the
+            // next update.
+            let initial = subscription.initial.take().zip(subscription.access_type());
+            if let Some(value) =
+                initial.and_then(|(data, access)| subscription.row(&data, access.hide_private()))
+            {
+                let msg = FromServerStreamingMessage::data_update(subscription.vehicle_id, value);
+                context.metrics.row_sent();
+                send_message(socket, msg).await.map_err(|err| {
+                    let error = format!("Could not send message: {err:?}");
+                    SocketError::NotReportableError(error)
+                })?;
+            }
             subscriptions.insert(subscription.vehicle_id, subscription);
         }
+
+        if subscriptions.is_empty() && (timed_out || delete_subscription.is_some()) {
+            idle_since = clock.now();
+        }
     }
 
     Ok(())
@@ -269,9 +507,32 @@ async fn send_error(socket: &mut WebSocket, error: DataError) {
 }
 
 async fn process_client_message(
+    socket: &mut WebSocket,
     text: String,
     config: &tokens::Config,
-    vehicles: &[Vehicle],
+    vehicles: &Vehicles,
+    users: &Users,
+    context: &Context,
+) -> Result<(Option<VehicleGuid>, Option<Subscription>), SocketError> {
+    match subscription_change(socket, text, config, vehicles, users, context).await {
+        // Like the real server, a bad subscription only gets an error for its tag, the other
+        // subscriptions on the connection carry on.
+        Err(SocketError::ReportableError(error)) => {
+            send_error(socket, error).await;
+            Ok((None, None))
+        }
+        result => result,
+    }
+}
+
+/// The subscription to remove or add for a message from the client
+async fn subscription_change(
+    socket: &mut WebSocket,
+    text: String,
+    config: &tokens::Config,
+    vehicles: &Vehicles,
+    users: &Users,
+    context: &Context,
 ) -> Result<(Option<VehicleGuid>, Option<Subscription>), SocketError> {
     // // Parse the subscription message.
     let message = serde_json::from_str::<ToServerStreamingMessage>(&text).map_err(|err| {
@@ -280,8 +541,30 @@ async fn process_client_message(
         SocketError::NotReportableError(error)
     })?;
 
-    match message {
-        ToServerStreamingMessage::DataSubscribeOauth { token, value, tag } => {
+    let (user, value, tag, options) = match message {
+        ToServerStreamingMessage::DataSubscribe {
+            token,
+            value,
+            tag,
+            options,
+        } => {
+            let user = validate_vehicle_token(&token, &tag, users, vehicles)
+                .await
+                .ok_or_else(|| {
+                    error!("Invalid vehicle token for {tag}");
+                    let error =
+                        DataError::new(&tag, ErrorType::ClientError, "Can't validate token. ");
+                    SocketError::ReportableError(error)
+                })?;
+
+            (user, value, tag, options)
+        }
+        ToServerStreamingMessage::DataSubscribeOauth {
+            token,
+            value,
+            tag,
+            options,
+        } => {
             let claims = validate_access_token(&token, config).map_err(|err| {
                 error!("Invalid token: {err}");
                 let error = DataError::new(&tag, ErrorType::ClientError, "Invalid token");
@@ -297,45 +580,162 @@ async fn process_client_message(
                 return Err(SocketError::ReportableError(error));
             }
 
-            // The vehicle_id is the tag
+            (claims.sub, value, tag, options)
+        }
+        ToServerStreamingMessage::DataUnsubscribe { tag } => {
             let vehicle_id: VehicleGuid = tag.clone().parse().map_err(|err| {
                 error!("Vehicle id is not an integer: {err}");
                 let error = DataError::new(&tag, ErrorType::ClientError, "Invalid vehicle id");
                 SocketError::ReportableError(error)
             })?;
 
-            // Find the vehicle
-            let maybe_vehicle = vehicles.iter().find(|v| v.vehicle_id == vehicle_id);
-            let vehicle = match maybe_vehicle {
-                Some(vehicle) => vehicle,
-                None => {
-                    error!("Vehicle id not found: {vehicle_id:?}");
-                    let error = DataError::new(&tag, ErrorType::ClientError, "Invalid vehicle id");
-                    return Err(SocketError::ReportableError(error));
-                }
-            };
+            return Ok((Some(vehicle_id), None));
+        }
+    };
 
-            // Deserialize the incoming data
-            let fields = Arc::new(deserialize_field_names(&value));
+    // The vehicle_id is the tag
+    let vehicle_id: VehicleGuid = tag.clone().parse().map_err(|err| {
+        error!("Vehicle id is not an integer: {err}");
+        let error = DataError::new(&tag, ErrorType::ClientError, "Invalid vehicle id");
+        SocketError::ReportableError(error)
+    })?;
 
-            // Subscribe to the vehicle
-            let rx = vehicle.command.subscribe().await?;
+    // Find the vehicle, only if it is shared with the user
+    let maybe_vehicle = vehicles.find_by_guid_for_user(vehicle_id, user);
+    let (vehicle, _) = match maybe_vehicle {
+        Some(vehicle) => vehicle,
+        None => {
+            error!("Vehicle id not found: {vehicle_id:?}");
+            let error = DataError::new(&tag, ErrorType::ClientError, "Invalid vehicle id");
+            return Err(SocketError::ReportableError(error));
+        }
+    };
 
-            let add = Subscription {
-                vehicle_id,
-                fields,
-                rx,
-            };
-            Ok((None, Some(add)))
+    // Deserialize the incoming data, columns would not line up if we skipped any
+    let fields = deserialize_field_names(&value).map_err(|unknown| {
+        error!("Unknown streaming fields: {unknown:?}");
+        let message = format!("Unknown fields: {}", unknown.join(","));
+        let error = DataError::new(&tag, ErrorType::ClientError, message);
+        SocketError::ReportableError(error)
+    })?;
+
+    // Subscribe to the vehicle, a sleeping vehicle is not an error for the connection
+    let (rx, initial) = match vehicle.command.subscribe().await {
+        Ok(subscription) => subscription,
+        Err(error) => {
+            debug!("Could not subscribe to {vehicle_id:?}: {error}");
+            send_error(socket, error).await;
+            return Ok((None, None));
         }
-        ToServerStreamingMessage::DataUnsubscribe { tag } => {
-            let vehicle_id: VehicleGuid = tag.clone().parse().map_err(|err| {
-                error!("Vehicle id is not an integer: {err}");
-                let error = DataError::new(&tag, ErrorType::ClientError, "Invalid vehicle id");
-                SocketError::ReportableError(error)
-            })?;
+    };
+
+    let add = Subscription {
+        vehicle_id,
+        fields,
+        options,
+        user,
+        access: vehicle.access.subscribe(),
+        rx,
+        initial,
+        last_data: context.clock.now(),
+        last_row: None,
+    };
+    Ok((None, Some(add)))
+}
 
-            Ok((Some(vehicle_id), None))
+/// Check a legacy `email:vehicle_token` token, returning the user if it is valid for the vehicle
+async fn validate_vehicle_token(
+    token: &str,
+    tag: &str,
+    users: &Users,
+    vehicles: &Vehicles,
+) -> Option<UserId> {
+    let decoded = STANDARD.decode(token).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (email, vehicle_token) = decoded.rsplit_once(':')?;
+
+    let user = users.find_by_email(email)?.id;
+    let (vehicle, _) = vehicles.find_by_guid_for_user(tag.parse().ok()?, user)?;
+
+    let valid = vehicle
+        .data
+        .read()
+        .await
+        .tokens
+        .iter()
+        .any(|t| t == vehicle_token);
+    valid.then_some(user)
+}
+
+#[cfg(test)]
+mod test {
+    #![allow(clippy::unwrap_used)]
+
+    use super::*;
+
+    fn data(time: Timestamp) -> Arc<StreamingData> {
+        Arc::new(StreamingData {
+            id: VehicleGuid::new(999_456_000),
+            time,
+            speed: Some(60.0),
+            odometer: None,
+            soc: None,
+            elevation: None,
+            est_heading: None,
+            est_lat: None,
+            est_lng: None,
+            power: None,
+            shift_state: None,
+            range: None,
+            est_range: None,
+            heading: None,
+            native_latitude: None,
+            native_longitude: None,
+            native_power: None,
+            native_type: None,
+            native_location_supported: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_lagging_subscription_skips_to_newest() {
+        let owner = UserId::new(1);
+        let (tx, rx) = broadcast::channel(2);
+        let (_access_tx, access) = watch::channel(Access {
+            owner,
+            drivers: vec![],
+        });
+        let mut subscription = Subscription {
+            vehicle_id: VehicleGuid::new(999_456_000),
+            fields: vec![StreamingFields::Speed],
+            options: StreamingOptions::default(),
+            user: owner,
+            access,
+            rx,
+            initial: None,
+            last_data: Instant::now(),
+            last_row: None,
+        };
+
+        for time in 1..=5 {
+            tx.send(data(time)).unwrap();
         }
+
+        let metrics = Metrics::new();
+        let SubscriptionEvent::Data(Ok(row)) = subscription.next(&metrics).await else {
+            panic!("expected a row");
+        };
+        assert_eq!(row, "5,60");
+
+        let streaming = metrics.snapshot().streaming;
+        assert_eq!(streaming.rows_skipped, 4);
+        assert_eq!(streaming.lag_events, 1);
+
+        // Once it has caught up, the subscription gets every row again
+        tx.send(data(6)).unwrap();
+        let SubscriptionEvent::Data(Ok(row)) = subscription.next(&metrics).await else {
+            panic!("expected a row");
+        };
+        assert_eq!(row, "6,60");
     }
 }
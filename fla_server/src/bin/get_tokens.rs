@@ -1,6 +1,6 @@
 use std::collections::HashSet;
 
-use fla_common::auth::RawToken;
+use fla_common::{auth::RawToken, simulator::DEFAULT_USER};
 use fla_server::tokens::{self, new_token};
 
 fn main() {
@@ -23,11 +23,12 @@ fn main() {
         tokens::ScopeEnum::VehicleChargingCmds,
         tokens::ScopeEnum::EnergyDeviceData,
         tokens::ScopeEnum::EnergyCmds,
+        tokens::ScopeEnum::SimulatorAdmin,
     ]
     .into_iter()
     .collect::<HashSet<tokens::ScopeEnum>>();
 
-    let token: RawToken = new_token(&config, &scopes).unwrap();
+    let token: RawToken = new_token(&config, DEFAULT_USER, &scopes).unwrap();
 
     println!("{:?}", token);
 }
@@ -1,14 +1,17 @@
 //! Error handling
+use std::time::Duration;
+
 use axum::{
     response::{IntoResponse, Response},
     Json,
 };
-use http::StatusCode;
+use http::{header::RETRY_AFTER, StatusCode};
 use tracing::error;
 
 use fla_common::responses::error;
 
 /// An error response
+#[derive(Debug)]
 pub enum ResponseError {
     /// If the date_request or command is unknown
     InvalidCommand,
@@ -16,6 +19,9 @@ pub enum ResponseError {
     /// If the new_field data isn't valid
     InvalidField,
 
+    /// A value in the request isn't valid, with an explanation
+    InvalidValue(String),
+
     /// OAuth token has expired
     TokenExpired,
 
@@ -28,6 +34,9 @@ pub enum ResponseError {
     /// You do not have access to this resource, do you have the required scopes?
     MissingScopes,
 
+    /// You have access to the vehicle, but not enough to do this
+    Forbidden,
+
     /// The requested resource does not exist
     NotFound,
 
@@ -36,6 +45,9 @@ pub enum ResponseError {
 
     /// Vehicle responded with an error - might need a reboot, OTA update, or service
     DeviceUnexpectedResponse,
+
+    /// Too many requests, try again after the given real time
+    RateLimited(Duration),
 }
 
 impl ResponseError {
@@ -61,6 +73,10 @@ impl IntoResponse for ResponseError {
                 let error = error("error:invalid_field", "Invalid field");
                 (StatusCode::BAD_REQUEST, Json(error)).into_response()
             }
+            Self::InvalidValue(message) => {
+                let error = error("error:invalid_field", message);
+                (StatusCode::BAD_REQUEST, Json(error)).into_response()
+            }
             Self::TokenExpired => (StatusCode::UNAUTHORIZED, ()).into_response(),
             Self::InternalServerError(message) => {
                 let error = error("Internal Server Error", "Something went wrong");
@@ -75,6 +91,10 @@ impl IntoResponse for ResponseError {
                 let error = error("Unauthorized missing scopes", "Unauthorized missing scopes");
                 (StatusCode::FORBIDDEN, Json(error)).into_response()
             }
+            Self::Forbidden => {
+                let error = error("Forbidden", "Forbidden");
+                (StatusCode::FORBIDDEN, Json(error)).into_response()
+            }
             Self::NotFound => {
                 let error = error("Not Found", "Not Found");
                 (StatusCode::NOT_FOUND, Json(error)).into_response()
@@ -88,6 +108,17 @@ impl IntoResponse for ResponseError {
                 let code = StatusCode::try_from(540).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
                 (code, "Device responded with an error").into_response()
             }
+            Self::RateLimited(retry_after) => {
+                // Round up, retrying early would just be rejected again.
+                let seconds = retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0);
+                let error = error("Too Many Requests", "Rate limit exceeded");
+                (
+                    StatusCode::TOO_MANY_REQUESTS,
+                    [(RETRY_AFTER, seconds.to_string())],
+                    Json(error),
+                )
+                    .into_response()
+            }
         }
     }
 }
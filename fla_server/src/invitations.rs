@@ -0,0 +1,227 @@
+//! Invitations to share a vehicle with another user
+//!
+//! The owner of a vehicle creates an invitation, and another user redeems it to become a driver.
+//! Revoking a redeemed invitation removes the driver again, which also ends any streaming
+//! subscriptions they have for the vehicle.
+
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, PoisonError,
+};
+
+use chrono::Duration;
+use fla_common::{
+    simulator::FleetDriver,
+    types::{Invitation, InvitationId, InvitationState, UserId, VehicleId},
+};
+use thiserror::Error;
+
+use crate::{
+    errors::ResponseError,
+    simulator::Context,
+    types::{Vehicle, Vehicles},
+};
+
+/// How many days an invitation may be redeemed for
+const EXPIRES_IN_DAYS: i64 = 7;
+
+/// The id of the first invitation, later invitations count up from here
+const FIRST_ID: u64 = 2_252_269_995_476_000;
+
+/// An error using an invitation
+#[derive(Error, Debug)]
+pub enum InvitationError {
+    /// There is no such invitation
+    #[error("Invitation not found")]
+    NotFound,
+
+    /// The invitation has already been redeemed or revoked
+    #[error("Invitation is {0:?}")]
+    NotPending(InvitationState),
+
+    /// The invitation is too old to be redeemed
+    #[error("Invitation has expired")]
+    Expired,
+
+    /// The user already owns or drives the vehicle
+    #[error("Vehicle is already shared with user {0}")]
+    AlreadyShared(UserId),
+}
+
+impl From<InvitationError> for ResponseError {
+    fn from(err: InvitationError) -> Self {
+        match err {
+            InvitationError::NotFound => Self::NotFound,
+            InvitationError::NotPending(_)
+            | InvitationError::Expired
+            | InvitationError::AlreadyShared(_) => Self::InvalidValue(err.to_string()),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Record {
+    vehicle: VehicleId,
+    invitation: Invitation,
+    redeemed_by: Option<UserId>,
+}
+
+/// All invitations, for every vehicle
+#[derive(Debug, Default)]
+pub struct Invitations {
+    records: Mutex<Vec<Record>>,
+
+    /// How many invitations have ever been created, so ids are never reused
+    created: AtomicU64,
+}
+
+impl Invitations {
+    /// All invitations for a vehicle, in the order they were created
+    #[must_use]
+    pub fn list(&self, vehicle: VehicleId) -> Vec<Invitation> {
+        self.records
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .filter(|r| r.vehicle == vehicle)
+            .map(|r| r.invitation.clone())
+            .collect()
+    }
+
+    /// Create an invitation to share a vehicle
+    pub fn create(&self, vehicle: &Vehicle, sender: UserId, context: &Context) -> Invitation {
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let id = InvitationId::new(FIRST_ID + self.created.fetch_add(1, Ordering::Relaxed));
+        let now = context.clock.utc_now();
+        let invitation = Invitation {
+            id,
+            owner_id: vehicle.access.borrow().owner,
+            sender_id: sender,
+            vin: vehicle.spec.vin.clone(),
+            state: InvitationState::Pending,
+            share_type: "customer".to_string(),
+            share_link: format!(
+                "https://www.tesla.com/_rs/1/{:016x}",
+                context.seeded_hash(&id.to_string())
+            ),
+            created_at: now,
+            expires_at: now + Duration::days(EXPIRES_IN_DAYS),
+            redeemed_at: None,
+            id_s: id.to_string(),
+        };
+
+        records.push(Record {
+            vehicle: vehicle.id,
+            invitation: invitation.clone(),
+            redeemed_by: None,
+        });
+        invitation
+    }
+
+    /// Forget every invitation for a vehicle, removing the drivers that redeemed them
+    ///
+    /// Used when a vehicle is reset or removed, so the invitations cannot be redeemed against a
+    /// later vehicle with the same id.
+    pub fn remove_vehicle(&self, vehicle: &Vehicle) {
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        records.retain(|record| {
+            if record.vehicle != vehicle.id {
+                return true;
+            }
+            if let Some(user) = record.redeemed_by {
+                vehicle
+                    .access
+                    .send_modify(|access| access.drivers.retain(|driver| driver.user != user));
+            }
+            false
+        });
+    }
+
+    /// Forget every invitation, used when every vehicle is replaced
+    pub fn clear(&self) {
+        self.records
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Revoke an invitation, removing the driver if it was redeemed
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vehicle has no such invitation, or it was already revoked.
+    pub fn revoke(
+        &self,
+        vehicle: &Vehicle,
+        id: InvitationId,
+    ) -> Result<Invitation, InvitationError> {
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        let record = records
+            .iter_mut()
+            .find(|r| r.vehicle == vehicle.id && r.invitation.id == id)
+            .ok_or(InvitationError::NotFound)?;
+
+        if record.invitation.state == InvitationState::Revoked {
+            return Err(InvitationError::NotPending(record.invitation.state));
+        }
+        record.invitation.state = InvitationState::Revoked;
+
+        if let Some(user) = record.redeemed_by {
+            vehicle
+                .access
+                .send_modify(|access| access.drivers.retain(|driver| driver.user != user));
+        }
+
+        Ok(record.invitation.clone())
+    }
+
+    /// Redeem an invitation, sharing the vehicle with the user
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if there is no pending invitation with this code, it has expired, or the
+    /// user already has access to the vehicle.
+    pub fn redeem(
+        &self,
+        vehicles: &Vehicles,
+        code: &str,
+        user: UserId,
+        context: &Context,
+    ) -> Result<(Arc<Vehicle>, Invitation), InvitationError> {
+        let mut records = self.records.lock().unwrap_or_else(PoisonError::into_inner);
+        let record = records
+            .iter_mut()
+            .find(|r| r.invitation.code() == code)
+            .ok_or(InvitationError::NotFound)?;
+
+        if record.invitation.state != InvitationState::Pending {
+            return Err(InvitationError::NotPending(record.invitation.state));
+        }
+
+        let now = context.clock.utc_now();
+        if now >= record.invitation.expires_at {
+            return Err(InvitationError::Expired);
+        }
+
+        let vehicle = vehicles
+            .find(record.vehicle)
+            .ok_or(InvitationError::NotFound)?;
+        if vehicle.access_for(user).is_some() {
+            return Err(InvitationError::AlreadyShared(user));
+        }
+
+        vehicle.access.send_modify(|access| {
+            access.drivers.push(FleetDriver {
+                user,
+                hide_private: false,
+            });
+        });
+
+        record.invitation.state = InvitationState::Redeemed;
+        record.invitation.redeemed_at = Some(now);
+        record.redeemed_by = Some(user);
+
+        Ok((vehicle, record.invitation.clone()))
+    }
+}
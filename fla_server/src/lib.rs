@@ -2,11 +2,13 @@
 
 use std::sync::Arc;
 
-use axum::extract::FromRef;
+use axum::{extract::FromRef, Router};
 
 pub mod api;
 pub mod errors;
+pub mod invitations;
 pub mod middleware;
+pub mod rate_limit;
 pub mod simulator;
 pub mod tokens;
 pub mod types;
@@ -17,6 +19,33 @@ pub struct Config {
     /// The token configuration
     pub token: Arc<tokens::Config>,
 
-    /// The dummy test vehicles
-    pub vehicles: Arc<Vec<types::Vehicle>>,
+    /// The simulated vehicles
+    pub vehicles: Arc<types::Vehicles>,
+
+    /// The simulated users
+    pub users: Arc<types::Users>,
+
+    /// The simulator shared by all vehicles
+    pub simulator: Arc<simulator::Context>,
+
+    /// The rate limits for API requests
+    pub rate_limiter: Arc<rate_limit::RateLimiter>,
+
+    /// The invitations to share vehicles
+    pub invitations: Arc<invitations::Invitations>,
+
+    /// The named snapshots of the simulated vehicles
+    pub snapshots: Arc<simulator::snapshot::Snapshots>,
+
+    /// The built-in Fleet Telemetry receiver
+    pub telemetry: Arc<simulator::telemetry::Receiver>,
+}
+
+/// Retrieve router for all APIs
+pub fn router(config: &Config) -> Router {
+    Router::new()
+        .nest("/", api::owner::router(config))
+        .nest("/", api::streaming::router(config))
+        .nest("/", api::auth::router(config))
+        .nest("/", api::admin::router(config))
 }
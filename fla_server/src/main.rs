@@ -1,38 +1,179 @@
 //! The main HTTP server
 
-use axum::Router;
-use std::sync::Arc;
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use std::{path::PathBuf, sync::Arc, time::Duration};
 use tower_http::trace::TraceLayer;
+use tracing::{error, info};
 
 use fla_server::Config;
 use fla_server::{
-    api::{auth, owner, streaming},
-    simulator::data,
+    invitations::Invitations,
+    rate_limit::RateLimiter,
+    simulator::{
+        clock::Clock,
+        data, fleet,
+        snapshot::{Snapshot, Snapshots},
+        telemetry, Context,
+    },
     tokens,
+    types::Users,
 };
 
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Parameters {
+    /// How many simulated seconds pass per real second
+    #[arg(long, default_value_t = 1.0)]
+    speed: f64,
+
+    /// Only advance the simulator clock when requested via the admin API
+    #[arg(long, conflicts_with = "speed")]
+    manual: bool,
+
+    /// Simulated time to start at, defaults to the current time
+    #[arg(long)]
+    start_time: Option<DateTime<Utc>>,
+
+    /// Seed for all random behaviour of the simulator
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+
+    /// TOML or JSON file defining the simulated vehicles, defaults to two test vehicles
+    #[arg(long)]
+    fleet: Option<PathBuf>,
+
+    /// Directory to keep named snapshots in, defaults to keeping them in memory
+    #[arg(long)]
+    snapshot_dir: Option<PathBuf>,
+
+    /// JSON file to save the state of every vehicle to, restored at startup if it exists
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+
+    /// How often to save the state file, in real seconds
+    #[arg(long, default_value_t = 300)]
+    save_interval: u64,
+}
+
+/// Save the state of every vehicle to the state file
+async fn save_state(config: &Config, path: &std::path::Path) {
+    let result = Snapshot::take(&config.vehicles, &config.simulator)
+        .await
+        .map_err(|err| format!("{err:?}"))
+        .and_then(|snapshot| snapshot.save(path).map_err(|err| err.to_string()));
+
+    match result {
+        Ok(()) => info!("Saved state to {}", path.display()),
+        Err(err) => error!("Cannot save state to {}: {err}", path.display()),
+    }
+}
+
+/// Wait until the server is asked to stop, by Ctrl-C or by SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+            }
+            Err(err) => {
+                error!("Cannot listen for SIGTERM: {err}");
+                _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        _ = tokio::signal::ctrl_c().await;
+    }
+
+    info!("Shutting down");
+}
+
 #[tokio::main]
 async fn main() {
     tracing_subscriber::fmt()
         .with_max_level(tracing::Level::DEBUG)
         .init();
 
+    let params = Parameters::parse();
+    let fleet = match &params.fleet {
+        Some(path) => fleet::load(path).unwrap_or_else(|err| {
+            eprintln!("Cannot load fleet: {err}");
+            std::process::exit(1);
+        }),
+        None => data::default_fleet(),
+    };
+
+    let start_time = params.start_time.unwrap_or_else(Utc::now);
+    let clock = if params.manual {
+        Clock::manual(start_time)
+    } else {
+        Clock::new_at(params.speed, start_time)
+    };
+    let simulator = Arc::new(Context::new(clock, params.seed));
+
     let config = Config {
         token: Arc::new(tokens::Config {
             secret: "mom-said-yes".to_string(),
         }),
-        vehicles: Arc::new(data::get_vehicles()),
+        vehicles: Arc::new(data::get_fleet_vehicles(&fleet, &simulator)),
+        users: Arc::new(Users::new(fleet.users)),
+        simulator,
+        rate_limiter: Arc::new(RateLimiter::default()),
+        invitations: Arc::new(Invitations::default()),
+        snapshots: Arc::new(
+            params
+                .snapshot_dir
+                .map_or_else(Snapshots::default, Snapshots::in_dir),
+        ),
+        telemetry: Arc::new(telemetry::Receiver::default()),
     };
 
-    let app = Router::new()
-        .nest("/", owner::router(&config))
-        .nest("/", streaming::router(&config))
-        .nest("/", auth::router(&config))
-        .layer(TraceLayer::new_for_http());
+    if let Some(path) = params.state_file.as_ref().filter(|path| path.exists()) {
+        let restored = match Snapshot::load(path) {
+            Ok(snapshot) => snapshot
+                .restore(&config.vehicles, &config.users, &config.simulator)
+                .await
+                .map_err(|err| format!("{err:?}")),
+            Err(err) => Err(err.to_string()),
+        };
+        if let Err(err) = restored {
+            eprintln!("Cannot restore state from {}: {err}", path.display());
+            std::process::exit(1);
+        }
+    }
+
+    if let Some(path) = params.state_file.clone() {
+        let config = config.clone();
+        let period = Duration::from_secs(params.save_interval.max(1));
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+            loop {
+                interval.tick().await;
+                save_state(&config, &path).await;
+            }
+        });
+    }
+
+    let app = fla_server::router(&config).layer(TraceLayer::new_for_http());
 
     #[allow(clippy::expect_used)]
     axum::Server::bind(&"[::]:4080".parse().expect("Could not bind to port"))
         .serve(app.into_make_service())
+        .with_graceful_shutdown(shutdown_signal())
         .await
         .expect("Could not start server");
+
+    if let Some(path) = &params.state_file {
+        save_state(&config, path).await;
+    }
 }
@@ -1,17 +1,30 @@
-//! Authentication middleware
+//! Authentication and fault injection middleware
 
-use std::sync::Arc;
+use std::{collections::HashMap, sync::Arc};
 
-use crate::tokens;
-use axum::{extract::State, http::Request, middleware::Next, response::Response};
+use crate::{
+    rate_limit::{Category, RateLimiter},
+    simulator::Context,
+    tokens,
+    types::Vehicles,
+};
+use axum::{
+    body::{self, Bytes, Full},
+    extract::{Path, State},
+    http::{header::CONTENT_LENGTH, Request},
+    middleware::Next,
+    response::Response,
+    Extension,
+};
 use axum_auth::AuthBearer;
-use tracing::error;
+use fla_common::{
+    simulator::FaultKind,
+    types::{UserId, VehicleId},
+};
+use tracing::{debug, error};
 
 use crate::errors::ResponseError;
 
-#[derive(Clone)]
-struct CurrentUser {/* ... */}
-
 /// Extract the current access token claims from the request
 ///
 /// # Errors
@@ -34,3 +47,163 @@ pub async fn access_token<B: Send>(
         }
     }
 }
+
+/// Only let tokens with the `simulator_admin` scope through
+///
+/// Must run after [`access_token`], which provides the claims.
+///
+/// # Errors
+///
+/// Returns `ResponseError::MissingScopes` if the token does not have the scope
+pub async fn simulator_admin<B: Send>(
+    Extension(claims): Extension<Arc<tokens::AccessClaims>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    if !claims.scopes.contains(&tokens::ScopeEnum::SimulatorAdmin) {
+        debug!("Token for user {} is not a simulator admin", claims.sub);
+        return Err(ResponseError::MissingScopes);
+    }
+    Ok(next.run(req).await)
+}
+
+/// Damage the body of responses for a vehicle, if a fault has been injected
+///
+/// # Errors
+///
+/// Returns `ResponseError::DeviceNotAvailable` if the simulator cannot be reached.
+pub async fn response_fault<B: Send>(
+    State(vehicles): State<Arc<Vehicles>>,
+    Path(id): Path<VehicleId>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    let Some(vehicle) = vehicles.find(id) else {
+        return Ok(next.run(req).await);
+    };
+
+    let fault = vehicle.command.body_fault().await?;
+    let response = next.run(req).await;
+
+    let Some(fault) = fault else {
+        return Ok(response);
+    };
+
+    debug!("Damaging response for vehicle {id}: {fault:?}");
+    let (mut parts, body) = response.into_parts();
+    let bytes = hyper::body::to_bytes(body)
+        .await
+        .map_err(|err| ResponseError::internal_error(err.to_string()))?;
+
+    let bytes = match fault {
+        FaultKind::PartialJson => bytes.slice(..bytes.len() / 2),
+        _ => Bytes::from_static(b"{\"response\": <html>502 Bad Gateway</html>"),
+    };
+
+    parts.headers.remove(CONTENT_LENGTH);
+    Ok(Response::from_parts(parts, body::boxed(Full::from(bytes))))
+}
+
+/// The vehicle in the request path, if any, routes may have other parameters too
+fn path_vehicle_id(params: Option<Path<HashMap<String, String>>>) -> Option<VehicleId> {
+    params.and_then(|Path(params)| params.get("id")?.parse().ok())
+}
+
+/// Use up the budgets of the user and vehicle, the user comes from the [`access_token`] claims
+async fn rate_limit<B>(
+    category: Category,
+    context: &Context,
+    limiter: &RateLimiter,
+    user: UserId,
+    id: Option<VehicleId>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    let now = context.clock.now();
+    limiter
+        .check(now, user, id, category)
+        .map_err(|retry_after| {
+            debug!(
+                "Rate limited {category:?} request for vehicle {id:?}, retry after {retry_after:?}"
+            );
+            ResponseError::RateLimited(context.clock.real_duration(retry_after))
+        })?;
+    Ok(next.run(req).await)
+}
+
+/// Limit the rate of data requests
+///
+/// # Errors
+///
+/// Returns `ResponseError::RateLimited` if the request is over budget.
+pub async fn rate_limit_data<B>(
+    State(context): State<Arc<Context>>,
+    State(limiter): State<Arc<RateLimiter>>,
+    Extension(claims): Extension<Arc<tokens::AccessClaims>>,
+    params: Option<Path<HashMap<String, String>>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    let id = path_vehicle_id(params);
+    rate_limit(
+        Category::Data,
+        &context,
+        &limiter,
+        claims.sub,
+        id,
+        req,
+        next,
+    )
+    .await
+}
+
+/// Limit the rate of wake up requests
+///
+/// # Errors
+///
+/// Returns `ResponseError::RateLimited` if the request is over budget.
+pub async fn rate_limit_wake_up<B>(
+    State(context): State<Arc<Context>>,
+    State(limiter): State<Arc<RateLimiter>>,
+    Extension(claims): Extension<Arc<tokens::AccessClaims>>,
+    Path(id): Path<VehicleId>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    rate_limit(
+        Category::WakeUp,
+        &context,
+        &limiter,
+        claims.sub,
+        Some(id),
+        req,
+        next,
+    )
+    .await
+}
+
+/// Limit the rate of commands
+///
+/// # Errors
+///
+/// Returns `ResponseError::RateLimited` if the request is over budget.
+pub async fn rate_limit_command<B>(
+    State(context): State<Arc<Context>>,
+    State(limiter): State<Arc<RateLimiter>>,
+    Extension(claims): Extension<Arc<tokens::AccessClaims>>,
+    params: Option<Path<HashMap<String, String>>>,
+    req: Request<B>,
+    next: Next<B>,
+) -> Result<Response, ResponseError> {
+    let id = path_vehicle_id(params);
+    rate_limit(
+        Category::Command,
+        &context,
+        &limiter,
+        claims.sub,
+        id,
+        req,
+        next,
+    )
+    .await
+}
@@ -0,0 +1,254 @@
+//! Rate limiting of API requests
+//!
+//! Every request uses up part of a budget for its user and for its vehicle. The user budget is
+//! shared by every access token of the user, so refreshing a token does not start a new budget.
+//! Budgets are sliding windows measured on the simulator clock, so a manual clock also controls
+//! when a budget recovers.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Mutex, PoisonError, RwLock},
+    time::Duration,
+};
+
+use fla_common::{
+    simulator::{RateBudget, RateBudgets, RateLimits},
+    types::{UserId, VehicleId},
+};
+use tokio::time::Instant;
+
+/// The kind of request, each kind has a separate budget
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Category {
+    /// Requests for vehicle lists and vehicle data
+    Data,
+
+    /// Wake up requests
+    WakeUp,
+
+    /// Commands sent to vehicles
+    Command,
+}
+
+impl Category {
+    const fn budget(self, budgets: &RateBudgets) -> Option<RateBudget> {
+        match self {
+            Self::Data => budgets.data,
+            Self::WakeUp => budgets.wake_up,
+            Self::Command => budgets.command,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+enum Key {
+    User(UserId),
+    Vehicle(VehicleId),
+}
+
+impl Key {
+    const fn budget(&self, limits: &RateLimits, category: Category) -> Option<RateBudget> {
+        match self {
+            Self::User(_) => category.budget(&limits.per_token),
+            Self::Vehicle(_) => category.budget(&limits.per_vehicle),
+        }
+    }
+}
+
+/// Tracks the requests made against each budget
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    limits: RwLock<RateLimits>,
+    requests: Mutex<HashMap<(Key, Category), VecDeque<Instant>>>,
+}
+
+impl RateLimiter {
+    /// Create a new rate limiter
+    #[must_use]
+    pub fn new(limits: RateLimits) -> Self {
+        Self {
+            limits: RwLock::new(limits),
+            requests: Mutex::default(),
+        }
+    }
+
+    /// The current rate limits
+    #[must_use]
+    pub fn limits(&self) -> RateLimits {
+        *self.limits.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Replace the rate limits, forgetting all earlier requests
+    pub fn set_limits(&self, limits: RateLimits) {
+        *self.limits.write().unwrap_or_else(PoisonError::into_inner) = limits;
+        self.requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clear();
+    }
+
+    /// Record a request, if it is within all of its budgets
+    ///
+    /// # Errors
+    ///
+    /// Returns how long to wait, in simulated time, before trying again if a budget has been used
+    /// up.
+    pub fn check(
+        &self,
+        now: Instant,
+        user: UserId,
+        vehicle: Option<VehicleId>,
+        category: Category,
+    ) -> Result<(), Duration> {
+        let limits = self.limits();
+
+        let mut budgets = vec![];
+        let key = Key::User(user);
+        if let Some(budget) = key.budget(&limits, category) {
+            budgets.push((key, budget));
+        }
+        if let Some(id) = vehicle {
+            let key = Key::Vehicle(id);
+            if let Some(budget) = key.budget(&limits, category) {
+                budgets.push((key, budget));
+            }
+        }
+
+        let mut requests = self.requests.lock().unwrap_or_else(PoisonError::into_inner);
+
+        // Forget requests that have left their window, and budgets with no requests left.
+        requests.retain(|(key, category), times| {
+            let Some(budget) = key.budget(&limits, *category) else {
+                return false;
+            };
+            while times
+                .front()
+                .is_some_and(|time| now.duration_since(*time) >= budget.period())
+            {
+                times.pop_front();
+            }
+            !times.is_empty()
+        });
+
+        // Check every budget before using any of them, a rejected request costs nothing.
+        let retry_after = budgets
+            .iter()
+            .filter_map(|(key, budget)| {
+                let times = requests.get(&(*key, category));
+                if times.map_or(0, VecDeque::len) < budget.requests as usize {
+                    None
+                } else {
+                    let oldest = times.and_then(VecDeque::front).copied().unwrap_or(now);
+                    Some((oldest + budget.period()).duration_since(now))
+                }
+            })
+            .max();
+
+        if let Some(retry_after) = retry_after {
+            return Err(retry_after);
+        }
+
+        for (key, _) in budgets {
+            requests.entry((key, category)).or_default().push_back(now);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn budget(requests: u32, period_seconds: u64) -> Option<RateBudget> {
+        Some(RateBudget {
+            requests,
+            period_seconds,
+        })
+    }
+
+    #[test]
+    fn test_sliding_window() {
+        let limiter = RateLimiter::new(RateLimits {
+            per_token: RateBudgets {
+                data: budget(2, 60),
+                ..RateBudgets::default()
+            },
+            ..RateLimits::default()
+        });
+        let start = Instant::now();
+        let id = Some(VehicleId::new(1));
+        let a = UserId::new(1);
+
+        assert_eq!(limiter.check(start, a, id, Category::Data), Ok(()));
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.check(later, a, id, Category::Data), Ok(()));
+
+        let later = start + Duration::from_secs(20);
+        let retry_after = Duration::from_secs(40);
+        assert_eq!(
+            limiter.check(later, a, id, Category::Data),
+            Err(retry_after)
+        );
+
+        // Other users and other kinds of requests are not affected.
+        assert_eq!(
+            limiter.check(later, UserId::new(2), id, Category::Data),
+            Ok(())
+        );
+        assert_eq!(limiter.check(later, a, id, Category::WakeUp), Ok(()));
+
+        let later = start + Duration::from_secs(60);
+        assert_eq!(limiter.check(later, a, id, Category::Data), Ok(()));
+    }
+
+    #[test]
+    fn test_forget_old_requests() {
+        let limiter = RateLimiter::new(RateLimits {
+            per_token: RateBudgets {
+                data: budget(1, 60),
+                ..RateBudgets::default()
+            },
+            ..RateLimits::default()
+        });
+        let start = Instant::now();
+
+        for user in [1, 2, 3] {
+            let user = UserId::new(user);
+            assert_eq!(limiter.check(start, user, None, Category::Data), Ok(()));
+        }
+
+        let later = start + Duration::from_secs(60);
+        assert_eq!(
+            limiter.check(later, UserId::new(4), None, Category::Data),
+            Ok(())
+        );
+
+        let requests = limiter
+            .requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        assert_eq!(requests.len(), 1);
+    }
+
+    #[test]
+    fn test_per_vehicle() {
+        let limiter = RateLimiter::new(RateLimits {
+            per_vehicle: RateBudgets {
+                wake_up: budget(1, 60),
+                ..RateBudgets::default()
+            },
+            ..RateLimits::default()
+        });
+        let now = Instant::now();
+        let one = Some(VehicleId::new(1));
+        let two = Some(VehicleId::new(2));
+        let a = UserId::new(1);
+
+        assert_eq!(limiter.check(now, a, one, Category::WakeUp), Ok(()));
+        assert!(limiter
+            .check(now, UserId::new(2), one, Category::WakeUp)
+            .is_err());
+        assert_eq!(limiter.check(now, a, two, Category::WakeUp), Ok(()));
+    }
+}
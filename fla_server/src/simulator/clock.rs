@@ -0,0 +1,283 @@
+//! Virtual clock for the simulator
+//!
+//! All simulated vehicles share one clock. Simulated time runs at `speed` times real time, and
+//! the UTC timestamps reported by the simulator are derived from the same clock so they stay
+//! consistent with the accelerated time.
+//!
+//! A manual clock does not move by itself. It only advances when [`Clock::advance`] is called,
+//! which makes the simulation fully deterministic.
+//!
+//! Simulated instants are represented as [`tokio::time::Instant`] values on a virtual timeline.
+//! They must only be compared with other instants obtained from the same [`Clock`].
+
+use std::{sync::RwLock, time::Duration};
+
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+use tokio::{sync::watch, time::Instant};
+
+/// How the clock moves forward
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockMode {
+    /// Simulated time runs at `speed` times real time
+    Running {
+        /// How many simulated seconds pass per real second
+        speed: f64,
+    },
+
+    /// Simulated time only moves when advanced explicitly
+    Manual,
+}
+
+/// An error changing the clock
+#[derive(Error, Debug)]
+pub enum ClockError {
+    /// Only a manual clock can be advanced
+    #[error("Clock is not in manual mode")]
+    NotManual,
+}
+
+#[derive(Debug)]
+struct ClockState {
+    /// Real instant at which `virtual_epoch` was last set
+    real_epoch: Instant,
+
+    /// Simulated instant at `real_epoch`
+    virtual_epoch: Instant,
+}
+
+/// The simulator wide virtual clock
+#[derive(Debug)]
+pub struct Clock {
+    /// Simulated instant corresponding to `utc_origin`
+    origin: Instant,
+
+    /// UTC time when the clock was started
+    utc_origin: DateTime<Utc>,
+
+    /// How the clock moves forward
+    mode: ClockMode,
+
+    /// Mutable part of the clock
+    state: RwLock<ClockState>,
+
+    /// Notified whenever a manual clock is advanced
+    advanced: watch::Sender<Instant>,
+}
+
+impl Clock {
+    /// Create a new clock starting at the current time
+    ///
+    /// A `speed` of 1.0 is real time, 60.0 turns every real second into a simulated minute.
+    #[must_use]
+    pub fn new(speed: f64) -> Self {
+        Self::new_at(speed, Utc::now())
+    }
+
+    /// Create a new clock starting at the given UTC time
+    #[must_use]
+    pub fn new_at(speed: f64, start: DateTime<Utc>) -> Self {
+        let speed = if speed.is_finite() && speed > 0.0 {
+            speed
+        } else {
+            1.0
+        };
+
+        Self::with_mode(ClockMode::Running { speed }, start)
+    }
+
+    /// Create a new manual clock starting at the given UTC time
+    #[must_use]
+    pub fn manual(start: DateTime<Utc>) -> Self {
+        Self::with_mode(ClockMode::Manual, start)
+    }
+
+    fn with_mode(mode: ClockMode, start: DateTime<Utc>) -> Self {
+        let now = Instant::now();
+        let (advanced, _) = watch::channel(now);
+
+        Self {
+            origin: now,
+            utc_origin: start,
+            mode,
+            state: RwLock::new(ClockState {
+                real_epoch: now,
+                virtual_epoch: now,
+            }),
+            advanced,
+        }
+    }
+
+    /// How the clock moves forward
+    #[must_use]
+    pub const fn mode(&self) -> ClockMode {
+        self.mode
+    }
+
+    /// The current simulated instant
+    #[must_use]
+    pub fn now(&self) -> Instant {
+        let state = self
+            .state
+            .read()
+            .unwrap_or_else(std::sync::PoisonError::into_inner);
+
+        match self.mode {
+            ClockMode::Running { speed } => {
+                let elapsed = Instant::now().duration_since(state.real_epoch);
+                state.virtual_epoch + elapsed.mul_f64(speed)
+            }
+            ClockMode::Manual => state.virtual_epoch,
+        }
+    }
+
+    /// The current simulated UTC time
+    #[must_use]
+    pub fn utc_now(&self) -> DateTime<Utc> {
+        self.utc_at(self.now())
+    }
+
+    /// The simulated UTC time at a simulated instant
+    #[must_use]
+    pub fn utc_at(&self, instant: Instant) -> DateTime<Utc> {
+        let elapsed = instant.duration_since(self.origin);
+        chrono::Duration::from_std(elapsed)
+            .map_or(self.utc_origin, |elapsed| self.utc_origin + elapsed)
+    }
+
+    /// Move a manual clock forward
+    ///
+    /// # Errors
+    ///
+    /// Returns `ClockError::NotManual` if the clock is running by itself.
+    pub fn advance(&self, duration: Duration) -> Result<Instant, ClockError> {
+        if self.mode != ClockMode::Manual {
+            return Err(ClockError::NotManual);
+        }
+
+        let now = {
+            let mut state = self
+                .state
+                .write()
+                .unwrap_or_else(std::sync::PoisonError::into_inner);
+            state.virtual_epoch += duration;
+            state.real_epoch = Instant::now();
+            state.virtual_epoch
+        };
+
+        self.advanced.send_replace(now);
+        Ok(now)
+    }
+
+    /// Wait until the simulated instant has been reached
+    ///
+    /// Returns immediately if the instant is already in the past.
+    pub async fn sleep_until(&self, instant: Instant) {
+        match self.mode {
+            ClockMode::Running { speed } => {
+                let remaining = instant.saturating_duration_since(self.now());
+                tokio::time::sleep(remaining.div_f64(speed)).await;
+            }
+            ClockMode::Manual => {
+                let mut advanced = self.advanced.subscribe();
+                while self.now() < instant {
+                    if advanced.changed().await.is_err() {
+                        // The clock is gone, time will never move again.
+                        std::future::pending::<()>().await;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Convert a simulated duration into real time
+    ///
+    /// A manual clock has no relationship with real time, so the duration is returned as is.
+    #[must_use]
+    pub fn real_duration(&self, duration: Duration) -> Duration {
+        match self.mode {
+            ClockMode::Running { speed } => duration.div_f64(speed),
+            ClockMode::Manual => duration,
+        }
+    }
+}
+
+impl Default for Clock {
+    fn default() -> Self {
+        Self::new(1.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_accelerated_time() {
+        let start = Utc::now();
+        let clock = Clock::new_at(60.0, start);
+        let before = clock.now();
+
+        tokio::time::sleep(Duration::from_secs(1)).await;
+
+        assert_eq!(clock.now().duration_since(before), Duration::from_secs(60));
+        assert_eq!(clock.utc_now() - start, chrono::Duration::seconds(60));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_sleep_until() {
+        let clock = Clock::new(600.0);
+        let real_start = Instant::now();
+
+        clock
+            .sleep_until(clock.now() + Duration::from_secs(600))
+            .await;
+
+        assert_eq!(real_start.elapsed(), Duration::from_secs(1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_manual_time() {
+        let start = Utc::now();
+        let clock = std::sync::Arc::new(Clock::manual(start));
+        let before = clock.now();
+
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(clock.now(), before);
+        assert_eq!(clock.utc_now(), start);
+
+        let c = clock.clone();
+        let sleeper = tokio::spawn(async move {
+            c.sleep_until(before + Duration::from_secs(10)).await;
+        });
+
+        clock.advance(Duration::from_secs(5)).unwrap();
+        tokio::task::yield_now().await;
+        assert!(!sleeper.is_finished());
+
+        clock.advance(Duration::from_secs(5)).unwrap();
+        sleeper.await.unwrap();
+        assert_eq!(clock.utc_now() - start, chrono::Duration::seconds(10));
+    }
+
+    #[test]
+    fn test_real_duration() {
+        let clock = Clock::new(60.0);
+        assert_eq!(
+            clock.real_duration(Duration::from_secs(120)),
+            Duration::from_secs(2)
+        );
+
+        let clock = Clock::manual(Utc::now());
+        assert_eq!(
+            clock.real_duration(Duration::from_secs(120)),
+            Duration::from_secs(120)
+        );
+    }
+
+    #[test]
+    fn test_advance_running_clock() {
+        let clock = Clock::new(1.0);
+        assert!(clock.advance(Duration::from_secs(1)).is_err());
+    }
+}
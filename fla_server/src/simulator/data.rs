@@ -1,42 +1,89 @@
 //! Dummy test data
 
-use crate::types::Vehicle;
-use fla_common::types::{VehicleDefinition, VehicleGuid, VehicleId, VehicleStateEnum};
+use std::sync::Arc;
 
-/// Get test vehicles
+use tap::Pipe;
+
+use crate::{
+    simulator::Context,
+    types::{Vehicle, Vehicles},
+};
+use fla_common::{
+    simulator::{
+        Fleet, FleetBattery, FleetDriver, FleetLocation, FleetUser, FleetVehicle,
+        SimulationStateEnum, VehicleModel, DEFAULT_USER,
+    },
+    types::{UserId, VehicleGuid, VehicleId},
+};
+
+/// A user that drives the second test vehicle, but cannot see where it is
+pub const DRIVER_USER: UserId = UserId::new(800_002);
+
+/// Get the test fleet, used if no fleet file is given
+#[must_use]
+pub fn default_fleet() -> Fleet {
+    Fleet {
+        users: vec![
+            FleetUser {
+                id: DEFAULT_USER,
+                email: "test@example.com".to_string(),
+            },
+            FleetUser {
+                id: DRIVER_USER,
+                email: "driver@example.com".to_string(),
+            },
+        ],
+        vehicles: vec![
+            FleetVehicle {
+                id: VehicleId::new(123_456_789),
+                vehicle_id: VehicleGuid::new(999_456_789),
+                vin: "5YJ3E1EA7JF000789".to_string(),
+                display_name: "My Model 3".to_string(),
+                model: VehicleModel::Model3,
+                trim: None,
+                color: Some("Black".to_string()),
+                option_codes: None,
+                location: FleetLocation::default(),
+                battery: FleetBattery::default(),
+                state: SimulationStateEnum::Idle,
+                owner: DEFAULT_USER,
+                drivers: vec![],
+            },
+            FleetVehicle {
+                id: VehicleId::new(123_456_000),
+                vehicle_id: VehicleGuid::new(999_456_000),
+                vin: "5YJ3E1EA7JF000000".to_string(),
+                display_name: "Not my Model 3".to_string(),
+                model: VehicleModel::Model3,
+                trim: None,
+                color: Some("Black".to_string()),
+                option_codes: None,
+                location: FleetLocation::default(),
+                battery: FleetBattery::default(),
+                state: SimulationStateEnum::Idle,
+                owner: DEFAULT_USER,
+                drivers: vec![FleetDriver {
+                    user: DRIVER_USER,
+                    hide_private: true,
+                }],
+            },
+        ],
+    }
+}
+
+/// Start simulating every vehicle in a fleet
 #[must_use]
-pub fn get_vehicles() -> Vec<Vehicle> {
-    let data = [VehicleDefinition {
-        id: VehicleId::new(123_456_789),
-        vehicle_id: VehicleGuid::new(999_456_789),
-        vin: "5YJ3E1EA7JF000789".to_string(),
-        display_name: "My Model 3".to_string(),
-        option_codes: Some("AD15,MDL3,PBSB,RENA,BT37,ID3W,RF3G,S3PB,DRLH,APF0,COUS,BC3B,CH07,PC30,FC3P,FG31,GLFR,HL31,HM31,IL31,LLP1,LP01,MR31,FM3B,RS3H,SA3P,STCP,SC04,ST01,SU3C,T3CA,TW00,TM00,UT3P,WR00,AU3P,APH3,AF00,ZCST,MI00,CDM0".to_string()),
-        color: Some("Black".to_string()),
-        tokens: vec!["abcdef1234567890".to_string()],
-        state: VehicleStateEnum::Online,
-        in_service: false,
-        id_s: "12345678901234567".to_string(),
-        calendar_enabled: true,
-        api_version: 6,
-        backseat_token: None,
-        backseat_token_updated_at: None,
-    }, VehicleDefinition {
-        id: VehicleId::new(123_456_000),
-        vehicle_id: VehicleGuid::new(999_456_000),
-        vin: "5YJ3E1EA7JF000000".to_string(),
-        display_name: "Not my Model 3".to_string(),
-        option_codes: Some("AD15,MDL3,PBSB,RENA,BT37,ID3W,RF3G,S3PB,DRLH,APF0,COUS,BC3B,CH07,PC30,FC3P,FG31,GLFR,HL31,HM31,IL31,LLP1,LP01,MR31,FM3B,RS3H,SA3P,STCP,SC04,ST01,SU3C,T3CA,TW00,TM00,UT3P,WR00,AU3P,APH3,AF00,ZCST,MI00,CDM0".to_string()),
-        color: Some("Black".to_string()),
-        tokens: vec!["abcdef1234567890".to_string()],
-        state: VehicleStateEnum::Offline,
-        in_service: false,
-        id_s: "12345678901234567".to_string(),
-        calendar_enabled: true,
-        api_version: 6,
-        backseat_token: None,
-        backseat_token_updated_at: None,
-    }];
+pub fn get_fleet_vehicles(fleet: &Fleet, context: &Arc<Context>) -> Vehicles {
+    fleet
+        .vehicles
+        .iter()
+        .map(|vehicle| Vehicle::new(vehicle.clone(), context.clone()))
+        .collect::<Vec<_>>()
+        .pipe(Vehicles::new)
+}
 
-    data.into_iter().map(Vehicle::new).collect()
+/// Get test vehicles
+#[must_use]
+pub fn get_vehicles(context: &Arc<Context>) -> Vehicles {
+    get_fleet_vehicles(&default_fleet(), context)
 }
@@ -0,0 +1,72 @@
+//! Weather and tyre physics for parked vehicles
+
+use std::f64::consts::PI;
+
+use fla_common::simulator::ParkedPolicy;
+
+/// Atmospheric pressure in bar, tyre pressures are reported above this
+const ATMOSPHERE: f64 = 1.013_25;
+
+/// Temperature at which cold tyre pressures are given, in °C
+const REFERENCE_TEMP: f64 = 20.0;
+
+/// Below this fraction of the recommended pressure a soft warning is raised
+const SOFT_WARNING: f64 = 0.9;
+
+/// Below this fraction of the recommended pressure a hard warning is raised
+const HARD_WARNING: f64 = 0.75;
+
+/// Outside temperature in °C at a local time of day in hours
+///
+/// Coldest just before sunrise, warmest mid afternoon.
+pub fn outside_temp(hour: f64, policy: &ParkedPolicy) -> f64 {
+    let phase = (hour - 15.0) / 24.0 * 2.0 * PI;
+    policy.mean_outside_temp + policy.daily_temp_range / 2.0 * phase.cos()
+}
+
+/// Cabin temperature in °C of a parked car at a local time of day in hours
+///
+/// The sun heats up the cabin during the day, at night it is the same as outside.
+pub fn inside_temp(hour: f64, outside_temp: f64) -> f64 {
+    let sun = ((hour - 6.0) / 12.0 * PI).sin().max(0.0);
+    outside_temp + 10.0 * sun
+}
+
+/// Tyre pressure in bar at a temperature, given the pressure at 20 °C
+pub fn tyre_pressure(cold_pressure: f64, temp: f64) -> f64 {
+    let absolute = (cold_pressure + ATMOSPHERE) * (temp + 273.15) / (REFERENCE_TEMP + 273.15);
+    (absolute - ATMOSPHERE).max(0.0)
+}
+
+/// Soft and hard warnings for a tyre pressure, given the recommended pressure
+pub fn tyre_warnings(pressure: f64, recommended: f64) -> (bool, bool) {
+    (
+        pressure < recommended * SOFT_WARNING,
+        pressure < recommended * HARD_WARNING,
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_day_cycle() {
+        let policy = ParkedPolicy::default();
+        assert!((outside_temp(15.0, &policy) - 20.0).abs() < 1e-9);
+        assert!((outside_temp(3.0, &policy) - 10.0).abs() < 1e-9);
+        assert!((inside_temp(0.0, 10.0) - 10.0).abs() < 1e-9);
+        assert!((inside_temp(12.0, 20.0) - 30.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_tyre_pressure() {
+        assert!((tyre_pressure(2.9, 20.0) - 2.9).abs() < 1e-9);
+        assert!(tyre_pressure(2.9, -10.0) < 2.6);
+        assert!(tyre_pressure(2.9, 40.0) > 3.1);
+
+        assert_eq!(tyre_warnings(2.9, 2.9), (false, false));
+        assert_eq!(tyre_warnings(2.5, 2.9), (true, false));
+        assert_eq!(tyre_warnings(2.0, 2.9), (true, true));
+    }
+}
@@ -0,0 +1,151 @@
+//! Faults injected into a simulated vehicle
+
+use std::time::Duration;
+
+use fla_common::simulator::{Fault, FaultKind};
+use rand::{rngs::StdRng, Rng};
+use tokio::sync::oneshot;
+
+use crate::errors::ResponseError;
+
+use super::TIMEOUT;
+
+/// Faults that affect requests sent to the vehicle
+const REQUEST_FAULTS: [FaultKind; 4] = [
+    FaultKind::DeviceNotAvailable,
+    FaultKind::DeviceUnexpectedResponse,
+    FaultKind::Latency,
+    FaultKind::Timeout,
+];
+
+/// Faults that affect the body of HTTP responses
+const BODY_FAULTS: [FaultKind; 2] = [FaultKind::MalformedJson, FaultKind::PartialJson];
+
+/// The faults currently active for a vehicle
+#[derive(Debug, Default)]
+pub struct Faults(Vec<Fault>);
+
+impl From<Vec<Fault>> for Faults {
+    fn from(faults: Vec<Fault>) -> Self {
+        Self(faults)
+    }
+}
+
+impl Faults {
+    /// All active faults
+    pub fn list(&self) -> Vec<Fault> {
+        self.0.clone()
+    }
+
+    /// Add a new fault
+    pub fn add(&mut self, fault: Fault) {
+        self.0.push(fault);
+    }
+
+    /// Remove all faults
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// Find a fault of one of the given kinds that affects this request
+    ///
+    /// Counts down faults that only affect a limited number of requests.
+    pub fn take(&mut self, rng: &mut StdRng, kinds: &[FaultKind]) -> Option<Fault> {
+        let index = self.0.iter().position(|fault| {
+            kinds.contains(&fault.kind)
+                && fault
+                    .probability
+                    .is_none_or(|p| rng.gen_bool(p.clamp(0.0, 1.0)))
+        })?;
+
+        let fault = self.0[index];
+        if let Some(count) = &mut self.0[index].count {
+            *count = count.saturating_sub(1);
+            if *count == 0 {
+                self.0.remove(index);
+            }
+        }
+
+        Some(fault)
+    }
+
+    /// Decide how a request to the vehicle will be answered
+    pub fn delivery(&mut self, rng: &mut StdRng) -> Delivery {
+        match self.take(rng, &REQUEST_FAULTS) {
+            Some(Fault {
+                kind: FaultKind::DeviceNotAvailable,
+                ..
+            }) => Delivery::Failed(ResponseError::DeviceNotAvailable),
+            Some(Fault {
+                kind: FaultKind::DeviceUnexpectedResponse,
+                ..
+            }) => Delivery::Failed(ResponseError::DeviceUnexpectedResponse),
+            Some(Fault {
+                kind: FaultKind::Latency,
+                latency_ms,
+                ..
+            }) => Delivery::Delayed(Duration::from_millis(latency_ms)),
+            Some(Fault {
+                kind: FaultKind::Timeout,
+                ..
+            }) => Delivery::Lost,
+            _ => Delivery::Normal,
+        }
+    }
+
+    /// Decide if the body of a HTTP response should be damaged
+    pub fn body_fault(&mut self, rng: &mut StdRng) -> Option<FaultKind> {
+        self.take(rng, &BODY_FAULTS).map(|fault| fault.kind)
+    }
+}
+
+/// How a request to the vehicle will be answered
+pub enum Delivery {
+    /// Answered straight away
+    Normal,
+
+    /// Answered after a delay
+    Delayed(Duration),
+
+    /// The vehicle did not get the request, an error is returned
+    Failed(ResponseError),
+
+    /// The vehicle did not get the request, and nobody will answer
+    Lost,
+}
+
+impl Delivery {
+    /// Does the vehicle get to see the request
+    pub const fn reaches_vehicle(&self) -> bool {
+        matches!(self, Self::Normal | Self::Delayed(_))
+    }
+
+    /// Send the answer
+    pub fn send<T: Send + 'static>(
+        self,
+        tx: oneshot::Sender<Result<T, ResponseError>>,
+        response: Result<T, ResponseError>,
+    ) {
+        match self {
+            Self::Normal => {
+                _ = tx.send(response);
+            }
+            Self::Delayed(delay) => {
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    _ = tx.send(response);
+                });
+            }
+            Self::Failed(err) => {
+                _ = tx.send(Err(err));
+            }
+            Self::Lost => {
+                // Hang on to the sender until the caller has given up.
+                tokio::spawn(async move {
+                    tokio::time::sleep(TIMEOUT * 2).await;
+                    drop(tx);
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,374 @@
+//! Fleets of simulated vehicles read from files
+//!
+//! A fleet file is TOML or JSON, chosen by the file extension.
+
+use std::{collections::HashSet, fmt::Display, path::Path};
+
+use fla_common::{
+    simulator::{Fleet, FleetVehicle},
+    types::UserId,
+};
+use thiserror::Error;
+
+use super::profiles::profile;
+
+/// A problem with one value in a fleet
+#[derive(Debug, Eq, PartialEq)]
+pub struct Invalid {
+    /// Where the value is, for example `vehicles[1].vin`
+    pub path: String,
+
+    /// What is wrong with the value
+    pub message: String,
+}
+
+impl Display for Invalid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+/// An error loading a fleet
+#[derive(Error, Debug)]
+pub enum FleetError {
+    /// The file could not be read
+    #[error("Cannot read {path}: {source}")]
+    Read {
+        /// The file
+        path: String,
+
+        /// The reason
+        source: std::io::Error,
+    },
+
+    /// The file extension is not `.toml` or `.json`
+    #[error("Unknown fleet file format {0}, expected .toml or .json")]
+    UnknownFormat(String),
+
+    /// The TOML could not be parsed
+    #[error("{0}")]
+    Toml(#[from] toml::de::Error),
+
+    /// The JSON could not be parsed
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+
+    /// The fleet was parsed but has invalid values
+    #[error("Invalid fleet:\n{}", display_list(.0))]
+    Invalid(Vec<Invalid>),
+}
+
+fn display_list(list: &[Invalid]) -> String {
+    list.iter()
+        .map(|invalid| format!("  {invalid}"))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Read and validate a fleet file
+///
+/// # Errors
+///
+/// Returns an error if the file cannot be read or parsed, or if the fleet is invalid.
+pub fn load(path: &Path) -> Result<Fleet, FleetError> {
+    let text = std::fs::read_to_string(path).map_err(|source| FleetError::Read {
+        path: path.display().to_string(),
+        source,
+    })?;
+
+    let fleet: Fleet = match path.extension().and_then(|ext| ext.to_str()) {
+        Some("toml") => toml::from_str(&text)?,
+        Some("json") => serde_json::from_str(&text)?,
+        _ => return Err(FleetError::UnknownFormat(path.display().to_string())),
+    };
+
+    validate(&fleet)?;
+    Ok(fleet)
+}
+
+/// Check that a fleet can be simulated
+///
+/// # Errors
+///
+/// Returns every invalid value found.
+pub fn validate(fleet: &Fleet) -> Result<(), FleetError> {
+    let mut errors = vec![];
+
+    if fleet.vehicles.is_empty() {
+        errors.push(Invalid {
+            path: "vehicles".to_string(),
+            message: "at least one vehicle is required".to_string(),
+        });
+    }
+
+    let mut users = HashSet::new();
+    let mut emails = HashSet::new();
+
+    for (index, user) in fleet.users.iter().enumerate() {
+        let mut invalid = |field: &str, message: String| {
+            errors.push(Invalid {
+                path: format!("users[{index}].{field}"),
+                message,
+            });
+        };
+
+        if !users.insert(user.id) {
+            invalid("id", format!("duplicate id {}", user.id));
+        }
+        if user.email.trim().is_empty() {
+            invalid("email", "must not be empty".to_string());
+        } else if !emails.insert(user.email.to_lowercase()) {
+            invalid("email", format!("duplicate email {}", user.email));
+        }
+    }
+
+    let mut ids = HashSet::new();
+    let mut vehicle_ids = HashSet::new();
+    let mut vins = HashSet::new();
+
+    for (index, vehicle) in fleet.vehicles.iter().enumerate() {
+        let mut invalid = |field: &str, message: String| {
+            errors.push(Invalid {
+                path: format!("vehicles[{index}].{field}"),
+                message,
+            });
+        };
+
+        if !ids.insert(vehicle.id) {
+            invalid("id", format!("duplicate id {}", vehicle.id));
+        }
+        if !vehicle_ids.insert(vehicle.vehicle_id) {
+            invalid(
+                "vehicle_id",
+                format!("duplicate vehicle_id {:?}", vehicle.vehicle_id),
+            );
+        }
+        if !vins.insert(vehicle.vin.as_str()) {
+            invalid("vin", format!("duplicate vin {}", vehicle.vin));
+        }
+
+        validate_vehicle(vehicle, &mut invalid);
+        validate_access(vehicle, &users, &mut invalid);
+    }
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(FleetError::Invalid(errors))
+    }
+}
+
+fn validate_access(
+    vehicle: &FleetVehicle,
+    users: &HashSet<UserId>,
+    invalid: &mut impl FnMut(&str, String),
+) {
+    if !users.contains(&vehicle.owner) {
+        invalid("owner", format!("unknown user {}", vehicle.owner));
+    }
+
+    let mut drivers = HashSet::new();
+    for (index, driver) in vehicle.drivers.iter().enumerate() {
+        let field = format!("drivers[{index}].user");
+        if !users.contains(&driver.user) {
+            invalid(&field, format!("unknown user {}", driver.user));
+        } else if driver.user == vehicle.owner {
+            invalid(
+                &field,
+                format!("user {} already owns the vehicle", driver.user),
+            );
+        } else if !drivers.insert(driver.user) {
+            invalid(&field, format!("duplicate driver {}", driver.user));
+        }
+    }
+}
+
+fn validate_vehicle(vehicle: &FleetVehicle, invalid: &mut impl FnMut(&str, String)) {
+    if vehicle.vin.len() != 17 {
+        invalid("vin", format!("{} must be 17 characters long", vehicle.vin));
+    } else if let Some(c) = vehicle
+        .vin
+        .chars()
+        .find(|c| !c.is_ascii_alphanumeric() || matches!(c, 'I' | 'O' | 'Q' | 'i' | 'o' | 'q'))
+    {
+        invalid(
+            "vin",
+            format!("{} contains invalid character {c:?}", vehicle.vin),
+        );
+    }
+
+    if vehicle.display_name.trim().is_empty() {
+        invalid("display_name", "must not be empty".to_string());
+    }
+
+    let profile = profile(vehicle.model);
+    if let Some(trim) = &vehicle.trim {
+        if profile.trim(trim).is_none() {
+            invalid(
+                "trim",
+                format!(
+                    "{trim} is not a trim of {}, expected one of {}",
+                    profile.car_type,
+                    profile.trim_names().join(", ")
+                ),
+            );
+        }
+    }
+
+    let location = &vehicle.location;
+    if !(-90.0..=90.0).contains(&location.latitude) {
+        invalid(
+            "location.latitude",
+            format!("{} is not between -90 and 90", location.latitude),
+        );
+    }
+    if !(-180.0..=180.0).contains(&location.longitude) {
+        invalid(
+            "location.longitude",
+            format!("{} is not between -180 and 180", location.longitude),
+        );
+    }
+    if location.heading >= 360 {
+        invalid(
+            "location.heading",
+            format!("{} is not less than 360", location.heading),
+        );
+    }
+
+    let battery = &vehicle.battery;
+    if !(0.0..=100.0).contains(&battery.level) {
+        invalid(
+            "battery.level",
+            format!("{} is not between 0 and 100", battery.level),
+        );
+    }
+    if !(50..=100).contains(&battery.charge_limit_soc) {
+        invalid(
+            "battery.charge_limit_soc",
+            format!("{} is not between 50 and 100", battery.charge_limit_soc),
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use fla_common::simulator::{FleetDriver, FleetUser, VehicleModel, DEFAULT_USER};
+
+    const FLEET: &str = r#"
+        [[vehicles]]
+        id = 1
+        vehicle_id = 11
+        vin = "5YJ3E1EA7JF000001"
+        display_name = "One"
+        state = "Sleeping"
+
+        [vehicles.battery]
+        level = 80.0
+
+        [[vehicles]]
+        id = 2
+        vehicle_id = 12
+        vin = "7SAYGDEE5PA000002"
+        display_name = "Two"
+        model = "modely"
+        trim = "p74d"
+
+        [vehicles.location]
+        latitude = -37.8136
+        longitude = 144.9631
+    "#;
+
+    #[test]
+    fn test_parse_toml() {
+        let fleet: Fleet = toml::from_str(FLEET).unwrap();
+        validate(&fleet).unwrap();
+
+        assert_eq!(fleet.vehicles.len(), 2);
+        assert_eq!(fleet.vehicles[0].model, VehicleModel::Model3);
+        assert_eq!(fleet.vehicles[1].model, VehicleModel::ModelY);
+        assert_eq!(fleet.vehicles[0].trim, None);
+        assert_eq!(fleet.vehicles[1].trim.as_deref(), Some("p74d"));
+        assert!((fleet.vehicles[0].battery.level - 80.0).abs() < f64::EPSILON);
+        assert_eq!(fleet.vehicles[1].battery.charge_limit_soc, 90);
+        assert!((fleet.vehicles[1].location.latitude + 37.8136).abs() < f64::EPSILON);
+    }
+
+    #[test]
+    fn test_example() {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("fleet.example.toml");
+        let fleet = load(&path).unwrap();
+        assert_eq!(fleet.vehicles.len(), 2);
+        assert_eq!(fleet.users.len(), 2);
+        assert_eq!(fleet.vehicles[0].trim.as_deref(), Some("74d"));
+        assert_eq!(fleet.vehicles[1].drivers.len(), 1);
+    }
+
+    #[test]
+    fn test_unknown_field() {
+        let err = toml::from_str::<Fleet>("[[vehicles]]\nid = 1\ncolour = \"Red\"").unwrap_err();
+        assert!(err.to_string().contains("colour"), "{err}");
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut fleet: Fleet = toml::from_str(FLEET).unwrap();
+        fleet.vehicles[1].id = fleet.vehicles[0].id;
+        fleet.vehicles[1].vin = "5YJ3E1EA7JF00000I".to_string();
+        fleet.vehicles[1].trim = Some("awd".to_string());
+        fleet.vehicles[1].battery.level = 120.0;
+
+        let Err(FleetError::Invalid(errors)) = validate(&fleet) else {
+            panic!("fleet should be invalid");
+        };
+        let paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "vehicles[1].id",
+                "vehicles[1].vin",
+                "vehicles[1].trim",
+                "vehicles[1].battery.level"
+            ]
+        );
+        assert_eq!(
+            errors[2].message,
+            "awd is not a trim of modely, expected one of 74d, p74d, 50"
+        );
+    }
+
+    #[test]
+    fn test_invalid_users() {
+        let mut fleet: Fleet = toml::from_str(FLEET).unwrap();
+        fleet.users.push(FleetUser {
+            id: DEFAULT_USER,
+            email: "TEST@example.com".to_string(),
+        });
+        fleet.vehicles[0].owner = UserId::new(1);
+        fleet.vehicles[1].drivers = vec![
+            FleetDriver {
+                user: DEFAULT_USER,
+                hide_private: false,
+            },
+            FleetDriver {
+                user: UserId::new(2),
+                hide_private: false,
+            },
+        ];
+
+        let Err(FleetError::Invalid(errors)) = validate(&fleet) else {
+            panic!("fleet should be invalid");
+        };
+        let paths: Vec<_> = errors.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(
+            paths,
+            [
+                "users[1].id",
+                "users[1].email",
+                "vehicles[0].owner",
+                "vehicles[1].drivers[0].user",
+                "vehicles[1].drivers[1].user"
+            ]
+        );
+    }
+}
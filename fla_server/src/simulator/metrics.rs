@@ -0,0 +1,63 @@
+//! Counters for the simulator
+//!
+//! These are cheap enough to update on every streaming row, and are read by `/admin/metrics`.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use fla_common::simulator::{self, StreamingMetrics};
+
+/// Counters shared by every connection
+#[derive(Debug, Default)]
+pub struct Metrics {
+    rows_sent: AtomicU64,
+    rows_skipped: AtomicU64,
+    lag_events: AtomicU64,
+    connections: AtomicU64,
+}
+
+impl Metrics {
+    /// All counters start at zero
+    #[must_use]
+    pub const fn new() -> Self {
+        Self {
+            rows_sent: AtomicU64::new(0),
+            rows_skipped: AtomicU64::new(0),
+            lag_events: AtomicU64::new(0),
+            connections: AtomicU64::new(0),
+        }
+    }
+
+    /// A streaming row was sent to a client
+    pub fn row_sent(&self) {
+        self.rows_sent.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A streaming subscription fell behind and skipped rows
+    pub fn lagged(&self, skipped: u64) {
+        self.rows_skipped.fetch_add(skipped, Ordering::Relaxed);
+        self.lag_events.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A streaming connection was opened
+    pub fn connected(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A streaming connection was closed
+    pub fn disconnected(&self) {
+        self.connections.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    /// The current values of the counters
+    #[must_use]
+    pub fn snapshot(&self) -> simulator::Metrics {
+        simulator::Metrics {
+            streaming: StreamingMetrics {
+                rows_sent: self.rows_sent.load(Ordering::Relaxed),
+                rows_skipped: self.rows_skipped.load(Ordering::Relaxed),
+                lag_events: self.lag_events.load(Ordering::Relaxed),
+                connections: self.connections.load(Ordering::Relaxed),
+            },
+        }
+    }
+}
@@ -1,30 +1,110 @@
 //! Simulate a car
+pub mod clock;
 pub mod data;
+mod environment;
+mod faults;
+pub mod fleet;
+pub mod metrics;
+pub mod profiles;
 pub mod server;
+pub mod snapshot;
+pub mod telemetry;
 mod types;
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use fla_common::{
-    simulator::SimulationStateEnum,
+    simulator::{Fault, FaultKind, ParkedPolicy, SimulationStateEnum, SleepPolicy, VehicleStatus},
     streaming::{DataError, StreamingData},
+    telemetry::FleetTelemetryConfig,
     types::{VehicleData, VehicleGuid},
 };
 use tokio::sync::{broadcast, mpsc, oneshot};
 
 use crate::errors;
 
+use self::{clock::Clock, metrics::Metrics, snapshot::VehicleSnapshot, telemetry::Output};
+
 type WakeUpResponse = Result<(), errors::ResponseError>;
 type VehicleDataResponse = Result<VehicleData, errors::ResponseError>;
 type SimulateResponse = Result<(), errors::ResponseError>;
-type SubscribeResponse = Result<broadcast::Receiver<Arc<StreamingData>>, DataError>;
+type AdvanceResponse = Result<(), errors::ResponseError>;
+
+/// A new streaming subscription, and the current data if the vehicle is streaming
+pub type Subscription = (
+    broadcast::Receiver<Arc<StreamingData>>,
+    Option<Arc<StreamingData>>,
+);
+type SubscribeResponse = Result<Subscription, DataError>;
 
 enum Command {
     WakeUp(oneshot::Sender<WakeUpResponse>),
     GetVehicleData(oneshot::Sender<VehicleDataResponse>),
     Subscribe(oneshot::Sender<SubscribeResponse>),
     Simulate(SimulationStateEnum, oneshot::Sender<SimulateResponse>),
+    Steer(u16, oneshot::Sender<()>),
+    GetSleepPolicy(oneshot::Sender<SleepPolicy>),
+    SetSleepPolicy(SleepPolicy, oneshot::Sender<()>),
+    GetParkedPolicy(oneshot::Sender<ParkedPolicy>),
+    SetParkedPolicy(ParkedPolicy, oneshot::Sender<()>),
+    GetFaults(oneshot::Sender<Vec<Fault>>),
+    AddFault(Fault, oneshot::Sender<()>),
+    ClearFaults(oneshot::Sender<()>),
+    BodyFault(oneshot::Sender<Option<FaultKind>>),
     WatchState(oneshot::Sender<broadcast::Receiver<SimulationStateEnum>>),
+    Advance(Duration, oneshot::Sender<AdvanceResponse>),
+    Sync(oneshot::Sender<()>),
+    GetStatus(oneshot::Sender<VehicleStatus>),
+    Reset(oneshot::Sender<()>),
+    Snapshot(oneshot::Sender<VehicleSnapshot>),
+    Restore(Box<VehicleSnapshot>, oneshot::Sender<()>),
+    GetTelemetryConfig(oneshot::Sender<Option<FleetTelemetryConfig>>),
+    SetTelemetryConfig(Option<(FleetTelemetryConfig, Output)>, oneshot::Sender<()>),
+}
+
+/// Simulator state shared by all vehicles
+#[derive(Debug)]
+pub struct Context {
+    /// The virtual clock
+    pub clock: Clock,
+
+    /// Seed for all random behaviour, identical inputs give identical outputs
+    pub seed: u64,
+
+    /// Counters for the whole simulator
+    pub metrics: Metrics,
+}
+
+impl Context {
+    /// Create a new simulator context
+    #[must_use]
+    pub const fn new(clock: Clock, seed: u64) -> Self {
+        Self {
+            clock,
+            seed,
+            metrics: Metrics::new(),
+        }
+    }
+
+    /// Get the random seed for a single vehicle
+    #[must_use]
+    pub fn vehicle_seed(&self, vin: &str) -> u64 {
+        // Hashed, so the seed does not depend on the order vehicles are created in.
+        self.seeded_hash(vin)
+    }
+
+    /// Hash a value together with the seed
+    ///
+    /// The result cannot be guessed from the value, but is the same for the same seed.
+    #[must_use]
+    pub fn seeded_hash(&self, value: &str) -> u64 {
+        // FNV-1a
+        value
+            .bytes()
+            .fold(self.seed ^ 0xcbf2_9ce4_8422_2325, |hash, byte| {
+                (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3)
+            })
+    }
 }
 
 /// A handle to the simulator
@@ -93,6 +173,8 @@ impl CommandSender {
 
     /// Simulate a state
     ///
+    /// Returns once the new state is visible.
+    ///
     /// # Errors
     ///
     /// If the simulator is dead, an error will be returned.
@@ -107,7 +189,358 @@ impl CommandSender {
         tokio::time::timeout(TIMEOUT, rx)
             .await
             .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)??;
+
+        self.sync().await
+    }
+
+    /// Change the heading, used for the next drive if the vehicle is not driving
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn steer(&self, heading: u16) -> Result<(), errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::Steer(heading, tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
             .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Get the sleep policy
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn get_sleep_policy(&self) -> Result<SleepPolicy, errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::GetSleepPolicy(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Set the sleep policy
+    ///
+    /// If the vehicle is idle, the time until it falls asleep starts again.
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn set_sleep_policy(&self, policy: SleepPolicy) -> Result<(), errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::SetSleepPolicy(policy, tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Get the parked policy
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn get_parked_policy(&self) -> Result<ParkedPolicy, errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::GetParkedPolicy(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Set the parked policy
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn set_parked_policy(
+        &self,
+        policy: ParkedPolicy,
+    ) -> Result<(), errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::SetParkedPolicy(policy, tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Get the active faults
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn get_faults(&self) -> Result<Vec<Fault>, errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::GetFaults(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Inject a fault
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn add_fault(&self, fault: Fault) -> Result<(), errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::AddFault(fault, tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Remove all faults
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn clear_faults(&self) -> Result<(), errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::ClearFaults(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Find out if the next HTTP response should be damaged
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn body_fault(&self) -> Result<Option<FaultKind>, errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::BodyFault(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Advance the simulator clock and wait until this vehicle has caught up
+    ///
+    /// The clock is shared, so all other vehicles will also move forward.
+    ///
+    /// # Errors
+    ///
+    /// If the clock is not in manual mode, an error will be returned.
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn advance(&self, duration: Duration) -> AdvanceResponse {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::Advance(duration, tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)??;
+
+        self.sync().await
+    }
+
+    /// Wait until the simulator has processed everything that is due
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn sync(&self) -> Result<(), errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::Sync(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Get the internal state of the simulator
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn status(&self) -> Result<VehicleStatus, errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::GetStatus(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Put the vehicle back into the state it was created in
+    ///
+    /// Policies and faults go back to their defaults, and streaming connections are closed.
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn reset(&self) -> Result<(), errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::Reset(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        self.sync().await
+    }
+
+    /// Take a snapshot of everything the simulator knows
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn snapshot(&self) -> Result<VehicleSnapshot, errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::Snapshot(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Replace everything the simulator knows with a snapshot
+    ///
+    /// Streaming connections are closed.
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn restore(&self, snapshot: VehicleSnapshot) -> Result<(), errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::Restore(Box::new(snapshot), tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        self.sync().await
+    }
+
+    /// Get the Fleet Telemetry configuration
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn telemetry_config(
+        &self,
+    ) -> Result<Option<FleetTelemetryConfig>, errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::GetTelemetryConfig(tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
+    }
+
+    /// Set the Fleet Telemetry configuration, and where records are sent
+    ///
+    /// Every configured field is sent as soon as the vehicle is awake. `None` stops sending
+    /// records.
+    ///
+    /// # Errors
+    ///
+    /// If the simulator is dead, an error will be returned.
+    /// If the request times out, an error will be returned.
+    pub async fn set_telemetry_config(
+        &self,
+        config: Option<(FleetTelemetryConfig, Output)>,
+    ) -> Result<(), errors::ResponseError> {
+        let (tx, rx) = oneshot::channel();
+        self.0
+            .send(Command::SetTelemetryConfig(config, tx))
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?;
+
+        tokio::time::timeout(TIMEOUT, rx)
+            .await
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)?
+            .map_err(|_| errors::ResponseError::DeviceNotAvailable)
     }
 
     /// Watch the state of the vehicle
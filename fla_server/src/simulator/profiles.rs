@@ -0,0 +1,254 @@
+//! Built in profiles for each vehicle model
+
+use fla_common::simulator::VehicleModel;
+
+/// The badge, pack size and efficiency of one trim of a model
+#[derive(Debug)]
+pub struct TrimProfile {
+    /// Value of `vehicle_config.trim_badging`, also the name of the trim in fleet files
+    pub trim_badging: &'static str,
+
+    /// Usable pack size in kWh
+    pub pack_kwh: f64,
+
+    /// Energy used per mile driven in Wh
+    pub wh_per_mile: f64,
+}
+
+/// The configuration and trims of a model
+#[derive(Debug)]
+pub struct ModelProfile {
+    /// Value of `vehicle_config.car_type`
+    pub car_type: &'static str,
+
+    /// The trims of the model, the first one is the default
+    pub trims: &'static [TrimProfile],
+
+    /// Value of `vehicle_config.wheel_type`
+    pub wheel_type: &'static str,
+
+    /// Value of `vehicle_config.rear_seat_heaters`
+    pub rear_seat_heaters: i64,
+
+    /// Value of `vehicle_config.third_row_seats`
+    pub third_row_seats: &'static str,
+
+    /// Value of `vehicle_config.has_air_suspension`
+    pub has_air_suspension: bool,
+
+    /// Value of `vehicle_config.efficiency_package`
+    pub efficiency_package: &'static str,
+
+    /// Option codes for the model, without a paint code
+    option_codes: &'static [&'static str],
+}
+
+const fn trim(trim_badging: &'static str, pack_kwh: f64, wh_per_mile: f64) -> TrimProfile {
+    TrimProfile {
+        trim_badging,
+        pack_kwh,
+        wh_per_mile,
+    }
+}
+
+const MODEL_S: ModelProfile = ModelProfile {
+    car_type: "models",
+    trims: &[trim("100d", 95.0, 290.0), trim("p100d", 95.0, 320.0)],
+    wheel_type: "Tempest19",
+    rear_seat_heaters: 1,
+    third_row_seats: "None",
+    has_air_suspension: true,
+    efficiency_package: "Default",
+    option_codes: &[
+        "MDLS", "AD15", "AF02", "APF2", "APH4", "AU01", "BT10", "CDM0", "CH05", "COUS", "DRLH",
+        "DV4W", "FG02", "HP00", "ID3W", "IL31", "PI01", "RFPX", "SC04", "ST31", "SU01", "TM00",
+        "WT19",
+    ],
+};
+
+const MODEL_3: ModelProfile = ModelProfile {
+    car_type: "model3",
+    trims: &[
+        trim("74d", 75.0, 250.0),
+        trim("p74d", 75.0, 270.0),
+        trim("50", 50.0, 230.0),
+    ],
+    wheel_type: "Pinwheel18",
+    rear_seat_heaters: 1,
+    third_row_seats: "None",
+    has_air_suspension: false,
+    efficiency_package: "M32021",
+    option_codes: &[
+        "AD15", "MDL3", "RENA", "BT37", "ID3W", "RF3G", "S3PB", "DRLH", "APF0", "COUS", "BC3B",
+        "CH07", "PC30", "FC3P", "FG31", "GLFR", "HL31", "HM31", "IL31", "LLP1", "LP01", "MR31",
+        "FM3B", "RS3H", "SA3P", "STCP", "SC04", "ST01", "SU3C", "T3CA", "TW00", "TM00", "UT3P",
+        "WR00", "AU3P", "APH3", "AF00", "ZCST", "MI00", "CDM0",
+    ],
+};
+
+const MODEL_X: ModelProfile = ModelProfile {
+    car_type: "modelx",
+    trims: &[trim("100d", 95.0, 340.0), trim("p100d", 95.0, 360.0)],
+    wheel_type: "Turbine20",
+    rear_seat_heaters: 3,
+    third_row_seats: "FuturisFoldFlat",
+    has_air_suspension: true,
+    efficiency_package: "Default",
+    option_codes: &[
+        "MDLX", "AD15", "AF02", "APF2", "APH4", "AU01", "BT10", "CDM0", "CH05", "COUS", "DRLH",
+        "DV4W", "FG02", "HP00", "ID3W", "IL31", "PI01", "SC04", "ST31", "SU01", "S07P", "TM00",
+        "WT20",
+    ],
+};
+
+const MODEL_Y: ModelProfile = ModelProfile {
+    car_type: "modely",
+    trims: &[
+        trim("74d", 75.0, 280.0),
+        trim("p74d", 75.0, 300.0),
+        trim("50", 57.0, 265.0),
+    ],
+    wheel_type: "Apollo19",
+    rear_seat_heaters: 1,
+    third_row_seats: "None",
+    has_air_suspension: false,
+    efficiency_package: "MY2021",
+    option_codes: &[
+        "AD15", "MDLY", "RENA", "BTF0", "ID3W", "RFPG", "DRLH", "APF0", "COUS", "BC3B", "CH07",
+        "PC30", "FC3P", "FG31", "GLFR", "HL31", "HM31", "IL31", "LLP1", "LP01", "MR31", "FM3B",
+        "RS3H", "SA3P", "STCP", "SC04", "ST01", "SU3C", "T3CA", "TW00", "TM00", "UT3P", "WY19B",
+        "AU3P", "APH3", "AF00", "ZCST", "MI00", "CDM0",
+    ],
+};
+
+const CYBERTRUCK: ModelProfile = ModelProfile {
+    car_type: "cybertruck",
+    trims: &[trim("awd", 123.0, 420.0), trim("cyberbeast", 123.0, 450.0)],
+    wheel_type: "CyberTruck20",
+    rear_seat_heaters: 3,
+    third_row_seats: "None",
+    has_air_suspension: true,
+    efficiency_package: "Default",
+    option_codes: &[
+        "MDLC", "AD15", "APF2", "APH4", "AU3P", "BTC1", "CDM0", "COUS", "DRLH", "DV4W", "HP00",
+        "ID3W", "IL31", "SC04", "ST01", "SU01", "TM00", "WTC2",
+    ],
+};
+
+/// Get the profile of a model
+#[must_use]
+pub const fn profile(model: VehicleModel) -> &'static ModelProfile {
+    match model {
+        VehicleModel::ModelS => &MODEL_S,
+        VehicleModel::Model3 => &MODEL_3,
+        VehicleModel::ModelX => &MODEL_X,
+        VehicleModel::ModelY => &MODEL_Y,
+        VehicleModel::Cybertruck => &CYBERTRUCK,
+    }
+}
+
+/// Get the profile of a trim, or of the default trim of the model if none is given
+///
+/// Fleets are validated when they are loaded, an unknown trim also gets the default trim.
+#[must_use]
+pub fn trim_profile(model: VehicleModel, trim: Option<&str>) -> &'static TrimProfile {
+    let profile = profile(model);
+    trim.and_then(|trim| profile.trim(trim))
+        .unwrap_or(&profile.trims[0])
+}
+
+/// Get the paint option code for a colour
+fn paint_code(color: Option<&str>) -> Option<&'static str> {
+    match color?.to_lowercase().replace(' ', "").as_str() {
+        "black" | "solidblack" => Some("PBSB"),
+        "white" | "pearlwhite" => Some("PPSW"),
+        "red" | "redmulticoat" => Some("PPMR"),
+        "blue" | "deepbluemetallic" => Some("PPSB"),
+        "silver" | "midnightsilver" | "midnightsilvermetallic" => Some("PMNG"),
+        "grey" | "gray" | "stealthgrey" => Some("PN01"),
+        _ => None,
+    }
+}
+
+impl TrimProfile {
+    /// Miles of rated range for each percent of battery
+    #[must_use]
+    pub fn range_per_percent(&self) -> f64 {
+        self.pack_kwh * 1000.0 / self.wh_per_mile / 100.0
+    }
+
+    /// Percent of battery used for each km driven
+    #[must_use]
+    pub fn percent_per_km(&self) -> f64 {
+        self.wh_per_mile / 1.609_344 / (self.pack_kwh * 1000.0) * 100.0
+    }
+}
+
+impl ModelProfile {
+    /// Find a trim by its badge
+    #[must_use]
+    pub fn trim(&self, trim_badging: &str) -> Option<&'static TrimProfile> {
+        self.trims.iter().find(|t| t.trim_badging == trim_badging)
+    }
+
+    /// The badges of every trim, for error messages
+    #[must_use]
+    pub fn trim_names(&self) -> Vec<&'static str> {
+        self.trims.iter().map(|t| t.trim_badging).collect()
+    }
+
+    /// Comma separated option codes for a vehicle of this model
+    #[must_use]
+    pub fn option_codes(&self, color: Option<&str>) -> String {
+        self.option_codes
+            .iter()
+            .copied()
+            .chain(paint_code(color))
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_option_codes() {
+        let models = [
+            (VehicleModel::ModelS, "MDLS"),
+            (VehicleModel::Model3, "MDL3"),
+            (VehicleModel::ModelX, "MDLX"),
+            (VehicleModel::ModelY, "MDLY"),
+            (VehicleModel::Cybertruck, "MDLC"),
+        ];
+
+        for (model, code) in models {
+            let codes = profile(model).option_codes(Some("Pearl White"));
+            let codes: Vec<_> = codes.split(',').collect();
+            assert!(codes.contains(&code), "{model:?} is missing {code}");
+            assert!(codes.contains(&"PPSW"), "{model:?} is missing paint");
+        }
+
+        assert!(!profile(VehicleModel::Model3)
+            .option_codes(Some("Purple"))
+            .contains("PBSB"));
+    }
+
+    #[test]
+    fn test_range() {
+        let model_3 = trim_profile(VehicleModel::Model3, None);
+        assert!((model_3.range_per_percent() - 3.0).abs() < 1e-9);
+
+        let standard = trim_profile(VehicleModel::Model3, Some("50"));
+        assert!(standard.range_per_percent() < model_3.range_per_percent());
+        assert_eq!(
+            trim_profile(VehicleModel::Model3, Some("nope")).trim_badging,
+            "74d"
+        );
+
+        // Driving the full range uses the full battery.
+        let km = model_3.range_per_percent() * 100.0 * 1.609_344;
+        assert!((model_3.percent_per_km() * km - 100.0).abs() < 1e-9);
+    }
+}
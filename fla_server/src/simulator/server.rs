@@ -1,9 +1,10 @@
 //! Simulator server
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
-use chrono::{DateTime, Utc};
+use chrono::{DateTime, Timelike, Utc};
 use fla_common::{
+    simulator::{FaultKind, FleetVehicle, ParkedPolicy, SleepPolicy, TyreValues, VehicleStatus},
     streaming::{DataError, StreamingData},
     types::{
         ChargeState, ChargingStateEnum, ClimateState, DriveState, GranularAccess, GuiSettings,
@@ -12,48 +13,53 @@ use fla_common::{
     },
 };
 use flat_projection::FlatProjection;
+use rand::{rngs::StdRng, SeedableRng};
 use tap::Pipe;
 use tokio::{
     select,
-    sync::{broadcast, mpsc},
-    time::{sleep_until, Instant},
+    sync::{broadcast, mpsc, RwLock},
+    time::Instant,
 };
 use tracing::debug;
 
-use crate::{errors::ResponseError, simulator::SimulationStateEnum};
+use crate::{errors::ResponseError, simulator::SimulationStateEnum, types::vehicle_tokens};
 
 use super::{
+    clock::Clock,
+    environment,
+    faults::Faults,
+    profiles::{profile, trim_profile},
+    snapshot::{StateSnapshot, VehicleSnapshot},
+    telemetry::Emitter,
     types::{SimulationChargeState, SimulationDriveState, SimulationState, VehicleDataState},
-    Command, CommandSender,
+    Command, CommandSender, Context,
 };
 
 #[allow(clippy::too_many_lines)]
-fn get_vehicle_data(vehicle: &VehicleDefinition, now: DateTime<Utc>) -> VehicleDataState {
+fn get_vehicle_data(vehicle: &FleetVehicle, now: DateTime<Utc>) -> VehicleDataState {
     let timestamp = now.timestamp_millis();
 
-    let battery_level = 42;
-
-    // Simulated car has 1% battery for 2 miles of range.
-    let range = f32::from(battery_level * 2);
+    let battery_level = vehicle.battery.level.round() as u8;
+    let location = &vehicle.location;
+    let profile = profile(vehicle.model);
+    let trim = trim_profile(vehicle.model, vehicle.trim.as_deref());
+    let range = (f64::from(battery_level) * trim.range_per_percent()) as f32;
 
     VehicleDataState {
         id: vehicle.id,
         user_id: 800_001,
         vehicle_id: vehicle.vehicle_id,
         vin: vehicle.vin.to_string(),
-        color: None,
+        color: vehicle.color.clone(),
         access_type: "OWNER".to_string(),
         granular_access: GranularAccess {
             hide_private: false,
         },
-        tokens: vec![
-            "4f993c5b9e2b937b".to_string(),
-            "7a3153b1bbb48a96".to_string(),
-        ],
-        state: vehicle.state.clone(),
+        tokens: vehicle_tokens(vehicle),
+        state: vehicle.state.into(),
         in_service: false,
-        id_s: vehicle.id_s.clone(),
-        calendar_enabled: vehicle.calendar_enabled,
+        id_s: vehicle.id.to_string(),
+        calendar_enabled: true,
         api_version: 54,
         backseat_token: None,
         backseat_token_updated_at: None,
@@ -66,7 +72,7 @@ fn get_vehicle_data(vehicle: &VehicleDefinition, now: DateTime<Utc>) -> VehicleD
             charge_current_request_max: 48,
             charge_enable_request: true,
             charge_energy_added: 48.45,
-            charge_limit_soc: 0,
+            charge_limit_soc: vehicle.battery.charge_limit_soc,
             charge_limit_soc_max: 100,
             charge_limit_soc_min: 50,
             charge_limit_soc_std: 90,
@@ -109,7 +115,7 @@ fn get_vehicle_data(vehicle: &VehicleDefinition, now: DateTime<Utc>) -> VehicleD
             time_to_full_charge: None,
             timestamp,
             trip_charging: false,
-            usable_battery_level: 42,
+            usable_battery_level: i64::from(battery_level),
             user_charge_enable_request: None,
         },
         climate_state: ClimateState {
@@ -154,13 +160,13 @@ fn get_vehicle_data(vehicle: &VehicleDefinition, now: DateTime<Utc>) -> VehicleD
             wiper_blade_heater: false,
         },
         drive_state: DriveState {
-            active_route_latitude: 37.776_549_4,
-            active_route_longitude: -122.419_541_8,
+            active_route_latitude: location.latitude,
+            active_route_longitude: location.longitude,
             active_route_traffic_minutes_delay: 0.0,
             gps_as_of: 1_692_137_422,
-            heading: 0,
-            latitude: Some(37.776_549_4),
-            longitude: Some(-122.419_541_8),
+            heading: location.heading,
+            latitude: Some(location.latitude),
+            longitude: Some(location.longitude),
             native_latitude: None,
             native_location_supported: 1,
             native_longitude: None,
@@ -186,19 +192,19 @@ fn get_vehicle_data(vehicle: &VehicleDefinition, now: DateTime<Utc>) -> VehicleD
             can_accept_navigation_requests: true,
             can_actuate_trunks: true,
             car_special_type: "base".to_string(),
-            car_type: "modely".to_string(),
+            car_type: profile.car_type.to_string(),
             charge_port_type: "US".to_string(),
             cop_user_set_temp_supported: true,
             dashcam_clip_save_supported: true,
             default_charge_to_max: false,
             driver_assist: "TeslaAP3".to_string(),
             ece_restrictions: false,
-            efficiency_package: "MY2021".to_string(),
+            efficiency_package: profile.efficiency_package.to_string(),
             eu_vehicle: false,
             exterior_color: "MidnightSilver".to_string(),
             exterior_trim: Some("Black".to_string()),
             exterior_trim_override: String::new(),
-            has_air_suspension: false,
+            has_air_suspension: profile.has_air_suspension,
             has_ludicrous_mode: false,
             has_seat_cooling: false,
             headlamp_type: "Premium".to_string(),
@@ -210,7 +216,7 @@ fn get_vehicle_data(vehicle: &VehicleDefinition, now: DateTime<Utc>) -> VehicleD
             plg: true,
             pws: true,
             rear_drive_unit: "PM216MOSFET".to_string(),
-            rear_seat_heaters: 1,
+            rear_seat_heaters: profile.rear_seat_heaters,
             rear_seat_type: 0,
             rhd: false,
             roof_color: "RoofColorGlass".to_string(),
@@ -218,14 +224,14 @@ fn get_vehicle_data(vehicle: &VehicleDefinition, now: DateTime<Utc>) -> VehicleD
             spoiler_type: "None".to_string(),
             sun_roof_installed: None,
             supports_qr_pairing: false,
-            third_row_seats: "None".to_string(),
+            third_row_seats: profile.third_row_seats.to_string(),
             timestamp,
-            trim_badging: "74d".to_string(),
+            trim_badging: trim.trim_badging.to_string(),
             use_range_badging: true,
             utc_offset: -25200,
             webcam_selfie_supported: true,
             webcam_supported: true,
-            wheel_type: "Apollo19".to_string(),
+            wheel_type: profile.wheel_type.to_string(),
         },
         vehicle_state: VehicleState {
             api_version: 54,
@@ -324,128 +330,332 @@ fn get_vehicle_data(vehicle: &VehicleDefinition, now: DateTime<Utc>) -> VehicleD
         },
 
         elevation: 0,
+        battery_level: vehicle.battery.level,
+        model: vehicle.model,
+        trim: vehicle.trim.clone(),
+        tyre_pressures: TyreValues {
+            fl: 3.1,
+            fr: 3.1,
+            rl: 3.15,
+            rr: 3.0,
+        },
     }
 }
 
 /// Start the simulator
 #[must_use]
 #[allow(clippy::needless_pass_by_value)]
-pub fn start(vehicle: VehicleDefinition) -> CommandSender {
+#[allow(clippy::too_many_lines)]
+pub fn start(
+    vehicle: FleetVehicle,
+    definition: Arc<RwLock<VehicleDefinition>>,
+    context: Arc<Context>,
+) -> CommandSender {
     let vehicle_id = vehicle.vehicle_id;
     let (s_tx, _) = broadcast::channel(1);
     let (c_tx, mut c_rx) = mpsc::channel(1);
     let mut maybe_s_tx: Option<broadcast::Sender<Arc<StreamingData>>> = None;
 
     tokio::spawn(async move {
+        let clock = &context.clock;
+        let mut rng = StdRng::seed_from_u64(context.vehicle_seed(&vehicle.vin));
+
         // Simulated real time values.
 
-        let mut data = get_vehicle_data(&vehicle, Utc::now());
-        let mut ss: SimulationState = SimulationState::idle(Instant::now());
+        let mut policy = SleepPolicy::default();
+        let mut parked_policy = ParkedPolicy::default();
+        let mut faults = Faults::default();
+        let mut telemetry: Option<Emitter> = None;
+        let mut stream_time: Option<Instant> = None;
+        let mut parked_time = clock.now();
+        let (mut data, mut ss) =
+            initial_state(&vehicle, clock, parked_time, &policy, &parked_policy);
 
         loop {
             let old_sse = SimulationStateEnum::from(&ss);
 
-            let new_ss = select! {
-                Some(state) = maybe_update_drive(&ss) => {
+            // Fleet Telemetry records are only sent while the vehicle is awake.
+            let was_asleep = ss.is_asleep();
+            let telemetry_due = telemetry
+                .as_ref()
+                .and_then(Emitter::next_due)
+                .filter(|_| !ss.is_asleep());
+
+            // Timers are polled before commands, so that a command never observes a state that
+            // is behind the clock. Each timer is processed at the time it was scheduled for.
+            let (new_ss, now) = select! {
+                biased;
+
+                Some((state, now)) = maybe_update_drive(clock, &ss) => {
                     debug!("Car {:?} is driving", data.id);
-                    let (drive_state, elevation, charge_state, ss) = get_updated_drive_state(&data, &ss, state);
+                    let (drive_state, elevation, charge_state, battery_level, ss) = get_updated_drive_state(clock, now, &policy, &data, state);
                     data.drive_state = drive_state;
                     data.elevation = elevation;
                     data.charge_state = charge_state;
+                    data.battery_level = battery_level;
 
                     let streaming_data: StreamingData = (&data).into();
                     debug!("Streaming data, time {:#?}", streaming_data.time);
 
+                    if maybe_s_tx.is_some() && faults.take(&mut rng, &[FaultKind::DropStreaming]).is_some() {
+                        debug!("Dropping streaming connections for car {:?}", data.id);
+                        maybe_s_tx = None;
+                    }
+
                     if let Some(s_tx) = &maybe_s_tx {
                         // It is not an error if we are sending and nobody is listening.
                         _ = s_tx.send(Arc::new(streaming_data.clone()));
                     }
 
-                    ss
+                    (ss, now)
                 }
-                Some(state) = maybe_update_charge(&ss) => {
+                Some((state, now)) = maybe_update_charge(clock, &ss) => {
                     debug!("Car {:?} is charging", data.id);
-                    let (charge_state, ss) = get_updated_charge_state(&data, &ss, state);
+                    let (charge_state, battery_level, ss) = get_updated_charge_state(clock, now, &policy, &data, &ss, state);
                     data.charge_state = charge_state;
-                    ss
+                    data.battery_level = battery_level;
+                    (ss, now)
+                }
+                Some(now) = maybe_fall_asleep(clock, &ss) => {
+                    debug!("Car {:?} is falling asleep", data.id);
+                    (SimulationState::fall_asleep(now, &policy), now)
                 }
-                Some(()) = maybe_sleep(&ss) => {
+                Some(now) = maybe_sleep(clock, &ss) => {
                     debug!("Car {:?} is going to sleep", data.id);
-                    SimulationState::sleeping()
+                    (SimulationState::sleeping(), now)
                 }
-                Some(()) = maybe_wake_up(&ss) => {
+                Some(now) = maybe_wake_up(clock, &ss) => {
                     debug!("Car {:?} is waking up", data.id);
-                    SimulationState::idle(Instant::now())
+                    (SimulationState::idle(now, &policy), now)
+                }
+                now = next_parked_update(clock, parked_time) => {
+                    let elapsed = now.duration_since(parked_time);
+                    update_parked_state(clock, now, elapsed, &parked_policy, &ss, &mut data);
+                    parked_time = now;
+                    (ss, now)
+                }
+                Some(now) = maybe_at(clock, telemetry_due) => {
+                    if let Some(emitter) = &mut telemetry {
+                        emitter.emit(&data, clock.utc_at(now), now);
+                    }
+                    (ss, now)
+                }
+                Some(now) = maybe_at(clock, stream_time) => {
+                    if let Some(s_tx) = &maybe_s_tx {
+                        _ = s_tx.send(Arc::new(parked_streaming_data(clock, now, &data)));
+                    }
+                    stream_time = Some(now + STREAMING_INTERVAL);
+                    (ss, now)
                 }
                 cmd = c_rx.recv() => {
-                    match cmd {
+                    let now = clock.now();
+                    let ss = match cmd {
                         Some(Command::WakeUp(tx)) => {
                             debug!("Received wake request for car {:?}", data.id);
-                            if ss.is_asleep() {
+                            let delivery = faults.delivery(&mut rng);
+                            if !delivery.reaches_vehicle() {
+                                debug!("Wake request for car {:?} failed by fault", data.id);
+                                delivery.send(tx, Err(ResponseError::DeviceNotAvailable));
+                                ss
+                            } else if let SimulationState::Drowsy { .. } = ss {
+                                debug!("Car {:?} is drowsy, staying awake", data.id);
+                                delivery.send(tx, Ok(()));
+                                ss.wake_up(now, &policy)
+                            } else if ss.is_asleep() {
                                 debug!("Car {:?} is asleep, waking up", data.id);
-                                _= Err(ResponseError::DeviceNotAvailable).pipe(|x| tx.send(x));
-                                ss.wake_up(Instant::now())
+                                delivery.send(tx, Err(ResponseError::DeviceNotAvailable));
+                                ss.wake_up(now, &policy)
                             } else {
                                 debug!("Car {:?} is awake", data.id);
-                                _ = Ok(()).pipe(|x| tx.send(x));
-                                ss.update_sleep_time(Instant::now())
+                                delivery.send(tx, Ok(()));
+                                ss.update_sleep_time(now, &policy)
                             }
                         }
                         Some(Command::GetVehicleData(tx)) => {
                             debug!("Received get vehicle data for car {:?}", data.id);
-                            if ss.is_asleep() {
-                                _= Err(ResponseError::DeviceNotAvailable).pipe(|x| tx.send(x));
+                            let delivery = faults.delivery(&mut rng);
+                            if !delivery.reaches_vehicle() {
+                                debug!("Get vehicle data for car {:?} failed by fault", data.id);
+                                delivery.send(tx, Err(ResponseError::DeviceNotAvailable));
+                                ss
+                            } else if ss.is_asleep() {
+                                delivery.send(tx, Err(ResponseError::DeviceNotAvailable));
                                 ss
                             } else {
                                 let response = (&data).into();
-                                _ = Ok(response).pipe(|x| tx.send(x));
-                                ss.update_sleep_time(Instant::now())
+                                delivery.send(tx, Ok(response));
+                                if policy.polling_keeps_awake {
+                                    ss.update_sleep_time(now, &policy)
+                                } else {
+                                    ss
+                                }
                             }
                         }
                         Some(Command::Subscribe(tx)) => {
                             debug!("Received subscribe request for car {:?}", data.id);
                             if ss.is_asleep() {
-                                _ = Err(DataError::offline(data.vehicle_id)).pipe(|x| tx.send(x));
-                            } else if let Some(s_tx) = &maybe_s_tx {
-                                _ = s_tx.subscribe().pipe(Ok).pipe(|x| tx.send(x));
+                                _ = Err(DataError::disconnected(data.vehicle_id)).pipe(|x| tx.send(x));
                             } else {
-                                let (s_tx, s_rx) = broadcast::channel(1);
-                                _ = s_rx.pipe(Ok).pipe(|x| tx.send(x));
-                                maybe_s_tx = Some(s_tx);
+                                let s_tx = maybe_s_tx.get_or_insert_with(|| broadcast::channel(STREAMING_CAPACITY).0);
+                                let initial = if ss.is_driving() {
+                                    StreamingData::from(&data)
+                                } else {
+                                    parked_streaming_data(clock, now, &data)
+                                };
+                                _ = tx.send(Ok((s_tx.subscribe(), Some(Arc::new(initial)))));
                             }
                             ss
                         }
                         Some(Command::Simulate(sse, tx)) => {
                             debug!("Received simulate request for car {:?} {ss:?}", data.id);
-                            let now = Instant::now();
                             _ = Ok(()).pipe(|x| tx.send(x));
-
-                            match sse {
-                                SimulationStateEnum::Driving => {
-                                    ss.drive(&data, now)
-                                }
-                                SimulationStateEnum::Charging  => {
-                                    ss.charge(&data, now)
-                                }
-                                SimulationStateEnum::Idle => {
-                                    SimulationState::idle(now)
-                                }
-                                SimulationStateEnum::IdleNoSleep => SimulationState::IdleNoSleep,
-                                SimulationStateEnum::Sleeping => {
-                                    SimulationState::sleeping()
+                            simulate(sse, ss, &data, now, &policy)
+                        }
+                        Some(Command::Steer(heading, tx)) => {
+                            debug!("Received steer request for car {:?} {heading}", data.id);
+                            data.drive_state.heading = heading;
+                            _ = tx.send(());
+                            match ss {
+                                SimulationState::Driving { mut state, update_time } => {
+                                    state.heading = heading;
+                                    SimulationState::Driving { state, update_time }
                                 }
+                                ss => ss,
                             }
                         }
+                        Some(Command::GetSleepPolicy(tx)) => {
+                            _ = tx.send(policy);
+                            ss
+                        }
+                        Some(Command::SetSleepPolicy(new_policy, tx)) => {
+                            debug!("Received sleep policy for car {:?}: {new_policy:?}", data.id);
+                            policy = new_policy;
+                            _ = tx.send(());
+                            ss.update_sleep_time(now, &policy)
+                        }
+                        Some(Command::GetParkedPolicy(tx)) => {
+                            _ = tx.send(parked_policy);
+                            ss
+                        }
+                        Some(Command::SetParkedPolicy(new_policy, tx)) => {
+                            debug!("Received parked policy for car {:?}: {new_policy:?}", data.id);
+                            parked_policy = new_policy;
+                            _ = tx.send(());
+                            ss
+                        }
+                        Some(Command::GetFaults(tx)) => {
+                            _ = tx.send(faults.list());
+                            ss
+                        }
+                        Some(Command::AddFault(fault, tx)) => {
+                            debug!("Adding fault for car {:?}: {fault:?}", data.id);
+                            faults.add(fault);
+                            _ = tx.send(());
+                            ss
+                        }
+                        Some(Command::ClearFaults(tx)) => {
+                            debug!("Clearing faults for car {:?}", data.id);
+                            faults.clear();
+                            _ = tx.send(());
+                            ss
+                        }
+                        Some(Command::BodyFault(tx)) => {
+                            _ = tx.send(faults.body_fault(&mut rng));
+                            ss
+                        }
                         Some(Command::WatchState(tx)) => {
                             debug!("Received watch state request for car {:?}", data.id);
                             _ = s_tx.subscribe().pipe(|x| tx.send(x));
                             ss
                         }
+                        Some(Command::Advance(duration, tx)) => {
+                            debug!("Received advance request for car {:?}", data.id);
+                            let result = clock
+                                .advance(duration)
+                                .map(|_| ())
+                                .map_err(|err| {
+                                    debug!("Cannot advance clock: {err}");
+                                    ResponseError::InvalidCommand
+                                });
+                            _ = tx.send(result);
+                            ss
+                        }
+                        Some(Command::Sync(tx)) => {
+                            _ = tx.send(());
+                            ss
+                        }
+                        Some(Command::GetStatus(tx)) => {
+                            let status = VehicleStatus {
+                                vehicle: vehicle.clone(),
+                                simulation_state: (&ss).into(),
+                                battery_level: data.battery_level,
+                                latitude: data.drive_state.latitude.unwrap_or_default(),
+                                longitude: data.drive_state.longitude.unwrap_or_default(),
+                                heading: data.drive_state.heading,
+                                sleep_policy: policy,
+                                parked_policy,
+                                faults: faults.list(),
+                                streaming_connections: maybe_s_tx
+                                    .as_ref()
+                                    .map_or(0, broadcast::Sender::receiver_count),
+                            };
+                            _ = tx.send(status);
+                            ss
+                        }
+                        Some(Command::Snapshot(tx)) => {
+                            let snapshot = VehicleSnapshot {
+                                vehicle: vehicle.clone(),
+                                data: data.clone(),
+                                state: StateSnapshot::new(&ss, now),
+                                sleep_policy: policy,
+                                parked_policy,
+                                faults: faults.list(),
+                            };
+                            _ = tx.send(snapshot);
+                            ss
+                        }
+                        Some(Command::Restore(snapshot, tx)) => {
+                            debug!("Restoring car {:?} from snapshot", data.id);
+                            let snapshot = *snapshot;
+                            data = snapshot.data;
+                            policy = snapshot.sleep_policy;
+                            parked_policy = snapshot.parked_policy;
+                            faults = snapshot.faults.into();
+                            maybe_s_tx = None;
+                            parked_time = now;
+                            _ = tx.send(());
+                            snapshot.state.restore(now)
+                        }
+                        Some(Command::Reset(tx)) => {
+                            debug!("Resetting car {:?}", data.id);
+                            rng = StdRng::seed_from_u64(context.vehicle_seed(&vehicle.vin));
+                            policy = SleepPolicy::default();
+                            parked_policy = ParkedPolicy::default();
+                            faults = Faults::default();
+                            telemetry = None;
+                            maybe_s_tx = None;
+                            parked_time = now;
+                            let ss;
+                            (data, ss) = initial_state(&vehicle, clock, now, &policy, &parked_policy);
+                            _ = tx.send(());
+                            ss
+                        }
+                        Some(Command::GetTelemetryConfig(tx)) => {
+                            _ = tx.send(telemetry.as_ref().map(|emitter| emitter.config().clone()));
+                            ss
+                        }
+                        Some(Command::SetTelemetryConfig(config, tx)) => {
+                            debug!("Received telemetry config for car {:?}: {config:?}", data.id);
+                            telemetry = config.map(|(config, output)| Emitter::new(config, output, now));
+                            _ = tx.send(());
+                            ss
+                        }
                         None => {
                             debug!("Command channel closed, exiting simulator");
                             break;
                         }
-                    }
+                    };
+                    (ss, now)
                 }
             };
 
@@ -453,6 +663,13 @@ pub fn start(vehicle: VehicleDefinition) -> CommandSender {
 
             data.state = new_sse.into();
 
+            // Publish the state before the next command is processed, so that anybody who
+            // has synchronised with the simulator sees it.
+            if definition.read().await.state != data.state {
+                debug!("Vehicle {:?} is now {:?}", data.id, data.state);
+                definition.write().await.state = data.state.clone();
+            }
+
             Into::<SimulationStateEnum>::into(&new_ss)
                 .pipe(|x| s_tx.send(x))
                 .ok();
@@ -463,20 +680,22 @@ pub fn start(vehicle: VehicleDefinition) -> CommandSender {
             }
 
             if old_sse != new_sse {
+                let timestamp = clock.utc_at(now).timestamp_millis();
                 match (old_sse, new_sse) {
                     (SimulationStateEnum::Driving, _) => {
                         data.drive_state.speed = None;
                         data.drive_state.shift_state = None;
                         data.drive_state.power = None;
-                        data.drive_state.timestamp = Utc::now().timestamp_millis();
+                        data.drive_state.timestamp = timestamp;
                     }
                     (SimulationStateEnum::Charging, _) => {
                         data.charge_state.charging_state = ChargingStateEnum::Disconnected;
                         data.charge_state.charge_amps = 0;
-                        data.charge_state.timestamp = Utc::now().timestamp_millis();
+                        data.charge_state.timestamp = timestamp;
                     }
                     (SimulationStateEnum::Idle, _) => {}
                     (SimulationStateEnum::IdleNoSleep, _) => {}
+                    (SimulationStateEnum::Drowsy, _) => {}
                     (SimulationStateEnum::Sleeping, _) => {}
                 }
                 debug!(
@@ -485,6 +704,22 @@ pub fn start(vehicle: VehicleDefinition) -> CommandSender {
                 );
             }
 
+            // A car that is awake but not driving streams its parked state, driving rows are sent
+            // by the drive updates.
+            let parked_streaming = !new_ss.is_asleep()
+                && !new_ss.is_driving()
+                && maybe_s_tx
+                    .as_ref()
+                    .is_some_and(|s_tx| s_tx.receiver_count() > 0);
+            stream_time = parked_streaming.then(|| stream_time.unwrap_or(now + STREAMING_INTERVAL));
+
+            // Every field is due again once the vehicle wakes up.
+            if was_asleep && !new_ss.is_asleep() {
+                if let Some(emitter) = &mut telemetry {
+                    emitter.restart(now);
+                }
+            }
+
             ss = new_ss;
         }
     });
@@ -492,52 +727,213 @@ pub fn start(vehicle: VehicleDefinition) -> CommandSender {
     CommandSender(c_tx, vehicle_id)
 }
 
-async fn maybe_update_drive(ss: &SimulationState) -> Option<&SimulationDriveState> {
+/// The state of a vehicle when it is created
+fn initial_state(
+    vehicle: &FleetVehicle,
+    clock: &Clock,
+    now: Instant,
+    policy: &SleepPolicy,
+    parked_policy: &ParkedPolicy,
+) -> (VehicleDataState, SimulationState) {
+    let mut data = get_vehicle_data(vehicle, clock.utc_at(now));
+    let ss = simulate(
+        vehicle.state,
+        SimulationState::idle(now, policy),
+        &data,
+        now,
+        policy,
+    );
+    update_parked_state(clock, now, Duration::ZERO, parked_policy, &ss, &mut data);
+    (data, ss)
+}
+
+/// Put the vehicle into a new simulation state
+fn simulate(
+    sse: SimulationStateEnum,
+    ss: SimulationState,
+    data: &VehicleDataState,
+    now: Instant,
+    policy: &SleepPolicy,
+) -> SimulationState {
+    match sse {
+        SimulationStateEnum::Driving => ss.drive(data, now),
+        SimulationStateEnum::Charging => ss.charge(data, now),
+        SimulationStateEnum::Idle => SimulationState::idle(now, policy),
+        SimulationStateEnum::IdleNoSleep => SimulationState::IdleNoSleep,
+        SimulationStateEnum::Drowsy => SimulationState::Drowsy {
+            sleep_time: now + policy.drowsy_period(),
+        },
+        SimulationStateEnum::Sleeping => SimulationState::sleeping(),
+    }
+}
+
+async fn maybe_update_drive<'a>(
+    clock: &Clock,
+    ss: &'a SimulationState,
+) -> Option<(&'a SimulationDriveState, Instant)> {
     if let SimulationState::Driving { update_time, state } = ss {
-        sleep_until(*update_time).await;
-        Some(state)
+        clock.sleep_until(*update_time).await;
+        Some((state, *update_time))
     } else {
         None
     }
 }
 
-async fn maybe_update_charge(ss: &SimulationState) -> Option<&SimulationChargeState> {
+async fn maybe_update_charge<'a>(
+    clock: &Clock,
+    ss: &'a SimulationState,
+) -> Option<(&'a SimulationChargeState, Instant)> {
     if let SimulationState::Charging { update_time, state } = ss {
-        sleep_until(*update_time).await;
-        Some(state)
+        clock.sleep_until(*update_time).await;
+        Some((state, *update_time))
     } else {
         None
     }
 }
 
-async fn maybe_sleep(ss: &SimulationState) -> Option<()> {
+async fn maybe_fall_asleep(clock: &Clock, ss: &SimulationState) -> Option<Instant> {
     if let SimulationState::Idle { sleep_time } = ss {
-        sleep_until(*sleep_time).await;
-        Some(())
+        clock.sleep_until(*sleep_time).await;
+        Some(*sleep_time)
     } else {
         None
     }
 }
 
-async fn maybe_wake_up(ss: &SimulationState) -> Option<()> {
+async fn maybe_sleep(clock: &Clock, ss: &SimulationState) -> Option<Instant> {
+    if let SimulationState::Drowsy { sleep_time } = ss {
+        clock.sleep_until(*sleep_time).await;
+        Some(*sleep_time)
+    } else {
+        None
+    }
+}
+
+async fn maybe_wake_up(clock: &Clock, ss: &SimulationState) -> Option<Instant> {
     if let SimulationState::Sleeping {
         wake_up_time: Some(wake_up_time),
     } = ss
     {
-        sleep_until(*wake_up_time).await;
-        Some(())
+        clock.sleep_until(*wake_up_time).await;
+        Some(*wake_up_time)
     } else {
         None
     }
 }
 
+async fn maybe_at(clock: &Clock, time: Option<Instant>) -> Option<Instant> {
+    let time = time?;
+    clock.sleep_until(time).await;
+    Some(time)
+}
+
+/// How often a car that is awake but not driving sends streaming data
+const STREAMING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many rows a slow streaming subscription can fall behind before it skips to the newest
+const STREAMING_CAPACITY: usize = 16;
+
+/// Streaming data for a car that is not driving, the drive state timestamp is not updated
+fn parked_streaming_data(clock: &Clock, now: Instant, data: &VehicleDataState) -> StreamingData {
+    let mut streaming_data = StreamingData::from(data);
+    streaming_data.time = clock.utc_at(now).timestamp_millis();
+    streaming_data
+}
+
+/// How often the state of a parked car is updated
+const PARKED_INTERVAL: Duration = Duration::from_secs(60);
+
+async fn next_parked_update(clock: &Clock, parked_time: Instant) -> Instant {
+    let update_time = parked_time + PARKED_INTERVAL;
+    clock.sleep_until(update_time).await;
+    update_time
+}
+
+/// Update battery drain, temperatures and tyre pressures
+///
+/// The weather changes whatever the car is doing, but the battery only drains like this when
+/// the car is parked.
+fn update_parked_state(
+    clock: &Clock,
+    now: Instant,
+    elapsed: Duration,
+    policy: &ParkedPolicy,
+    ss: &SimulationState,
+    data: &mut VehicleDataState,
+) {
+    let utc_now = clock.utc_at(now);
+    let timestamp = utc_now.timestamp_millis();
+    let hours = elapsed.as_secs_f64() / 3600.0;
+
+    let drain_per_hour = match ss {
+        SimulationState::Driving { .. } | SimulationState::Charging { .. } => None,
+        SimulationState::Idle { .. } | SimulationState::IdleNoSleep => {
+            Some(policy.awake_drain_per_hour)
+        }
+        SimulationState::Drowsy { .. } | SimulationState::Sleeping { .. } => {
+            Some(policy.asleep_drain_per_hour)
+        }
+    };
+
+    if let Some(drain_per_hour) = drain_per_hour {
+        data.set_battery_level(data.battery_level - drain_per_hour * hours);
+        data.charge_state.timestamp = timestamp;
+    }
+
+    let local_time = utc_now + chrono::Duration::seconds(data.vehicle_config.utc_offset);
+    let hour = f64::from(local_time.num_seconds_from_midnight()) / 3600.0;
+    let outside_temp = environment::outside_temp(hour, policy);
+    let inside_temp = environment::inside_temp(hour, outside_temp);
+
+    let climate_state = &mut data.climate_state;
+    climate_state.outside_temp = outside_temp as f32;
+    climate_state.inside_temp = inside_temp as f32;
+    climate_state.timestamp = timestamp;
+
+    let days = hours / 24.0;
+    let cold = &mut data.tyre_pressures;
+    let leak = &policy.tyre_leak_per_day;
+    cold.fl = (cold.fl - leak.fl * days).max(0.0);
+    cold.fr = (cold.fr - leak.fr * days).max(0.0);
+    cold.rl = (cold.rl - leak.rl * days).max(0.0);
+    cold.rr = (cold.rr - leak.rr * days).max(0.0);
+
+    let vs = &mut data.vehicle_state;
+    let front = f64::from(vs.tpms_rcp_front_value);
+    let rear = f64::from(vs.tpms_rcp_rear_value);
+
+    let fl = environment::tyre_pressure(cold.fl, outside_temp);
+    (vs.tpms_soft_warning_fl, vs.tpms_hard_warning_fl) = environment::tyre_warnings(fl, front);
+    vs.tpms_pressure_fl = fl as f32;
+    vs.tpms_last_seen_pressure_time_fl = Some(timestamp);
+
+    let fr = environment::tyre_pressure(cold.fr, outside_temp);
+    (vs.tpms_soft_warning_fr, vs.tpms_hard_warning_fr) = environment::tyre_warnings(fr, front);
+    vs.tpms_pressure_fr = fr as f32;
+    vs.tpms_last_seen_pressure_time_fr = Some(timestamp);
+
+    let rl = environment::tyre_pressure(cold.rl, outside_temp);
+    (vs.tpms_soft_warning_rl, vs.tpms_hard_warning_rl) = environment::tyre_warnings(rl, rear);
+    vs.tpms_pressure_rl = rl as f32;
+    vs.tpms_last_seen_pressure_time_rl = Some(timestamp);
+
+    let rr = environment::tyre_pressure(cold.rr, outside_temp);
+    (vs.tpms_soft_warning_rr, vs.tpms_hard_warning_rr) = environment::tyre_warnings(rr, rear);
+    vs.tpms_pressure_rr = rr as f32;
+    vs.tpms_last_seen_pressure_time_rr = Some(timestamp);
+
+    vs.timestamp = timestamp;
+}
+
 fn get_updated_drive_state(
+    clock: &Clock,
+    now: Instant,
+    policy: &SleepPolicy,
     data: &VehicleDataState,
-    ss: &SimulationState,
     state: &SimulationDriveState,
-) -> (DriveState, u32, ChargeState, SimulationState) {
-    let now = Utc::now();
-    let duration = Instant::now().duration_since(state.time).as_secs_f64();
+) -> (DriveState, u32, ChargeState, f64, SimulationState) {
+    let utc_now = clock.utc_at(now);
+    let duration = now.duration_since(state.time).as_secs_f64();
     let heading = f64::from(state.heading);
     let speed = f64::from(state.speed);
 
@@ -551,9 +947,9 @@ fn get_updated_drive_state(
     point.y += distance * heading.to_radians().cos();
     let (longitude, latitude) = proj.unproject(&point);
 
-    let battery_level = f64::from(state.battery_level) - distance;
-    let finished_driving = battery_level <= 0.0;
-    let battery_level = battery_level.min(100.0).max(0.0) as u8;
+    let precise_battery_level = state.battery_level - distance * data.trim().percent_per_km();
+    let finished_driving = precise_battery_level <= 0.0;
+    let battery_level = precise_battery_level.clamp(0.0, 100.0) as u8;
 
     debug!("driving, latitude: {latitude:?}, longitude: {longitude:?}, distance: {distance}, battery: {battery_level}, finished driving: {finished_driving}");
 
@@ -561,8 +957,8 @@ fn get_updated_drive_state(
         active_route_latitude: latitude,
         active_route_longitude: longitude,
         active_route_traffic_minutes_delay: 0.0,
-        gps_as_of: now.timestamp_millis(),
-        heading: 0,
+        gps_as_of: utc_now.timestamp_millis(),
+        heading: state.heading,
         latitude: Some(latitude),
         longitude: Some(longitude),
         native_latitude: None,
@@ -572,12 +968,12 @@ fn get_updated_drive_state(
         power: Some(500),
         shift_state: Some(ShiftState::Drive),
         speed: Some(state.speed),
-        timestamp: now.timestamp_millis(),
+        timestamp: utc_now.timestamp_millis(),
     };
 
     let mut charge_state = data.charge_state.clone();
     charge_state.battery_level = battery_level;
-    charge_state.battery_range = f32::from(charge_state.battery_level * 2);
+    charge_state.battery_range = data.range(battery_level);
     charge_state.ideal_battery_range = charge_state.battery_range;
     charge_state.est_battery_range = charge_state.battery_range;
 
@@ -587,27 +983,42 @@ fn get_updated_drive_state(
         drive_state,
         elevation,
         charge_state,
+        precise_battery_level.clamp(0.0, 100.0),
         if finished_driving {
-            SimulationState::idle(Instant::now())
+            SimulationState::idle(now, policy)
         } else {
-            ss.clone().drive(data, Instant::now())
+            SimulationState::Driving {
+                state: SimulationDriveState {
+                    time: now,
+                    latitude,
+                    longitude,
+                    heading: state.heading,
+                    speed: state.speed,
+                    battery_level: precise_battery_level,
+                },
+                update_time: now + Duration::from_secs(1),
+            }
         },
     )
 }
 
 fn get_updated_charge_state(
+    clock: &Clock,
+    now: Instant,
+    policy: &SleepPolicy,
     data: &VehicleDataState,
     ss: &SimulationState,
     state: &SimulationChargeState,
-) -> (ChargeState, SimulationState) {
-    let now = Utc::now();
-    let duration = Instant::now().duration_since(state.time).as_secs_f64();
+) -> (ChargeState, f64, SimulationState) {
+    let utc_now = clock.utc_at(now);
+    let duration = now.duration_since(state.time).as_secs_f64();
 
     // Charges at 10% per minute or 20 miles per minute.
-    let battery_level = f64::from(state.battery_level) + duration / 60.0 * 10.0;
-    let finished_charging = battery_level >= 100.0;
+    let precise_battery_level = state.battery_level + duration / 60.0 * 10.0;
+    let finished_charging = precise_battery_level >= 100.0;
 
-    let battery_level = battery_level.min(100.0).max(0.0) as u8;
+    let precise_battery_level = precise_battery_level.clamp(0.0, 100.0);
+    let battery_level = precise_battery_level as u8;
 
     let time_to_full_charge = if finished_charging {
         None
@@ -615,7 +1026,7 @@ fn get_updated_charge_state(
         Some((100.0 - f64::from(battery_level)) / 10.0 / 60.0)
     };
 
-    let range = f32::from(battery_level * 2);
+    let range = data.range(battery_level);
     debug!(
         "charging, battery level: {battery_level}, time to full charge: {:?}, finished charging: {finished_charging}",
         time_to_full_charge.map(|x| x * 60.0)
@@ -630,7 +1041,7 @@ fn get_updated_charge_state(
         charge_current_request_max: 48,
         charge_enable_request: true,
         charge_energy_added: 48.45,
-        charge_limit_soc: 0,
+        charge_limit_soc: data.charge_state.charge_limit_soc,
         charge_limit_soc_max: 100,
         charge_limit_soc_min: 50,
         charge_limit_soc_std: 90,
@@ -671,15 +1082,23 @@ fn get_updated_charge_state(
         scheduled_departure_time_minutes: 480,
         supercharger_session_trip_planner: false,
         time_to_full_charge,
-        timestamp: now.timestamp_millis(),
+        timestamp: utc_now.timestamp_millis(),
         trip_charging: false,
-        usable_battery_level: 42,
+        usable_battery_level: i64::from(battery_level),
         user_charge_enable_request: None,
     };
 
     if finished_charging {
-        (charge_state, SimulationState::idle(Instant::now()))
+        (
+            charge_state,
+            precise_battery_level,
+            SimulationState::idle(now, policy),
+        )
     } else {
-        (charge_state, ss.clone().charge(data, Instant::now()))
+        (
+            charge_state,
+            precise_battery_level,
+            ss.clone().charge(data, now),
+        )
     }
 }
@@ -0,0 +1,466 @@
+//! Snapshots of the whole simulated fleet
+//!
+//! A snapshot holds everything a vehicle simulator knows, so it can be written to a file and
+//! restored later, even by another server. Timers are stored relative to the time of the
+//! snapshot, so a restored vehicle carries on where it left off.
+
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use fla_common::{
+    simulator::{Fault, Fleet, FleetVehicle, ParkedPolicy, SleepPolicy, SnapshotInfo},
+    types::Timestamp,
+};
+use futures::future::try_join_all;
+use serde::{Deserialize, Serialize};
+use tap::Pipe;
+use thiserror::Error;
+use tokio::time::Instant;
+
+use crate::{
+    errors::ResponseError,
+    types::{Users, Vehicle, Vehicles},
+};
+
+use super::{
+    fleet,
+    types::{SimulationChargeState, SimulationDriveState, SimulationState, VehicleDataState},
+    Context,
+};
+
+/// The state of every simulated vehicle at one point in time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Snapshot {
+    /// Simulated time the snapshot was taken, Unix timestamp in milliseconds
+    pub time: Timestamp,
+
+    /// Every vehicle
+    vehicles: Vec<VehicleSnapshot>,
+}
+
+/// The state of one vehicle simulator
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VehicleSnapshot {
+    pub(crate) vehicle: FleetVehicle,
+    pub(crate) data: VehicleDataState,
+    pub(crate) state: StateSnapshot,
+    pub(crate) sleep_policy: SleepPolicy,
+    pub(crate) parked_policy: ParkedPolicy,
+    pub(crate) faults: Vec<Fault>,
+}
+
+/// A `SimulationState`, with times in seconds relative to the snapshot
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "state", rename_all = "snake_case")]
+pub(crate) enum StateSnapshot {
+    Driving {
+        started: f64,
+        latitude: f64,
+        longitude: f64,
+        heading: u16,
+        speed: f32,
+        battery_level: f64,
+        update: f64,
+    },
+    Charging {
+        started: f64,
+        battery_level: f64,
+        update: f64,
+    },
+    Idle {
+        sleep: f64,
+    },
+    IdleNoSleep,
+    Drowsy {
+        sleep: f64,
+    },
+    Sleeping {
+        wake_up: Option<f64>,
+    },
+}
+
+fn offset(now: Instant, time: Instant) -> f64 {
+    if time >= now {
+        time.duration_since(now).as_secs_f64()
+    } else {
+        -now.duration_since(time).as_secs_f64()
+    }
+}
+
+fn at(now: Instant, offset: f64) -> Instant {
+    let duration = Duration::try_from_secs_f64(offset.abs()).unwrap_or_default();
+    if offset >= 0.0 {
+        now + duration
+    } else {
+        now.checked_sub(duration).unwrap_or(now)
+    }
+}
+
+impl StateSnapshot {
+    /// Take a snapshot of a simulation state
+    pub fn new(ss: &SimulationState, now: Instant) -> Self {
+        match ss {
+            SimulationState::Driving { state, update_time } => Self::Driving {
+                started: offset(now, state.time),
+                latitude: state.latitude,
+                longitude: state.longitude,
+                heading: state.heading,
+                speed: state.speed,
+                battery_level: state.battery_level,
+                update: offset(now, *update_time),
+            },
+            SimulationState::Charging { state, update_time } => Self::Charging {
+                started: offset(now, state.time),
+                battery_level: state.battery_level,
+                update: offset(now, *update_time),
+            },
+            SimulationState::Idle { sleep_time } => Self::Idle {
+                sleep: offset(now, *sleep_time),
+            },
+            SimulationState::IdleNoSleep => Self::IdleNoSleep,
+            SimulationState::Drowsy { sleep_time } => Self::Drowsy {
+                sleep: offset(now, *sleep_time),
+            },
+            SimulationState::Sleeping { wake_up_time } => Self::Sleeping {
+                wake_up: wake_up_time.map(|time| offset(now, time)),
+            },
+        }
+    }
+
+    /// Turn the snapshot back into a simulation state
+    pub fn restore(&self, now: Instant) -> SimulationState {
+        match *self {
+            Self::Driving {
+                started,
+                latitude,
+                longitude,
+                heading,
+                speed,
+                battery_level,
+                update,
+            } => SimulationState::Driving {
+                state: SimulationDriveState {
+                    time: at(now, started),
+                    latitude,
+                    longitude,
+                    heading,
+                    speed,
+                    battery_level,
+                },
+                update_time: at(now, update),
+            },
+            Self::Charging {
+                started,
+                battery_level,
+                update,
+            } => SimulationState::Charging {
+                state: SimulationChargeState {
+                    time: at(now, started),
+                    battery_level,
+                },
+                update_time: at(now, update),
+            },
+            Self::Idle { sleep } => SimulationState::Idle {
+                sleep_time: at(now, sleep),
+            },
+            Self::IdleNoSleep => SimulationState::IdleNoSleep,
+            Self::Drowsy { sleep } => SimulationState::Drowsy {
+                sleep_time: at(now, sleep),
+            },
+            Self::Sleeping { wake_up } => SimulationState::Sleeping {
+                wake_up_time: wake_up.map(|offset| at(now, offset)),
+            },
+        }
+    }
+}
+
+impl Snapshot {
+    /// Take a snapshot of every vehicle
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a vehicle simulator does not respond.
+    pub async fn take(vehicles: &Vehicles, context: &Context) -> Result<Self, ResponseError> {
+        let time = context.clock.utc_now().timestamp_millis();
+        let vehicles = vehicles
+            .list()
+            .iter()
+            .map(|v| async {
+                // Keep drivers added by invitations since the vehicle was created.
+                let mut snapshot = v.command.snapshot().await?;
+                snapshot.vehicle.drivers = v.access.borrow().drivers.clone();
+                Ok::<_, ResponseError>(snapshot)
+            })
+            .pipe(try_join_all)
+            .await?;
+
+        Ok(Self { time, vehicles })
+    }
+
+    /// Replace every vehicle with the vehicles in the snapshot
+    ///
+    /// Vehicles that are not in the snapshot are removed, and all streaming connections are
+    /// closed.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vehicles in the snapshot are invalid, or if a vehicle simulator
+    /// does not respond.
+    pub async fn restore(
+        &self,
+        vehicles: &Vehicles,
+        users: &Users,
+        context: &Arc<Context>,
+    ) -> Result<(), ResponseError> {
+        let fleet = Fleet {
+            users: users.list().to_vec(),
+            vehicles: self.vehicles.iter().map(|v| v.vehicle.clone()).collect(),
+        };
+        fleet::validate(&fleet).map_err(|err| ResponseError::InvalidValue(err.to_string()))?;
+
+        let restored = self
+            .vehicles
+            .iter()
+            .map(|snapshot| async {
+                let vehicle = Vehicle::new(snapshot.vehicle.clone(), context.clone());
+                vehicle.command.restore(snapshot.clone()).await?;
+                Ok::<_, ResponseError>(vehicle)
+            })
+            .pipe(try_join_all)
+            .await?;
+
+        vehicles.replace(restored);
+        Ok(())
+    }
+
+    /// Summary of the snapshot
+    #[must_use]
+    pub fn info(&self, name: &str) -> SnapshotInfo {
+        SnapshotInfo {
+            name: name.to_string(),
+            time: self.time,
+            vehicles: self.vehicles.len(),
+        }
+    }
+
+    /// Read a snapshot from a JSON file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or is not a snapshot.
+    pub fn load(path: &Path) -> Result<Self, SnapshotError> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&text)?)
+    }
+
+    /// Write the snapshot to a JSON file
+    ///
+    /// The file is replaced in one step, so a crash never leaves half a snapshot behind.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be written.
+    pub fn save(&self, path: &Path) -> Result<(), SnapshotError> {
+        let text = serde_json::to_string_pretty(self)?;
+        let tmp = path.with_extension("json.tmp");
+        std::fs::write(&tmp, text)?;
+        std::fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+/// An error storing or loading a snapshot
+#[derive(Error, Debug)]
+pub enum SnapshotError {
+    /// Snapshot names may only contain letters, digits, `-` and `_`
+    #[error("Invalid snapshot name {0:?}")]
+    InvalidName(String),
+
+    /// There is no snapshot with this name
+    #[error("No snapshot named {0:?}")]
+    NotFound(String),
+
+    /// The snapshot could not be read or written
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+
+    /// The snapshot is not valid JSON
+    #[error("{0}")]
+    Json(#[from] serde_json::Error),
+}
+
+impl From<SnapshotError> for ResponseError {
+    fn from(err: SnapshotError) -> Self {
+        match err {
+            SnapshotError::InvalidName(_) => Self::InvalidValue(err.to_string()),
+            SnapshotError::NotFound(_) => Self::NotFound,
+            SnapshotError::Io(_) | SnapshotError::Json(_) => Self::internal_error(err.to_string()),
+        }
+    }
+}
+
+/// Named snapshots, kept as files in a directory or otherwise in memory
+#[derive(Debug, Default)]
+pub struct Snapshots {
+    dir: Option<PathBuf>,
+    memory: Mutex<HashMap<String, Snapshot>>,
+}
+
+impl Snapshots {
+    /// Keep snapshots as `<name>.json` files in a directory
+    #[must_use]
+    pub fn in_dir(dir: PathBuf) -> Self {
+        Self {
+            dir: Some(dir),
+            memory: Mutex::default(),
+        }
+    }
+
+    fn path(&self, name: &str) -> Result<Option<PathBuf>, SnapshotError> {
+        let valid = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+        if !valid {
+            return Err(SnapshotError::InvalidName(name.to_string()));
+        }
+
+        Ok(self
+            .dir
+            .as_ref()
+            .map(|dir| dir.join(format!("{name}.json"))))
+    }
+
+    /// Store a snapshot, replacing any snapshot with the same name
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the name is invalid or the snapshot cannot be written.
+    pub fn save(&self, name: &str, snapshot: Snapshot) -> Result<(), SnapshotError> {
+        match self.path(name)? {
+            Some(path) => snapshot.save(&path),
+            None => {
+                self.memory
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .insert(name.to_string(), snapshot);
+                Ok(())
+            }
+        }
+    }
+
+    /// Get a snapshot by name
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the snapshot does not exist or cannot be read.
+    pub fn load(&self, name: &str) -> Result<Snapshot, SnapshotError> {
+        match self.path(name)? {
+            Some(path) if !path.exists() => Err(SnapshotError::NotFound(name.to_string())),
+            Some(path) => Snapshot::load(&path),
+            None => self
+                .memory
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(name)
+                .cloned()
+                .ok_or_else(|| SnapshotError::NotFound(name.to_string())),
+        }
+    }
+
+    /// Remove a snapshot
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the snapshot does not exist or cannot be removed.
+    pub fn delete(&self, name: &str) -> Result<(), SnapshotError> {
+        match self.path(name)? {
+            Some(path) if !path.exists() => Err(SnapshotError::NotFound(name.to_string())),
+            Some(path) => Ok(std::fs::remove_file(path)?),
+            None => self
+                .memory
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .remove(name)
+                .map(|_| ())
+                .ok_or_else(|| SnapshotError::NotFound(name.to_string())),
+        }
+    }
+
+    /// Summaries of all snapshots, sorted by name
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the snapshots cannot be read.
+    pub fn list(&self) -> Result<Vec<SnapshotInfo>, SnapshotError> {
+        let mut list = match &self.dir {
+            Some(dir) => {
+                let mut list = vec![];
+                for entry in std::fs::read_dir(dir)? {
+                    let path = entry?.path();
+                    let name = path.file_stem().and_then(|name| name.to_str());
+                    if let (Some(name), Some("json")) =
+                        (name, path.extension().and_then(|ext| ext.to_str()))
+                    {
+                        list.push(Snapshot::load(&path)?.info(name));
+                    }
+                }
+                list
+            }
+            None => self
+                .memory
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .iter()
+                .map(|(name, snapshot)| snapshot.info(name))
+                .collect(),
+        };
+
+        list.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(list)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_state_offsets() {
+        let now = Instant::now() + Duration::from_secs(3600);
+        let ss = SimulationState::Sleeping {
+            wake_up_time: Some(now + Duration::from_secs(30)),
+        };
+
+        let snapshot = StateSnapshot::new(&ss, now);
+        let text = serde_json::to_string(&snapshot).unwrap();
+        assert_eq!(text, r#"{"state":"sleeping","wake_up":30.0}"#);
+
+        let later = now + Duration::from_secs(100);
+        let SimulationState::Sleeping {
+            wake_up_time: Some(wake_up_time),
+        } = snapshot.restore(later)
+        else {
+            panic!("vehicle should be sleeping");
+        };
+        assert_eq!(wake_up_time, later + Duration::from_secs(30));
+
+        assert_eq!(
+            at(now, offset(now, now - Duration::from_secs(5))),
+            now - Duration::from_secs(5)
+        );
+    }
+
+    #[test]
+    fn test_invalid_name() {
+        let snapshots = Snapshots::default();
+        let err = snapshots.load("../etc/passwd").unwrap_err();
+        assert!(matches!(err, SnapshotError::InvalidName(_)));
+        let err = snapshots.load("missing").unwrap_err();
+        assert!(matches!(err, SnapshotError::NotFound(_)));
+    }
+}
@@ -0,0 +1,235 @@
+//! Fleet Telemetry emulation
+//!
+//! A configured vehicle sends a [`Payload`] whenever one of its fields is due, but only while it
+//! is awake. If the configured host name is a loopback address, the records are sent as binary
+//! websocket messages to `ws://hostname:port/`. Otherwise they go to the built-in receiver, which
+//! can be read from `/admin/telemetry`.
+
+use std::{collections::BTreeMap, net::IpAddr, sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use fla_common::telemetry::{Datum, Field, FleetTelemetryConfig, LocationValue, Payload, Value};
+use futures::SinkExt;
+use prost::Message as _;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::Instant,
+};
+use tokio_tungstenite::{connect_async, tungstenite::Message};
+use tracing::debug;
+
+use super::types::VehicleDataState;
+
+/// How many records the built-in receiver keeps for slow readers
+const RECEIVER_CAPACITY: usize = 64;
+
+/// How many records wait for a websocket receiver before they are dropped
+const FORWARD_CAPACITY: usize = 64;
+
+/// The built-in receiver, shared by all vehicles
+#[derive(Debug)]
+pub struct Receiver(broadcast::Sender<Arc<Payload>>);
+
+impl Default for Receiver {
+    fn default() -> Self {
+        Self(broadcast::channel(RECEIVER_CAPACITY).0)
+    }
+}
+
+impl Receiver {
+    /// Read the records sent to the built-in receiver from now on
+    #[must_use]
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Payload>> {
+        self.0.subscribe()
+    }
+
+    /// Where a vehicle with this configuration sends its records
+    #[must_use]
+    pub fn output(&self, config: &FleetTelemetryConfig) -> Output {
+        match local_url(config) {
+            Some(url) => Output::Forward(forward(url)),
+            None => Output::BuiltIn(self.0.clone()),
+        }
+    }
+}
+
+/// Where a vehicle sends its records
+#[derive(Debug)]
+pub enum Output {
+    /// The built-in receiver
+    BuiltIn(broadcast::Sender<Arc<Payload>>),
+
+    /// A websocket receiver running on this machine
+    Forward(mpsc::Sender<Payload>),
+}
+
+impl Output {
+    fn send(&self, payload: Payload) {
+        match self {
+            Self::BuiltIn(tx) => {
+                // It is not an error if we are sending and nobody is listening.
+                _ = tx.send(Arc::new(payload));
+            }
+            Self::Forward(tx) => {
+                if let Err(err) = tx.try_send(payload) {
+                    debug!("Dropping telemetry record: {err}");
+                }
+            }
+        }
+    }
+}
+
+/// The websocket URL of the receiver, if it is running on this machine
+fn local_url(config: &FleetTelemetryConfig) -> Option<String> {
+    let hostname = config.hostname.as_str();
+    match hostname.parse::<IpAddr>() {
+        Ok(IpAddr::V4(ip)) if ip.is_loopback() => Some(format!("ws://{ip}:{}/", config.port)),
+        Ok(IpAddr::V6(ip)) if ip.is_loopback() => Some(format!("ws://[{ip}]:{}/", config.port)),
+        Err(_) if hostname == "localhost" => Some(format!("ws://localhost:{}/", config.port)),
+        _ => None,
+    }
+}
+
+/// Send records to a websocket receiver until the vehicle stops sending them
+///
+/// The connection is opened when the first record is sent, and opened again after an error.
+/// Records that cannot be sent are dropped, like a vehicle without a connection.
+fn forward(url: String) -> mpsc::Sender<Payload> {
+    let (tx, mut rx) = mpsc::channel::<Payload>(FORWARD_CAPACITY);
+
+    tokio::spawn(async move {
+        let mut maybe_socket = None;
+
+        while let Some(payload) = rx.recv().await {
+            if maybe_socket.is_none() {
+                match connect_async(url.as_str()).await {
+                    Ok((socket, _)) => maybe_socket = Some(socket),
+                    Err(err) => {
+                        debug!("Cannot connect to telemetry receiver {url}: {err}");
+                        continue;
+                    }
+                }
+            }
+
+            if let Some(socket) = &mut maybe_socket {
+                if let Err(err) = socket.send(Message::Binary(payload.encode_to_vec())).await {
+                    debug!("Cannot send to telemetry receiver {url}: {err}");
+                    maybe_socket = None;
+                }
+            }
+        }
+
+        if let Some(mut socket) = maybe_socket {
+            _ = socket.close(None).await;
+        }
+    });
+
+    tx
+}
+
+/// Sends the records of one vehicle
+#[derive(Debug)]
+pub struct Emitter {
+    config: FleetTelemetryConfig,
+    output: Output,
+    due: BTreeMap<Field, Instant>,
+}
+
+impl Emitter {
+    /// Start sending records, every field is due straight away
+    #[must_use]
+    pub fn new(config: FleetTelemetryConfig, output: Output, now: Instant) -> Self {
+        let due = config.fields.keys().map(|field| (*field, now)).collect();
+        Self {
+            config,
+            output,
+            due,
+        }
+    }
+
+    /// The configuration of the vehicle
+    #[must_use]
+    pub const fn config(&self) -> &FleetTelemetryConfig {
+        &self.config
+    }
+
+    /// When the next field is due
+    #[must_use]
+    pub fn next_due(&self) -> Option<Instant> {
+        self.due.values().min().copied()
+    }
+
+    /// Make every field due, after the vehicle wakes up
+    pub fn restart(&mut self, now: Instant) {
+        for due in self.due.values_mut() {
+            *due = now;
+        }
+    }
+
+    /// Send the fields that are due
+    pub fn emit(&mut self, data: &VehicleDataState, created_at: DateTime<Utc>, now: Instant) {
+        let mut fields = Vec::new();
+        for (field, due) in &mut self.due {
+            if *due <= now {
+                let interval = self
+                    .config
+                    .fields
+                    .get(field)
+                    .map_or(1, |config| config.interval_seconds.max(1));
+                *due = now + Duration::from_secs(u64::from(interval));
+                fields.push(*field);
+            }
+        }
+
+        if self
+            .config
+            .exp
+            .is_some_and(|exp| created_at.timestamp() >= exp)
+        {
+            debug!("Telemetry configuration for {} has expired", data.vin);
+            return;
+        }
+
+        let payload = Payload {
+            data: fields
+                .into_iter()
+                .filter_map(|field| datum(data, field))
+                .collect(),
+            created_at: Some(created_at.into()),
+            vin: data.vin.clone(),
+            is_resend: false,
+        };
+
+        if !payload.data.is_empty() {
+            self.output.send(payload);
+        }
+    }
+}
+
+/// The current value of a field, `None` if the vehicle does not know it
+fn datum(data: &VehicleDataState, field: Field) -> Option<Datum> {
+    let drive_state = &data.drive_state;
+    let charge_state = &data.charge_state;
+
+    let value = match field {
+        Field::Unknown => return None,
+        Field::ChargeState => Value::StringValue(charge_state.charging_state.to_string()),
+        Field::VehicleSpeed => Value::DoubleValue(f64::from(drive_state.speed?)),
+        Field::Odometer => Value::DoubleValue(f64::from(data.vehicle_state.odometer)),
+        Field::Soc => Value::DoubleValue(data.battery_level),
+        Field::Gear => Value::StringValue(drive_state.shift_state.as_ref()?.to_string()),
+        Field::Location => Value::LocationValue(LocationValue {
+            latitude: drive_state.latitude?,
+            longitude: drive_state.longitude?,
+        }),
+        Field::GpsHeading => Value::DoubleValue(f64::from(drive_state.heading)),
+        Field::RatedRange => Value::DoubleValue(f64::from(charge_state.battery_range)),
+        Field::ChargeLimitSoc => Value::IntValue(i32::from(charge_state.charge_limit_soc)),
+        Field::EstBatteryRange => Value::DoubleValue(f64::from(charge_state.est_battery_range)),
+        Field::IdealBatteryRange => Value::DoubleValue(f64::from(charge_state.ideal_battery_range)),
+        Field::BatteryLevel => Value::DoubleValue(f64::from(charge_state.battery_level)),
+        Field::ChargeAmps => Value::IntValue(i32::try_from(charge_state.charge_amps).ok()?),
+    };
+
+    Some(Datum::new(field, value))
+}
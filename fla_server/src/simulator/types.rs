@@ -1,13 +1,16 @@
 use fla_common::{
-    simulator::SimulationStateEnum,
+    simulator::{SimulationStateEnum, SleepPolicy, TyreValues, VehicleModel},
     streaming::StreamingData,
     types::{
         ChargeState, ClimateState, DriveState, GranularAccess, GuiSettings, Timestamp,
         VehicleConfig, VehicleData, VehicleGuid, VehicleId, VehicleState, VehicleStateEnum,
     },
 };
+use serde::{Deserialize, Serialize};
 use tokio::time::Instant;
 
+use super::profiles::{trim_profile, TrimProfile};
+
 #[derive(Debug, Clone)]
 pub struct SimulationDriveState {
     pub time: Instant,
@@ -15,18 +18,18 @@ pub struct SimulationDriveState {
     pub longitude: f64,
     pub heading: u16,
     pub speed: f32,
-    pub battery_level: u8,
+    pub battery_level: f64,
 }
 
-impl From<&VehicleDataState> for SimulationDriveState {
-    fn from(data: &VehicleDataState) -> Self {
+impl SimulationDriveState {
+    pub fn new(data: &VehicleDataState, now: Instant) -> Self {
         Self {
-            time: Instant::now(),
+            time: now,
             latitude: data.drive_state.latitude.unwrap_or(0.0),
             longitude: data.drive_state.longitude.unwrap_or(0.0),
             heading: data.drive_state.heading,
             speed: 60.0,
-            battery_level: data.charge_state.battery_level,
+            battery_level: data.battery_level,
         }
     }
 }
@@ -34,15 +37,15 @@ impl From<&VehicleDataState> for SimulationDriveState {
 #[derive(Debug, Clone)]
 pub struct SimulationChargeState {
     pub time: Instant,
-    pub battery_level: u8,
+    pub battery_level: f64,
     // pub battery_range: f32,
 }
 
-impl From<&VehicleDataState> for SimulationChargeState {
-    fn from(data: &VehicleDataState) -> Self {
+impl SimulationChargeState {
+    pub fn new(data: &VehicleDataState, now: Instant) -> Self {
         Self {
-            time: Instant::now(),
-            battery_level: data.charge_state.battery_level,
+            time: now,
+            battery_level: data.battery_level,
             // battery_range: data.charge_state.battery_range,
         }
     }
@@ -68,13 +71,16 @@ pub enum SimulationState {
     /// The vehicle is idle but should not go to sleep
     IdleNoSleep,
 
+    /// The vehicle is asleep but still reported as online
+    Drowsy { sleep_time: Instant },
+
     /// The vehicle is sleeping
     Sleeping { wake_up_time: Option<Instant> },
 }
 
 impl SimulationState {
     pub fn is_asleep(&self) -> bool {
-        matches!(self, Self::Sleeping { .. })
+        matches!(self, Self::Drowsy { .. } | Self::Sleeping { .. })
     }
 
     pub fn is_driving(&self) -> bool {
@@ -85,7 +91,7 @@ impl SimulationState {
         let state = if let Self::Driving { state, .. } = self {
             state
         } else {
-            SimulationDriveState::from(data)
+            SimulationDriveState::new(data, now)
         };
         Self::Driving {
             state,
@@ -97,7 +103,7 @@ impl SimulationState {
         let state = if let Self::Charging { state, .. } = self {
             state
         } else {
-            SimulationChargeState::from(data)
+            SimulationChargeState::new(data, now)
         };
         Self::Charging {
             state,
@@ -105,9 +111,20 @@ impl SimulationState {
         }
     }
 
-    pub fn idle(now: Instant) -> Self {
+    pub fn idle(now: Instant, policy: &SleepPolicy) -> Self {
         Self::Idle {
-            sleep_time: now + std::time::Duration::from_secs(60),
+            sleep_time: now + policy.idle_timeout(),
+        }
+    }
+
+    /// Fall asleep, staying visible as online for the drowsy period first
+    pub fn fall_asleep(now: Instant, policy: &SleepPolicy) -> Self {
+        if policy.drowsy_seconds > 0 {
+            Self::Drowsy {
+                sleep_time: now + policy.drowsy_period(),
+            }
+        } else {
+            Self::sleeping()
         }
     }
 
@@ -115,25 +132,24 @@ impl SimulationState {
         Self::Sleeping { wake_up_time: None }
     }
 
-    pub fn wake_up(self, now: Instant) -> Self {
-        if let Self::Sleeping {
-            wake_up_time: Some(_),
-        } = self
-        {
+    pub fn wake_up(self, now: Instant, policy: &SleepPolicy) -> Self {
+        match self {
             // Wake up time already set, do nothing
-            return self;
-        }
-        // Schedule wake up time
-        Self::Sleeping {
-            wake_up_time: Some(now + std::time::Duration::from_secs(60)),
+            Self::Sleeping {
+                wake_up_time: Some(_),
+            } => self,
+            // Still reachable, so it never went offline
+            Self::Drowsy { .. } => Self::idle(now, policy),
+            // Schedule wake up time
+            _ => Self::Sleeping {
+                wake_up_time: Some(now + policy.wake_latency()),
+            },
         }
     }
 
-    pub fn update_sleep_time(self, now: Instant) -> Self {
+    pub fn update_sleep_time(self, now: Instant, policy: &SleepPolicy) -> Self {
         if let Self::Idle { .. } = self {
-            Self::Idle {
-                sleep_time: now + std::time::Duration::from_secs(60),
-            }
+            Self::idle(now, policy)
         } else {
             self
         }
@@ -147,6 +163,7 @@ impl From<&SimulationState> for SimulationStateEnum {
             SimulationState::Charging { .. } => Self::Charging,
             SimulationState::Idle { .. } => Self::Idle,
             SimulationState::IdleNoSleep => Self::IdleNoSleep,
+            SimulationState::Drowsy { .. } => Self::Drowsy,
             SimulationState::Sleeping { .. } => Self::Sleeping,
         }
     }
@@ -154,7 +171,7 @@ impl From<&SimulationState> for SimulationStateEnum {
 
 /// Current state of all Vehicle Data
 #[allow(missing_docs)]
-#[derive(Debug)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VehicleDataState {
     // These fields are from VehicleData.
     pub id: VehicleId,
@@ -183,6 +200,39 @@ pub struct VehicleDataState {
 
     // Extra data not in VehicleData.
     pub elevation: u32,
+    pub battery_level: f64,
+    pub model: VehicleModel,
+    #[serde(default)]
+    pub trim: Option<String>,
+
+    /// Tyre pressures at 20 °C
+    pub tyre_pressures: TyreValues,
+}
+
+impl VehicleDataState {
+    /// Set the battery level, keeping track of fractions of a percent
+    pub fn set_battery_level(&mut self, battery_level: f64) {
+        self.battery_level = battery_level.clamp(0.0, 100.0);
+        let battery_level = self.battery_level as u8;
+        let range = self.range(battery_level);
+
+        let charge_state = &mut self.charge_state;
+        charge_state.battery_level = battery_level;
+        charge_state.usable_battery_level = i64::from(battery_level);
+        charge_state.battery_range = range;
+        charge_state.est_battery_range = range;
+        charge_state.ideal_battery_range = range;
+    }
+
+    /// Rated range in miles for a battery level
+    pub fn range(&self, battery_level: u8) -> f32 {
+        (f64::from(battery_level) * self.trim().range_per_percent()) as f32
+    }
+
+    /// The profile of the trim of the vehicle
+    pub fn trim(&self) -> &'static TrimProfile {
+        trim_profile(self.model, self.trim.as_deref())
+    }
 }
 
 impl From<&VehicleDataState> for StreamingData {
@@ -202,6 +252,13 @@ impl From<&VehicleDataState> for StreamingData {
             range: Some(data.charge_state.battery_range),
             est_range: Some(data.charge_state.est_battery_range),
             heading: Some(data.drive_state.heading),
+            // The simulator only knows WGS coordinates, so native values are the same.
+            native_latitude: data.drive_state.latitude,
+            native_longitude: data.drive_state.longitude,
+            native_power: data.drive_state.power,
+            native_type: Some(data.drive_state.native_type.clone()),
+            native_location_supported: u8::try_from(data.drive_state.native_location_supported)
+                .ok(),
         }
     }
 }
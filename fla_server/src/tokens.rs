@@ -3,7 +3,7 @@
 use std::{collections::HashSet, str::FromStr};
 
 use chrono::{Duration, Utc};
-use fla_common::auth::RawToken;
+use fla_common::{auth::RawToken, types::UserId};
 use jsonwebtoken::{decode, encode, Algorithm, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -23,6 +23,8 @@ pub enum Purpose {
 pub struct AccessClaims {
     /// The purpose of the token
     pub purpose: Purpose,
+    /// The user the token belongs to
+    pub sub: UserId,
     /// The expiration time of the token
     pub exp: usize,
     /// The scopes of the token
@@ -59,6 +61,9 @@ pub enum ScopeEnum {
 
     /// The user's energy commands
     EnergyCmds,
+
+    /// Administration of the simulator, this is not a Tesla scope
+    SimulatorAdmin,
 }
 
 impl FromStr for ScopeEnum {
@@ -75,6 +80,7 @@ impl FromStr for ScopeEnum {
             "vehicle_charging_cmds" => Ok(Self::VehicleChargingCmds),
             "energy_device_data" => Ok(Self::EnergyDeviceData),
             "energy_cmds" => Ok(Self::EnergyCmds),
+            "simulator_admin" => Ok(Self::SimulatorAdmin),
             _ => Err(()),
         }
     }
@@ -85,6 +91,8 @@ impl FromStr for ScopeEnum {
 pub struct RefreshClaims {
     /// The purpose of the token
     pub purpose: Purpose,
+    /// The user the token belongs to
+    pub sub: UserId,
     /// The expiration time of the token
     pub exp: usize,
     /// The scopes of the token
@@ -108,13 +116,14 @@ pub enum TokenGenerationError {
     TimestampError,
 }
 
-/// Generate a new token with the given scopes
+/// Generate a new token for a user with the given scopes
 ///
 /// # Errors
 ///
 /// If the token cannot be generated, an error will be returned.
 pub fn new_token(
     config: &Config,
+    user: UserId,
     scopes: &HashSet<ScopeEnum>,
 ) -> Result<RawToken, TokenGenerationError> {
     let encoding_key = EncodingKey::from_secret(config.secret.as_ref());
@@ -128,6 +137,7 @@ pub fn new_token(
         &Header::default(),
         &AccessClaims {
             purpose: Purpose::Access,
+            sub: user,
             exp: timestamp,
             scopes: scopes.clone(),
         },
@@ -138,6 +148,7 @@ pub fn new_token(
         &Header::default(),
         &RefreshClaims {
             purpose: Purpose::Refresh,
+            sub: user,
             exp: timestamp,
             scopes: scopes.clone(),
         },
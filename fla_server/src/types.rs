@@ -1,9 +1,83 @@
-use std::{fmt::Formatter, sync::Arc};
+use std::{
+    fmt::Formatter,
+    sync::{Arc, PoisonError},
+};
 
-use crate::simulator;
-use fla_common::types::{VehicleDefinition, VehicleGuid, VehicleId};
-use tokio::sync::RwLock;
-use tracing::log::debug;
+use crate::simulator::{
+    self,
+    fleet::{self, FleetError},
+    profiles::profile,
+    Context,
+};
+use fla_common::{
+    simulator::{Fleet, FleetDriver, FleetUser, FleetVehicle},
+    types::{GranularAccess, UserId, VehicleDefinition, VehicleGuid, VehicleId},
+};
+use tokio::sync::{watch, RwLock};
+
+/// How a user may use a vehicle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessType {
+    /// The user owns the vehicle
+    Owner,
+
+    /// The vehicle is shared with the user
+    Driver {
+        /// The location of the vehicle is hidden from the user
+        hide_private: bool,
+    },
+}
+
+impl AccessType {
+    /// Value of `access_type` in vehicle data
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Owner => "OWNER",
+            Self::Driver { .. } => "DRIVER",
+        }
+    }
+
+    /// Is the location of the vehicle hidden from the user?
+    #[must_use]
+    pub const fn hide_private(self) -> bool {
+        matches!(self, Self::Driver { hide_private: true })
+    }
+
+    /// Value of `granular_access` in vehicle data
+    #[must_use]
+    pub const fn granular_access(self) -> GranularAccess {
+        GranularAccess {
+            hide_private: self.hide_private(),
+        }
+    }
+}
+
+/// The users that may use a vehicle
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Access {
+    /// The user that owns the vehicle
+    pub owner: UserId,
+
+    /// Other users the vehicle is shared with
+    pub drivers: Vec<FleetDriver>,
+}
+
+impl Access {
+    /// How a user may use the vehicle, `None` if they may not use it at all
+    #[must_use]
+    pub fn for_user(&self, user: UserId) -> Option<AccessType> {
+        if user == self.owner {
+            return Some(AccessType::Owner);
+        }
+        self.drivers
+            .iter()
+            .find(|driver| driver.user == user)
+            .map(|driver| AccessType::Driver {
+                hide_private: driver.hide_private,
+            })
+    }
+}
 
 /// A vehicle
 pub struct Vehicle {
@@ -18,49 +92,71 @@ pub struct Vehicle {
 
     /// The command sender
     pub command: simulator::CommandSender,
+
+    /// How the vehicle was defined
+    pub spec: FleetVehicle,
+
+    /// The users that may use the vehicle
+    pub access: watch::Sender<Access>,
 }
 
 impl Vehicle {
     /// Create a new vehicle
     #[must_use]
-    pub fn new(data: VehicleDefinition) -> Vehicle {
-        let id = data.id;
-        let vehicle_id = data.vehicle_id;
-        let command = simulator::server::start(data.clone());
-        let data = Arc::new(RwLock::new(data));
-
-        let d = data.clone();
-        let c = command.clone();
+    pub fn new(vehicle: FleetVehicle, context: Arc<Context>) -> Vehicle {
+        let id = vehicle.id;
+        let vehicle_id = vehicle.vehicle_id;
+        let definition = Arc::new(RwLock::new(get_definition(&vehicle)));
+        let command = simulator::server::start(vehicle.clone(), definition.clone(), context);
+        let (access, _) = watch::channel(Access {
+            owner: vehicle.owner,
+            drivers: vehicle.drivers.clone(),
+        });
 
-        let vehicle = Self {
+        Self {
             id,
             vehicle_id,
-            data,
+            data: definition,
             command,
-        };
+            spec: vehicle,
+            access,
+        }
+    }
 
-        tokio::spawn(async move {
-            let mut stream = c.watch_state().await.unwrap_or_else(|_| {
-                panic!("Failed to get state stream for vehicle {}", id.to_string());
-            });
-            // We must drop this so we don't force the vehicle to stay alive.
-            drop(c);
-
-            while let Ok(data) = stream.recv().await {
-                let online_state = data.into();
-                let current = d.read().await.state.clone();
-                if current != online_state {
-                    debug!(
-                        "Vehicle {:?} was {:?} is now {:?}",
-                        id, current, online_state
-                    );
-                    let mut data = d.write().await;
-                    data.state = online_state;
-                }
-            }
-        });
+    /// How a user may use the vehicle, `None` if they may not use it at all
+    #[must_use]
+    pub fn access_for(&self, user: UserId) -> Option<AccessType> {
+        self.access.borrow().for_user(user)
+    }
+}
+
+/// The streaming tokens of a vehicle, the same in its definition and in its vehicle data
+pub(crate) fn vehicle_tokens(vehicle: &FleetVehicle) -> Vec<String> {
+    vec![
+        format!("{:0>16}", vehicle.vehicle_id),
+        format!("{:0>16}", vehicle.id),
+    ]
+}
 
-        vehicle
+fn get_definition(vehicle: &FleetVehicle) -> VehicleDefinition {
+    VehicleDefinition {
+        id: vehicle.id,
+        vehicle_id: vehicle.vehicle_id,
+        vin: vehicle.vin.clone(),
+        display_name: vehicle.display_name.clone(),
+        option_codes: vehicle
+            .option_codes
+            .clone()
+            .or_else(|| Some(profile(vehicle.model).option_codes(vehicle.color.as_deref()))),
+        color: vehicle.color.clone(),
+        tokens: vehicle_tokens(vehicle),
+        state: vehicle.state.into(),
+        in_service: false,
+        id_s: vehicle.id.to_string(),
+        calendar_enabled: true,
+        api_version: 6,
+        backseat_token: None,
+        backseat_token_updated_at: None,
     }
 }
 
@@ -73,3 +169,175 @@ impl std::fmt::Debug for Vehicle {
             .finish_non_exhaustive()
     }
 }
+
+/// All simulated vehicles, vehicles can be added and removed while the server is running
+#[derive(Debug, Default)]
+pub struct Vehicles(std::sync::RwLock<Vec<Arc<Vehicle>>>);
+
+impl Vehicles {
+    /// Create a new list of vehicles
+    #[must_use]
+    pub fn new(vehicles: Vec<Vehicle>) -> Self {
+        Self(std::sync::RwLock::new(
+            vehicles.into_iter().map(Arc::new).collect(),
+        ))
+    }
+
+    /// All vehicles, in the order they were added
+    #[must_use]
+    pub fn list(&self) -> Vec<Arc<Vehicle>> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// Find a vehicle by ID
+    #[must_use]
+    pub fn find(&self, id: VehicleId) -> Option<Arc<Vehicle>> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|v| v.id == id)
+            .cloned()
+    }
+
+    /// Find a vehicle by GUID
+    #[must_use]
+    pub fn find_by_guid(&self, vehicle_id: VehicleGuid) -> Option<Arc<Vehicle>> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|v| v.vehicle_id == vehicle_id)
+            .cloned()
+    }
+
+    /// Find a vehicle by VIN
+    #[must_use]
+    pub fn find_by_vin(&self, vin: &str) -> Option<Arc<Vehicle>> {
+        self.0
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .find(|v| v.spec.vin == vin)
+            .cloned()
+    }
+
+    /// All vehicles a user may use, in the order they were added
+    #[must_use]
+    pub fn list_for_user(&self, user: UserId) -> Vec<(Arc<Vehicle>, AccessType)> {
+        self.list()
+            .into_iter()
+            .filter_map(|v| v.access_for(user).map(|access| (v, access)))
+            .collect()
+    }
+
+    /// Find a vehicle by ID, if the user may use it
+    #[must_use]
+    pub fn find_for_user(&self, id: VehicleId, user: UserId) -> Option<(Arc<Vehicle>, AccessType)> {
+        let vehicle = self.find(id)?;
+        let access = vehicle.access_for(user)?;
+        Some((vehicle, access))
+    }
+
+    /// Find a vehicle by GUID, if the user may use it
+    #[must_use]
+    pub fn find_by_guid_for_user(
+        &self,
+        vehicle_id: VehicleGuid,
+        user: UserId,
+    ) -> Option<(Arc<Vehicle>, AccessType)> {
+        let vehicle = self.find_by_guid(vehicle_id)?;
+        let access = vehicle.access_for(user)?;
+        Some((vehicle, access))
+    }
+
+    /// Find a vehicle by VIN, if the user may use it
+    #[must_use]
+    pub fn find_by_vin_for_user(
+        &self,
+        vin: &str,
+        user: UserId,
+    ) -> Option<(Arc<Vehicle>, AccessType)> {
+        let vehicle = self.find_by_vin(vin)?;
+        let access = vehicle.access_for(user)?;
+        Some((vehicle, access))
+    }
+
+    /// Start simulating a new vehicle
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the vehicle is invalid, or clashes with an existing vehicle.
+    pub fn add(
+        &self,
+        vehicle: FleetVehicle,
+        users: &Users,
+        context: &Arc<Context>,
+    ) -> Result<Arc<Vehicle>, FleetError> {
+        let mut vehicles = self.0.write().unwrap_or_else(PoisonError::into_inner);
+
+        let fleet = Fleet {
+            users: users.list().to_vec(),
+            vehicles: vehicles
+                .iter()
+                .map(|v| v.spec.clone())
+                .chain([vehicle.clone()])
+                .collect(),
+        };
+        fleet::validate(&fleet)?;
+
+        let vehicle = Arc::new(Vehicle::new(vehicle, context.clone()));
+        vehicles.push(vehicle.clone());
+        Ok(vehicle)
+    }
+
+    /// Replace all vehicles
+    pub fn replace(&self, vehicles: Vec<Vehicle>) {
+        let vehicles = vehicles.into_iter().map(Arc::new).collect();
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = vehicles;
+    }
+
+    /// Stop simulating a vehicle
+    ///
+    /// The simulator stops once nothing is using the vehicle any more.
+    pub fn remove(&self, id: VehicleId) -> Option<Arc<Vehicle>> {
+        let mut vehicles = self.0.write().unwrap_or_else(PoisonError::into_inner);
+        let index = vehicles.iter().position(|v| v.id == id)?;
+        Some(vehicles.remove(index))
+    }
+}
+
+/// All simulated users
+#[derive(Debug, Default)]
+pub struct Users(Vec<FleetUser>);
+
+impl Users {
+    /// Create a new list of users
+    #[must_use]
+    pub fn new(users: Vec<FleetUser>) -> Self {
+        Self(users)
+    }
+
+    /// All users
+    #[must_use]
+    pub fn list(&self) -> &[FleetUser] {
+        &self.0
+    }
+
+    /// Find a user by ID
+    #[must_use]
+    pub fn find(&self, id: UserId) -> Option<&FleetUser> {
+        self.0.iter().find(|user| user.id == id)
+    }
+
+    /// Find a user by email, ignoring case
+    #[must_use]
+    pub fn find_by_email(&self, email: &str) -> Option<&FleetUser> {
+        self.0
+            .iter()
+            .find(|user| user.email.eq_ignore_ascii_case(email))
+    }
+}
@@ -0,0 +1,56 @@
+//! Run a scenario file against the simulator and report whether its expectations held.
+
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use clap::Parser;
+use fla_test::{get_client, scenario::Scenario, start_manual_server};
+
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Parameters {
+    /// The scenario file
+    file: PathBuf,
+
+    /// Run against a new simulator in this process, instead of a running server
+    #[arg(long)]
+    local: bool,
+
+    /// Simulated time to start the local simulator at, defaults to the current time
+    #[arg(long, requires = "local")]
+    start_time: Option<DateTime<Utc>>,
+
+    /// Seed for the local simulator
+    #[arg(long, requires = "local", default_value_t = 0)]
+    seed: u64,
+}
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    let params = Parameters::parse();
+
+    let scenario = Scenario::load(&params.file).unwrap_or_else(|err| {
+        eprintln!("{err}");
+        std::process::exit(1);
+    });
+
+    let client = if params.local {
+        start_manual_server(params.start_time.unwrap_or_else(Utc::now), params.seed)
+    } else {
+        get_client()
+    };
+
+    let report = scenario.run(&client).await.unwrap_or_else(|err| {
+        eprintln!("Scenario failed: {err}");
+        std::process::exit(1);
+    });
+
+    println!("{report}");
+    if !report.passed() {
+        std::process::exit(1);
+    }
+}
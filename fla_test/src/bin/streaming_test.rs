@@ -7,6 +7,7 @@ use clap::Parser;
 use fla_common::{streaming::StreamingFields, types::VehicleGuid};
 use fla_server::tokens::ScopeEnum;
 use fla_test::{get_client_with_token, get_token_with_scopes};
+use futures_util::StreamExt;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -40,9 +41,9 @@ async fn main() {
         StreamingFields::EstRange,
         StreamingFields::Heading,
     ];
-    let mut streaming = client.streaming(params.vehicle_id, fields).unwrap();
+    let mut streaming = client.streaming(params.vehicle_id, fields);
 
-    while let Some(msg) = streaming.recv().await {
-        println!("Woof Received: {msg:?}");
+    while let Some(event) = streaming.next().await {
+        println!("Woof Received: {event:?}");
     }
 }
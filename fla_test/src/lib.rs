@@ -1,14 +1,26 @@
 //! Test infrastructure
 #![allow(clippy::unwrap_used)]
 
-use std::collections::HashSet;
+use std::{collections::HashSet, net::TcpListener, sync::Arc, time::Duration};
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use envconfig::Envconfig;
-use fla_client::Token;
-use fla_server::tokens::{self, new_token, ScopeEnum};
+use fla_client::{Backoff, StreamingError, StreamingEvent, Token};
+use fla_common::{simulator::DEFAULT_USER, streaming::StreamingData, types::UserId};
+use fla_server::{
+    invitations::Invitations,
+    rate_limit::RateLimiter,
+    simulator::{clock::Clock, data, snapshot::Snapshots, telemetry, Context},
+    tokens::{self, new_token, validate_access_token, ScopeEnum},
+    types::Users,
+    Config,
+};
+use futures_util::{Stream, StreamExt};
+use jsonwebtoken::{encode, EncodingKey, Header};
 use url::Url;
 
+pub mod scenario;
+
 fn get_token_config() -> tokens::Config {
     // This config must match the server.
     tokens::Config {
@@ -17,6 +29,7 @@ fn get_token_config() -> tokens::Config {
 }
 
 #[derive(Envconfig, Debug)]
+#[allow(clippy::struct_field_names)]
 struct Environment {
     #[envconfig(from = "TESLA_ACCESS_TOKEN")]
     tesla_access_token: Option<String>,
@@ -56,12 +69,33 @@ pub fn get_token_for_all_scopes() -> Token {
         tokens::ScopeEnum::VehicleChargingCmds,
         tokens::ScopeEnum::EnergyDeviceData,
         tokens::ScopeEnum::EnergyCmds,
+        tokens::ScopeEnum::SimulatorAdmin,
     ]
     .into_iter()
     .collect::<HashSet<tokens::ScopeEnum>>();
     get_token_with_scopes(&scopes)
 }
 
+/// Get a token for all scopes, with an access token that expires at `expires_at`
+///
+/// The server accepts access tokens for 60 seconds after they expire. The refresh token and the
+/// times the client renews the token are left alone.
+///
+/// # Panics
+///
+/// Panics if the token cannot be generated
+#[must_use]
+pub fn get_token_expiring_at(expires_at: DateTime<Utc>) -> Token {
+    let config = get_token_config();
+    let mut token = get_token_for_all_scopes();
+    let mut claims = validate_access_token(&token.access_token, &config).unwrap();
+    claims.exp = usize::try_from(expires_at.timestamp()).unwrap();
+
+    let key = EncodingKey::from_secret(config.secret.as_ref());
+    token.access_token = encode(&Header::default(), &claims, &key).unwrap();
+    token
+}
+
 /// Get a token for the specified scopes
 ///
 /// # Parameters
@@ -78,8 +112,28 @@ pub fn get_token_for_all_scopes() -> Token {
 #[must_use]
 #[allow(clippy::implicit_hasher)]
 pub fn get_token_with_scopes(scopes: &HashSet<ScopeEnum>) -> Token {
+    get_token_for_user(DEFAULT_USER, scopes)
+}
+
+/// Get a token for a user with the specified scopes
+///
+/// # Parameters
+///
+/// * `user` - The user the token belongs to
+/// * `scopes` - The scopes to get a token for
+///
+/// # Returns
+///
+/// A token for the user with the specified scopes
+///
+/// # Panics
+///
+/// Panics if the token cannot be generated
+#[must_use]
+#[allow(clippy::implicit_hasher)]
+pub fn get_token_for_user(user: UserId, scopes: &HashSet<ScopeEnum>) -> Token {
     let config = get_token_config();
-    new_token(&config, scopes).unwrap().into()
+    new_token(&config, user, scopes).unwrap().into()
 }
 
 /// Get a client for connecting to the server
@@ -99,9 +153,9 @@ pub fn get_client_with_token(token: Token) -> fla_client::Client {
     let env = Environment::init_from_env().unwrap();
     let now = Utc::now();
 
-    if env.tesla_access_token.is_some() {
+    if let Some(access_token) = env.tesla_access_token {
         let token = Token {
-            access_token: env.tesla_access_token.unwrap(),
+            access_token,
             refresh_token: env.tesla_refresh_token.unwrap(),
             renew_at: now,
             expires_at: now,
@@ -124,3 +178,129 @@ pub fn get_client_with_token(token: Token) -> fla_client::Client {
             .unwrap()
     }
 }
+
+/// A simulator server with a manual clock, running in this process
+///
+/// The clock only moves when advanced, and all random behaviour is derived from the seed, so the
+/// same steps always give the same results.
+#[derive(Debug, Clone, Copy)]
+pub struct ManualServer {
+    port: u16,
+}
+
+impl ManualServer {
+    /// Start a server with the default fleet
+    ///
+    /// # Panics
+    ///
+    /// Panics if the server cannot be started
+    #[must_use]
+    pub fn start(start: DateTime<Utc>, seed: u64) -> Self {
+        let simulator = Arc::new(Context::new(Clock::manual(start), seed));
+        let fleet = data::default_fleet();
+        let config = Config {
+            token: Arc::new(get_token_config()),
+            vehicles: Arc::new(data::get_fleet_vehicles(&fleet, &simulator)),
+            users: Arc::new(Users::new(fleet.users)),
+            simulator,
+            rate_limiter: Arc::new(RateLimiter::default()),
+            invitations: Arc::new(Invitations::default()),
+            snapshots: Arc::new(Snapshots::default()),
+            telemetry: Arc::new(telemetry::Receiver::default()),
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(fla_server::router(&config).into_make_service());
+
+        tokio::spawn(async move {
+            server.await.unwrap();
+        });
+
+        Self { port }
+    }
+
+    /// The URL of the streaming API
+    #[must_use]
+    pub fn streaming_url(&self) -> String {
+        format!("ws://127.0.0.1:{}/streaming/", self.port)
+    }
+
+    /// Get a client configuration for the server, to add a token to
+    #[must_use]
+    pub fn config(&self) -> fla_client::Config<fla_client::NoTokens> {
+        let port = self.port;
+        // The server is in this process, so there is no need to wait long before trying again.
+        let backoff = Backoff {
+            initial: Duration::from_millis(10),
+            max: Duration::from_millis(100),
+        };
+        fla_client::Config::new()
+            .auth_url(format!("http://127.0.0.1:{port}/"))
+            .owner_url(format!("http://127.0.0.1:{port}/"))
+            .streaming_url(self.streaming_url())
+            .streaming_backoff(backoff)
+            .wake_backoff(backoff)
+    }
+
+    /// Get a client connected to the server with a specified token
+    ///
+    /// # Panics
+    ///
+    /// Panics if the client cannot be created
+    #[must_use]
+    pub fn client(&self, token: Token) -> fla_client::Client {
+        self.config().token(token).build().unwrap()
+    }
+}
+
+/// Start a simulator server with a manual clock in this process
+///
+/// The clock only moves when advanced, and all random behaviour is derived from `seed`, so the
+/// same steps always give the same results.
+///
+/// # Returns
+///
+/// A client connected to the new server, with a token for all scopes
+///
+/// # Panics
+///
+/// Panics if the server cannot be started
+#[must_use]
+pub fn start_manual_server(start: DateTime<Utc>, seed: u64) -> fla_client::Client {
+    ManualServer::start(start, seed).client(get_token_for_all_scopes())
+}
+
+/// The next streaming event, `None` if the stream ended
+///
+/// # Panics
+///
+/// Panics if nothing happens within 5 seconds
+pub async fn next_event<S>(streaming: &mut S) -> Option<Result<StreamingEvent, StreamingError>>
+where
+    S: Stream<Item = Result<StreamingEvent, StreamingError>> + Unpin,
+{
+    tokio::time::timeout(Duration::from_secs(5), streaming.next())
+        .await
+        .unwrap()
+}
+
+/// The next row of streaming data, skipping hello messages
+///
+/// # Panics
+///
+/// Panics if anything else happens first, or nothing happens within 5 seconds
+pub async fn next_row<S>(streaming: &mut S) -> StreamingData
+where
+    S: Stream<Item = Result<StreamingEvent, StreamingError>> + Unpin,
+{
+    loop {
+        match next_event(streaming).await {
+            Some(Ok(StreamingEvent::Data(data))) => return data,
+            Some(Ok(StreamingEvent::Hello { .. })) => {}
+            event => panic!("expected data, got {event:?}"),
+        }
+    }
+}
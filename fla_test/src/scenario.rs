@@ -0,0 +1,667 @@
+//! Scenarios of timed simulator events
+//!
+//! A scenario is a TOML file with a timeline of steps for simulated vehicles. Each step waits for
+//! its trigger, performs an action and then checks its expectations. Scenarios move the simulator
+//! clock themselves, so the server must use a manual clock.
+//!
+//! ```toml
+//! vehicle = 123456789
+//!
+//! [routes.to_work]
+//! legs = [{ heading = 90, duration = "20m" }, { heading = 0, duration = "10m" }]
+//!
+//! [[steps]]
+//! at = "7h"
+//! action = "wake_up"
+//!
+//! [[steps]]
+//! after = "1m"
+//! action = "drive"
+//! route = "to_work"
+//!
+//! [[steps]]
+//! after = "2m"
+//! expect = { state = "Sleeping" }
+//! ```
+
+use std::{collections::BTreeMap, fmt::Display, path::Path, str::FromStr, time::Duration};
+
+use fla_client::Client;
+use fla_common::{
+    responses::TeslaResponse,
+    simulator::{SimulationStateEnum, VehicleStatus},
+    types::{VehicleId, VehicleStateEnum},
+};
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+
+/// How often a `when` condition is checked
+const POLL: Duration = Duration::from_secs(10);
+
+/// How long a `when` condition is waited for, unless the step has a timeout
+const DEFAULT_TIMEOUT: Duration = Duration::from_hours(24);
+
+/// A span of simulated time, written like `45s`, `10m`, `1h30m` or `2d`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct TimeSpan(pub Duration);
+
+impl FromStr for TimeSpan {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut seconds = 0;
+        let mut number = String::new();
+
+        for c in s.trim().chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+
+            let unit = match c {
+                'd' => 86_400,
+                'h' => 3_600,
+                'm' => 60,
+                's' => 1,
+                _ => return Err(format!("invalid time span {s:?}, unknown unit {c:?}")),
+            };
+            let value: u64 = number
+                .parse()
+                .map_err(|_| format!("invalid time span {s:?}, expected a number before {c:?}"))?;
+            seconds += value * unit;
+            number.clear();
+        }
+
+        if !number.is_empty() || s.trim().is_empty() {
+            return Err(format!(
+                "invalid time span {s:?}, expected a unit like 45s, 10m, 1h30m or 2d"
+            ));
+        }
+
+        Ok(Self(Duration::from_secs(seconds)))
+    }
+}
+
+impl Display for TimeSpan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut seconds = self.0.as_secs();
+        if seconds == 0 {
+            return write!(f, "0s");
+        }
+
+        for (unit, size) in [('d', 86_400), ('h', 3_600), ('m', 60), ('s', 1)] {
+            if seconds >= size {
+                write!(f, "{}{unit}", seconds / size)?;
+                seconds %= size;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for TimeSpan {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// A timeline of events for simulated vehicles
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Scenario {
+    /// The vehicle each step applies to, unless the step names another vehicle
+    pub vehicle: VehicleId,
+
+    /// Routes that `drive` steps can follow, by name
+    #[serde(default)]
+    pub routes: BTreeMap<String, Route>,
+
+    /// The timeline, run in order
+    pub steps: Vec<Step>,
+}
+
+/// A route driven one leg after another
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Route {
+    /// The legs of the route
+    pub legs: Vec<Leg>,
+}
+
+/// Part of a route driven in a straight line
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Leg {
+    /// Heading in degrees
+    pub heading: u16,
+
+    /// How long to drive
+    pub duration: TimeSpan,
+}
+
+/// One event of a scenario
+///
+/// A step has at most one trigger, `at`, `after` or `when`. Without a trigger it runs as soon as
+/// the previous step has finished.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Step {
+    /// The vehicle, defaults to the vehicle of the scenario
+    pub vehicle: Option<VehicleId>,
+
+    /// Run at this time after the start of the scenario
+    pub at: Option<TimeSpan>,
+
+    /// Run this long after the previous step
+    pub after: Option<TimeSpan>,
+
+    /// Run once the vehicle matches the condition
+    pub when: Option<Condition>,
+
+    /// How long to wait for the `when` condition, defaults to one day
+    pub timeout: Option<TimeSpan>,
+
+    /// What to do
+    pub action: Option<Action>,
+
+    /// Heading to drive in, for the `drive` action
+    pub heading: Option<u16>,
+
+    /// Route to drive, for the `drive` action; the vehicle parks at the end of the route
+    pub route: Option<String>,
+
+    /// What should be true once the action is done
+    pub expect: Option<Expectation>,
+}
+
+/// Something a step does to a vehicle
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Send a wake up request, like a client would
+    WakeUp,
+
+    /// Start driving
+    Drive,
+
+    /// Start charging
+    Charge,
+
+    /// Stop and stay idle, the vehicle falls asleep by its sleep policy
+    Park,
+
+    /// Fall asleep now
+    Sleep,
+}
+
+/// The state a `when` step waits for; every given value must match
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Condition {
+    /// The battery level is below this percentage
+    pub battery_below: Option<f64>,
+
+    /// The battery level is above this percentage
+    pub battery_above: Option<f64>,
+
+    /// The vehicle is in this state
+    pub state: Option<SimulationStateEnum>,
+}
+
+/// What should be true after a step; every given value is checked
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Expectation {
+    /// The vehicle is in this state
+    pub state: Option<SimulationStateEnum>,
+
+    /// The vehicle is reported as online or not by the vehicles API
+    pub online: Option<bool>,
+
+    /// The battery level is at least this percentage
+    pub battery_min: Option<f64>,
+
+    /// The battery level is at most this percentage
+    pub battery_max: Option<f64>,
+}
+
+/// An error loading or running a scenario
+#[derive(Error, Debug)]
+pub enum ScenarioError {
+    /// The file could not be read
+    #[error("Cannot read {path}: {source}")]
+    Read {
+        /// The file
+        path: String,
+
+        /// The reason
+        source: std::io::Error,
+    },
+
+    /// The TOML could not be parsed
+    #[error("{0}")]
+    Toml(#[from] toml::de::Error),
+
+    /// The scenario was parsed but has invalid values
+    #[error("Invalid scenario:\n{}", .0.join("\n"))]
+    Invalid(Vec<String>),
+
+    /// A step with `at` comes after the time it should run at
+    #[error("steps[{step}] should run at {at}, but the scenario is already at {elapsed}")]
+    Past {
+        /// Index of the step
+        step: usize,
+
+        /// When the step should run
+        at: TimeSpan,
+
+        /// How far the scenario is
+        elapsed: TimeSpan,
+    },
+
+    /// The server rejected a request
+    #[error("{0}")]
+    Request(#[from] fla_client::Error),
+
+    /// The server returned an error response
+    #[error("Error response: {0}")]
+    Response(String),
+}
+
+impl Condition {
+    fn holds(&self, status: &VehicleStatus) -> bool {
+        self.battery_below
+            .is_none_or(|level| status.battery_level < level)
+            && self
+                .battery_above
+                .is_none_or(|level| status.battery_level > level)
+            && self
+                .state
+                .is_none_or(|state| status.simulation_state == state)
+    }
+
+    const fn is_empty(&self) -> bool {
+        self.battery_below.is_none() && self.battery_above.is_none() && self.state.is_none()
+    }
+}
+
+impl Display for Condition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut parts = vec![];
+        if let Some(level) = self.battery_below {
+            parts.push(format!("battery below {level}%"));
+        }
+        if let Some(level) = self.battery_above {
+            parts.push(format!("battery above {level}%"));
+        }
+        if let Some(state) = self.state {
+            parts.push(format!("state is {state:?}"));
+        }
+        write!(f, "{}", parts.join(" and "))
+    }
+}
+
+impl Expectation {
+    const fn is_empty(&self) -> bool {
+        self.state.is_none()
+            && self.online.is_none()
+            && self.battery_min.is_none()
+            && self.battery_max.is_none()
+    }
+}
+
+/// The result of checking one expectation
+#[derive(Debug)]
+pub struct Outcome {
+    /// Index of the step
+    pub step: usize,
+
+    /// Time since the start of the scenario
+    pub elapsed: TimeSpan,
+
+    /// The vehicle checked
+    pub vehicle: VehicleId,
+
+    /// What was expected
+    pub expected: String,
+
+    /// What was found instead, `None` if the expectation held
+    pub failure: Option<String>,
+}
+
+impl Outcome {
+    /// Did the expectation hold?
+    #[must_use]
+    pub const fn passed(&self) -> bool {
+        self.failure.is_none()
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "steps[{}] at {}, vehicle {}: {}",
+            self.step, self.elapsed, self.vehicle, self.expected
+        )?;
+        match &self.failure {
+            None => write!(f, ": ok"),
+            Some(failure) => write!(f, ": FAILED, {failure}"),
+        }
+    }
+}
+
+/// The results of running a scenario
+#[derive(Debug, Default)]
+pub struct Report {
+    /// Every expectation checked, in order
+    pub outcomes: Vec<Outcome>,
+}
+
+impl Report {
+    /// Did every expectation hold?
+    #[must_use]
+    pub fn passed(&self) -> bool {
+        self.outcomes.iter().all(Outcome::passed)
+    }
+
+    /// The expectations that did not hold
+    pub fn failures(&self) -> impl Iterator<Item = &Outcome> {
+        self.outcomes.iter().filter(|outcome| !outcome.passed())
+    }
+}
+
+impl Display for Report {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for outcome in &self.outcomes {
+            writeln!(f, "{outcome}")?;
+        }
+        let failed = self.failures().count();
+        write!(
+            f,
+            "{} of {} expectations held",
+            self.outcomes.len() - failed,
+            self.outcomes.len()
+        )
+    }
+}
+
+impl Scenario {
+    /// Read and validate a scenario file
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the file cannot be read or parsed, or if the scenario is invalid.
+    pub fn load(path: &Path) -> Result<Self, ScenarioError> {
+        let text = std::fs::read_to_string(path).map_err(|source| ScenarioError::Read {
+            path: path.display().to_string(),
+            source,
+        })?;
+        text.parse()
+    }
+
+    /// Check that a scenario can be run
+    ///
+    /// # Errors
+    ///
+    /// Returns every problem found.
+    pub fn validate(&self) -> Result<(), ScenarioError> {
+        let mut errors = vec![];
+
+        for (name, route) in &self.routes {
+            if route.legs.is_empty() {
+                errors.push(format!("routes.{name}: at least one leg is required"));
+            }
+            for (index, leg) in route.legs.iter().enumerate() {
+                if leg.heading >= 360 {
+                    errors.push(format!(
+                        "routes.{name}.legs[{index}].heading: {} is not less than 360",
+                        leg.heading
+                    ));
+                }
+            }
+        }
+
+        for (index, step) in self.steps.iter().enumerate() {
+            let mut invalid = |message: String| errors.push(format!("steps[{index}]: {message}"));
+
+            let triggers = [step.at.is_some(), step.after.is_some(), step.when.is_some()];
+            if triggers.into_iter().filter(|t| *t).count() > 1 {
+                invalid("only one of at, after and when may be given".to_string());
+            }
+            if step.timeout.is_some() && step.when.is_none() {
+                invalid("timeout is only used with when".to_string());
+            }
+            if step.when.as_ref().is_some_and(Condition::is_empty) {
+                invalid("when needs at least one condition".to_string());
+            }
+            if step.expect.as_ref().is_some_and(Expectation::is_empty) {
+                invalid("expect needs at least one expectation".to_string());
+            }
+
+            if step.action != Some(Action::Drive)
+                && (step.heading.is_some() || step.route.is_some())
+            {
+                invalid("heading and route are only used with the drive action".to_string());
+            }
+            if step.heading.is_some() && step.route.is_some() {
+                invalid("only one of heading and route may be given".to_string());
+            }
+            if let Some(heading) = step.heading.filter(|heading| *heading >= 360) {
+                invalid(format!("heading {heading} is not less than 360"));
+            }
+            if let Some(route) = step
+                .route
+                .as_ref()
+                .filter(|route| !self.routes.contains_key(*route))
+            {
+                invalid(format!("unknown route {route:?}"));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(ScenarioError::Invalid(errors))
+        }
+    }
+
+    /// Run the scenario against a simulator with a manual clock
+    ///
+    /// Failed expectations and conditions that are never met are recorded in the report, and the
+    /// scenario carries on.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if a request to the simulator fails, or if a step comes after the time it
+    /// should run at.
+    pub async fn run(&self, client: &Client) -> Result<Report, ScenarioError> {
+        let mut runner = Runner {
+            client,
+            elapsed: Duration::ZERO,
+            report: Report::default(),
+        };
+
+        for (index, step) in self.steps.iter().enumerate() {
+            let id = step.vehicle.unwrap_or(self.vehicle);
+            if !runner.trigger(index, id, step).await? {
+                continue;
+            }
+            if let Some(action) = step.action {
+                runner.act(id, action, step, &self.routes).await?;
+            }
+            if let Some(expect) = &step.expect {
+                runner.check(index, id, expect).await?;
+            }
+        }
+
+        Ok(runner.report)
+    }
+}
+
+impl FromStr for Scenario {
+    type Err = ScenarioError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let scenario: Self = toml::from_str(s)?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+}
+
+fn response<T>(response: TeslaResponse<T>) -> Result<T, ScenarioError> {
+    match response {
+        TeslaResponse::Success { response } => Ok(response),
+        TeslaResponse::Error(error) => Err(ScenarioError::Response(format!("{error:?}"))),
+    }
+}
+
+struct Runner<'a> {
+    client: &'a Client,
+    elapsed: Duration,
+    report: Report,
+}
+
+impl Runner<'_> {
+    async fn advance(&mut self, duration: Duration) -> Result<(), ScenarioError> {
+        if !duration.is_zero() {
+            self.client.advance(duration).await?;
+            self.elapsed += duration;
+        }
+        Ok(())
+    }
+
+    async fn status(&self, id: VehicleId) -> Result<VehicleStatus, ScenarioError> {
+        response(self.client.simulator_vehicle(id).await?)
+    }
+
+    fn record(
+        &mut self,
+        step: usize,
+        vehicle: VehicleId,
+        expected: String,
+        failure: Option<String>,
+    ) {
+        self.report.outcomes.push(Outcome {
+            step,
+            elapsed: TimeSpan(self.elapsed),
+            vehicle,
+            expected,
+            failure,
+        });
+    }
+
+    /// Wait for the trigger of a step, returns `false` if it never happened
+    async fn trigger(
+        &mut self,
+        index: usize,
+        id: VehicleId,
+        step: &Step,
+    ) -> Result<bool, ScenarioError> {
+        if let Some(at) = step.at {
+            let wait = at.0.checked_sub(self.elapsed).ok_or(ScenarioError::Past {
+                step: index,
+                at,
+                elapsed: TimeSpan(self.elapsed),
+            })?;
+            self.advance(wait).await?;
+        }
+
+        if let Some(after) = step.after {
+            self.advance(after.0).await?;
+        }
+
+        if let Some(when) = &step.when {
+            let timeout = step.timeout.map_or(DEFAULT_TIMEOUT, |timeout| timeout.0);
+            let mut waited = Duration::ZERO;
+            loop {
+                if when.holds(&self.status(id).await?) {
+                    break;
+                }
+                if waited >= timeout {
+                    let failure = format!("not reached within {}", TimeSpan(timeout));
+                    self.record(index, id, format!("when {when}"), Some(failure));
+                    return Ok(false);
+                }
+                let poll = POLL.min(timeout.saturating_sub(waited));
+                self.advance(poll).await?;
+                waited += poll;
+            }
+        }
+
+        Ok(true)
+    }
+
+    async fn act(
+        &mut self,
+        id: VehicleId,
+        action: Action,
+        step: &Step,
+        routes: &BTreeMap<String, Route>,
+    ) -> Result<(), ScenarioError> {
+        let client = self.client;
+        match action {
+            Action::WakeUp => match client.wake_up(id).await {
+                // A sleeping vehicle is not available until it has woken up.
+                Err(fla_client::Error::VehicleUnavailable) => {}
+                result => _ = result?,
+            },
+            Action::Drive => {
+                if let Some(heading) = step.heading {
+                    client.steer(id, heading).await?;
+                }
+                client.simulate(id, SimulationStateEnum::Driving).await?;
+
+                if let Some(route) = step.route.as_ref().and_then(|route| routes.get(route)) {
+                    for leg in &route.legs {
+                        client.steer(id, leg.heading).await?;
+                        self.advance(leg.duration.0).await?;
+                    }
+                    client.simulate(id, SimulationStateEnum::Idle).await?;
+                }
+            }
+            Action::Charge => client.simulate(id, SimulationStateEnum::Charging).await?,
+            Action::Park => client.simulate(id, SimulationStateEnum::Idle).await?,
+            Action::Sleep => client.simulate(id, SimulationStateEnum::Sleeping).await?,
+        }
+        Ok(())
+    }
+
+    async fn check(
+        &mut self,
+        index: usize,
+        id: VehicleId,
+        expect: &Expectation,
+    ) -> Result<(), ScenarioError> {
+        let status = self.status(id).await?;
+        let level = status.battery_level;
+
+        if let Some(state) = expect.state {
+            let failure = (status.simulation_state != state)
+                .then(|| format!("state is {:?}", status.simulation_state));
+            self.record(index, id, format!("state is {state:?}"), failure);
+        }
+
+        if let Some(online) = expect.online {
+            let vehicle = response(self.client.get_vehicle(id).await?)?;
+            let is_online = vehicle.state == VehicleStateEnum::Online;
+            let failure = (is_online != online).then(|| format!("vehicle is {:?}", vehicle.state));
+            let expected = if online { "online" } else { "not online" };
+            self.record(index, id, format!("vehicle is {expected}"), failure);
+        }
+
+        if let Some(min) = expect.battery_min {
+            let failure = (level < min).then(|| format!("battery is {level:.1}%"));
+            self.record(index, id, format!("battery at least {min}%"), failure);
+        }
+
+        if let Some(max) = expect.battery_max {
+            let failure = (level > max).then(|| format!("battery is {level:.1}%"));
+            self.record(index, id, format!("battery at most {max}%"), failure);
+        }
+
+        Ok(())
+    }
+}
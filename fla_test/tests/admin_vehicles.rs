@@ -0,0 +1,166 @@
+//! Tests for adding, removing and resetting simulated vehicles
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use fla_client::Client;
+use fla_common::{
+    simulator::{
+        Fault, FaultKind, FleetBattery, FleetLocation, FleetVehicle, SimulationStateEnum,
+        VehicleModel, DEFAULT_USER,
+    },
+    types::{VehicleGuid, VehicleId, VehicleStateEnum},
+};
+use fla_test::start_manual_server;
+
+fn new_vehicle() -> FleetVehicle {
+    FleetVehicle {
+        id: VehicleId::new(42),
+        vehicle_id: VehicleGuid::new(4242),
+        vin: "7SAYGDEE5PA000042".to_string(),
+        display_name: "Test Model Y".to_string(),
+        model: VehicleModel::ModelY,
+        trim: None,
+        color: None,
+        option_codes: None,
+        location: FleetLocation::default(),
+        battery: FleetBattery {
+            level: 80.0,
+            charge_limit_soc: 90,
+        },
+        owner: DEFAULT_USER,
+        drivers: vec![],
+        state: SimulationStateEnum::Sleeping,
+    }
+}
+
+fn start() -> Client {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    start_manual_server(start, 0)
+}
+
+#[tokio::test]
+async fn test_add_and_remove() {
+    let client = start();
+    let id = VehicleId::new(42);
+
+    let status = client
+        .add_vehicle(&new_vehicle())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(status.vehicle, new_vehicle());
+    assert_eq!(status.simulation_state, SimulationStateEnum::Sleeping);
+
+    let vehicle = client
+        .get_vehicle(id)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(vehicle.display_name, "Test Model Y");
+    assert_eq!(vehicle.state, VehicleStateEnum::Offline);
+    assert!(vehicle.option_codes.unwrap().contains("MDLY"));
+
+    let statuses = client
+        .simulator_vehicles()
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    let ids: Vec<_> = statuses.iter().map(|s| s.vehicle.id).collect();
+    assert_eq!(
+        ids,
+        [VehicleId::new(123_456_789), VehicleId::new(123_456_000), id]
+    );
+
+    client.remove_vehicle(id).await.unwrap();
+    let status = client.get_vehicle(id).await.unwrap_err().status().unwrap();
+    assert_eq!(status.as_u16(), 404);
+    let status = client
+        .remove_vehicle(id)
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 404);
+}
+
+#[tokio::test]
+async fn test_add_invalid() {
+    let client = start();
+
+    client.add_vehicle(&new_vehicle()).await.unwrap();
+
+    // Same id as a vehicle that already exists.
+    let status = client
+        .add_vehicle(&new_vehicle())
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 400);
+
+    let vehicle = FleetVehicle {
+        id: VehicleId::new(43),
+        vehicle_id: VehicleGuid::new(4343),
+        vin: "not a vin".to_string(),
+        ..new_vehicle()
+    };
+    let status = client
+        .add_vehicle(&vehicle)
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 400);
+}
+
+#[tokio::test]
+async fn test_reset() {
+    let client = start();
+    let id = VehicleId::new(42);
+
+    client.add_vehicle(&new_vehicle()).await.unwrap();
+    client
+        .simulate(id, SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+    client
+        .add_fault(id, &Fault::new(FaultKind::DeviceNotAvailable))
+        .await
+        .unwrap();
+    client.advance(Duration::from_mins(1)).await.unwrap();
+
+    let status = client
+        .simulator_vehicle(id)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(status.simulation_state, SimulationStateEnum::Driving);
+    assert!(status.battery_level < 80.0);
+    assert_eq!(status.faults.len(), 1);
+
+    let status = client
+        .reset_vehicle(id)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(status.simulation_state, SimulationStateEnum::Sleeping);
+    assert!((status.battery_level - 80.0).abs() < f64::EPSILON);
+    assert!((status.latitude - FleetLocation::default().latitude).abs() < f64::EPSILON);
+    assert!(status.faults.is_empty());
+
+    let vehicle = client
+        .get_vehicle(id)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(vehicle.state, VehicleStateEnum::Offline);
+}
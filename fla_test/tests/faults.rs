@@ -0,0 +1,178 @@
+//! Tests for injecting faults into vehicles
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::{collections::HashSet, time::Duration};
+
+use chrono::{TimeZone, Utc};
+use fla_client::{Client, Error, StreamingEvent};
+use fla_common::{
+    simulator::{Fault, FaultKind, SimulationStateEnum},
+    streaming::{ErrorType, StreamingFields},
+    types::{VehicleDataEndpoint, VehicleGuid, VehicleId},
+};
+use fla_test::{next_event, next_row, start_manual_server};
+use tokio::time::Instant;
+
+fn id() -> VehicleId {
+    VehicleId::new(123_456_789)
+}
+
+async fn start() -> Client {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    let client = start_manual_server(start, 0);
+
+    client
+        .simulate(id(), SimulationStateEnum::IdleNoSleep)
+        .await
+        .unwrap();
+
+    client
+}
+
+/// Poll the vehicle data, returning the HTTP status code
+async fn poll(client: &Client) -> u16 {
+    let endpoints: HashSet<_> = [VehicleDataEndpoint::ChargeState].into();
+    match client.get_vehicle_data(id(), &endpoints).await {
+        Ok(_) => 200,
+        Err(err) => err.status().unwrap().as_u16(),
+    }
+}
+
+#[tokio::test]
+async fn test_device_not_available() {
+    let client = start().await;
+
+    let fault = Fault::new(FaultKind::DeviceNotAvailable).count(2);
+    let faults = client.add_fault(id(), &fault).await.unwrap();
+    assert_eq!(faults.get_response().unwrap(), vec![fault]);
+
+    assert_eq!(poll(&client).await, 408);
+    assert_eq!(poll(&client).await, 408);
+    assert_eq!(poll(&client).await, 200);
+
+    let faults = client.faults(id()).await.unwrap().get_response().unwrap();
+    assert!(faults.is_empty());
+}
+
+#[tokio::test]
+async fn test_device_unexpected_response() {
+    let client = start().await;
+
+    let fault = Fault::new(FaultKind::DeviceUnexpectedResponse);
+    client.add_fault(id(), &fault).await.unwrap();
+
+    assert_eq!(poll(&client).await, 540);
+    assert_eq!(poll(&client).await, 540);
+
+    let err = client.wake_up(id()).await.unwrap_err();
+    assert!(matches!(err, Error::VehicleError), "{err:?}");
+
+    client.clear_faults(id()).await.unwrap();
+    assert_eq!(poll(&client).await, 200);
+}
+
+#[tokio::test]
+async fn test_probability() {
+    async fn failures(seed: u64) -> Vec<u16> {
+        let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+        let client = start_manual_server(start, seed);
+
+        let fault = Fault::new(FaultKind::DeviceNotAvailable).probability(0.5);
+        client.add_fault(id(), &fault).await.unwrap();
+
+        let mut results = vec![];
+        for _ in 0..50 {
+            results.push(poll(&client).await);
+        }
+        results
+    }
+
+    let results = failures(7).await;
+    let failed = results.iter().filter(|x| **x == 408).count();
+    assert!((10..=40).contains(&failed), "{failed} requests failed");
+
+    // The same seed fails the same requests.
+    assert_eq!(failures(7).await, results);
+}
+
+#[tokio::test]
+async fn test_latency() {
+    let client = start().await;
+
+    let fault = Fault::new(FaultKind::Latency).count(1).latency_ms(500);
+    client.add_fault(id(), &fault).await.unwrap();
+
+    let begin = Instant::now();
+    assert_eq!(poll(&client).await, 200);
+    assert!(begin.elapsed() >= Duration::from_millis(500));
+
+    let begin = Instant::now();
+    assert_eq!(poll(&client).await, 200);
+    assert!(begin.elapsed() < Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn test_timeout() {
+    let client = start().await;
+
+    let fault = Fault::new(FaultKind::Timeout).count(1);
+    client.add_fault(id(), &fault).await.unwrap();
+
+    let begin = Instant::now();
+    assert_eq!(poll(&client).await, 408);
+    assert!(begin.elapsed() >= Duration::from_secs(10));
+
+    assert_eq!(poll(&client).await, 200);
+}
+
+#[tokio::test]
+async fn test_damaged_json() {
+    let client = start().await;
+
+    let fault = Fault::new(FaultKind::MalformedJson).count(1);
+    client.add_fault(id(), &fault).await.unwrap();
+    let fault = Fault::new(FaultKind::PartialJson).count(1);
+    client.add_fault(id(), &fault).await.unwrap();
+
+    // The error keeps the damaged body, to see what went wrong
+    let err = client.wake_up(id()).await.unwrap_err();
+    assert!(matches!(err, Error::Deserialize { .. }), "{err:?}");
+
+    let err = client.wake_up(id()).await.unwrap_err();
+    let Error::Deserialize { body, .. } = err else {
+        panic!("expected a deserialize error, got {err:?}");
+    };
+    assert!(!body.is_empty());
+
+    client.wake_up(id()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_drop_streaming() {
+    let client = start().await;
+
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+
+    let guid = VehicleGuid::new(999_456_789);
+    let mut streaming = client.streaming(guid, vec![StreamingFields::Speed]);
+    next_row(&mut streaming).await;
+
+    let fault = Fault::new(FaultKind::DropStreaming).count(1);
+    client.add_fault(id(), &fault).await.unwrap();
+    client.advance(Duration::from_secs(1)).await.unwrap();
+
+    let event = next_event(&mut streaming).await;
+    let Some(Ok(StreamingEvent::Error(error))) = event else {
+        panic!("expected an error, got {event:?}");
+    };
+    assert!(matches!(error.error_type, ErrorType::VehicleDisconnected));
+
+    // The client subscribes again, the fault has been used up.
+    next_row(&mut streaming).await;
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    next_row(&mut streaming).await;
+}
@@ -0,0 +1,106 @@
+//! Tests for the HTTP client options
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::time::Duration;
+
+use fla_client::{Client, Config, Error, HasToken};
+use fla_common::types::VehicleId;
+use fla_test::get_token_for_all_scopes;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// A server that accepts connections, but never answers
+async fn silent_server() -> (TcpListener, String) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/", listener.local_addr().unwrap());
+    (listener, url)
+}
+
+fn client(url: &str, options: impl FnOnce(Config<HasToken>) -> Config<HasToken>) -> Client {
+    let config = Config::new()
+        .auth_url(url)
+        .owner_url(url)
+        .token(get_token_for_all_scopes());
+    options(config).build().unwrap()
+}
+
+async fn read_request(stream: &mut TcpStream) -> String {
+    let mut request = vec![0; 4096];
+    let len = stream.read(&mut request).await.unwrap();
+    String::from_utf8_lossy(&request[..len]).to_lowercase()
+}
+
+#[tokio::test]
+async fn test_user_agent() {
+    let (listener, url) = silent_server().await;
+    let client = client(&url, |config| {
+        config
+            .user_agent("fleet-app/1.0")
+            .timeout(Duration::from_millis(500))
+    });
+
+    let request =
+        tokio::spawn(async move { client.get_vehicle(VehicleId::new(123_456_789)).await });
+    let (mut stream, _) = listener.accept().await.unwrap();
+    assert!(read_request(&mut stream)
+        .await
+        .contains("user-agent: fleet-app/1.0"));
+
+    drop(stream);
+    assert!(request.await.unwrap().is_err());
+}
+
+#[tokio::test]
+async fn test_timeout() {
+    let (listener, url) = silent_server().await;
+    let client = client(&url, |config| config.timeout(Duration::from_millis(200)));
+
+    let request =
+        tokio::spawn(async move { client.get_vehicle(VehicleId::new(123_456_789)).await });
+    let (_stream, _) = listener.accept().await.unwrap();
+
+    let err = tokio::time::timeout(Duration::from_secs(5), request)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap_err();
+    assert!(
+        matches!(&err, Error::Request(err) if err.is_timeout()),
+        "{err:?}"
+    );
+}
+
+#[tokio::test]
+async fn test_shared_http_client() {
+    let (listener, url) = silent_server().await;
+    let http = reqwest::Client::builder()
+        .user_agent("shared/1.0")
+        .build()
+        .unwrap();
+    let first = client(&url, |config| config.http_client(http.clone()));
+    let second = client(&url, |config| config.http_client(http));
+
+    let requests = tokio::spawn(async move {
+        let id = VehicleId::new(123_456_789);
+        (first.get_vehicle(id).await, second.get_vehicle(id).await)
+    });
+
+    // Both clients send their requests over the same connection
+    let (mut stream, _) = listener.accept().await.unwrap();
+    for _ in 0..2 {
+        assert!(read_request(&mut stream)
+            .await
+            .contains("user-agent: shared/1.0"));
+        stream
+            .write_all(b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\n\r\n")
+            .await
+            .unwrap();
+    }
+
+    let (first, second) = requests.await.unwrap();
+    assert!(matches!(first, Err(Error::NotFound)), "{first:?}");
+    assert!(matches!(second, Err(Error::NotFound)), "{second:?}");
+}
@@ -0,0 +1,176 @@
+//! Tests for invitations to share a vehicle
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use fla_client::{Client, StreamingError};
+use fla_common::{
+    simulator::SimulationStateEnum,
+    streaming::StreamingFields,
+    types::{InvitationState, VehicleGuid, VehicleId},
+};
+use fla_server::simulator::data::DRIVER_USER;
+use fla_test::{get_token_for_all_scopes, next_event, next_row, ManualServer};
+
+fn id() -> VehicleId {
+    VehicleId::new(123_456_789)
+}
+
+fn guid() -> VehicleGuid {
+    VehicleGuid::new(999_456_789)
+}
+
+/// Start a server, returning clients for the owner and for a user the vehicle is not shared with
+async fn start() -> (Client, Client) {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    let server = ManualServer::start(start, 0);
+    let owner = server.client(get_token_for_all_scopes());
+    let token = owner.user_token(DRIVER_USER).await.unwrap();
+    let other = server.client(token.into());
+    (owner, other)
+}
+
+fn status<T>(result: Result<T, fla_client::Error>) -> u16 {
+    result.map(|_| ()).unwrap_err().status().unwrap().as_u16()
+}
+
+#[tokio::test]
+async fn test_redeem_and_revoke() {
+    let (owner, driver) = start().await;
+    assert_eq!(status(driver.get_vehicle(id()).await), 404);
+
+    let invitation = owner
+        .create_invitation(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(invitation.state, InvitationState::Pending);
+    assert_eq!(invitation.vin, "5YJ3E1EA7JF000789");
+
+    let redeemed = driver
+        .redeem_invitation(invitation.code())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(redeemed.vehicle_id_s, id().to_string());
+    driver.get_vehicle(id()).await.unwrap();
+
+    let invitations = owner
+        .invitations(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(invitations.len(), 1);
+    assert_eq!(invitations[0].state, InvitationState::Redeemed);
+    assert!(invitations[0].redeemed_at.is_some());
+
+    // An invitation can only be redeemed once, and only the owner manages invitations
+    assert_eq!(
+        status(driver.redeem_invitation(invitation.code()).await),
+        400
+    );
+    assert_eq!(status(driver.invitations(id()).await), 403);
+
+    owner
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+    let mut streaming = driver.streaming(guid(), vec![StreamingFields::Speed]);
+    next_row(&mut streaming).await;
+
+    owner.revoke_invitation(id(), invitation.id).await.unwrap();
+    assert_eq!(status(driver.get_vehicle(id()).await), 404);
+
+    // The stream ends without waiting for more data
+    assert!(matches!(
+        next_event(&mut streaming).await,
+        Some(Err(StreamingError::Rejected(_)))
+    ));
+    assert!(next_event(&mut streaming).await.is_none());
+}
+
+#[tokio::test]
+async fn test_invalid_redeem() {
+    let (owner, driver) = start().await;
+
+    assert_eq!(status(driver.redeem_invitation("nope").await), 404);
+
+    // The owner already has access
+    let invitation = owner
+        .create_invitation(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(
+        status(owner.redeem_invitation(invitation.code()).await),
+        400
+    );
+
+    // A revoked invitation cannot be redeemed
+    owner.revoke_invitation(id(), invitation.id).await.unwrap();
+    assert_eq!(
+        status(driver.redeem_invitation(invitation.code()).await),
+        400
+    );
+
+    // An expired invitation cannot be redeemed
+    let invitation = owner
+        .create_invitation(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    owner.advance(Duration::from_hours(7 * 24)).await.unwrap();
+    assert_eq!(
+        status(driver.redeem_invitation(invitation.code()).await),
+        400
+    );
+}
+
+#[tokio::test]
+async fn test_forget_invitations() {
+    let (owner, driver) = start().await;
+
+    let create = || async {
+        owner
+            .create_invitation(id())
+            .await
+            .unwrap()
+            .get_response()
+            .unwrap()
+    };
+
+    // Resetting the vehicle forgets its invitations, and the drivers that redeemed them
+    let redeemed = create().await;
+    driver.redeem_invitation(redeemed.code()).await.unwrap();
+    let pending = create().await;
+    owner.reset_vehicle(id()).await.unwrap();
+    assert_eq!(status(driver.redeem_invitation(pending.code()).await), 404);
+    assert_eq!(status(driver.get_vehicle(id()).await), 404);
+    let invitations = owner.invitations(id()).await.unwrap().get_response();
+    assert!(invitations.unwrap().is_empty());
+
+    // A vehicle added again with the same id has none of the old invitations
+    let pending = create().await;
+    let vehicle = owner.remove_vehicle(id()).await.unwrap();
+    owner
+        .add_vehicle(&vehicle.get_response().unwrap().vehicle)
+        .await
+        .unwrap();
+    assert_eq!(status(driver.redeem_invitation(pending.code()).await), 404);
+
+    // Restoring a snapshot forgets every invitation
+    owner.save_snapshot("before").await.unwrap();
+    let pending = create().await;
+    owner.restore_snapshot("before").await.unwrap();
+    assert_eq!(status(driver.redeem_invitation(pending.code()).await), 404);
+
+    // New invitations never reuse the code of a forgotten one
+    assert_ne!(create().await.code(), pending.code());
+}
@@ -0,0 +1,166 @@
+//! Tests for parked vehicles changing over time
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::{collections::HashSet, time::Duration};
+
+use chrono::{TimeZone, Utc};
+use fla_client::Client;
+use fla_common::{
+    simulator::{ParkedPolicy, SimulationStateEnum, TyreValues},
+    types::{VehicleData, VehicleDataEndpoint, VehicleId},
+};
+use fla_test::start_manual_server;
+
+fn id() -> VehicleId {
+    VehicleId::new(123_456_789)
+}
+
+/// Start a server at 02:00 local time, the vehicle is in UTC-7
+async fn start(policy: ParkedPolicy) -> Client {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    let client = start_manual_server(start, 0);
+
+    client.set_parked_policy(id(), &policy).await.unwrap();
+    client
+        .simulate(id(), SimulationStateEnum::IdleNoSleep)
+        .await
+        .unwrap();
+
+    client
+}
+
+async fn advance(client: &Client, seconds: u64) {
+    client.advance(Duration::from_secs(seconds)).await.unwrap();
+}
+
+async fn vehicle_data(client: &Client) -> VehicleData {
+    let endpoints: HashSet<_> = [
+        VehicleDataEndpoint::ChargeState,
+        VehicleDataEndpoint::ClimateState,
+        VehicleDataEndpoint::VehicleState,
+    ]
+    .into();
+
+    client
+        .get_vehicle_data(id(), &endpoints)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn test_awake_drain() {
+    let client = start(ParkedPolicy {
+        awake_drain_per_hour: 1.0,
+        ..ParkedPolicy::default()
+    })
+    .await;
+
+    let data = vehicle_data(&client).await;
+    assert_eq!(data.charge_state.unwrap().battery_level, 42);
+
+    advance(&client, 10 * 3600 + 1800).await;
+
+    let charge_state = vehicle_data(&client).await.charge_state.unwrap();
+    assert_eq!(charge_state.battery_level, 31);
+    assert_eq!(charge_state.usable_battery_level, 31);
+    assert!((charge_state.battery_range - 93.0).abs() < f32::EPSILON);
+}
+
+#[tokio::test]
+async fn test_asleep_drain() {
+    let client = start(ParkedPolicy {
+        awake_drain_per_hour: 1.0,
+        asleep_drain_per_hour: 0.1,
+        ..ParkedPolicy::default()
+    })
+    .await;
+
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+
+    advance(&client, 25 * 3600).await;
+
+    client
+        .simulate(id(), SimulationStateEnum::IdleNoSleep)
+        .await
+        .unwrap();
+
+    let charge_state = vehicle_data(&client).await.charge_state.unwrap();
+    assert_eq!(charge_state.battery_level, 39);
+}
+
+#[tokio::test]
+async fn test_day_cycle() {
+    let client = start(ParkedPolicy {
+        mean_outside_temp: 15.0,
+        daily_temp_range: 10.0,
+        ..ParkedPolicy::default()
+    })
+    .await;
+
+    // 03:00 local time, coldest time of the day.
+    advance(&client, 3600).await;
+    let climate_state = vehicle_data(&client).await.climate_state.unwrap();
+    assert!((climate_state.outside_temp - 10.0).abs() < 0.01);
+    assert!((climate_state.inside_temp - 10.0).abs() < 0.01);
+
+    // 15:00 local time, warmest time of the day and the sun is heating the cabin.
+    advance(&client, 12 * 3600).await;
+    let climate_state = vehicle_data(&client).await.climate_state.unwrap();
+    assert!((climate_state.outside_temp - 20.0).abs() < 0.01);
+    assert!(climate_state.inside_temp > 25.0);
+}
+
+#[tokio::test]
+async fn test_tpms_cold_weather() {
+    let client = start(ParkedPolicy {
+        mean_outside_temp: -20.0,
+        daily_temp_range: 0.0,
+        ..ParkedPolicy::default()
+    })
+    .await;
+
+    advance(&client, 60).await;
+
+    let vehicle_state = vehicle_data(&client).await.vehicle_state.unwrap();
+    assert!(vehicle_state.tpms_pressure_fl < 2.6);
+    assert!(vehicle_state.tpms_soft_warning_fl);
+    assert!(vehicle_state.tpms_soft_warning_fr);
+    assert!(vehicle_state.tpms_soft_warning_rl);
+    assert!(vehicle_state.tpms_soft_warning_rr);
+    assert!(!vehicle_state.tpms_hard_warning_fl);
+}
+
+#[tokio::test]
+async fn test_tpms_leak() {
+    let client = start(ParkedPolicy {
+        mean_outside_temp: 20.0,
+        daily_temp_range: 0.0,
+        tyre_leak_per_day: TyreValues {
+            fl: 0.5,
+            ..TyreValues::default()
+        },
+        ..ParkedPolicy::default()
+    })
+    .await;
+
+    advance(&client, 24 * 3600).await;
+
+    let vehicle_state = vehicle_data(&client).await.vehicle_state.unwrap();
+    assert!((vehicle_state.tpms_pressure_fl - 2.6).abs() < 0.001);
+    assert!(vehicle_state.tpms_soft_warning_fl);
+    assert!(!vehicle_state.tpms_hard_warning_fl);
+    assert!(!vehicle_state.tpms_soft_warning_fr);
+
+    advance(&client, 24 * 3600).await;
+
+    let vehicle_state = vehicle_data(&client).await.vehicle_state.unwrap();
+    assert!((vehicle_state.tpms_pressure_fl - 2.1).abs() < 0.001);
+    assert!(vehicle_state.tpms_hard_warning_fl);
+    assert!(!vehicle_state.tpms_hard_warning_fr);
+}
@@ -0,0 +1,164 @@
+//! Tests for limiting the rate of API requests
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::{collections::HashSet, time::Duration};
+
+use chrono::{TimeZone, Utc};
+use fla_client::{Client, Error};
+use fla_common::{
+    simulator::{RateBudget, RateBudgets, RateLimits, SimulationStateEnum},
+    types::{VehicleDataEndpoint, VehicleId},
+};
+use fla_test::start_manual_server;
+
+fn id() -> VehicleId {
+    VehicleId::new(123_456_789)
+}
+
+async fn start(limits: RateLimits) -> Client {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    let client = start_manual_server(start, 0);
+
+    client
+        .simulate(id(), SimulationStateEnum::IdleNoSleep)
+        .await
+        .unwrap();
+
+    let result = client.set_rate_limits(&limits).await.unwrap();
+    assert_eq!(result.get_response().unwrap(), limits);
+
+    client
+}
+
+/// Poll the vehicle data, returning the HTTP status code
+async fn poll(client: &Client) -> u16 {
+    let endpoints: HashSet<_> = [VehicleDataEndpoint::ChargeState].into();
+    match client.get_vehicle_data(id(), &endpoints).await {
+        Ok(_) => 200,
+        Err(err) => err.status().unwrap().as_u16(),
+    }
+}
+
+/// Wake up the vehicle, returning the HTTP status code
+async fn wake_up(client: &Client) -> u16 {
+    match client.wake_up(id()).await {
+        Ok(_) => 200,
+        Err(err) => err.status().unwrap().as_u16(),
+    }
+}
+
+#[tokio::test]
+async fn test_data_budget() {
+    let client = start(RateLimits {
+        per_token: RateBudgets {
+            data: Some(RateBudget {
+                requests: 2,
+                period_seconds: 60,
+            }),
+            ..RateBudgets::default()
+        },
+        ..RateLimits::default()
+    })
+    .await;
+
+    assert_eq!(poll(&client).await, 200);
+    assert_eq!(poll(&client).await, 200);
+    assert_eq!(poll(&client).await, 429);
+
+    // Wake up requests have their own budget.
+    assert_eq!(wake_up(&client).await, 200);
+
+    // The server says when the budget has room again.
+    client.advance(Duration::from_secs(59)).await.unwrap();
+    let endpoints: HashSet<_> = [VehicleDataEndpoint::ChargeState].into();
+    let err = client.get_vehicle_data(id(), &endpoints).await.unwrap_err();
+    let Error::RateLimited { retry_after } = err else {
+        panic!("expected rate limited, got {err:?}");
+    };
+    assert_eq!(retry_after, Some(Duration::from_secs(1)));
+
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    assert_eq!(poll(&client).await, 200);
+}
+
+#[tokio::test]
+async fn test_refresh_keeps_budget() {
+    let client = start(RateLimits {
+        per_token: RateBudgets {
+            data: Some(RateBudget {
+                requests: 1,
+                period_seconds: 60,
+            }),
+            ..RateBudgets::default()
+        },
+        ..RateLimits::default()
+    })
+    .await;
+
+    assert_eq!(poll(&client).await, 200);
+    assert_eq!(poll(&client).await, 429);
+
+    // A new token for the same user does not get a new budget.
+    let before = client.token().access_token;
+    client.refresh_token().await.unwrap();
+    assert_ne!(client.token().access_token, before);
+    assert_eq!(poll(&client).await, 429);
+}
+
+#[tokio::test]
+async fn test_wake_up_budget() {
+    let client = start(RateLimits {
+        per_vehicle: RateBudgets {
+            wake_up: Some(RateBudget {
+                requests: 1,
+                period_seconds: 300,
+            }),
+            ..RateBudgets::default()
+        },
+        ..RateLimits::default()
+    })
+    .await;
+
+    assert_eq!(wake_up(&client).await, 200);
+    assert_eq!(wake_up(&client).await, 429);
+    assert_eq!(poll(&client).await, 200);
+
+    // Changing the limits starts every budget again.
+    client
+        .set_rate_limits(&RateLimits::default())
+        .await
+        .unwrap();
+    assert_eq!(wake_up(&client).await, 200);
+    assert_eq!(wake_up(&client).await, 200);
+}
+
+#[tokio::test]
+async fn test_wake_and_wait_rate_limited() {
+    let client = start(RateLimits {
+        per_vehicle: RateBudgets {
+            wake_up: Some(RateBudget {
+                requests: 1,
+                period_seconds: 300,
+            }),
+            ..RateBudgets::default()
+        },
+        ..RateLimits::default()
+    })
+    .await;
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+    assert_eq!(wake_up(&client).await, 408);
+
+    // The server asks to wait longer than the timeout, so there is no point trying
+    let err = client
+        .wake_and_wait(id(), Duration::from_secs(10))
+        .await
+        .unwrap_err();
+    let Error::RateLimited { retry_after } = err else {
+        panic!("expected rate limited, got {err:?}");
+    };
+    assert_eq!(retry_after, Some(Duration::from_mins(5)));
+}
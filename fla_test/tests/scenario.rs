@@ -0,0 +1,153 @@
+//! Tests for running scenarios
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::{collections::HashSet, path::Path, time::Duration};
+
+use chrono::{TimeZone, Utc};
+use fla_client::Client;
+use fla_common::{
+    simulator::SimulationStateEnum,
+    types::{VehicleDataEndpoint, VehicleId},
+};
+use fla_test::{
+    scenario::{Scenario, ScenarioError, TimeSpan},
+    start_manual_server,
+};
+
+fn start() -> Client {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 0, 0, 0).unwrap();
+    start_manual_server(start, 0)
+}
+
+#[test]
+fn test_time_span() {
+    let span: TimeSpan = "1h30m".parse().unwrap();
+    assert_eq!(span.0, Duration::from_mins(90));
+    assert_eq!(span.to_string(), "1h30m");
+
+    let span: TimeSpan = "2d5s".parse().unwrap();
+    assert_eq!(span.to_string(), "2d5s");
+
+    assert!("30".parse::<TimeSpan>().is_err());
+    assert!("5y".parse::<TimeSpan>().is_err());
+    assert!("".parse::<TimeSpan>().is_err());
+}
+
+#[test]
+fn test_invalid() {
+    let text = r#"
+        vehicle = 123456789
+
+        [[steps]]
+        at = "1h"
+        after = "1m"
+        action = "park"
+        heading = 90
+
+        [[steps]]
+        action = "drive"
+        route = "nowhere"
+    "#;
+
+    let Err(ScenarioError::Invalid(errors)) = text.parse::<Scenario>() else {
+        panic!("scenario should be invalid");
+    };
+    assert_eq!(
+        errors,
+        [
+            "steps[0]: only one of at, after and when may be given",
+            "steps[0]: heading and route are only used with the drive action",
+            "steps[1]: unknown route \"nowhere\"",
+        ]
+    );
+
+    let err = "vehicle = 1\nsteps = [{ action = \"fly\" }]"
+        .parse::<Scenario>()
+        .unwrap_err();
+    assert!(err.to_string().contains("fly"), "{err}");
+}
+
+#[tokio::test]
+async fn test_commute() {
+    let client = start();
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("scenarios/commute.toml");
+    let scenario = Scenario::load(&path).unwrap();
+
+    let report = scenario.run(&client).await.unwrap();
+    assert!(report.passed(), "{report}");
+    assert_eq!(report.outcomes.len(), 10);
+}
+
+#[tokio::test]
+async fn test_steer_while_driving() {
+    let client = start();
+    let id = VehicleId::new(123_456_789);
+
+    client
+        .simulate(id, SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+    client.steer(id, 90).await.unwrap();
+    client.advance(Duration::from_secs(1)).await.unwrap();
+
+    let endpoints: HashSet<_> = [VehicleDataEndpoint::DriveState].into();
+    let drive_state = client
+        .get_vehicle_data(id, &endpoints)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap()
+        .drive_state
+        .unwrap();
+    assert_eq!(drive_state.heading, 90);
+}
+
+#[tokio::test]
+async fn test_failures_reported() {
+    let client = start();
+    let text = r#"
+        vehicle = 123456789
+
+        [[steps]]
+        action = "charge"
+        expect = { state = "Driving", battery_min = 40.0 }
+
+        [[steps]]
+        when = { battery_below = 10.0 }
+        timeout = "1m"
+        action = "park"
+
+        [[steps]]
+        after = "10m"
+        expect = { state = "Sleeping" }
+    "#;
+    let scenario: Scenario = text.parse().unwrap();
+
+    let report = scenario.run(&client).await.unwrap();
+    assert!(!report.passed());
+
+    let lines: Vec<_> = report.outcomes.iter().map(ToString::to_string).collect();
+    assert_eq!(
+        lines,
+        [
+            "steps[0] at 0s, vehicle 123456789: state is Driving: FAILED, state is Charging",
+            "steps[0] at 0s, vehicle 123456789: battery at least 40%: ok",
+            "steps[1] at 1m, vehicle 123456789: when battery below 10%: FAILED, not reached within 1m",
+            "steps[2] at 11m, vehicle 123456789: state is Sleeping: ok",
+        ]
+    );
+
+    let at = r#"
+        vehicle = 123456789
+
+        [[steps]]
+        at = "1h"
+
+        [[steps]]
+        at = "30m"
+    "#;
+    let scenario: Scenario = at.parse().unwrap();
+    let err = scenario.run(&client).await.unwrap_err();
+    assert!(matches!(err, ScenarioError::Past { step: 1, .. }), "{err}");
+}
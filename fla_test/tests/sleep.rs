@@ -0,0 +1,266 @@
+//! Tests for vehicles falling asleep and waking up
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use chrono::{TimeZone, Utc};
+use fla_client::{Backoff, Client, Error};
+use fla_common::{
+    simulator::{SimulationStateEnum, SleepPolicy},
+    types::{VehicleDataEndpoint, VehicleId, VehicleStateEnum},
+};
+use fla_test::{get_token_for_all_scopes, start_manual_server, ManualServer};
+
+fn id() -> VehicleId {
+    VehicleId::new(123_456_789)
+}
+
+async fn start(policy: SleepPolicy) -> Client {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    let client = start_manual_server(start, 0);
+
+    client.set_sleep_policy(id(), &policy).await.unwrap();
+    client
+        .simulate(id(), SimulationStateEnum::Idle)
+        .await
+        .unwrap();
+
+    client
+}
+
+async fn state(client: &Client) -> VehicleStateEnum {
+    client
+        .get_vehicle(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap()
+        .state
+}
+
+/// Poll the vehicle data, returning the HTTP status code
+async fn poll(client: &Client) -> u16 {
+    let endpoints: HashSet<_> = [VehicleDataEndpoint::ChargeState].into();
+    match client.get_vehicle_data(id(), &endpoints).await {
+        Ok(_) => 200,
+        Err(err) => err.status().unwrap().as_u16(),
+    }
+}
+
+async fn advance(client: &Client, seconds: u64) {
+    client.advance(Duration::from_secs(seconds)).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_sleep_policy() {
+    let policy = SleepPolicy {
+        idle_timeout_seconds: 900,
+        wake_latency_seconds: 30,
+        polling_keeps_awake: false,
+        drowsy_seconds: 0,
+    };
+    let client = start(policy).await;
+
+    let response = client.sleep_policy(id()).await.unwrap().get_response();
+    assert_eq!(response.unwrap(), policy);
+}
+
+#[tokio::test]
+async fn test_polling_keeps_awake() {
+    let client = start(SleepPolicy {
+        idle_timeout_seconds: 900,
+        polling_keeps_awake: true,
+        ..SleepPolicy::default()
+    })
+    .await;
+
+    // Polling every 10 minutes for an hour never lets the car sleep.
+    for _ in 0..6 {
+        advance(&client, 600).await;
+        assert_eq!(poll(&client).await, 200);
+        assert_eq!(state(&client).await, VehicleStateEnum::Online);
+    }
+}
+
+#[tokio::test]
+async fn test_polling_allows_sleep() {
+    let client = start(SleepPolicy {
+        idle_timeout_seconds: 900,
+        polling_keeps_awake: false,
+        ..SleepPolicy::default()
+    })
+    .await;
+
+    advance(&client, 600).await;
+    assert_eq!(poll(&client).await, 200);
+
+    advance(&client, 299).await;
+    assert_eq!(poll(&client).await, 200);
+    assert_eq!(state(&client).await, VehicleStateEnum::Online);
+
+    advance(&client, 1).await;
+    assert_eq!(poll(&client).await, 408);
+    assert_eq!(state(&client).await, VehicleStateEnum::Offline);
+}
+
+#[tokio::test]
+async fn test_wake_latency() {
+    let client = start(SleepPolicy {
+        wake_latency_seconds: 30,
+        ..SleepPolicy::default()
+    })
+    .await;
+
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+
+    let err = client.wake_up(id()).await.unwrap_err();
+    assert!(matches!(err, Error::VehicleUnavailable), "{err:?}");
+
+    advance(&client, 29).await;
+    assert_eq!(poll(&client).await, 408);
+    assert_eq!(state(&client).await, VehicleStateEnum::Offline);
+
+    advance(&client, 1).await;
+    assert_eq!(poll(&client).await, 200);
+    assert_eq!(state(&client).await, VehicleStateEnum::Online);
+}
+
+#[tokio::test]
+async fn test_drowsy() {
+    let client = start(SleepPolicy {
+        idle_timeout_seconds: 600,
+        polling_keeps_awake: false,
+        drowsy_seconds: 120,
+        ..SleepPolicy::default()
+    })
+    .await;
+
+    // The car stops answering, but is still listed as online.
+    advance(&client, 600).await;
+    assert_eq!(poll(&client).await, 408);
+    assert_eq!(state(&client).await, VehicleStateEnum::Online);
+
+    advance(&client, 120).await;
+    assert_eq!(poll(&client).await, 408);
+    assert_eq!(state(&client).await, VehicleStateEnum::Offline);
+}
+
+#[tokio::test]
+async fn test_wake_drowsy() {
+    let client = start(SleepPolicy {
+        idle_timeout_seconds: 600,
+        polling_keeps_awake: false,
+        drowsy_seconds: 120,
+        ..SleepPolicy::default()
+    })
+    .await;
+
+    // Waking a drowsy car keeps it online rather than waiting for the wake latency.
+    advance(&client, 600).await;
+    let vehicle = client.wake_up(id()).await.unwrap().get_response().unwrap();
+    assert_eq!(vehicle.state, VehicleStateEnum::Online);
+    assert_eq!(poll(&client).await, 200);
+
+    // The idle timer starts again from the wake up.
+    advance(&client, 599).await;
+    assert_eq!(poll(&client).await, 200);
+    assert_eq!(state(&client).await, VehicleStateEnum::Online);
+
+    advance(&client, 1).await;
+    assert_eq!(poll(&client).await, 408);
+}
+
+#[tokio::test]
+async fn test_wake_and_wait() {
+    let client = Arc::new(start(SleepPolicy::default()).await);
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+
+    // Ask first, so the vehicle is online 60 seconds from now
+    let err = client.wake_up(id()).await.unwrap_err();
+    assert!(matches!(err, Error::VehicleUnavailable), "{err:?}");
+
+    let waiting = {
+        let client = client.clone();
+        tokio::spawn(async move { client.wake_and_wait(id(), Duration::from_secs(10)).await })
+    };
+
+    advance(&client, 59).await;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert!(!waiting.is_finished());
+
+    advance(&client, 1).await;
+    let vehicle = tokio::time::timeout(Duration::from_secs(5), waiting)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert_eq!(vehicle.id, id());
+    assert_eq!(vehicle.state, VehicleStateEnum::Online);
+}
+
+#[tokio::test]
+async fn test_wake_and_wait_checks_at_timeout() {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    let server = ManualServer::start(start, 0);
+    let backoff = Backoff {
+        initial: Duration::from_millis(500),
+        max: Duration::from_secs(10),
+    };
+    let client = server
+        .config()
+        .wake_backoff(backoff)
+        .token(get_token_for_all_scopes())
+        .build()
+        .map(Arc::new)
+        .unwrap();
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+
+    // Checks at 0, 0.5 and 1.5 seconds, the next wait of 2 seconds would go past the timeout.
+    // The vehicle comes online in between, and is seen by the check at the timeout.
+    let waiting = {
+        let client = client.clone();
+        tokio::spawn(async move { client.wake_and_wait(id(), Duration::from_secs(2)).await })
+    };
+    tokio::time::sleep(Duration::from_millis(1700)).await;
+    advance(&client, 60).await;
+
+    let vehicle = waiting.await.unwrap().unwrap();
+    assert_eq!(vehicle.state, VehicleStateEnum::Online);
+}
+
+#[tokio::test]
+async fn test_wake_and_wait_timeout() {
+    let client = start(SleepPolicy::default()).await;
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+
+    // The clock does not move, so the vehicle never wakes up
+    let err = client
+        .wake_and_wait(id(), Duration::from_millis(200))
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::VehicleUnavailable), "{err:?}");
+
+    // An awake vehicle is online straight away
+    client
+        .simulate(id(), SimulationStateEnum::Idle)
+        .await
+        .unwrap();
+    let vehicle = client
+        .wake_and_wait(id(), Duration::from_millis(200))
+        .await
+        .unwrap();
+    assert_eq!(vehicle.state, VehicleStateEnum::Online);
+}
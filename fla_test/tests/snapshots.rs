@@ -0,0 +1,159 @@
+//! Tests for snapshots and restores of the simulated vehicles
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::time::Duration;
+
+use chrono::{TimeZone, Utc};
+use fla_client::Client;
+use fla_common::{
+    simulator::{
+        Fault, FaultKind, FleetBattery, FleetLocation, FleetVehicle, SimulationStateEnum,
+        VehicleModel, DEFAULT_USER,
+    },
+    types::{VehicleGuid, VehicleId},
+};
+use fla_test::start_manual_server;
+
+fn start() -> Client {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    start_manual_server(start, 0)
+}
+
+#[tokio::test]
+async fn test_save_and_restore() {
+    let client = start();
+    let id = VehicleId::new(123_456_789);
+
+    let info = client
+        .save_snapshot("start")
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(info.name, "start");
+    assert_eq!(info.vehicles, 2);
+
+    let vehicle = FleetVehicle {
+        id: VehicleId::new(42),
+        vehicle_id: VehicleGuid::new(4242),
+        vin: "7SAYGDEE5PA000042".to_string(),
+        display_name: "Test Model Y".to_string(),
+        model: VehicleModel::ModelY,
+        trim: None,
+        color: None,
+        option_codes: None,
+        location: FleetLocation::default(),
+        battery: FleetBattery::default(),
+        owner: DEFAULT_USER,
+        drivers: vec![],
+        state: SimulationStateEnum::Idle,
+    };
+    client.add_vehicle(&vehicle).await.unwrap();
+    client
+        .simulate(id, SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+    client
+        .add_fault(id, &Fault::new(FaultKind::DeviceNotAvailable))
+        .await
+        .unwrap();
+    client.advance(Duration::from_mins(1)).await.unwrap();
+
+    client.restore_snapshot("start").await.unwrap();
+
+    let statuses = client
+        .simulator_vehicles()
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    let ids: Vec<_> = statuses.iter().map(|s| s.vehicle.id).collect();
+    assert_eq!(ids, [id, VehicleId::new(123_456_000)]);
+
+    let status = &statuses[0];
+    assert_eq!(status.simulation_state, SimulationStateEnum::Idle);
+    assert!((status.battery_level - 42.0).abs() < f64::EPSILON);
+    assert!(status.faults.is_empty());
+}
+
+#[tokio::test]
+async fn test_restore_keeps_driving() {
+    let client = start();
+    let id = VehicleId::new(123_456_789);
+
+    client
+        .simulate(id, SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+    client.advance(Duration::from_mins(1)).await.unwrap();
+    client.save_snapshot("driving").await.unwrap();
+
+    let saved = client
+        .simulator_vehicle(id)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+
+    client
+        .simulate(id, SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+    client.restore_snapshot("driving").await.unwrap();
+
+    let status = client
+        .simulator_vehicle(id)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(status.simulation_state, SimulationStateEnum::Driving);
+    assert!((status.battery_level - saved.battery_level).abs() < f64::EPSILON);
+
+    client.advance(Duration::from_mins(1)).await.unwrap();
+    let status = client
+        .simulator_vehicle(id)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(status.simulation_state, SimulationStateEnum::Driving);
+    assert!(status.battery_level < saved.battery_level);
+}
+
+#[tokio::test]
+async fn test_list_and_delete() {
+    let client = start();
+
+    client.save_snapshot("b").await.unwrap();
+    client.save_snapshot("a").await.unwrap();
+
+    let names: Vec<_> = client
+        .snapshots()
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap()
+        .into_iter()
+        .map(|info| info.name)
+        .collect();
+    assert_eq!(names, ["a", "b"]);
+
+    client.delete_snapshot("a").await.unwrap();
+    let status = client
+        .restore_snapshot("a")
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 404);
+
+    let status = client
+        .save_snapshot("not.valid")
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 400);
+}
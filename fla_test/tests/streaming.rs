@@ -1,18 +1,38 @@
+//! Tests for the streaming API
 #![allow(clippy::unwrap_used)]
 #![allow(clippy::expect_used)]
 
+use std::{collections::HashSet, time::Duration};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, TimeZone, Utc};
+use fla_client::{Backoff, StreamingError, StreamingEvent};
 use fla_common::{
     simulator::SimulationStateEnum,
-    streaming::StreamingFields,
-    types::{VehicleGuid, VehicleId},
+    streaming::{
+        ErrorType, FromServerStreamingMessage, StreamingData, StreamingFields, StreamingOptions,
+        ToServerStreamingMessage,
+    },
+    types::{ShiftState, VehicleDataEndpoint, VehicleGuid, VehicleId},
+};
+use fla_test::{
+    get_token_expiring_at, get_token_for_all_scopes, next_event, next_row, start_manual_server,
+    ManualServer,
 };
-use fla_test::get_client;
+use futures_util::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_tungstenite::{connect_async, tungstenite::Message, MaybeTlsStream, WebSocketStream};
 
-#[tokio::test]
-async fn test_streaming() {
-    let client = get_client();
+fn id() -> VehicleId {
+    VehicleId::new(123_456_000)
+}
+
+fn guid() -> VehicleGuid {
+    VehicleGuid::new(999_456_000)
+}
 
-    let fields = vec![
+fn fields() -> Vec<StreamingFields> {
+    vec![
         StreamingFields::Speed,
         StreamingFields::Odometer,
         StreamingFields::Soc,
@@ -25,25 +45,692 @@ async fn test_streaming() {
         StreamingFields::Range,
         StreamingFields::EstRange,
         StreamingFields::Heading,
-    ];
+        StreamingFields::NativeLatitude,
+        StreamingFields::NativeLongitude,
+        StreamingFields::NativePower,
+        StreamingFields::NativeType,
+        StreamingFields::NativeLocationSupported,
+    ]
+}
+
+fn start_time() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap()
+}
+
+/// Drive for a few seconds, returning every streaming row and the final vehicle data
+async fn drive(seed: u64, seconds: u32) -> (Vec<StreamingData>, String) {
+    let client = start_manual_server(start_time(), seed);
+
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+
+    let mut streaming = client.streaming(guid(), fields());
+    let mut rows = vec![next_row(&mut streaming).await];
+
+    for _ in 0..seconds {
+        client.advance(Duration::from_secs(1)).await.unwrap();
+        rows.push(next_row(&mut streaming).await);
+    }
+
+    let endpoints: HashSet<_> = [
+        VehicleDataEndpoint::ChargeState,
+        VehicleDataEndpoint::DriveState,
+        VehicleDataEndpoint::LocationData,
+        VehicleDataEndpoint::VehicleState,
+    ]
+    .into();
+
+    let vehicle_data = client
+        .get_vehicle_data(id(), &endpoints)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+
+    (rows, format!("{vehicle_data:?}"))
+}
+
+#[tokio::test]
+async fn test_streaming() {
+    let start = start_time().timestamp_millis();
+    let (rows, _) = drive(42, 3).await;
+
+    assert_eq!(rows.len(), 4);
+
+    // The first row is sent as soon as we subscribe, before the car has moved.
+    assert_eq!(rows[0].id, guid());
+    assert_eq!(rows[0].time, start);
+    assert_eq!(rows[0].shift_state, None);
+
+    for (row, seconds) in rows[1..].iter().zip(1..) {
+        assert_eq!(row.id, guid());
+        assert_eq!(row.time, start + seconds * 1000);
+        assert_eq!(row.shift_state, Some(ShiftState::Drive));
+        assert_eq!(row.power, Some(500));
+
+        let speed = row.speed.unwrap();
+        assert!((50.0..=70.0).contains(&speed), "speed {speed} out of range");
+
+        assert_eq!(row.native_latitude, row.est_lat);
+        assert_eq!(row.native_longitude, row.est_lng);
+        assert_eq!(row.native_power, row.power);
+        assert_eq!(row.native_type.as_deref(), Some("wgs"));
+        assert_eq!(row.native_location_supported, Some(1));
+    }
+
+    // The car moves north, so it should be further north every second.
+    let latitudes: Vec<f64> = rows[1..].iter().map(|x| x.est_lat.unwrap()).collect();
+    assert!(latitudes.windows(2).all(|x| x[0] < x[1]));
+}
+
+#[tokio::test]
+async fn test_streaming_is_deterministic() {
+    let (rows_1, data_1) = drive(42, 5).await;
+    let (rows_2, data_2) = drive(42, 5).await;
+
+    assert_eq!(format!("{rows_1:?}"), format!("{rows_2:?}"));
+    assert_eq!(data_1, data_2);
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+/// Connect to the streaming API without the client, to see every message
+async fn connect(server: &ManualServer) -> Socket {
+    let (mut socket, _) = connect_async(server.streaming_url()).await.unwrap();
+
+    let Some(FromServerStreamingMessage::ControlHello { connection_timeout }) =
+        next_message(&mut socket).await
+    else {
+        panic!("expected hello");
+    };
+    assert_eq!(connection_timeout, 30_000);
+
+    socket
+}
+
+async fn subscribe(socket: &mut Socket) {
+    subscribe_fields(socket, "speed").await;
+}
+
+async fn subscribe_fields(socket: &mut Socket, fields: &str) {
+    subscribe_with_options(socket, fields, StreamingOptions::default()).await;
+}
+
+async fn subscribe_with_options(socket: &mut Socket, fields: &str, options: StreamingOptions) {
+    let msg = ToServerStreamingMessage::DataSubscribeOauth {
+        token: get_token_for_all_scopes().access_token,
+        value: fields.to_string(),
+        tag: guid().to_string(),
+        options,
+    };
+    let msg = serde_json::to_string(&msg).unwrap();
+    socket.send(Message::Text(msg)).await.unwrap();
+}
+
+/// The next message from the server, `None` if the connection was closed
+async fn next_message(socket: &mut Socket) -> Option<FromServerStreamingMessage> {
+    let msg = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .unwrap();
+    match msg {
+        Some(Ok(Message::Binary(msg))) => Some(serde_json::from_slice(&msg).unwrap()),
+        Some(Ok(Message::Close(_)) | Err(_)) | None => None,
+        Some(Ok(msg)) => panic!("unexpected message {msg:?}"),
+    }
+}
+
+fn assert_disconnected(msg: Option<FromServerStreamingMessage>) {
+    let Some(FromServerStreamingMessage::DataError(error)) = msg else {
+        panic!("expected error, got {msg:?}");
+    };
+    assert_eq!(error.tag, guid().to_string());
+    assert!(matches!(error.error_type, ErrorType::VehicleDisconnected));
+}
+
+#[tokio::test]
+async fn test_streaming_sleeping() {
+    let server = ManualServer::start(start_time(), 0);
+    let client = server.client(get_token_for_all_scopes());
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+
+    let mut socket = connect(&server).await;
+    subscribe(&mut socket).await;
+    assert_disconnected(next_message(&mut socket).await);
+
+    // The connection stays open until it has been idle for the timeout
+    client.advance(Duration::from_secs(29)).await.unwrap();
+    subscribe(&mut socket).await;
+    assert_disconnected(next_message(&mut socket).await);
+
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    assert!(next_message(&mut socket).await.is_none());
+}
+
+#[tokio::test]
+async fn test_streaming_goes_quiet() {
+    let server = ManualServer::start(start_time(), 0);
+    let client = server.client(get_token_for_all_scopes());
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+
+    let options = StreamingOptions {
+        change_only: true,
+        ..StreamingOptions::default()
+    };
+    let mut socket = connect(&server).await;
+    subscribe_with_options(&mut socket, "speed", options).await;
+    assert!(matches!(
+        next_message(&mut socket).await,
+        Some(FromServerStreamingMessage::DataUpdate { .. })
+    ));
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    assert!(matches!(
+        next_message(&mut socket).await,
+        Some(FromServerStreamingMessage::DataUpdate { .. })
+    ));
+
+    // A parked car sends the same data every second, which is not sent when only changes are
+    client
+        .simulate(id(), SimulationStateEnum::Idle)
+        .await
+        .unwrap();
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    assert!(matches!(
+        next_message(&mut socket).await,
+        Some(FromServerStreamingMessage::DataUpdate { .. })
+    ));
+    client.advance(Duration::from_secs(30)).await.unwrap();
+    assert_disconnected(next_message(&mut socket).await);
+
+    client.advance(Duration::from_secs(30)).await.unwrap();
+    assert!(next_message(&mut socket).await.is_none());
+}
+
+#[tokio::test]
+async fn test_streaming_parked() {
+    let start = start_time().timestamp_millis();
+    let client = start_manual_server(start_time(), 0);
+    client
+        .simulate(id(), SimulationStateEnum::Idle)
+        .await
+        .unwrap();
+
+    let mut streaming = client.streaming(guid(), fields());
+    let row = next_row(&mut streaming).await;
+    assert_eq!(row.time, start);
+    assert_eq!(row.shift_state, None);
+    assert!(row.soc.is_some());
+
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    let row = next_row(&mut streaming).await;
+    assert_eq!(row.time, start + 1000);
+    assert_eq!(row.shift_state, None);
+
+    let metrics = client.metrics().await.unwrap().get_response().unwrap();
+    let streaming = metrics.streaming;
+    assert_eq!(streaming.rows_sent + streaming.rows_skipped, 2);
+}
+
+#[tokio::test]
+async fn test_streaming_interval() {
+    let start = start_time().timestamp_millis();
+    let client = start_manual_server(start_time(), 0);
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+
+    let options = StreamingOptions {
+        interval_ms: Some(3000),
+        ..StreamingOptions::default()
+    };
+    let mut streaming = client.streaming_with_options(guid(), fields(), options);
+    assert_eq!(next_row(&mut streaming).await.time, start);
+
+    for _ in 0..6 {
+        client.advance(Duration::from_secs(1)).await.unwrap();
+    }
+    assert_eq!(next_row(&mut streaming).await.time, start + 3000);
+    assert_eq!(next_row(&mut streaming).await.time, start + 6000);
+}
+
+#[tokio::test]
+async fn test_streaming_change_only() {
+    let start = start_time().timestamp_millis();
+    let client = start_manual_server(start_time(), 0);
+    client
+        .simulate(id(), SimulationStateEnum::Idle)
+        .await
+        .unwrap();
+
+    let options = StreamingOptions {
+        change_only: true,
+        ..StreamingOptions::default()
+    };
+    let fields = vec![StreamingFields::Soc, StreamingFields::ShiftState];
+    let mut streaming = client.streaming_with_options(guid(), fields, options);
+    let row = next_row(&mut streaming).await;
+    assert_eq!(row.time, start);
+    assert_eq!(row.shift_state, None);
+
+    // The parked row a second later is the same, so the next row is when the car drives off
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    let row = next_row(&mut streaming).await;
+    assert_eq!(row.time, start + 2000);
+    assert_eq!(row.shift_state, Some(ShiftState::Drive));
+}
+
+#[tokio::test]
+async fn test_streaming_resubscribes() {
+    let start = start_time().timestamp_millis();
+    let client = start_manual_server(start_time(), 0);
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+
+    let mut streaming = client.streaming(guid(), fields());
+    let event = next_event(&mut streaming).await;
+    let Some(Ok(StreamingEvent::Hello { connection_timeout })) = event else {
+        panic!("expected hello, got {event:?}");
+    };
+    assert_eq!(connection_timeout, Duration::from_secs(30));
+    let event = next_event(&mut streaming).await;
+    let Some(Ok(StreamingEvent::Error(error))) = event else {
+        panic!("expected an error, got {event:?}");
+    };
+    assert!(matches!(error.error_type, ErrorType::VehicleDisconnected));
+
+    // The client keeps trying until the car wakes up
+    client
+        .simulate(id(), SimulationStateEnum::Idle)
+        .await
+        .unwrap();
+    let row = loop {
+        match next_event(&mut streaming).await {
+            Some(Ok(StreamingEvent::Data(row))) => break row,
+            Some(Ok(StreamingEvent::Error(_))) => {}
+            event => panic!("expected data, got {event:?}"),
+        }
+    };
+    assert_eq!(row.time, start);
+}
+
+#[tokio::test]
+async fn test_streaming_session() {
+    let start = start_time().timestamp_millis();
+    let client = start_manual_server(start_time(), 0);
+    let other_id = VehicleId::new(123_456_789);
+    let other_guid = VehicleGuid::new(999_456_789);
+    for id in [id(), other_id] {
+        client
+            .simulate(id, SimulationStateEnum::Driving)
+            .await
+            .unwrap();
+    }
+
+    // Each vehicle gets its own fields, over the same connection
+    let session = client.streaming_session();
+    let mut speed = session.add(guid(), vec![StreamingFields::Speed]);
+    let mut soc = session.add(other_guid, vec![StreamingFields::Soc]);
+
+    let row = next_row(&mut speed).await;
+    assert_eq!(row.id, guid());
+    assert!(row.speed.is_some());
+    assert_eq!(row.soc, None);
+
+    let row = next_row(&mut soc).await;
+    assert_eq!(row.id, other_guid);
+    assert!(row.soc.is_some());
+    assert_eq!(row.speed, None);
+
+    let metrics = client.metrics().await.unwrap().get_response().unwrap();
+    assert_eq!(metrics.streaming.connections, 1);
+
+    // Removing a vehicle ends its stream, and the other carries on
+    session.remove(other_guid);
+    assert!(next_event(&mut soc).await.is_none());
+
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    let row = next_row(&mut speed).await;
+    assert_eq!(row.id, guid());
+    assert_eq!(row.time, start + 1000);
+}
+
+#[tokio::test]
+async fn test_streaming_session_rejected() {
+    let start = start_time().timestamp_millis();
+    let client = start_manual_server(start_time(), 0);
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+
+    // There is no such vehicle, so the server rejects it
+    let session = client.streaming_session();
+    let mut speed = session.add(guid(), vec![StreamingFields::Speed]);
+    let mut unknown = session.add(VehicleGuid::new(42), vec![StreamingFields::Speed]);
+    next_row(&mut speed).await;
+
+    loop {
+        match next_event(&mut unknown).await {
+            Some(Err(StreamingError::Rejected(error))) => {
+                assert!(matches!(error.error_type, ErrorType::ClientError));
+                break;
+            }
+            Some(Ok(StreamingEvent::Hello { .. })) => {}
+            event => panic!("expected rejected, got {event:?}"),
+        }
+    }
+    assert!(next_event(&mut unknown).await.is_none());
+
+    // The other vehicle carries on over the same connection
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    let row = next_row(&mut speed).await;
+    assert_eq!(row.time, start + 1000);
+    let metrics = client.metrics().await.unwrap().get_response().unwrap();
+    assert_eq!(metrics.streaming.connections, 1);
+}
+
+#[tokio::test]
+async fn test_streaming_slow_reader() {
+    let client = start_manual_server(start_time(), 0);
+    let other_id = VehicleId::new(123_456_789);
+    let other_guid = VehicleGuid::new(999_456_789);
+    for id in [id(), other_id] {
+        client
+            .simulate(id, SimulationStateEnum::Driving)
+            .await
+            .unwrap();
+    }
 
-    let id = VehicleId::new(123_456_000);
-    let guid = VehicleGuid::new(999_456_000);
-    let mut streaming = client.streaming(guid, fields).unwrap();
+    // Nobody reads the stream of the other vehicle, that does not hold up this one
+    let session = client.streaming_session();
+    let mut speed = session.add(guid(), vec![StreamingFields::Speed]);
+    let mut unread = session.add(other_guid, vec![StreamingFields::Speed]);
+    next_row(&mut speed).await;
+    for _ in 0..20 {
+        client.advance(Duration::from_secs(1)).await.unwrap();
+        next_row(&mut speed).await;
+    }
+
+    // Once it is read, the other stream says how many events it missed
+    for _ in 0..10 {
+        next_event(&mut unread).await.unwrap().unwrap();
+    }
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    let event = next_event(&mut unread).await;
+    assert!(
+        matches!(event, Some(Ok(StreamingEvent::Lagged { skipped })) if skipped > 0),
+        "expected lagged, got {event:?}"
+    );
+    assert_eq!(next_row(&mut unread).await.id, other_guid);
+}
 
+#[tokio::test]
+async fn test_streaming_refreshed_token() {
+    let server = ManualServer::start(start_time(), 0);
+    // The server stops accepting the token a couple of seconds from now
+    let expires_at = Utc::now() - chrono::Duration::seconds(58);
+    let client = server.client(get_token_expiring_at(expires_at));
     client
-        .simulate(id, SimulationStateEnum::Driving)
+        .simulate(id(), SimulationStateEnum::Driving)
         .await
         .unwrap();
 
-    // FIXME: This is yuck
-    let mut iteration = 0;
-    while let Some(msg) = streaming.recv().await {
-        println!("Received: {msg:?}");
+    let mut streaming = client.streaming(guid(), vec![StreamingFields::Speed]);
+    next_row(&mut streaming).await;
+
+    client.refresh_token().await.unwrap();
+    tokio::time::sleep(Duration::from_secs(3)).await;
+
+    // Without subscriptions the server closes the connection, and the client opens a new one
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+    client.advance(Duration::from_secs(30)).await.unwrap();
+    loop {
+        match next_event(&mut streaming).await {
+            Some(Ok(StreamingEvent::Reconnecting { .. })) => break,
+            Some(Ok(StreamingEvent::Error(_) | StreamingEvent::Hello { .. })) => {}
+            event => panic!("expected reconnecting, got {event:?}"),
+        }
+    }
 
-        if iteration > 1 {
-            break;
+    // The old token has expired, so subscribing again needs the new one
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+    loop {
+        match next_event(&mut streaming).await {
+            Some(Ok(StreamingEvent::Data(_))) => break,
+            Some(Ok(_) | Err(StreamingError::Connection(_))) => {}
+            event => panic!("expected data, got {event:?}"),
         }
-        iteration += 1;
+    }
+}
+
+#[tokio::test]
+async fn test_streaming_reconnects() {
+    // Nothing is listening on the port once the listener is dropped
+    let port = TcpListener::bind("127.0.0.1:0")
+        .await
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let backoff = Backoff {
+        initial: Duration::from_millis(10),
+        max: Duration::from_millis(20),
+    };
+    let client = fla_client::Config::new()
+        .streaming_url(format!("ws://127.0.0.1:{port}/streaming/"))
+        .streaming_backoff(backoff)
+        .token(get_token_for_all_scopes())
+        .build()
+        .unwrap();
+
+    let mut streaming = client.streaming(guid(), fields());
+    for (attempt, delay) in [(1, 10), (2, 20), (3, 20)] {
+        assert!(matches!(
+            next_event(&mut streaming).await,
+            Some(Err(StreamingError::Connect(_)))
+        ));
+        let event = next_event(&mut streaming).await;
+        let Some(Ok(StreamingEvent::Reconnecting {
+            attempt: actual_attempt,
+            delay: actual_delay,
+        })) = event
+        else {
+            panic!("expected reconnecting, got {event:?}");
+        };
+        assert_eq!(actual_attempt, attempt);
+        assert_eq!(actual_delay, Duration::from_millis(delay));
+    }
+}
+
+#[tokio::test]
+async fn test_streaming_falls_asleep() {
+    let server = ManualServer::start(start_time(), 0);
+    let client = server.client(get_token_for_all_scopes());
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+
+    let mut socket = connect(&server).await;
+    subscribe(&mut socket).await;
+    next_message(&mut socket).await.unwrap();
+
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+    assert_disconnected(next_message(&mut socket).await);
+}
+
+#[tokio::test]
+async fn test_streaming_unknown_fields() {
+    let server = ManualServer::start(start_time(), 0);
+    let mut socket = connect(&server).await;
+    subscribe_fields(&mut socket, "speed,spede,native_type,soc_").await;
+
+    let Some(FromServerStreamingMessage::DataError(error)) = next_message(&mut socket).await else {
+        panic!("expected error");
+    };
+    assert_eq!(error.tag, guid().to_string());
+    assert!(matches!(error.error_type, ErrorType::ClientError));
+    assert_eq!(error.value, "Unknown fields: spede,soc_");
+}
+
+#[tokio::test]
+async fn test_streaming_bad_subscription() {
+    let server = ManualServer::start(start_time(), 0);
+    let client = server.client(get_token_for_all_scopes());
+    let other_id = VehicleId::new(123_456_789);
+    let other_guid = VehicleGuid::new(999_456_789);
+    client
+        .simulate(other_id, SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+
+    let mut socket = connect(&server).await;
+    for (guid, fields) in [(other_guid, "speed"), (guid(), "spede")] {
+        let msg = ToServerStreamingMessage::DataSubscribeOauth {
+            token: get_token_for_all_scopes().access_token,
+            value: fields.to_string(),
+            tag: guid.to_string(),
+            options: StreamingOptions::default(),
+        };
+        let msg = serde_json::to_string(&msg).unwrap();
+        socket.send(Message::Text(msg)).await.unwrap();
+    }
+
+    let Some(FromServerStreamingMessage::DataUpdate { tag, .. }) = next_message(&mut socket).await
+    else {
+        panic!("expected data");
+    };
+    assert_eq!(tag, other_guid.to_string());
+    let Some(FromServerStreamingMessage::DataError(error)) = next_message(&mut socket).await else {
+        panic!("expected error");
+    };
+    assert_eq!(error.tag, guid().to_string());
+    assert!(matches!(error.error_type, ErrorType::ClientError));
+
+    // The typo only ends its own subscription, the connection stays open for the other one
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    let Some(FromServerStreamingMessage::DataUpdate { tag, .. }) = next_message(&mut socket).await
+    else {
+        panic!("expected data");
+    };
+    assert_eq!(tag, other_guid.to_string());
+}
+
+/// A legacy `data:subscribe` token
+fn vehicle_token(email: &str, token: &str) -> String {
+    STANDARD.encode(format!("{email}:{token}"))
+}
+
+#[tokio::test]
+async fn test_streaming_with_vehicle_token() {
+    let server = ManualServer::start(start_time(), 0);
+    let client = server.client(get_token_for_all_scopes());
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+
+    let vehicle = client
+        .get_vehicle(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    let token = &vehicle.tokens[0];
+
+    let mut streaming =
+        client.streaming_with_vehicle_token(guid(), "TEST@example.com", token, fields());
+    let row = next_row(&mut streaming).await;
+    assert_eq!(row.id, guid());
+
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    let row = next_row(&mut streaming).await;
+    assert_eq!(row.shift_state, Some(ShiftState::Drive));
+}
+
+#[tokio::test]
+async fn test_streaming_vehicle_data_token() {
+    let client = start_manual_server(start_time(), 0);
+    client
+        .simulate(id(), SimulationStateEnum::IdleNoSleep)
+        .await
+        .unwrap();
+
+    // The tokens in the vehicle data are the ones the streaming server checks
+    let endpoints: HashSet<_> = [VehicleDataEndpoint::ChargeState].into();
+    let data = client
+        .get_vehicle_data(id(), &endpoints)
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert!(!data.tokens.is_empty());
+
+    for token in &data.tokens {
+        let mut streaming =
+            client.streaming_with_vehicle_token(guid(), "test@example.com", token, fields());
+        let row = next_row(&mut streaming).await;
+        assert_eq!(row.id, guid());
+    }
+}
+
+#[tokio::test]
+async fn test_streaming_invalid_vehicle_token() {
+    let server = ManualServer::start(start_time(), 0);
+    let client = server.client(get_token_for_all_scopes());
+    let vehicle = client
+        .get_vehicle(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    let token = &vehicle.tokens[0];
+
+    for token in [
+        vehicle_token("test@example.com", "0000000000000000"),
+        vehicle_token("nobody@example.com", token),
+        "not base64!".to_string(),
+    ] {
+        let mut socket = connect(&server).await;
+        let msg = ToServerStreamingMessage::DataSubscribe {
+            token,
+            value: "speed".to_string(),
+            tag: guid().to_string(),
+            options: StreamingOptions::default(),
+        };
+        let msg = serde_json::to_string(&msg).unwrap();
+        socket.send(Message::Text(msg)).await.unwrap();
+
+        let Some(FromServerStreamingMessage::DataError(error)) = next_message(&mut socket).await
+        else {
+            panic!("expected error");
+        };
+        assert_eq!(error.tag, guid().to_string());
+        assert!(matches!(error.error_type, ErrorType::ClientError));
+        assert_eq!(error.value, "Can't validate token. ");
     }
 }
@@ -0,0 +1,287 @@
+//! Tests for Fleet Telemetry emulation
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::time::Duration;
+
+use chrono::{DateTime, TimeZone, Utc};
+use fla_client::Client;
+use fla_common::{
+    simulator::SimulationStateEnum,
+    telemetry::{Field, FieldConfig, FleetTelemetryConfig, Payload, Value},
+    types::VehicleId,
+};
+use fla_server::simulator::data::DRIVER_USER;
+use fla_test::{get_token_expiring_at, get_token_for_all_scopes, ManualServer};
+use futures_util::StreamExt;
+use prost::Message as _;
+use tokio::{net::TcpListener, sync::mpsc};
+use tokio_tungstenite::tungstenite::Message;
+
+const VIN: &str = "5YJ3E1EA7JF000789";
+
+fn id() -> VehicleId {
+    VehicleId::new(123_456_789)
+}
+
+fn start_time() -> DateTime<Utc> {
+    Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap()
+}
+
+fn start() -> (ManualServer, Client) {
+    let server = ManualServer::start(start_time(), 0);
+    let client = server.client(get_token_for_all_scopes());
+    (server, client)
+}
+
+fn config(hostname: &str, port: u16, fields: &[(Field, u32)]) -> FleetTelemetryConfig {
+    FleetTelemetryConfig {
+        hostname: hostname.to_string(),
+        port,
+        ca: None,
+        fields: fields
+            .iter()
+            .map(|(field, interval_seconds)| {
+                (
+                    *field,
+                    FieldConfig {
+                        interval_seconds: *interval_seconds,
+                    },
+                )
+            })
+            .collect(),
+        alert_types: vec![],
+        exp: None,
+    }
+}
+
+async fn next(records: &mut mpsc::Receiver<Payload>) -> Payload {
+    tokio::time::timeout(Duration::from_secs(5), records.recv())
+        .await
+        .unwrap()
+        .unwrap()
+}
+
+fn created_at(payload: &Payload) -> DateTime<Utc> {
+    Option::<DateTime<Utc>>::from(payload.created_at.unwrap()).unwrap()
+}
+
+fn status<T>(result: Result<T, fla_client::Error>) -> u16 {
+    result.map(|_| ()).unwrap_err().status().unwrap().as_u16()
+}
+
+#[tokio::test]
+async fn test_telemetry_config() {
+    let (server, client) = start();
+
+    let status_before = client
+        .fleet_telemetry_config(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert!(!status_before.synced);
+    assert!(status_before.config.is_none());
+
+    let config = config("telemetry.example.com", 443, &[(Field::VehicleSpeed, 10)]);
+    let updated = client
+        .set_fleet_telemetry_config(vec![VIN.to_string()], config.clone())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(updated.updated_vehicles, 1);
+
+    let status_after = client
+        .fleet_telemetry_config(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert!(status_after.synced);
+    assert_eq!(status_after.config, Some(config.clone()));
+
+    client.delete_fleet_telemetry_config(id()).await.unwrap();
+    let status_deleted = client
+        .fleet_telemetry_config(id())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert!(status_deleted.config.is_none());
+
+    // Unknown vehicles, and vehicles not shared with the user, cannot be configured
+    assert_eq!(
+        status(
+            client
+                .set_fleet_telemetry_config(vec!["nope".to_string()], config.clone())
+                .await
+        ),
+        404
+    );
+    let token = client.user_token(DRIVER_USER).await.unwrap();
+    let driver = server.client(token.into());
+    assert_eq!(
+        status(
+            driver
+                .set_fleet_telemetry_config(vec![VIN.to_string()], config)
+                .await
+        ),
+        404
+    );
+
+    let invalid = self::config("telemetry.example.com", 443, &[(Field::VehicleSpeed, 0)]);
+    assert_eq!(
+        status(
+            client
+                .set_fleet_telemetry_config(vec![VIN.to_string()], invalid)
+                .await
+        ),
+        400
+    );
+}
+
+#[tokio::test]
+async fn test_builtin_receiver() {
+    let (_server, client) = start();
+    let mut records = client.telemetry().await.unwrap();
+
+    client
+        .simulate(id(), SimulationStateEnum::Driving)
+        .await
+        .unwrap();
+    let config = config(
+        "telemetry.example.com",
+        443,
+        &[
+            (Field::VehicleSpeed, 1),
+            (Field::Location, 1),
+            (Field::BatteryLevel, 10),
+        ],
+    );
+    client
+        .set_fleet_telemetry_config(vec![VIN.to_string()], config)
+        .await
+        .unwrap();
+
+    // Every field is due straight away
+    let first = next(&mut records).await;
+    assert_eq!(first.vin, VIN);
+    assert_eq!(created_at(&first), start_time());
+    assert!(!first.is_resend);
+    assert!(matches!(
+        first.get(Field::Location),
+        Some(Value::LocationValue(_))
+    ));
+    assert!(matches!(
+        first.get(Field::BatteryLevel),
+        Some(Value::DoubleValue(_))
+    ));
+
+    // Then each field at its own interval
+    client.advance(Duration::from_secs(1)).await.unwrap();
+    let second = next(&mut records).await;
+    assert_eq!(
+        created_at(&second),
+        start_time() + chrono::Duration::seconds(1)
+    );
+    assert!(matches!(
+        second.get(Field::VehicleSpeed),
+        Some(Value::DoubleValue(speed)) if *speed > 0.0
+    ));
+    assert!(second.get(Field::Location).is_some());
+    assert!(second.get(Field::BatteryLevel).is_none());
+}
+
+#[tokio::test]
+async fn test_no_records_while_asleep() {
+    let (_server, client) = start();
+    let mut records = client.telemetry().await.unwrap();
+
+    client
+        .simulate(id(), SimulationStateEnum::Sleeping)
+        .await
+        .unwrap();
+    let config = config("telemetry.example.com", 443, &[(Field::BatteryLevel, 10)]);
+    client
+        .set_fleet_telemetry_config(vec![VIN.to_string()], config)
+        .await
+        .unwrap();
+    client.advance(Duration::from_mins(2)).await.unwrap();
+
+    // The first record is sent when the vehicle wakes up
+    client
+        .simulate(id(), SimulationStateEnum::Idle)
+        .await
+        .unwrap();
+    let first = next(&mut records).await;
+    assert_eq!(
+        created_at(&first),
+        start_time() + chrono::Duration::seconds(120)
+    );
+}
+
+#[tokio::test]
+async fn test_telemetry_needs_admin() {
+    let (server, owner) = start();
+    let token = owner.user_token(DRIVER_USER).await.unwrap();
+    let driver = server.client(token.into());
+
+    // The records of every vehicle go to the reader, not only the ones the user can see.
+    let err = driver.telemetry().await.unwrap_err();
+    assert_eq!(err.status().unwrap().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn test_telemetry_renews_token() {
+    let server = ManualServer::start(start_time(), 0);
+    let mut token = get_token_expiring_at(Utc::now() - chrono::Duration::hours(1));
+    token.renew_at = Utc::now() - chrono::Duration::hours(1);
+    let client = server.client(token);
+
+    // The expired token is renewed before connecting
+    let mut records = client.telemetry().await.unwrap();
+
+    let config = config("telemetry.example.com", 443, &[(Field::BatteryLevel, 10)]);
+    client
+        .set_fleet_telemetry_config(vec![VIN.to_string()], config)
+        .await
+        .unwrap();
+    next(&mut records).await;
+}
+
+#[tokio::test]
+async fn test_local_receiver() {
+    let (_server, client) = start();
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    let config = config("127.0.0.1", port, &[(Field::Odometer, 10)]);
+    client
+        .set_fleet_telemetry_config(vec![VIN.to_string()], config)
+        .await
+        .unwrap();
+
+    let (stream, _) = tokio::time::timeout(Duration::from_secs(5), listener.accept())
+        .await
+        .unwrap()
+        .unwrap();
+    let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+
+    let Some(Ok(Message::Binary(msg))) = socket.next().await else {
+        panic!("Expected a binary message");
+    };
+    let payload = Payload::decode(msg.as_slice()).unwrap();
+    assert_eq!(payload.vin, VIN);
+    assert!(matches!(
+        payload.get(Field::Odometer),
+        Some(Value::DoubleValue(_))
+    ));
+
+    // The vehicle disconnects once it stops sending records
+    client.delete_fleet_telemetry_config(id()).await.unwrap();
+    let closed = tokio::time::timeout(Duration::from_secs(5), socket.next())
+        .await
+        .unwrap();
+    assert!(matches!(closed, None | Some(Ok(Message::Close(_)))));
+}
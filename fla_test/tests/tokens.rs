@@ -1,9 +1,22 @@
+//! Tests for the token API
 #![allow(clippy::unwrap_used)]
 
-use chrono::Utc;
-use fla_client::Token;
-use fla_server::tokens::{self, new_token, validate_access_token, validate_refresh_token};
-use std::collections::HashSet;
+use chrono::{TimeZone, Utc};
+use fla_client::{Client, Token};
+use fla_common::{simulator::DEFAULT_USER, types::VehicleId};
+use fla_server::{
+    simulator::data::DRIVER_USER,
+    tokens::{self, new_token, validate_access_token, validate_refresh_token},
+};
+use fla_test::{get_token_for_all_scopes, start_manual_server, ManualServer};
+use futures_util::future::join_all;
+use std::{
+    collections::HashSet,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
 
 #[tokio::test]
 async fn test_renew_token() {
@@ -16,7 +29,7 @@ async fn test_renew_token() {
         tokens::ScopeEnum::Openid,
         tokens::ScopeEnum::OfflineAccess,
         tokens::ScopeEnum::UserData,
-        // tokens::ScopeEnum::VehicleDeviceData,
+        tokens::ScopeEnum::VehicleDeviceData,
         tokens::ScopeEnum::VehicleCmds,
         tokens::ScopeEnum::VehicleChargingCmds,
         tokens::ScopeEnum::EnergyDeviceData,
@@ -25,11 +38,11 @@ async fn test_renew_token() {
     .into_iter()
     .collect::<HashSet<tokens::ScopeEnum>>();
 
-    let token: Token = new_token(&config, &scopes).unwrap().into();
+    let token: Token = new_token(&config, DEFAULT_USER, &scopes).unwrap().into();
     let old_expires_at = token.expires_at;
     let old_renew_at = token.renew_at;
 
-    let mut client = fla_test::get_client_with_token(token);
+    let client = fla_test::get_client_with_token(token);
     client.refresh_token().await.unwrap();
 
     let new_token = client.token();
@@ -38,25 +51,82 @@ async fn test_renew_token() {
     assert!(new_token.expires_at > old_expires_at);
     assert!(new_token.renew_at > old_renew_at);
 
-    // We do not expect user_data or vehicle_device_data to be in the scopes
-    let expected_scopes = [
-        tokens::ScopeEnum::Openid,
-        tokens::ScopeEnum::OfflineAccess,
-        // tokens::ScopeEnum::UserData,
-        // tokens::ScopeEnum::VehicleDeviceData,
-        tokens::ScopeEnum::VehicleCmds,
-        tokens::ScopeEnum::VehicleChargingCmds,
-        tokens::ScopeEnum::EnergyDeviceData,
-        tokens::ScopeEnum::EnergyCmds,
-    ]
-    .into_iter()
-    .collect::<HashSet<tokens::ScopeEnum>>();
-
+    // The client does not ask for user_data, but the new token keeps every scope of the old one
     let access_claims = validate_access_token(&new_token.access_token, &config).unwrap();
     assert_eq!(access_claims.purpose, tokens::Purpose::Access);
-    assert_eq!(access_claims.scopes, expected_scopes);
+    assert_eq!(access_claims.scopes, scopes);
 
     let refresh_claims = validate_refresh_token(&new_token.refresh_token, &config).unwrap();
     assert_eq!(refresh_claims.purpose, tokens::Purpose::Refresh);
-    assert_eq!(refresh_claims.scopes, expected_scopes);
+    assert_eq!(refresh_claims.scopes, scopes);
+}
+
+/// A client that counts how many times its token was updated
+fn counting_client(token: Token) -> (Client, Arc<AtomicUsize>) {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    let server = ManualServer::start(start, 0);
+    let updated = Arc::new(AtomicUsize::new(0));
+    let counter = updated.clone();
+    let client = server
+        .config()
+        .token(token)
+        .on_token_updated(move |_| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .build()
+        .unwrap();
+    (client, updated)
+}
+
+#[tokio::test]
+async fn test_refresh_before_renew_at() {
+    let token = Token {
+        renew_at: Utc::now() - chrono::Duration::minutes(1),
+        ..get_token_for_all_scopes()
+    };
+    let old_access_token = token.access_token.clone();
+    let (client, updated) = counting_client(token);
+
+    client
+        .get_vehicle(VehicleId::new(123_456_789))
+        .await
+        .unwrap();
+    assert_eq!(updated.load(Ordering::SeqCst), 1);
+
+    let token = client.token();
+    assert!(token.renew_at > Utc::now());
+    assert_ne!(token.access_token, old_access_token);
+
+    // The new token is not due, so it is not refreshed again
+    client
+        .get_vehicle(VehicleId::new(123_456_789))
+        .await
+        .unwrap();
+    assert_eq!(updated.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_refresh_on_unauthorized() {
+    let token = Token {
+        access_token: "revoked".to_string(),
+        ..get_token_for_all_scopes()
+    };
+    let (client, updated) = counting_client(token);
+
+    // Every request is rejected, but they share one refresh and all succeed on the retry
+    let results = join_all((0..5).map(|_| client.get_vehicle(VehicleId::new(123_456_789)))).await;
+    for result in results {
+        result.unwrap();
+    }
+    assert_eq!(updated.load(Ordering::SeqCst), 1);
+}
+
+#[tokio::test]
+async fn test_refresh_keeps_admin_scope() {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    let client = start_manual_server(start, 0);
+
+    // The client never asks for simulator_admin, the new token still has it
+    client.refresh_token().await.unwrap();
+    client.user_token(DRIVER_USER).await.unwrap();
 }
@@ -0,0 +1,153 @@
+//! Tests for simulated users, and vehicles shared with drivers
+#![allow(clippy::unwrap_used)]
+#![allow(clippy::expect_used)]
+
+use std::{collections::HashSet, time::Duration};
+
+use chrono::{TimeZone, Utc};
+use fla_client::Client;
+use fla_common::{
+    simulator::DEFAULT_USER,
+    types::{UserId, VehicleDataEndpoint, VehicleId},
+};
+use fla_server::simulator::data::DRIVER_USER;
+use fla_test::{get_token_for_all_scopes, ManualServer};
+
+fn shared() -> VehicleId {
+    VehicleId::new(123_456_000)
+}
+
+fn private() -> VehicleId {
+    VehicleId::new(123_456_789)
+}
+
+/// Start a server, returning clients for the owner and the driver
+async fn start() -> (Client, Client) {
+    let start = Utc.with_ymd_and_hms(2023, 11, 1, 9, 0, 0).unwrap();
+    let server = ManualServer::start(start, 0);
+    let owner = server.client(get_token_for_all_scopes());
+    let token = owner.user_token(DRIVER_USER).await.unwrap();
+    let driver = server.client(token.into());
+    (owner, driver)
+}
+
+fn endpoints() -> HashSet<VehicleDataEndpoint> {
+    [
+        VehicleDataEndpoint::DriveState,
+        VehicleDataEndpoint::LocationData,
+    ]
+    .into()
+}
+
+#[tokio::test]
+async fn test_users() {
+    let (owner, _) = start().await;
+
+    let users = owner.users().await.unwrap().get_response().unwrap();
+    let ids: Vec<_> = users.iter().map(|user| user.id).collect();
+    assert_eq!(ids, [DEFAULT_USER, DRIVER_USER]);
+
+    let status = owner
+        .user_token(UserId::new(1))
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 404);
+}
+
+#[tokio::test]
+async fn test_user_token_needs_admin() {
+    let (_, driver) = start().await;
+
+    // A user's token cannot be used to get a token for somebody else.
+    let status = driver
+        .user_token(DEFAULT_USER)
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 403);
+}
+
+#[tokio::test]
+async fn test_admin_needs_admin() {
+    let (_, driver) = start().await;
+
+    // A user's token cannot see every user's vehicles, or change the simulator.
+    let err = driver.simulator_vehicles().await.unwrap_err();
+    assert_eq!(err.status().unwrap().as_u16(), 403);
+
+    let err = driver.advance(Duration::from_secs(1)).await.unwrap_err();
+    assert_eq!(err.status().unwrap().as_u16(), 403);
+}
+
+#[tokio::test]
+async fn test_owner() {
+    let (owner, _) = start().await;
+
+    for id in [private(), shared()] {
+        let data = owner
+            .get_vehicle_data(id, &endpoints())
+            .await
+            .unwrap()
+            .get_response()
+            .unwrap();
+        assert_eq!(data.user_id, i64::from(DEFAULT_USER));
+        assert_eq!(data.access_type, "OWNER");
+        assert!(!data.granular_access.hide_private);
+        assert!(data.drive_state.unwrap().latitude.is_some());
+    }
+}
+
+#[tokio::test]
+async fn test_driver() {
+    let (_, driver) = start().await;
+
+    let vehicle = driver.get_vehicle(shared()).await.unwrap();
+    assert_eq!(vehicle.get_response().unwrap().id, shared());
+
+    let data = driver
+        .get_vehicle_data(shared(), &endpoints())
+        .await
+        .unwrap()
+        .get_response()
+        .unwrap();
+    assert_eq!(data.user_id, i64::from(DRIVER_USER));
+    assert_eq!(data.access_type, "DRIVER");
+    assert!(data.granular_access.hide_private);
+    let drive_state = data.drive_state.unwrap();
+    assert!(drive_state.latitude.is_none());
+    assert!(drive_state.longitude.is_none());
+
+    driver.wake_up(shared()).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_driver_not_shared() {
+    let (_, driver) = start().await;
+
+    let status = driver
+        .get_vehicle(private())
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 404);
+
+    let status = driver
+        .get_vehicle_data(private(), &endpoints())
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 404);
+
+    let status = driver
+        .wake_up(private())
+        .await
+        .unwrap_err()
+        .status()
+        .unwrap();
+    assert_eq!(status.as_u16(), 404);
+}
@@ -1,3 +1,4 @@
+//! Tests for the vehicles API
 #![allow(clippy::unwrap_used)]
 
 use fla_common::{